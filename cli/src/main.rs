@@ -65,8 +65,11 @@ async fn main() {
 
         match container.interact().await {
             Ok(status) => {
+                // Like a shell, report death by signal as 128 plus its number
                 if let Some(code) = status.code() {
                     std::process::exit(code);
+                } else if let Some(signal) = status.signal() {
+                    std::process::exit(128 + signal);
                 }
             }
             Err(err) => {
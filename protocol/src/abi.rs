@@ -34,6 +34,110 @@ pub const S_IFDIR: u32 = 0o040000;
 pub const S_IFCHR: u32 = 0o020000;
 pub const S_IFIFO: u32 = 0o010000;
 
+// setxattr(2) flags and limits, linux/xattr.h and linux/limits.h
+pub const XATTR_CREATE: i32 = 0x1;
+pub const XATTR_REPLACE: i32 = 0x2;
+pub const XATTR_NAME_MAX: usize = 255;
+pub const XATTR_SIZE_MAX: usize = 65536;
+
+// openat2(2) resolve flags, linux/openat2.h
+pub const RESOLVE_NO_XDEV: u64 = 0x01;
+pub const RESOLVE_NO_MAGICLINKS: u64 = 0x02;
+pub const RESOLVE_NO_SYMLINKS: u64 = 0x04;
+pub const RESOLVE_BENEATH: u64 = 0x08;
+pub const RESOLVE_IN_ROOT: u64 = 0x10;
+pub const RESOLVE_CACHED: u64 = 0x20;
+
+// size of the first struct open_how, with u64 flags, mode, and resolve
+pub const OPEN_HOW_SIZE_VER0: usize = 24;
+
+// most CPUs a container can be told about, the size of glibc's cpu_set_t
+pub const CPU_SETSIZE: usize = 1024;
+
+// longest path accepted by syscalls, including the nul, linux/limits.h
+pub const PATH_MAX: usize = 4096;
+
+// total size of execve(2) arguments and environment, as linux computes it
+// for the default 8 MiB stack rlimit
+pub const DEFAULT_ARG_MAX: usize = 2 * 1024 * 1024;
+
+/// x86_64 syscalls that pass through to the host kernel unless a container
+/// narrows the set
+///
+/// Everything else is emulated or denied. At most 64 entries, since the
+/// ones a container removes are sent as a bitmask.
+pub const PASSTHROUGH_SYSCALLS: &[u64] = &[
+    326, // copy_file_range
+    60,  // exit
+    231, // exit_group
+    202, // futex
+    97,  // getrlimit
+    8,   // lseek
+    319, // memfd_create
+    9,   // mmap
+    10,  // mprotect
+    25,  // mremap
+    11,  // munmap
+    35,  // nanosleep
+    7,   // poll
+    17,  // pread64
+    270, // pselect6
+    18,  // pwrite64
+    0,   // read
+    19,  // readv
+    219, // restart_syscall, after a signal interrupts a resumable sleep
+    13,  // rt_sigaction
+    14,  // rt_sigprocmask
+    15,  // rt_sigreturn
+    24,  // sched_yield
+    23,  // select
+    40,  // sendfile
+    273, // set_robust_list
+    131, // sigaltstack
+    201, // time
+    95,  // umask
+    1,   // write
+    20,  // writev
+    221, // fadvise64
+    // fixme: only allow pid==0 case
+    302, // prlimit64
+];
+
+/// x86_64 syscalls a container may add to the passthrough set
+///
+/// These are normally emulated or denied. None of them take a path or a pid,
+/// so on the host they only give up the sandbox's view of clocks, randomness,
+/// and open files. At most 64 entries, since the ones a container adds are
+/// sent as a bitmask.
+pub const OPTIONAL_PASSTHROUGH_SYSCALLS: &[u64] = &[
+    228, // clock_gettime
+    229, // clock_getres
+    230, // clock_nanosleep
+    96,  // gettimeofday
+    318, // getrandom
+    98,  // getrusage
+    100, // times
+    26,  // msync
+    27,  // mincore
+    74,  // fsync
+    75,  // fdatasync
+    73,  // flock
+    127, // rt_sigpending
+    128, // rt_sigtimedwait
+    130, // rt_sigsuspend
+    34,  // pause
+    271, // ppoll
+    291, // epoll_create1
+    233, // epoll_ctl
+    232, // epoll_wait
+    281, // epoll_pwait
+    290, // eventfd2
+    283, // timerfd_create
+    286, // timerfd_settime
+    287, // timerfd_gettime
+    324, // membarrier
+];
+
 #[derive(PartialEq, Eq, Ord, PartialOrd, Clone, Serialize, Deserialize)]
 #[repr(C)]
 pub struct Syscall {
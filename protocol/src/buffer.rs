@@ -14,6 +14,7 @@ pub enum Error {
     InvalidValue,
     Serialize,
     Deserialize,
+    MessageTooLarge,
 }
 
 impl fmt::Display for Error {
@@ -26,6 +27,13 @@ pub type Result<T> = core::result::Result<T, Error>;
 pub type BytesMax = U4096;
 pub type FilesMax = U128;
 
+/// Upper bound on the encoded size of any single message
+///
+/// Every message type has a small fixed encoding, so a partial message that
+/// has already buffered this many bytes can never complete. There's no
+/// framing to resync on, so the stream is treated as corrupt.
+pub const MAX_MESSAGE_BYTES: usize = 1024;
+
 #[derive(Default)]
 pub struct IPCBuffer {
     bytes: Queue<u8, BytesMax>,
@@ -153,7 +161,15 @@ impl<'a> IPCBuffer {
             self.bytes.range = saved_bytes_range;
             self.files.range = saved_files_range;
         }
-        result
+        match result {
+            Err(Error::UnexpectedEnd) if self.is_stalled() => Err(Error::MessageTooLarge),
+            result => result,
+        }
+    }
+
+    /// Would waiting for more data never let the front message finish?
+    fn is_stalled(&self) -> bool {
+        self.bytes.range.len() >= MAX_MESSAGE_BYTES || self.files.range.len() >= FilesMax::USIZE
     }
 
     pub fn extend_bytes(&mut self, data: &[u8]) -> Result<()> {
@@ -211,10 +211,13 @@ impl<'d, 'a> de::Deserializer<'d> for &'a mut IPCDeserializer<'d> {
     fn deserialize_enum<V: de::Visitor<'d>>(
         self,
         _name: &'static str,
-        _variants: &'static [&'static str],
+        variants: &'static [&'static str],
         visitor: V,
     ) -> Result<V::Value> {
-        visitor.visit_enum(self)
+        visitor.visit_enum(EnumAccess {
+            deserializer: self,
+            variant_count: variants.len(),
+        })
     }
 
     fn deserialize_newtype_struct<V: de::Visitor<'d>>(
@@ -259,14 +262,27 @@ impl<'d, 'a> de::VariantAccess<'d> for &'a mut IPCDeserializer<'d> {
     }
 }
 
-impl<'d, 'a> de::EnumAccess<'d> for &'a mut IPCDeserializer<'d> {
+struct EnumAccess<'d, 'a> {
+    deserializer: &'a mut IPCDeserializer<'d>,
+    variant_count: usize,
+}
+
+impl<'d, 'a> de::EnumAccess<'d> for EnumAccess<'d, 'a> {
     type Error = Error;
-    type Variant = Self;
+    type Variant = &'a mut IPCDeserializer<'d>;
 
-    fn variant_seed<V: de::DeserializeSeed<'d>>(self, seed: V) -> Result<(V::Value, Self)> {
-        let variant_index = self.input.pop_front_byte()?;
+    fn variant_seed<V: de::DeserializeSeed<'d>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Self::Variant)> {
+        let variant_index = self.deserializer.input.pop_front_byte()?;
+        // Checked here rather than by the visitor, so that a corrupt index
+        // is reported as a bad value instead of a generic serde error.
+        if variant_index as usize >= self.variant_count {
+            return Err(Error::InvalidValue);
+        }
         let variant = (variant_index as u32).into_deserializer();
         let v = de::DeserializeSeed::deserialize(seed, variant)?;
-        Ok((v, self))
+        Ok((v, self.deserializer))
     }
 }
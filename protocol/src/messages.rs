@@ -11,6 +11,11 @@ pub enum MessageToSand {
         args: SysFd,
         tracer_settings: TracerSettings,
     },
+    /// Start another process alongside the first, reading the same kind of
+    /// args as [MessageToSand::Init]
+    Spawn {
+        args: SysFd,
+    },
 }
 
 /// Any message sent from the sand process to the IPC server
@@ -29,6 +34,13 @@ pub struct InitArgsHeader {
     pub arg_count: usize,
     pub envp_len: usize,
     pub env_count: usize,
+    pub seccomp_rules: SeccompRuleList,
+    /// Bit `i` denies
+    /// [PASSTHROUGH_SYSCALLS](crate::abi::PASSTHROUGH_SYSCALLS)`[i]`
+    pub denied_passthrough: u64,
+    /// Bit `i` allows
+    /// [OPTIONAL_PASSTHROUGH_SYSCALLS](crate::abi::OPTIONAL_PASSTHROUGH_SYSCALLS)`[i]`
+    pub extra_passthrough: u64,
 }
 
 impl InitArgsHeader {
@@ -56,12 +68,27 @@ pub enum LogMessage {
     Emulated(abi::Syscall),
     Remote(abi::Syscall),
     Signal(u8, abi::UserRegs),
+    /// Ptrace requests the tracer has made so far, sent as each task exits
+    PtraceRequests(usize),
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
 pub struct TracerSettings {
     pub max_log_level: LogLevel,
     pub instruction_trace: bool,
+    pub extra_ioctls: IoctlList,
+    pub arg_max: usize,
+    pub uid: u32,
+    pub gid: u32,
+    pub cpus: u32,
+    pub hostname: UtsString,
+    pub kernel_release: UtsString,
+    pub memory_limit: u64,
+    // CLOCK_MONOTONIC nanoseconds when the container was launched
+    pub launch_time: u64,
+    // Optional passthrough syscalls the container asked for, as a bitmask over
+    // abi::OPTIONAL_PASSTHROUGH_SYSCALLS
+    pub extra_passthrough: u64,
 }
 
 /// A message delivered to one of the lightweight tasks in the tracer
@@ -80,8 +107,9 @@ pub enum FromTask {
     OpenProcess(SysPid),
     FileAccess {
         dir: Option<VFile>,
-        path: VString,
+        path: Option<VString>,
         mode: i32,
+        follow_links: FollowLinks,
     },
     FileOpen {
         dir: Option<VFile>,
@@ -98,6 +126,62 @@ pub enum FromTask {
     ProcessKill(VPid, Signal),
     ChangeWorkingDir(VString),
     GetWorkingDir,
-    Exited(i32),
+    Exited {
+        code: i32,
+        signal: Option<Signal>,
+    },
     Log(LogLevel, LogMessage),
+    GetXAttr {
+        file: Option<VFile>,
+        path: Option<VString>,
+        follow_links: FollowLinks,
+        name: VString,
+    },
+    SetXAttr {
+        file: Option<VFile>,
+        path: Option<VString>,
+        follow_links: FollowLinks,
+        name: VString,
+        value: VPtr,
+        size: usize,
+        flags: i32,
+    },
+    ListXAttr {
+        file: Option<VFile>,
+        path: Option<VString>,
+        follow_links: FollowLinks,
+    },
+    RemoveXAttr {
+        file: Option<VFile>,
+        path: Option<VString>,
+        follow_links: FollowLinks,
+        name: VString,
+    },
+    SeccompViolation(isize),
+    LoaderFailed(Errno),
+    FileOpenHow {
+        dir: Option<VFile>,
+        path: VString,
+        how: VPtr,
+        size: usize,
+    },
+    FdOpened {
+        fd: u32,
+        file: VFile,
+    },
+    FdDuplicated {
+        src: u32,
+        dest: u32,
+    },
+    FdClosed(u32),
+    FileUnlink {
+        dir: Option<VFile>,
+        path: VString,
+        flags: i32,
+    },
+    MakeDir {
+        dir: Option<VFile>,
+        path: VString,
+        mode: i32,
+    },
 }
@@ -115,6 +115,110 @@ fn incomplete_message() {
     assert!(buf.is_empty());
 }
 
+#[test]
+fn invalid_variant() {
+    let mut buf = buffer::IPCBuffer::new();
+    buf.push_back_byte(3).unwrap();
+    assert_eq!(
+        buf.pop_front::<MessageToSand>(),
+        Err(buffer::Error::InvalidValue)
+    );
+    assert_eq!(buf.as_slice().bytes, &[3]);
+}
+
+#[test]
+fn malformed_stream() {
+    let mut buf = buffer::IPCBuffer::new();
+    // A task message expecting two files, but the files never arrive
+    buf.extend_bytes(&[0x00, 0x99, 0x99, 0x66, 0x66, 0x00])
+        .unwrap();
+    assert_eq!(
+        buf.pop_front::<MessageToSand>(),
+        Err(buffer::Error::UnexpectedEnd)
+    );
+    while buf.as_slice().bytes.len() < buffer::MAX_MESSAGE_BYTES {
+        buf.push_back_byte(0x00).unwrap();
+    }
+    assert_eq!(
+        buf.pop_front::<MessageToSand>(),
+        Err(buffer::Error::MessageTooLarge)
+    );
+    assert_eq!(buf.as_slice().bytes.len(), buffer::MAX_MESSAGE_BYTES);
+}
+
+#[test]
+fn seccomp_rule_list() {
+    let mut list = SeccompRuleList::default();
+    assert!(list.is_empty());
+    assert!(list.insert(SeccompRule { nr: 1, errno: 13 }));
+    assert!(list.insert(SeccompRule { nr: 1, errno: 0 }));
+    assert_eq!(list.as_slice(), &[SeccompRule { nr: 1, errno: 0 }]);
+    for nr in 2..=SECCOMP_RULE_LIST_CAPACITY as u32 {
+        assert!(list.insert(SeccompRule { nr, errno: 1 }));
+    }
+    assert!(!list.insert(SeccompRule { nr: 1000, errno: 1 }));
+    assert!(list.insert(SeccompRule { nr: 2, errno: 2 }));
+    assert_eq!(list.as_slice().len(), SECCOMP_RULE_LIST_CAPACITY);
+    assert_eq!(list.as_slice()[1], SeccompRule { nr: 2, errno: 2 });
+    let logged = SeccompRule {
+        nr: 3,
+        errno: SECCOMP_RULE_LOG,
+    };
+    assert!(list.insert(logged));
+    assert_eq!(list.as_slice()[2], logged);
+
+    let mut header = InitArgsHeader::default();
+    header.seccomp_rules = list.clone();
+    let mut copy = InitArgsHeader::default();
+    copy.as_bytes_mut().copy_from_slice(header.as_bytes());
+    assert_eq!(copy.seccomp_rules, list);
+}
+
+#[test]
+fn ioctl_list() {
+    let mut list = IoctlList::default();
+    assert!(!list.contains(0x5401));
+    assert!(list.insert(0x5401));
+    assert!(list.insert(0x5401));
+    assert_eq!(list.as_slice(), &[0x5401]);
+    for i in 1..IOCTL_LIST_CAPACITY {
+        assert!(list.insert(0x1000 + i as u32));
+    }
+    assert!(!list.insert(0x2000));
+    assert!(!list.contains(0x2000));
+    assert!(list.contains(0x5401));
+    assert_eq!(list.as_slice().len(), IOCTL_LIST_CAPACITY);
+
+    let mut buf = buffer::IPCBuffer::new();
+    buf.push_back(&list).unwrap();
+    assert_eq!(buf.as_slice().bytes.len(), 1 + 4 * IOCTL_LIST_CAPACITY);
+    assert_eq!(buf.pop_front::<IoctlList>(), Ok(list));
+    assert!(buf.is_empty());
+
+    buf.push_back(&u8::MAX).unwrap();
+    buf.push_back(&[0x5401u32; IOCTL_LIST_CAPACITY]).unwrap();
+    let mut list = buf.pop_front::<IoctlList>().unwrap();
+    assert_eq!(list.as_slice().len(), IOCTL_LIST_CAPACITY);
+    assert!(list.contains(0x5401));
+    assert!(!list.insert(0x2000));
+}
+
+#[test]
+fn uts_string() {
+    assert_eq!(UtsString::new(b"host").unwrap().as_bytes(), b"host");
+    assert_eq!(UtsString::default().as_bytes(), b"");
+    assert!(UtsString::new(b"nul\0").is_none());
+    assert!(UtsString::new(&[b'x'; UTS_STRING_CAPACITY]).is_some());
+    assert!(UtsString::new(&[b'x'; UTS_STRING_CAPACITY + 1]).is_none());
+
+    let s = UtsString::new(b"4.0.0-bandsocks").unwrap();
+    let mut buf = buffer::IPCBuffer::new();
+    buf.push_back(&s).unwrap();
+    assert_eq!(buf.as_slice().bytes.len(), 1 + UTS_STRING_CAPACITY);
+    assert_eq!(buf.pop_front::<UtsString>(), Ok(s));
+    assert!(buf.is_empty());
+}
+
 macro_rules! check {
     ($name:ident, $msg:expr, $t:ty, $bytes:expr, $files:expr) => {
         #[test]
@@ -246,6 +350,136 @@ check!(
     ],
     []
 );
+check!(
+    sys_openat2_1,
+    MessageFromSand::Task {
+        task: VPid(0x01020304),
+        op: FromTask::FileOpenHow {
+            dir: None,
+            path: VString(VPtr(0x1111222233334444)),
+            how: VPtr(0x5555666677778888),
+            size: 24,
+        }
+    },
+    MessageFromSand,
+    [
+        0x00, 0x04, 0x03, 0x02, 0x01, 0x10, 0x00, 0x44, 0x44, 0x33, 0x33, 0x22, 0x22, 0x11, 0x11,
+        0x88, 0x88, 0x77, 0x77, 0x66, 0x66, 0x55, 0x55, 0x18, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00,
+    ],
+    []
+);
+check!(
+    spawn_1,
+    MessageToSand::Spawn { args: SysFd(7) },
+    MessageToSand,
+    [0x02],
+    [SysFd(7)]
+);
+check!(
+    fd_opened_1,
+    MessageFromSand::Task {
+        task: VPid(0x01020304),
+        op: FromTask::FdOpened {
+            fd: 3,
+            file: VFile { inode: 0x1234 },
+        }
+    },
+    MessageFromSand,
+    [
+        0x00, 0x04, 0x03, 0x02, 0x01, 0x11, 0x03, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00
+    ],
+    []
+);
+check!(
+    exited_1,
+    MessageFromSand::Task {
+        task: VPid(0x01020304),
+        op: FromTask::Exited {
+            code: 3,
+            signal: None
+        }
+    },
+    MessageFromSand,
+    [0x00, 0x04, 0x03, 0x02, 0x01, 0x08, 0x03, 0x00, 0x00, 0x00, 0x00],
+    []
+);
+check!(
+    exited_2,
+    MessageFromSand::Task {
+        task: VPid(0x01020304),
+        op: FromTask::Exited {
+            code: 137,
+            signal: Some(Signal(9))
+        }
+    },
+    MessageFromSand,
+    [0x00, 0x04, 0x03, 0x02, 0x01, 0x08, 0x89, 0x00, 0x00, 0x00, 0x01, 0x09, 0x00, 0x00, 0x00],
+    []
+);
+check!(
+    fd_closed_1,
+    MessageFromSand::Task {
+        task: VPid(0x01020304),
+        op: FromTask::FdClosed(0x0a0b0c0d)
+    },
+    MessageFromSand,
+    [0x00, 0x04, 0x03, 0x02, 0x01, 0x13, 0x0d, 0x0c, 0x0b, 0x0a],
+    []
+);
+check!(
+    file_unlink_1,
+    MessageFromSand::Task {
+        task: VPid(0x01020304),
+        op: FromTask::FileUnlink {
+            dir: Some(VFile { inode: 0x1122 }),
+            path: VString(VPtr(0x1111222233334444)),
+            flags: 0x200,
+        }
+    },
+    MessageFromSand,
+    [
+        0x00, 0x04, 0x03, 0x02, 0x01, 0x14, 0x01, 0x22, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x44, 0x44, 0x33, 0x33, 0x22, 0x22, 0x11, 0x11, 0x00, 0x02, 0x00, 0x00,
+    ],
+    []
+);
+check!(
+    make_dir_1,
+    MessageFromSand::Task {
+        task: VPid(0x01020304),
+        op: FromTask::MakeDir {
+            dir: None,
+            path: VString(VPtr(0x1111222233334444)),
+            mode: 0o755,
+        }
+    },
+    MessageFromSand,
+    [
+        0x00, 0x04, 0x03, 0x02, 0x01, 0x15, 0x00, 0x44, 0x44, 0x33, 0x33, 0x22, 0x22, 0x11, 0x11,
+        0xed, 0x01, 0x00, 0x00,
+    ],
+    []
+);
+check!(
+    sys_getxattr_1,
+    MessageFromSand::Task {
+        task: VPid(0x01020304),
+        op: FromTask::GetXAttr {
+            file: None,
+            path: Some(VString(VPtr(0x1111222233334444))),
+            follow_links: FollowLinks::NoFollow,
+            name: VString(VPtr(0x5555666677778888)),
+        }
+    },
+    MessageFromSand,
+    [
+        0x00, 0x04, 0x03, 0x02, 0x01, 0x0a, 0x00, 0x01, 0x44, 0x44, 0x33, 0x33, 0x22, 0x22, 0x11,
+        0x11, 0x00, 0x88, 0x88, 0x77, 0x77, 0x66, 0x66, 0x55, 0x55,
+    ],
+    []
+);
 check!(
     sys_open_reply_1,
     MessageToSand::Task {
@@ -284,3 +518,22 @@ check!(
     [0x00, 0x99, 0x99, 0x66, 0x66, 0],
     [SysFd(10), SysFd(20)]
 );
+
+#[test]
+fn passthrough_syscalls_fit_mask() {
+    let list = abi::PASSTHROUGH_SYSCALLS;
+    assert!(!list.is_empty() && list.len() <= 64);
+    for (i, nr) in list.iter().enumerate() {
+        assert!(!list[..i].contains(nr));
+    }
+}
+
+#[test]
+fn optional_passthrough_syscalls_fit_mask() {
+    let list = abi::OPTIONAL_PASSTHROUGH_SYSCALLS;
+    assert!(!list.is_empty() && list.len() <= 64);
+    for (i, nr) in list.iter().enumerate() {
+        assert!(!list[..i].contains(nr));
+        assert!(!abi::PASSTHROUGH_SYSCALLS.contains(nr));
+    }
+}
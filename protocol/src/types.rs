@@ -50,6 +50,184 @@ pub struct FileStat {
 
 pub type INodeNum = usize;
 
+/// Maximum number of extra ioctl requests in an [IoctlList]
+pub const IOCTL_LIST_CAPACITY: usize = 16;
+
+/// Fixed-capacity list of extra ioctl requests the tracer will allow through
+/// to the real file descriptor
+#[derive(Debug, Default, Clone, Eq, PartialEq, Deserialize, Serialize)]
+pub struct IoctlList {
+    len: u8,
+    items: [u32; IOCTL_LIST_CAPACITY],
+}
+
+impl IoctlList {
+    pub fn as_slice(&self) -> &[u32] {
+        &self.items[..(self.len as usize).min(IOCTL_LIST_CAPACITY)]
+    }
+
+    pub fn contains(&self, request: u32) -> bool {
+        self.as_slice().contains(&request)
+    }
+
+    /// Add a request to the list, returning false if there is no room
+    pub fn insert(&mut self, request: u32) -> bool {
+        let len = self.as_slice().len();
+        if self.contains(request) {
+            true
+        } else if len >= IOCTL_LIST_CAPACITY {
+            false
+        } else {
+            self.items[len] = request;
+            self.len = len as u8 + 1;
+            true
+        }
+    }
+}
+
+/// Maximum length of a [UtsString], leaving room for the nul terminator in
+/// each `uname()` field
+pub const UTS_STRING_CAPACITY: usize = 64;
+
+/// Fixed-capacity string reported in one of the `uname()` fields
+#[derive(Clone, Eq, PartialEq)]
+pub struct UtsString {
+    len: u8,
+    bytes: [u8; UTS_STRING_CAPACITY],
+}
+
+impl UtsString {
+    /// Returns None if the string is too long or contains a nul
+    pub fn new(s: &[u8]) -> Option<Self> {
+        if s.len() > UTS_STRING_CAPACITY || s.contains(&0) {
+            None
+        } else {
+            let mut result = UtsString::default();
+            result.bytes[..s.len()].copy_from_slice(s);
+            result.len = s.len() as u8;
+            Some(result)
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..(self.len as usize).min(UTS_STRING_CAPACITY)]
+    }
+}
+
+impl Default for UtsString {
+    fn default() -> Self {
+        UtsString {
+            len: 0,
+            bytes: [0; UTS_STRING_CAPACITY],
+        }
+    }
+}
+
+impl fmt::Debug for UtsString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "UtsString({:?})", self.as_bytes())
+    }
+}
+
+// Serde only derives for arrays up to 32 items, so this is sent as a tuple of
+// the length followed by every byte
+impl serde::Serialize for UtsString {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeTuple;
+        let mut tuple = serializer.serialize_tuple(1 + UTS_STRING_CAPACITY)?;
+        tuple.serialize_element(&self.len)?;
+        for byte in &self.bytes {
+            tuple.serialize_element(byte)?;
+        }
+        tuple.end()
+    }
+}
+
+impl<'d> serde::Deserialize<'d> for UtsString {
+    fn deserialize<D: serde::Deserializer<'d>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::{Error, SeqAccess, Visitor};
+        struct UtsStringVisitor;
+        impl<'d> Visitor<'d> for UtsStringVisitor {
+            type Value = UtsString;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a UtsString")
+            }
+
+            fn visit_seq<A: SeqAccess<'d>>(self, mut seq: A) -> Result<UtsString, A::Error> {
+                let mut result = UtsString {
+                    len: seq
+                        .next_element()?
+                        .ok_or_else(|| A::Error::invalid_length(0, &self))?,
+                    ..Default::default()
+                };
+                for (i, byte) in result.bytes.iter_mut().enumerate() {
+                    *byte = seq
+                        .next_element()?
+                        .ok_or_else(|| A::Error::invalid_length(1 + i, &self))?;
+                }
+                Ok(result)
+            }
+        }
+        deserializer.deserialize_tuple(1 + UTS_STRING_CAPACITY, UtsStringVisitor)
+    }
+}
+
+/// Maximum number of rules in a [SeccompRuleList]
+pub const SECCOMP_RULE_LIST_CAPACITY: usize = 32;
+
+/// Special errno value for a [SeccompRule] that allows its syscall but has the
+/// kernel log it
+pub const SECCOMP_RULE_LOG: u32 = u32::MAX;
+
+/// One extra restriction on a host syscall the loader would otherwise pass
+/// through
+///
+/// An errno of zero means the syscall raises SIGSYS instead of failing, and
+/// [SECCOMP_RULE_LOG] means it succeeds but is recorded in the audit log.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[repr(C)]
+pub struct SeccompRule {
+    pub nr: u32,
+    pub errno: u32,
+}
+
+/// Fixed-capacity list of [SeccompRule], sent as part of the
+/// [InitArgsHeader](crate::InitArgsHeader)
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+#[repr(C)]
+pub struct SeccompRuleList {
+    len: usize,
+    items: [SeccompRule; SECCOMP_RULE_LIST_CAPACITY],
+}
+
+impl SeccompRuleList {
+    pub fn as_slice(&self) -> &[SeccompRule] {
+        &self.items[..self.len.min(SECCOMP_RULE_LIST_CAPACITY)]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.as_slice().is_empty()
+    }
+
+    /// Add a rule, replacing any earlier rule for the same syscall
+    ///
+    /// Returns false if there is no room.
+    pub fn insert(&mut self, rule: SeccompRule) -> bool {
+        let len = self.as_slice().len();
+        if let Some(existing) = self.items[..len].iter_mut().find(|r| r.nr == rule.nr) {
+            *existing = rule;
+            true
+        } else if len >= SECCOMP_RULE_LIST_CAPACITY {
+            false
+        } else {
+            self.items[len] = rule;
+            self.len = len + 1;
+            true
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
 pub struct VFile {
     pub inode: INodeNum,
@@ -9,9 +9,22 @@ pub const O_ACCMODE: usize = 3;
 pub const O_RDONLY: usize = 0;
 pub const O_WRONLY: usize = 1;
 pub const O_RDWR: usize = 2;
+pub const F_DUPFD: usize = 0;
+pub const F_GETFD: usize = 1;
 pub const F_SETFD: usize = 2;
+pub const F_GETFL: usize = 3;
 pub const F_SETFL: usize = 4;
+pub const F_GETLK: usize = 5;
+pub const F_SETLK: usize = 6;
+pub const F_SETLKW: usize = 7;
 pub const F_SETOWN: usize = 8;
+pub const F_OFD_GETLK: usize = 36;
+pub const F_OFD_SETLK: usize = 37;
+pub const F_OFD_SETLKW: usize = 38;
+pub const F_DUPFD_CLOEXEC: usize = 1030;
+pub const F_SETPIPE_SZ: usize = 1031;
+pub const F_GETPIPE_SZ: usize = 1032;
+pub const F_ADD_SEALS: usize = 1033;
 pub const F_CLOEXEC: usize = 1;
 pub const FASYNC: usize = 0o20000;
 pub const O_NONBLOCK: usize = 0o4000;
@@ -19,11 +32,30 @@ pub const O_DIRECTORY: usize = 0o200000;
 pub const O_CLOEXEC: usize = 0o2000000;
 pub const AT_SYMLINK_NOFOLLOW: i32 = 0x100;
 pub const AT_FDCWD: i32 = -100;
+pub const AT_REMOVEDIR: i32 = 0x200;
+pub const AT_EACCESS: i32 = 0x200;
+pub const AT_NO_AUTOMOUNT: i32 = 0x800;
+pub const AT_EMPTY_PATH: i32 = 0x1000;
+pub const AT_STATX_SYNC_TYPE: i32 = 0x6000;
 pub const F_GET_SEALS: usize = 1034;
 pub const F_SEAL_SEAL: usize = 1;
 pub const F_SEAL_SHRINK: usize = 2;
 pub const F_SEAL_GROW: usize = 4;
 pub const F_SEAL_WRITE: usize = 8;
+pub const PRIO_PROCESS: usize = 0;
+pub const R_OK: i32 = 4;
+pub const W_OK: i32 = 2;
+pub const X_OK: i32 = 1;
+
+// linux/include/uapi/linux/sched.h
+pub const SCHED_OTHER: usize = 0;
+pub const SCHED_FIFO: usize = 1;
+pub const SCHED_RR: usize = 2;
+pub const SCHED_BATCH: usize = 3;
+pub const SCHED_IDLE: usize = 5;
+pub const SCHED_RESET_ON_FORK: usize = 0x40000000;
+pub const CLONE_FILES: usize = 0x00000400;
+pub const CLONE_PARENT: usize = 0x00008000;
 
 // linux/arch/x86/include/uapi/asm/stat.h
 #[derive(Debug)]
@@ -52,6 +84,44 @@ pub struct Stat {
     pub unused: [i64; 3],
 }
 
+// linux/include/uapi/linux/stat.h
+pub const STATX_BASIC_STATS: u32 = 0x7ff;
+pub const STATX__RESERVED: u32 = 0x8000_0000;
+
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct StatxTimestamp {
+    pub tv_sec: i64,
+    pub tv_nsec: u32,
+    pub reserved: i32,
+}
+
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct Statx {
+    pub stx_mask: u32,
+    pub stx_blksize: u32,
+    pub stx_attributes: u64,
+    pub stx_nlink: u32,
+    pub stx_uid: u32,
+    pub stx_gid: u32,
+    pub stx_mode: u16,
+    pub spare0: u16,
+    pub stx_ino: u64,
+    pub stx_size: u64,
+    pub stx_blocks: u64,
+    pub stx_attributes_mask: u64,
+    pub stx_atime: StatxTimestamp,
+    pub stx_btime: StatxTimestamp,
+    pub stx_ctime: StatxTimestamp,
+    pub stx_mtime: StatxTimestamp,
+    pub stx_rdev_major: u32,
+    pub stx_rdev_minor: u32,
+    pub stx_dev_major: u32,
+    pub stx_dev_minor: u32,
+    pub spare2: [u64; 14],
+}
+
 // linux/include/uapi/asm-generic/statfs.h
 #[derive(Debug)]
 #[repr(C)]
@@ -79,6 +149,7 @@ pub const PTRACE_SINGLESTEP: usize = 9;
 pub const PTRACE_SYSCALL: usize = 24;
 pub const PTRACE_SETOPTIONS: usize = 0x4200;
 pub const PTRACE_GETEVENTMSG: usize = 0x4201;
+pub const PTRACE_GETSIGINFO: usize = 0x4202;
 pub const PTRACE_GETREGSET: usize = 0x4204;
 pub const PTRACE_SETREGSET: usize = 0x4205;
 pub const PTRACE_GET_SYSCALL_INFO: usize = 0x420e;
@@ -121,6 +192,31 @@ pub const MREMAP_MAYMOVE: isize = 1;
 pub const PROT_READ: isize = 1;
 pub const PROT_WRITE: isize = 2;
 pub const PROT_EXEC: isize = 4;
+pub const MADV_NORMAL: i32 = 0;
+pub const MADV_RANDOM: i32 = 1;
+pub const MADV_SEQUENTIAL: i32 = 2;
+pub const MADV_WILLNEED: i32 = 3;
+pub const MADV_DONTNEED: i32 = 4;
+pub const MADV_FREE: i32 = 8;
+pub const MADV_REMOVE: i32 = 9;
+pub const MADV_DONTFORK: i32 = 10;
+pub const MADV_DOFORK: i32 = 11;
+pub const MADV_MERGEABLE: i32 = 12;
+pub const MADV_UNMERGEABLE: i32 = 13;
+pub const MADV_HUGEPAGE: i32 = 14;
+pub const MADV_NOHUGEPAGE: i32 = 15;
+pub const MADV_DONTDUMP: i32 = 16;
+pub const MADV_DODUMP: i32 = 17;
+pub const MADV_WIPEONFORK: i32 = 18;
+pub const MADV_KEEPONFORK: i32 = 19;
+pub const MADV_COLD: i32 = 20;
+pub const MADV_PAGEOUT: i32 = 21;
+pub const MADV_POPULATE_READ: i32 = 22;
+pub const MADV_POPULATE_WRITE: i32 = 23;
+pub const MADV_DONTNEED_LOCKED: i32 = 24;
+pub const MADV_COLLAPSE: i32 = 25;
+pub const MADV_HWPOISON: i32 = 100;
+pub const MADV_SOFT_OFFLINE: i32 = 101;
 
 // ELF constant, used as ptrace user reg set identifier
 pub const NT_PRSTATUS: usize = 1;
@@ -189,6 +285,9 @@ pub const SI_MAX_SIZE: usize = 128;
 
 // errno
 // linux/include/uapi/asm-generic/errno-base.h
+pub const EPERM: i32 = 1;
+pub const ENOENT: i32 = 2;
+pub const ESRCH: i32 = 3;
 pub const EINTR: i32 = 4;
 pub const EIO: i32 = 5;
 pub const E2BIG: i32 = 7;
@@ -198,10 +297,28 @@ pub const ECHILD: i32 = 10;
 pub const EAGAIN: i32 = 11;
 pub const EFAULT: i32 = 14;
 pub const EEXIST: i32 = 17;
+pub const ENOTDIR: i32 = 20;
 pub const EINVAL: i32 = 22;
+pub const ENOTTY: i32 = 25;
 pub const EROFS: i32 = 30;
+pub const ERANGE: i32 = 34;
+pub const ENAMETOOLONG: i32 = 36;
 pub const ENOSYS: i32 = 38;
+pub const ENOTSOCK: i32 = 88;
+pub const EPROTONOSUPPORT: i32 = 93;
+pub const ESOCKTNOSUPPORT: i32 = 94;
+pub const EAFNOSUPPORT: i32 = 97;
 pub const ECONNRESET: i32 = 104;
+pub const EISCONN: i32 = 106;
+pub const ECONNREFUSED: i32 = 111;
+
+// Kernel-internal errno values, seen by a tracer at syscall exit when a
+// signal interrupts a call. These never reach userspace.
+// linux/include/linux/errno.h
+pub const ERESTARTSYS: i32 = 512;
+pub const ERESTARTNOINTR: i32 = 513;
+pub const ERESTARTNOHAND: i32 = 514;
+pub const ERESTART_RESTARTBLOCK: i32 = 516;
 
 // signo
 // linux/include/uapi/asm-generic/signal.h
@@ -227,6 +344,17 @@ pub const SEEK_DATA: isize = 3;
 pub const SEEK_HOLE: isize = 4;
 pub const SEEK_MAX: isize = SEEK_HOLE;
 
+// ioctl
+// linux/include/uapi/asm-generic/ioctls.h
+pub const TCGETS: u32 = 0x5401;
+pub const TCSETS: u32 = 0x5402;
+pub const TCSETSW: u32 = 0x5403;
+pub const TCSETSF: u32 = 0x5404;
+pub const TIOCGWINSZ: u32 = 0x5413;
+pub const TIOCSWINSZ: u32 = 0x5414;
+pub const FIONREAD: u32 = 0x541B;
+pub const FIONBIO: u32 = 0x5421;
+
 // sendmsg() user_msghdr
 // linux/include/linux/socket.h
 #[derive(Debug, Clone)]
@@ -280,6 +408,14 @@ pub struct SigInfo {
     pub fields: [u32; 20],
 }
 
+impl SigInfo {
+    /// Syscall number that raised a SIGSYS, from the `_sigsys` member of the
+    /// union which overlaps si_status
+    pub fn si_syscall(&self) -> u32 {
+        self.si_status
+    }
+}
+
 // si_code
 // linux/include/uapi/asm-generic/siginfo.h
 pub const CLD_EXITED: u32 = 1;
@@ -288,6 +424,7 @@ pub const CLD_DUMPED: u32 = 3;
 pub const CLD_TRAPPED: u32 = 4;
 pub const CLD_STOPPED: u32 = 5;
 pub const CLD_CONTINUED: u32 = 6;
+pub const SYS_SECCOMP: u32 = 1;
 
 // sigaction
 // linux/inclide/linux/signal_types.h
@@ -302,6 +439,11 @@ pub struct SigAction {
 
 // arch/x86/include/uapi/asm/signal.h
 pub const SA_RESTORER: u32 = 0x04000000;
+pub const SA_RESTART: u32 = 0x10000000;
+
+// linux/include/uapi/asm-generic/signal-defs.h
+pub const SIG_DFL: usize = 0;
+pub const SIG_IGN: usize = 1;
 
 /// sigset_t
 /// linux/include/uapi/asm-generic/signal.h
@@ -319,6 +461,13 @@ pub const AF_UNIX: usize = 1;
 
 /// linux/include/linux/net.h
 pub const SOCK_STREAM: usize = 1;
+pub const SOCK_SEQPACKET: usize = 5;
+pub const SOCK_NONBLOCK: usize = O_NONBLOCK;
+pub const SOCK_CLOEXEC: usize = O_CLOEXEC;
+
+/// linux/include/uapi/linux/un.h
+pub const SUN_PATH_OFFSET: usize = 2;
+pub const SUN_PATH_LEN: usize = 108;
 
 /// linux/arch/x86/include/asm/page_64_types.h
 pub const TASK_SIZE: usize = (1 << 47) - PAGE_SIZE;
@@ -329,6 +478,27 @@ pub const ELF_STACK_ALIGN_MASK: usize = ELF_STACK_ALIGN - 1;
 
 pub const MMAP_RND_BITS: usize = 28;
 
+/// linux/include/uapi/linux/sysinfo.h
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct SysInfo {
+    pub uptime: i64,
+    pub loads: [u64; 3],
+    pub totalram: u64,
+    pub freeram: u64,
+    pub sharedram: u64,
+    pub bufferram: u64,
+    pub totalswap: u64,
+    pub freeswap: u64,
+    pub procs: u16,
+    pub pad: u16,
+    pub pad1: u32,
+    pub totalhigh: u64,
+    pub freehigh: u64,
+    pub mem_unit: u32,
+    pub pad2: u32,
+}
+
 /// linux/include/uapi/linux/time.h
 #[derive(Debug, Clone)]
 #[repr(C)]
@@ -347,6 +517,47 @@ impl TimeSpec {
     }
 }
 
+/// linux/include/uapi/linux/time.h
+pub const CLOCK_REALTIME: isize = 0;
+pub const CLOCK_MONOTONIC: isize = 1;
+pub const CLOCK_PROCESS_CPUTIME_ID: isize = 2;
+pub const CLOCK_THREAD_CPUTIME_ID: isize = 3;
+pub const CLOCK_MONOTONIC_RAW: isize = 4;
+pub const CLOCK_REALTIME_COARSE: isize = 5;
+pub const CLOCK_MONOTONIC_COARSE: isize = 6;
+pub const CLOCK_BOOTTIME: isize = 7;
+pub const CLOCK_REALTIME_ALARM: isize = 8;
+pub const CLOCK_BOOTTIME_ALARM: isize = 9;
+pub const CLOCK_TAI: isize = 11;
+
+/// linux/include/uapi/linux/prctl.h
+pub const PR_SET_PDEATHSIG: usize = 1;
+pub const PR_GET_DUMPABLE: usize = 3;
+pub const PR_SET_DUMPABLE: usize = 4;
+pub const PR_SET_NAME: usize = 15;
+pub const PR_GET_NAME: usize = 16;
+pub const PR_GET_SECCOMP: usize = 21;
+pub const PR_SET_SECCOMP: usize = 22;
+pub const PR_SET_TIMERSLACK: usize = 29;
+pub const PR_SET_CHILD_SUBREAPER: usize = 36;
+pub const PR_SET_NO_NEW_PRIVS: usize = 38;
+pub const PR_GET_NO_NEW_PRIVS: usize = 39;
+pub const PR_SET_VMA: usize = 0x53564d41;
+
+/// linux/include/linux/sched.h
+pub const TASK_COMM_LEN: usize = 16;
+
+/// linux/arch/x86/include/uapi/asm/prctl.h
+pub const ARCH_SET_GS: usize = 0x1001;
+pub const ARCH_SET_FS: usize = 0x1002;
+pub const ARCH_GET_FS: usize = 0x1003;
+pub const ARCH_GET_GS: usize = 0x1004;
+
+/// linux/include/uapi/linux/random.h
+pub const GRND_NONBLOCK: usize = 0x0001;
+pub const GRND_RANDOM: usize = 0x0002;
+pub const GRND_INSECURE: usize = 0x0004;
+
 /// linux/arch/x86/include/asm/elf.h (64-bit)
 pub const STACK_RND_MASK: usize = 0x3fffff;
 
@@ -365,6 +576,11 @@ pub struct UtsName {
     pub machine: [u8; 65],
 }
 
+// execve(2) limits on each argument or environment string and their count,
+// linux/include/uapi/linux/binfmts.h
+pub const MAX_ARG_STRLEN: usize = 32 * PAGE_SIZE;
+pub const MAX_ARG_STRINGS: usize = 0x7FFFFFFF;
+
 pub const PLATFORM_NAME_BYTES: &[u8] = b"x86_64\0";
 
 #[naked]
@@ -7,7 +7,10 @@ use crate::{
         string::VStringRange,
     },
     process::{stack::StackBuilder, task::StoppedTask},
-    protocol::{abi::UserRegs, Errno, VPtr, VString},
+    protocol::{
+        abi::{UserRegs, PATH_MAX},
+        Errno, VPtr, VString,
+    },
     remote::{
         file::{LoadedSegment, MapLocation, RemoteFd, TempRemoteFd},
         scratchpad::Scratchpad,
@@ -117,6 +120,32 @@ impl ElfEntry {
     }
 }
 
+/// Running total of argument and environment space used by one exec
+#[derive(Debug)]
+struct ArgSpace {
+    remaining: usize,
+}
+
+impl ArgSpace {
+    fn new(arg_max: usize) -> Self {
+        ArgSpace { remaining: arg_max }
+    }
+
+    /// Count a string and the pointer to it, failing like Linux once the
+    /// total exceeds the limit
+    fn take(&mut self, item: &VStringRange) -> Result<(), Errno> {
+        let range = item.range();
+        let size = range.end.0 - range.start.0 + size_of::<VPtr>();
+        match self.remaining.checked_sub(size) {
+            Some(remaining) => {
+                self.remaining = remaining;
+                Ok(())
+            }
+            None => Err(Errno(-abi::E2BIG)),
+        }
+    }
+}
+
 #[derive(Debug)]
 struct ElfFile {
     local: ExecFile,
@@ -341,17 +370,31 @@ impl ElfFile {
             .push_bytes(scratchpad, abi::PLATFORM_NAME_BYTES)
             .await?;
 
-        let filename_str =
-            VStringRange::parse(&mut scratchpad.trampoline.stopped_task, exec.filename)?;
+        let filename_str = VStringRange::parse(
+            &mut scratchpad.trampoline.stopped_task,
+            exec.filename,
+            PATH_MAX,
+        )?
+        .ok_or(Errno(-abi::ENAMETOOLONG))?;
         let filename_ptr = stack
             .push_remote_bytes(&mut scratchpad.trampoline, filename_str.range())
             .await?;
 
+        let arg_max = scratchpad
+            .trampoline
+            .stopped_task
+            .task
+            .task_data
+            .tracer_settings
+            .arg_max;
+        let mut arg_space = ArgSpace::new(arg_max);
+
         for idx in 0.. {
             if let Some(item) = exec
                 .argv
                 .item_range(&mut scratchpad.trampoline.stopped_task, idx)?
             {
+                arg_space.take(&item)?;
                 let argvec = stack
                     .push_remote_bytes(&mut scratchpad.trampoline, item.range())
                     .await?;
@@ -368,6 +411,7 @@ impl ElfFile {
                 .envp
                 .item_range(&mut scratchpad.trampoline.stopped_task, idx)?
             {
+                arg_space.take(&item)?;
                 let envvec = stack
                     .push_remote_bytes(&mut scratchpad.trampoline, item.range())
                     .await?;
@@ -2,13 +2,18 @@ use crate::{nolibc::File, protocol::InitArgsHeader};
 use alloc::vec::Vec;
 use sc::syscall;
 
+pub struct InitArgs {
+    pub header: InitArgsHeader,
+    bytes: Vec<u8>,
+}
+
 fn read_header(file: &File) -> InitArgsHeader {
     let mut header: InitArgsHeader = Default::default();
     file.read_exact(header.as_bytes_mut()).unwrap();
     header
 }
 
-pub fn with_args_file(file: &File) -> ! {
+pub fn read_args_file(file: &File) -> InitArgs {
     let header = read_header(file);
 
     let mut bytes = Vec::<u8>::new();
@@ -20,37 +25,46 @@ pub fn with_args_file(file: &File) -> ! {
     file.read_exact(&mut bytes).unwrap();
     file.close().unwrap();
 
-    let (dir, bytes) = bytes.split_at(header.dir_len);
-    let (filename, bytes) = bytes.split_at(header.filename_len);
-    let (argv, bytes) = bytes.split_at(header.argv_len);
-    let (envp, bytes) = bytes.split_at(header.envp_len);
-    assert_eq!(bytes.len(), 0);
+    InitArgs { header, bytes }
+}
 
-    let mut pointers = Vec::<usize>::new();
-    pointers.resize(header.arg_count + 1 + header.env_count + 1, 0);
+impl InitArgs {
+    pub fn exec(self) -> ! {
+        let header = self.header;
+        let bytes = &self.bytes[..];
 
-    let (argv_ptrs, pointers) = pointers.split_at_mut(header.arg_count + 1);
-    let (envp_ptrs, pointers) = pointers.split_at_mut(header.env_count + 1);
-    assert_eq!(pointers.len(), 0);
+        let (dir, bytes) = bytes.split_at(header.dir_len);
+        let (filename, bytes) = bytes.split_at(header.filename_len);
+        let (argv, bytes) = bytes.split_at(header.argv_len);
+        let (envp, bytes) = bytes.split_at(header.envp_len);
+        assert_eq!(bytes.len(), 0);
 
-    cstr_vec_pointers(argv, header.arg_count, argv_ptrs);
-    cstr_vec_pointers(envp, header.env_count, envp_ptrs);
+        let mut pointers = Vec::<usize>::new();
+        pointers.resize(header.arg_count + 1 + header.env_count + 1, 0);
 
-    // change directories
-    if 0 != unsafe { syscall!(CHDIR, dir.as_ptr()) } {
-        panic!("failed to change to startup directory");
-    }
+        let (argv_ptrs, pointers) = pointers.split_at_mut(header.arg_count + 1);
+        let (envp_ptrs, pointers) = pointers.split_at_mut(header.env_count + 1);
+        assert_eq!(pointers.len(), 0);
 
-    // now let the emulated kernel take over
-    let error = unsafe {
-        syscall!(
-            EXECVE,
-            filename.as_ptr(),
-            argv_ptrs.as_ptr(),
-            envp_ptrs.as_ptr()
-        ) as isize
-    };
-    panic!("initial exec failed ({})", error);
+        cstr_vec_pointers(argv, header.arg_count, argv_ptrs);
+        cstr_vec_pointers(envp, header.env_count, envp_ptrs);
+
+        // change directories
+        if 0 != unsafe { syscall!(CHDIR, dir.as_ptr()) } {
+            panic!("failed to change to startup directory");
+        }
+
+        // now let the emulated kernel take over
+        let error = unsafe {
+            syscall!(
+                EXECVE,
+                filename.as_ptr(),
+                argv_ptrs.as_ptr(),
+                envp_ptrs.as_ptr()
+            ) as isize
+        };
+        panic!("initial exec failed ({})", error);
+    }
 }
 
 fn cstr_vec_pointers(cstr_vec: &[u8], count: usize, pointers: &mut [usize]) {
@@ -32,6 +32,7 @@ mod parser;
 mod ptrace;
 mod remote;
 mod seccomp;
+mod spawner;
 mod syscall;
 mod tracer;
 
@@ -42,7 +43,7 @@ pub use protocol::exit::*;
 use crate::{
     ipc::Socket,
     nolibc::File,
-    protocol::{Errno, SysFd},
+    protocol::{Errno, InitArgsHeader, SysFd},
     tracer::Tracer,
 };
 use alloc::boxed::Box;
@@ -72,9 +73,10 @@ pub unsafe fn c_main(argv: &[*const u8], envp: &[*const u8]) -> usize {
         }
 
         RunMode::InitLoader(args_file) => {
-            seccomp::policy_for_loader();
-            stdio_for_loader();
-            init::with_args_file(&args_file);
+            let args = init::read_args_file(&args_file);
+            seccomp::policy_for_loader(args.header.extra_passthrough);
+            stdio_for_loader(&args.header);
+            args.exec();
         }
     }
     EXIT_OK
@@ -97,22 +99,26 @@ fn stdio_for_tracer(socket_file: &File) {
     ]);
 }
 
-fn stdio_for_loader() {
+fn stdio_for_loader(header: &InitArgsHeader) {
     // Replace the loader's stdin, stdout, and stderr with objects from the virtual
     // filesystem. These are not real open() calls at this point, they're being
-    // trapped.
+    // trapped. Each process gets streams mounted in its own /proc directory.
     let v_stdin =
-        unsafe { File::open(b"/proc/1/fd/0\0", abi::O_RDONLY, 0) }.expect("no init stdin");
+        unsafe { File::open(b"/proc/self/fd/0\0", abi::O_RDONLY, 0) }.expect("no init stdin");
     let v_stdout =
-        unsafe { File::open(b"/proc/1/fd/1\0", abi::O_WRONLY, 0) }.expect("no init stdout");
+        unsafe { File::open(b"/proc/self/fd/1\0", abi::O_WRONLY, 0) }.expect("no init stdout");
     let v_stderr =
-        unsafe { File::open(b"/proc/1/fd/2\0", abi::O_WRONLY, 0) }.expect("no init stderr");
+        unsafe { File::open(b"/proc/self/fd/2\0", abi::O_WRONLY, 0) }.expect("no init stderr");
     File::dup2(&v_stdin, &File::stdin()).unwrap();
     File::dup2(&v_stdout, &File::stdout()).unwrap();
     File::dup2(&v_stderr, &File::stderr()).unwrap();
     v_stdin.close().unwrap();
     v_stdout.close().unwrap();
     v_stderr.close().unwrap();
+
+    // Any restrictions from the container's seccomp profile go last, once the
+    // loader has nothing left to do but exec.
+    seccomp::policy_for_loader_profile(&header.seccomp_rules, header.denied_passthrough);
 }
 
 unsafe fn check_environment_determine_mode(argv: &[*const u8], envp: &[*const u8]) -> RunMode {
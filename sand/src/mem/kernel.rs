@@ -117,6 +117,30 @@ impl<'q, 's, 't> Iterator for KernelMemIterator<'q, 's, 't> {
     }
 }
 
+/// Check that `len` bytes at `ptr` are all in memory the guest can write
+pub fn is_writable(stopped_task: &mut StoppedTask, ptr: VPtr, len: usize) -> bool {
+    let end = match ptr.0.checked_add(len) {
+        Some(end) => end,
+        None => return false,
+    };
+    // Areas are in order, and each one has to pick up where the last left off
+    let mut next = ptr.0;
+    for area in KernelMemIterator::new(stopped_task) {
+        let mem = area.pages.mem_range();
+        if next >= end {
+            break;
+        }
+        if mem.end.0 <= next {
+            continue;
+        }
+        if mem.start.0 > next || !area.flags.protect.write {
+            return false;
+        }
+        next = mem.end.0;
+    }
+    next >= end
+}
+
 #[derive(Debug)]
 pub struct KernelMemAreas {
     pub vdso: KernelMemArea,
@@ -51,6 +51,30 @@ pub fn write_word(stopped_task: &mut StoppedTask, ptr: VPtr, word: usize) -> Res
     result
 }
 
+/// Write bytes at any alignment, keeping whatever shares their first and
+/// last words
+///
+/// Like any ptrace write this ignores memory protection, so the caller has
+/// to check that the guest could write here itself.
+pub fn write_bytes(stopped_task: &mut StoppedTask, ptr: VPtr, bytes: &[u8]) -> Result<(), Errno> {
+    const WORD: usize = size_of::<usize>();
+    let mut done = 0;
+    while done < bytes.len() {
+        let offset = (ptr.0 + done) % WORD;
+        let word_ptr = VPtr(ptr.0 + done - offset);
+        let len = (WORD - offset).min(bytes.len() - done);
+        let mut word = if len == WORD {
+            [0u8; WORD]
+        } else {
+            read_word(stopped_task, word_ptr)?.to_ne_bytes()
+        };
+        word[offset..offset + len].copy_from_slice(&bytes[done..done + len]);
+        write_word(stopped_task, word_ptr, usize::from_ne_bytes(word))?;
+        done += len;
+    }
+    Ok(())
+}
+
 pub fn write_padded_bytes(
     stopped_task: &mut StoppedTask,
     mut ptr: VPtr,
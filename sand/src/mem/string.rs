@@ -22,6 +22,9 @@ impl VStringArray {
         stopped_task: &mut StoppedTask,
         idx: usize,
     ) -> Result<Option<VString>, Errno> {
+        if idx >= abi::MAX_ARG_STRINGS {
+            return Err(Errno(-abi::E2BIG));
+        }
         match read_pointer(stopped_task, self.array_ptr() + (idx * size_of::<VPtr>())) {
             Err(err) => Err(err),
             Ok(ptr) if ptr == VPtr::null() => Ok(None),
@@ -37,7 +40,11 @@ impl VStringArray {
         match self.item_ptr(stopped_task, idx) {
             Err(err) => Err(err),
             Ok(None) => Ok(None),
-            Ok(Some(ptr)) => Ok(Some(VStringRange::parse(stopped_task, ptr)?)),
+            Ok(Some(ptr)) => match VStringRange::parse(stopped_task, ptr, abi::MAX_ARG_STRLEN) {
+                Err(err) => Err(err),
+                Ok(None) => Err(Errno(-abi::E2BIG)),
+                Ok(Some(range)) => Ok(Some(range)),
+            },
         }
     }
 }
@@ -49,7 +56,16 @@ impl VStringRange {
         self.0.clone()
     }
 
-    pub fn parse(stopped_task: &mut StoppedTask, vstring: VString) -> Result<VStringRange, Errno> {
+    /// Find the extent of a nul-terminated string in guest memory
+    ///
+    /// Returns None if there's no nul within `max_len` bytes, counting the
+    /// nul itself, so a huge string can't make the tracer scan unbounded
+    /// amounts of guest memory.
+    pub fn parse(
+        stopped_task: &mut StoppedTask,
+        vstring: VString,
+        max_len: usize,
+    ) -> Result<Option<VStringRange>, Errno> {
         // Use small read buffers that don't cross page boundaries
         type BufSize = U128;
         let ptr = vstring.0;
@@ -66,7 +82,10 @@ impl VStringRange {
         while let Some(Ok(byte)) = buf.next() {
             len += 1;
             if byte == 0 {
-                return Ok(VStringRange(ptr..(ptr + len)));
+                return Ok(Some(VStringRange(ptr..(ptr + len))));
+            }
+            if len >= max_len {
+                return Ok(None);
             }
         }
         Err(Errno(-abi::EFAULT))
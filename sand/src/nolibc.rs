@@ -134,6 +134,22 @@ impl File {
         Ok(())
     }
 
+    pub fn write_all(&self, bytes: &[u8]) -> Result<(), Errno> {
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let slice = &bytes[offset..];
+            let result = unsafe {
+                syscall!(WRITE, self.fd.0, slice.as_ptr() as usize, slice.len()) as isize
+            };
+            if result <= 0 {
+                return Err(Errno(result as i32));
+            } else {
+                offset += result as usize;
+            }
+        }
+        Ok(())
+    }
+
     pub fn socketpair(domain: usize, ty: usize, protocol: usize) -> Result<(File, File), Errno> {
         let mut pair = [0u32; 4];
         let result =
@@ -286,6 +302,17 @@ pub fn getrandom_usize() -> usize {
     usize::from_ne_bytes(bytes)
 }
 
+pub fn clock_gettime(clock: isize) -> Result<abi::TimeSpec, Errno> {
+    let mut time = abi::TimeSpec::from_secs(0);
+    let result =
+        unsafe { syscall!(CLOCK_GETTIME, clock, (&mut time) as *mut abi::TimeSpec) as isize };
+    if result == 0 {
+        Ok(time)
+    } else {
+        Err(Errno(result as i32))
+    }
+}
+
 pub unsafe fn mmap(
     addr: usize,
     length: usize,
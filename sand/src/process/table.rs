@@ -45,6 +45,10 @@ impl<'t, F: Future<Output = ()>> ProcessTable<'t, F> {
         }
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.map_sys_to_v.is_empty()
+    }
+
     pub fn syspid_to_v(&self, sys_pid: SysPid) -> Option<VPid> {
         self.map_sys_to_v.get(&sys_pid).copied()
     }
@@ -78,11 +82,13 @@ impl<'t, F: Future<Output = ()>> ProcessTable<'t, F> {
             let task_data = TaskData {
                 file_table,
                 tracer_settings,
+                name: [0; abi::TASK_COMM_LEN],
                 sys_pid,
                 vpid,
                 parent,
                 socket_pair,
                 mm,
+                loader_stage: parent.is_none(),
             };
             let index = table_index_for_vpid(vpid).unwrap();
             let min_table_len = index + 1;
@@ -141,6 +147,10 @@ impl FileTable {
         self.table.borrow_mut().remove(fd);
     }
 
+    pub fn fds(&self) -> Vec<RemoteFd> {
+        self.table.borrow().keys().cloned().collect()
+    }
+
     pub fn get(&self, fd: &RemoteFd) -> Result<VFile, Errno> {
         self.table
             .borrow()
@@ -149,9 +159,14 @@ impl FileTable {
             .ok_or(Errno(-abi::EBADF))
     }
 
-    pub fn dup(&mut self, src_fd: &RemoteFd, dest_fd: &RemoteFd) -> Result<(), Errno> {
-        let vfile = self.get(src_fd)?;
-        self.open(dest_fd.clone(), vfile);
-        Ok(())
+    /// Give `dest_fd` the same file as `src_fd`
+    ///
+    /// Descriptors that aren't VFS files, like pipes and sockets, have no
+    /// entry here, and duplicating one leaves `dest_fd` without one too.
+    pub fn dup(&mut self, src_fd: &RemoteFd, dest_fd: &RemoteFd) {
+        match self.get(src_fd) {
+            Ok(vfile) => self.open(dest_fd.clone(), vfile),
+            Err(_) => self.close(dest_fd),
+        }
     }
 }
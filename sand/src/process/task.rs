@@ -5,7 +5,8 @@ use crate::{
     process::{table::FileTable, Event, EventSource, MessageSender},
     protocol::{
         abi::{Syscall, UserRegs},
-        FromTask, LogLevel, LogMessage, ProcessHandle, SysPid, ToTask, TracerSettings, VPid, VPtr,
+        FromTask, LogLevel, LogMessage, ProcessHandle, Signal, SysPid, ToTask, TracerSettings,
+        VPid, VPtr,
     },
     ptrace,
     remote::file::RemoteFd,
@@ -43,6 +44,10 @@ pub struct TaskData {
     pub mm: TaskMemManagement,
     pub file_table: FileTable,
     pub tracer_settings: TracerSettings,
+    // set by prctl(PR_SET_NAME), nul padded
+    pub name: [u8; abi::TASK_COMM_LEN],
+    // still running the loader, which hasn't yet exec'd the container's program
+    pub loader_stage: bool,
 }
 
 pub async fn task_fn(events: EventSource<'_>, msg: MessageSender<'_>, task_data: TaskData) {
@@ -54,6 +59,8 @@ pub struct Task<'q> {
     pub process_handle: ProcessHandle,
     pub msg: MessageSender<'q>,
     pub events: EventSource<'q>,
+    // bitmask of signals that arrived while we were running code in the task
+    deferred_signals: u64,
 }
 
 #[derive(Debug)]
@@ -125,6 +132,7 @@ impl<'q> Task<'q> {
                 msg,
                 process_handle,
                 task_data,
+                deferred_signals: 0,
             },
             event => {
                 unexpected_event_panic(task_data.sys_pid, None, event, ExpectedEvent::OpenProcess)
@@ -163,6 +171,20 @@ impl<'q> Task<'q> {
                 {
                     self.handle_seccomp_trap().await
                 }
+                Event::Signal { sig, code, status }
+                    if sig == abi::SIGCHLD as u32
+                        && code == abi::CLD_TRAPPED
+                        && status == abi::SIGSYS as u32 =>
+                {
+                    let mut siginfo: abi::SigInfo = Default::default();
+                    ptrace::getsiginfo(self.task_data.sys_pid, &mut siginfo);
+                    if siginfo.si_code == abi::SYS_SECCOMP {
+                        return self
+                            .handle_seccomp_violation(siginfo.si_syscall() as isize)
+                            .await;
+                    }
+                    self.handle_signal(status as u8).await
+                }
                 Event::Signal { sig, code, status }
                     if sig == abi::SIGCHLD as u32 && code == abi::CLD_TRAPPED && status < 0x100 =>
                 {
@@ -171,7 +193,16 @@ impl<'q> Task<'q> {
                 Event::Signal { sig, code, status }
                     if sig == abi::SIGCHLD as u32 && code == abi::CLD_EXITED =>
                 {
-                    return self.handle_exited(status).await
+                    return self.handle_exited(status as i32, None).await
+                }
+                Event::Signal { sig, code, status }
+                    if sig == abi::SIGCHLD as u32
+                        && (code == abi::CLD_KILLED || code == abi::CLD_DUMPED) =>
+                {
+                    // Report like a shell would, 128 plus the signal number
+                    return self
+                        .handle_exited(128 + status as i32, Some(Signal(status)))
+                        .await;
                 }
                 event => {
                     let mut regs: UserRegs = Default::default();
@@ -189,8 +220,39 @@ impl<'q> Task<'q> {
         }
     }
 
-    fn cont(&self) {
-        if self.task_data.tracer_settings.instruction_trace {
+    /// Hold a signal until the task next resumes normally
+    ///
+    /// Signals that stop the task while a trampoline syscall is in progress
+    /// can't be delivered right away, or a handler would run in the middle of
+    /// our work. Instead they're delivered once the emulated
+    /// syscall has returned, the same as for a signal arriving during any
+    /// other system call that doesn't block.
+    pub fn defer_signal(&mut self, signal: u8) {
+        if (1..=64).contains(&signal) {
+            self.deferred_signals |= 1 << (signal - 1);
+        }
+    }
+
+    /// The deferred signal that will be delivered next, if any
+    pub fn next_deferred_signal(&self) -> Option<u8> {
+        if self.deferred_signals == 0 {
+            None
+        } else {
+            Some(self.deferred_signals.trailing_zeros() as u8 + 1)
+        }
+    }
+
+    fn take_deferred_signal(&mut self) -> Option<u8> {
+        let signal = self.next_deferred_signal()?;
+        self.deferred_signals &= !(1 << (signal - 1));
+        Some(signal)
+    }
+
+    fn cont(&mut self) {
+        if let Some(signal) = self.take_deferred_signal() {
+            // One signal per stop; any others go out at later stops
+            ptrace::cont_with_signal(self.task_data.sys_pid, signal);
+        } else if self.task_data.tracer_settings.instruction_trace {
             ptrace::single_step(self.task_data.sys_pid);
         } else {
             ptrace::cont(self.task_data.sys_pid);
@@ -220,15 +282,42 @@ impl<'q> Task<'q> {
 
         let msg = LogMessage::Signal(signal, stopped_task.regs.clone());
         self.log(log_level, msg);
-        self.cont();
+        if signal == abi::SIGTRAP || signal == abi::SIGSTOP {
+            self.cont();
+        } else {
+            // Pass along other signals, like the container's stop signal
+            ptrace::cont_with_signal(self.task_data.sys_pid, signal);
+        }
+    }
+
+    async fn handle_seccomp_violation(&mut self, nr: isize) {
+        // A denied host syscall is fatal. Report it, then make sure the process
+        // can't catch the SIGSYS and carry on.
+        let sys_pid = self.task_data.sys_pid;
+        self.msg.send(FromTask::SeccompViolation(nr));
+        ptrace::cont_with_signal(sys_pid, abi::SIGKILL);
+        expect_event_or_panic(
+            &mut self.events,
+            sys_pid,
+            Event::Signal {
+                sig: abi::SIGCHLD as u32,
+                code: abi::CLD_KILLED,
+                status: abi::SIGKILL as u32,
+            },
+        )
+        .await;
     }
 
     async fn handle_fork(&mut self, child_pid: u32) {
         panic!("fork not handled yet, pid {}", child_pid);
     }
 
-    async fn handle_exited(&mut self, exit_code: u32) {
-        self.msg.send(FromTask::Exited(exit_code as i32));
+    async fn handle_exited(&mut self, code: i32, signal: Option<Signal>) {
+        self.log(
+            LogLevel::Debug,
+            LogMessage::PtraceRequests(ptrace::request_count()),
+        );
+        self.msg.send(FromTask::Exited { code, signal });
     }
 
     async fn handle_seccomp_trap(&mut self) {
@@ -254,6 +343,38 @@ async fn expect_event_or_panic<'q, 's, 't>(
 }
 
 impl<'q, 's> StoppedTask<'q, 's> {
+    /// Wait for an event while the task runs code on our behalf
+    ///
+    /// Signal delivery stops may come first. Those signals are deferred, and
+    /// the task continues with `resume`, which should match how it was
+    /// originally resumed.
+    pub async fn expect_event_deferring_signals(&mut self, expected: Event, resume: fn(SysPid)) {
+        let sys_pid = self.task.task_data.sys_pid;
+        loop {
+            match self.task.events.next().await {
+                received if received == expected => return,
+                Event::Signal { sig, code, status }
+                    if sig == abi::SIGCHLD as u32
+                        && code == abi::CLD_TRAPPED
+                        && status < 0x100
+                        && status != abi::SIGTRAP as u32 =>
+                {
+                    self.task.defer_signal(status as u8);
+                    resume(sys_pid);
+                }
+                received => {
+                    unexpected_event_panic(
+                        sys_pid,
+                        Some(self),
+                        received,
+                        ExpectedEvent::Matching(expected),
+                    )
+                    .await
+                }
+            }
+        }
+    }
+
     pub async fn expect_event_or_panic(&mut self, expected: Event) {
         let sys_pid = self.task.task_data.sys_pid;
         let received = self.task.events.next().await;
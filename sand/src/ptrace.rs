@@ -2,9 +2,27 @@ use crate::{
     abi,
     protocol::{abi::UserRegs, SysPid},
 };
-use core::{mem, ptr::null};
+use core::{
+    mem,
+    ptr::null,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 use sc::syscall;
 
+static REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Count the ptrace requests made to any task so far
+///
+/// These are the main cost of emulating a syscall, so the count makes a
+/// useful benchmark. Waiting for a task doesn't count.
+pub fn request_count() -> usize {
+    REQUESTS.load(Ordering::Relaxed)
+}
+
+fn counted() {
+    REQUESTS.fetch_add(1, Ordering::Relaxed);
+}
+
 pub struct RawExecArgs<'a> {
     cmd: &'a [u8],
     argv: &'a [*const u8],
@@ -37,24 +55,35 @@ pub unsafe fn be_the_child_process(args: &RawExecArgs) -> ! {
 }
 
 pub fn cont(pid: SysPid) {
+    counted();
     unsafe {
         syscall!(PTRACE, abi::PTRACE_CONT, pid.0, 0, 0);
     }
 }
 
+pub fn cont_with_signal(pid: SysPid, signal: u8) {
+    counted();
+    unsafe {
+        syscall!(PTRACE, abi::PTRACE_CONT, pid.0, 0, signal as usize);
+    }
+}
+
 pub fn single_step(pid: SysPid) {
+    counted();
     unsafe {
         syscall!(PTRACE, abi::PTRACE_SINGLESTEP, pid.0, 0, 0);
     }
 }
 
 pub fn trace_syscall(pid: SysPid) {
+    counted();
     unsafe {
         syscall!(PTRACE, abi::PTRACE_SYSCALL, pid.0, 0, 0);
     }
 }
 
 pub fn setoptions(pid: SysPid) {
+    counted();
     let options = abi::PTRACE_O_EXITKILL
         | abi::PTRACE_O_TRACECLONE
         | abi::PTRACE_O_TRACEEXEC
@@ -69,6 +98,7 @@ pub fn setoptions(pid: SysPid) {
 }
 
 pub fn get_regs(pid: SysPid, regs: &mut UserRegs) {
+    counted();
     let mut iovec = abi::IOVec {
         base: regs as *mut UserRegs as *mut u8,
         len: mem::size_of_val(regs),
@@ -89,6 +119,7 @@ pub fn get_regs(pid: SysPid, regs: &mut UserRegs) {
 }
 
 pub fn set_regs(pid: SysPid, regs: &UserRegs) {
+    counted();
     let mut iovec = abi::IOVec {
         base: regs as *const UserRegs as *mut u8,
         len: mem::size_of_val(regs),
@@ -109,6 +140,7 @@ pub fn set_regs(pid: SysPid, regs: &UserRegs) {
 }
 
 pub fn geteventmsg(pid: SysPid) -> usize {
+    counted();
     let mut result = usize::MAX;
     match unsafe {
         syscall!(
@@ -124,7 +156,24 @@ pub fn geteventmsg(pid: SysPid) -> usize {
     }
 }
 
+pub fn getsiginfo(pid: SysPid, info: &mut abi::SigInfo) {
+    counted();
+    match unsafe {
+        syscall!(
+            PTRACE,
+            abi::PTRACE_GETSIGINFO,
+            pid.0,
+            0,
+            info as *mut abi::SigInfo
+        ) as isize
+    } {
+        0 => (),
+        err => panic!("ptrace getsiginfo failed ({})", err),
+    }
+}
+
 pub fn poke(pid: SysPid, addr: usize, data: usize) -> Result<(), ()> {
+    counted();
     match unsafe { syscall!(PTRACE, abi::PTRACE_POKEDATA, pid.0, addr, data) as isize } {
         0 => Ok(()),
         _ => Err(()),
@@ -57,15 +57,21 @@ impl<'q, 's, 't> Trampoline<'q, 's, 't> {
         ptrace::set_regs(pid, &local_regs);
         ptrace::trace_syscall(pid);
         self.stopped_task
-            .expect_event_or_panic(Event::Signal {
-                sig: abi::SIGCHLD as u32,
-                code: abi::CLD_TRAPPED,
-                status: abi::PTRACE_SIG_TRACESYSGOOD,
-            })
+            .expect_event_deferring_signals(
+                Event::Signal {
+                    sig: abi::SIGCHLD as u32,
+                    code: abi::CLD_TRAPPED,
+                    status: abi::PTRACE_SIG_TRACESYSGOOD,
+                },
+                ptrace::trace_syscall,
+            )
             .await;
         ptrace::get_regs(pid, &mut local_regs);
 
-        // Save the results from the remote call
+        // Save the results from the remote call. If a signal interrupted it,
+        // this may be one of the kernel's restart codes. Those pass through
+        // to the emulated syscall, which decides whether the guest sees EINTR
+        // or runs the call again.
         let result = Syscall::ret_from_regs(&local_regs);
 
         let log_level = LogLevel::Debug;
@@ -91,11 +97,14 @@ impl<'q, 's, 't> Trampoline<'q, 's, 't> {
         ptrace::set_regs(pid, &local_regs);
         ptrace::single_step(pid);
         self.stopped_task
-            .expect_event_or_panic(Event::Signal {
-                sig: abi::SIGCHLD as u32,
-                code: abi::CLD_TRAPPED,
-                status: abi::PTRACE_SIG_SECCOMP,
-            })
+            .expect_event_deferring_signals(
+                Event::Signal {
+                    sig: abi::SIGCHLD as u32,
+                    code: abi::CLD_TRAPPED,
+                    status: abi::PTRACE_SIG_SECCOMP,
+                },
+                ptrace::single_step,
+            )
             .await;
         ptrace::get_regs(pid, &mut local_regs);
         let info = Syscall::from_regs(&local_regs);
@@ -1,68 +1,38 @@
-use crate::abi;
+use crate::{
+    abi,
+    protocol::{
+        abi::{OPTIONAL_PASSTHROUGH_SYSCALLS, PASSTHROUGH_SYSCALLS},
+        SeccompRuleList, SECCOMP_RULE_LOG,
+    },
+};
 use sc::nr;
 use seccomp_tiny::{abi::*, bpf::*, ProgramBuffer};
 
-// This file has two policies; the "tracer" policy is applied very early, and
-// covers this process for its entire lifetime. The "loader" policy is applied
-// during stage 2, and it applies additional ruless which the sandbox contents
-// use but not the tracer.
+// The "tracer" policy is applied very early, and covers this process for its
+// entire lifetime. It's tightened once init starts, after the "spawner" helper
+// that starts every loader has been cloned with its own policy. The "loader"
+// policy is applied during stage 2, and it applies additional ruless which the
+// sandbox contents use but not the tracer.
 //
 // For comparison, the container we might be running in likely has a policy like
 // this one: https://github.com/moby/moby/blob/master/profiles/seccomp/default.json
 
+// Fully allowed in all modes: protocol::abi::PASSTHROUGH_SYSCALLS
+// Allowed when a container asks: protocol::abi::OPTIONAL_PASSTHROUGH_SYSCALLS
+// to do: none of this has been audited yet. this will generally be all syscalls
+// that deal with existing fds or with memory, but nothing that deals with pids
+// and nothing that has a pathname in it.
+// to do: explicitly whitelist constants on functions like seek and mmap
+
 fn base_rules_for_all_policies() -> ProgramBuffer {
     let mut p = ProgramBuffer::new();
 
     // Keep syscall in the accumulator generally
     p.inst(load(offset_of!(SeccompData, nr)));
 
-    // Fully allowed in all modes
-    // to do: none of this has been audited yet. this will generally be all syscalls
-    // that deal with existing fds or with memory, but nothing that deals with pids
-    // and nothing that has a pathname in it.
-    // to do: explicitly whitelist constants on functions like seek and mmap
-    p.if_any_eq(
-        &[
-            nr::COPY_FILE_RANGE,
-            nr::EXIT,
-            nr::EXIT_GROUP,
-            nr::FUTEX,
-            nr::GETRANDOM,
-            nr::GETRLIMIT,
-            nr::LSEEK,
-            nr::MEMFD_CREATE,
-            nr::MMAP,
-            nr::MPROTECT,
-            nr::MREMAP,
-            nr::MUNMAP,
-            nr::NANOSLEEP,
-            nr::POLL,
-            nr::PREAD64,
-            nr::PSELECT6,
-            nr::PWRITE64,
-            nr::READ,
-            nr::READV,
-            nr::RT_SIGACTION,
-            nr::RT_SIGPROCMASK,
-            nr::RT_SIGRETURN,
-            nr::SELECT,
-            nr::SENDFILE,
-            nr::SET_ROBUST_LIST,
-            nr::SIGALTSTACK,
-            nr::TIME,
-            nr::WRITE,
-            nr::WRITEV,
-            // fixme: only allow some operations
-            nr::FCNTL,
-            nr::ARCH_PRCTL,
-            nr::PRCTL,
-            nr::FADVISE64,
-            // fixme: only allow pid==0 case
-            nr::SCHED_GETAFFINITY,
-            nr::PRLIMIT64,
-        ],
-        &[ret(SECCOMP_RET_ALLOW)],
-    );
+    for nr in PASSTHROUGH_SYSCALLS {
+        p.if_eq(*nr as usize, &[ret(SECCOMP_RET_ALLOW)]);
+    }
     p
 }
 
@@ -75,11 +45,15 @@ pub fn base_rules_for_tracer() -> ProgramBuffer {
         &[
             nr::SENDMSG,
             nr::RECVMSG,
+            nr::CLOCK_GETTIME,
             nr::CLOSE,
+            nr::FCNTL,
             nr::WAITID,
             nr::PTRACE,
             nr::GETPID,
+            nr::GETRANDOM,
             nr::SOCKETPAIR,
+            nr::PRCTL,
         ],
         &[ret(SECCOMP_RET_ALLOW)],
     );
@@ -90,9 +64,15 @@ pub fn base_rules_for_tracer() -> ProgramBuffer {
 pub fn policy_for_tracer_init() {
     let mut p = base_rules_for_tracer();
 
-    // During init, we need the tracer to make one real non-emulated fork and exec,
-    // which will subsequently be disallowed/emulated.
-    p.if_any_eq(&[nr::FORK, nr::EXECVE], &[ret(SECCOMP_RET_ALLOW)]);
+    // During init, the tracer clones a helper which makes a real non-emulated
+    // clone and exec for each loader. The loaders' own calls are emulated.
+    p.if_any_eq(&[nr::CLONE, nr::EXECVE], &[ret(SECCOMP_RET_ALLOW)]);
+
+    // Every loader inherits this filter, and filters can only tighten each
+    // other, so the spawner narrows these to the ones the container asked for.
+    for nr in OPTIONAL_PASSTHROUGH_SYSCALLS {
+        p.if_eq(*nr as usize, &[ret(SECCOMP_RET_ALLOW)]);
+    }
 
     // There is no tracer yet, but we want to allow tracing later.
     // With no tracer attached this blocks the syscall with ENOSYS.
@@ -109,22 +89,57 @@ pub fn policy_for_tracer_after_init() {
     p.activate();
 }
 
-pub fn policy_for_loader() {
+pub fn policy_for_spawner(extra_passthrough: u64) {
+    let mut p = base_rules_for_tracer();
+
+    p.if_any_eq(&[nr::CLONE, nr::EXECVE], &[ret(SECCOMP_RET_ALLOW)]);
+
+    for (index, nr) in OPTIONAL_PASSTHROUGH_SYSCALLS.iter().enumerate() {
+        if extra_passthrough & (1 << index) != 0 {
+            p.if_eq(*nr as usize, &[ret(SECCOMP_RET_ALLOW)]);
+        }
+    }
+
+    p.inst(ret(SECCOMP_RET_TRACE));
+
+    p.activate();
+}
+
+pub fn policy_for_loader(extra_passthrough: u64) {
     let mut p = base_rules_for_all_policies();
 
+    // Optional passthrough syscalls the container asked for, ahead of the
+    // rules that would emulate or deny them
+    for (index, nr) in OPTIONAL_PASSTHROUGH_SYSCALLS.iter().enumerate() {
+        if extra_passthrough & (1 << index) != 0 {
+            p.if_eq(*nr as usize, &[ret(SECCOMP_RET_ALLOW)]);
+        }
+    }
+
     // Calls to emulate / calls to allow the emulator to remotely issue
     p.if_any_eq(
         &[
             nr::ACCESS,
+            nr::ARCH_PRCTL,
             nr::BRK,
             nr::CHDIR,
+            nr::CLOCK_GETTIME,
             nr::CLONE,
             nr::CLOSE,
+            nr::CONNECT,
             nr::DUP,
             nr::DUP2,
+            nr::DUP3,
             nr::EXECVE,
+            nr::FACCESSAT,
+            nr::FACCESSAT2,
             nr::FCHDIR,
+            nr::FCNTL,
+            nr::FGETXATTR,
+            nr::FLISTXATTR,
             nr::FORK,
+            nr::FREMOVEXATTR,
+            nr::FSETXATTR,
             nr::FSTAT,
             nr::FSTATFS,
             nr::GETCWD,
@@ -136,22 +151,52 @@ pub fn policy_for_loader() {
             nr::GETPGRP,
             nr::GETPID,
             nr::GETPPID,
+            nr::GETPRIORITY,
+            nr::GETRANDOM,
             nr::GETTID,
             nr::GETUID,
+            nr::GETXATTR,
             nr::IOCTL,
+            nr::KILL,
+            nr::LGETXATTR,
+            nr::LISTXATTR,
+            nr::LLISTXATTR,
+            nr::LREMOVEXATTR,
+            nr::LSETXATTR,
             nr::LSTAT,
+            nr::MADVISE,
+            nr::MKDIR,
+            nr::MKDIRAT,
             nr::NEWFSTATAT,
             nr::OPEN,
             nr::OPENAT,
+            nr::OPENAT2,
+            nr::PIPE,
+            nr::PIPE2,
+            nr::PRCTL,
             nr::READLINK,
             nr::RECVMSG,
+            nr::REMOVEXATTR,
+            nr::RMDIR,
+            nr::SCHED_GETAFFINITY,
+            nr::SCHED_GETPARAM,
+            nr::SCHED_GETSCHEDULER,
+            nr::SCHED_SETAFFINITY,
+            nr::SCHED_SETSCHEDULER,
             nr::SENDMSG,
             nr::SETPGID,
+            nr::SETXATTR,
             nr::SET_TID_ADDRESS,
+            nr::SOCKET,
             nr::STAT,
             nr::STATFS,
+            nr::STATX,
             nr::SYSINFO,
+            nr::TGKILL,
+            nr::TKILL,
             nr::UNAME,
+            nr::UNLINK,
+            nr::UNLINKAT,
             nr::WAIT4,
         ],
         &[ret(SECCOMP_RET_TRACE)],
@@ -160,10 +205,8 @@ pub fn policy_for_loader() {
     // Reject network subsystem
     p.if_any_eq(
         &[
-            nr::SOCKET,
             nr::BIND,
             nr::LISTEN,
-            nr::CONNECT,
             nr::ACCEPT,
             nr::SHUTDOWN,
             nr::GETSOCKNAME,
@@ -178,12 +221,9 @@ pub fn policy_for_loader() {
     // Reject filesystem modification
     p.if_any_eq(
         &[
-            nr::MKDIR,
-            nr::RMDIR,
             nr::CHMOD,
             nr::CREAT,
             nr::LINK,
-            nr::UNLINK,
             nr::SYMLINK,
             nr::CHMOD,
             nr::FCHMOD,
@@ -193,8 +233,45 @@ pub fn policy_for_loader() {
         &[ret(SECCOMP_RET_ERRNO | -abi::EROFS as u16 as u32)],
     );
 
-    // All other syscalls panic via SIGSYS
+    // All other syscalls raise SIGSYS, reported as a seccomp violation
     p.inst(ret(SECCOMP_RET_TRAP));
 
     p.activate();
 }
+
+pub fn policy_for_loader_profile(rules: &SeccompRuleList, denied_passthrough: u64) {
+    // Optional extra filter stacked on the loader policy, from the container's
+    // seccomp profile and passthrough syscall list. The kernel evaluates every
+    // filter and keeps the most restrictive result, so this can only tighten
+    // the built-in policy. A log rule still ranks above allow, so the kernel
+    // audits those calls. Rules are limited to syscalls we would otherwise
+    // pass through to the host; restricting an emulated call would only break
+    // the emulator.
+    if rules.is_empty() && denied_passthrough == 0 {
+        return;
+    }
+    let mut p = ProgramBuffer::new();
+    p.inst(load(offset_of!(SeccompData, nr)));
+    for (index, nr) in PASSTHROUGH_SYSCALLS.iter().enumerate() {
+        // Removed from the passthrough set, as if the host didn't have it
+        if denied_passthrough & (1 << index) != 0 {
+            p.if_eq(
+                *nr as usize,
+                &[ret(SECCOMP_RET_ERRNO | -abi::ENOSYS as u16 as u32)],
+            );
+        }
+    }
+    for rule in rules.as_slice() {
+        let nr = rule.nr as u64;
+        if PASSTHROUGH_SYSCALLS.contains(&nr) {
+            let action = match rule.errno {
+                0 => SECCOMP_RET_TRAP,
+                SECCOMP_RULE_LOG => SECCOMP_RET_LOG,
+                errno => SECCOMP_RET_ERRNO | errno as u16 as u32,
+            };
+            p.if_eq(nr as usize, &[ret(action)]);
+        }
+    }
+    p.inst(ret(SECCOMP_RET_ALLOW));
+    p.activate();
+}
@@ -0,0 +1,110 @@
+//! Starting loaders without letting the tracer fork or exec
+//!
+//! The tracer gives up fork and exec once the container's init has started,
+//! before it reads any guest memory. Loaders for processes started later come
+//! from this helper instead, which is cloned at init with its own narrower
+//! filter. It shares the tracer's file table, so an args file received by the
+//! tracer is already open here, and each loader is cloned with CLONE_PARENT
+//! so it remains the tracer's child and can ask the tracer to trace it.
+
+use crate::{
+    abi,
+    nolibc::{exit, File, PROC_SELF_EXE},
+    protocol::{Errno, SysFd, SysPid},
+    ptrace,
+    ptrace::RawExecArgs,
+    seccomp, EXIT_OK, STAGE_2_INIT_LOADER,
+};
+use core::ptr::null;
+use heapless::{consts::*, String};
+use sc::syscall;
+
+pub struct Spawner {
+    channel: Option<File>,
+    sys_pid: SysPid,
+}
+
+impl Spawner {
+    /// Clone the helper, while the tracer is still allowed to
+    pub fn new(extra_passthrough: u64) -> Spawner {
+        let (channel, remote) =
+            File::socketpair(abi::AF_UNIX, abi::SOCK_SEQPACKET | abi::SOCK_CLOEXEC, 0)
+                .expect("spawner socket pair");
+        let flags = abi::CLONE_FILES | abi::SIGCHLD as usize;
+        match unsafe { syscall!(CLONE, flags, 0, 0, 0, 0) } as isize {
+            result if result == 0 => run(remote, extra_passthrough),
+            result if result < 0 => panic!("spawner clone error"),
+            result => Spawner {
+                channel: Some(channel),
+                sys_pid: SysPid(result as u32),
+            },
+        }
+    }
+
+    pub fn sys_pid(&self) -> SysPid {
+        self.sys_pid
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.channel.is_some()
+    }
+
+    /// Start a loader for an args file, returning its pid once it exists
+    ///
+    /// The loader stops itself before exec, and that stop can't be waited on
+    /// until this returns, so the tracer always knows the pid by then.
+    pub fn spawn(&self, args_fd: &SysFd) -> SysPid {
+        let channel = self.channel.as_ref().expect("spawner not running");
+        channel
+            .write_all(&args_fd.0.to_ne_bytes())
+            .expect("spawner request");
+        match recv_u32(channel).expect("spawner reply") as i32 {
+            result if result < 0 => panic!("loader clone error, {}", result),
+            result => SysPid(result as u32),
+        }
+    }
+
+    /// Stop the helper; it exits once it sees the channel close
+    pub fn close(&mut self) {
+        if let Some(channel) = self.channel.take() {
+            channel.close().expect("spawner channel leak");
+        }
+    }
+}
+
+fn recv_u32(channel: &File) -> Result<u32, Errno> {
+    let mut bytes = [0u8; 4];
+    loop {
+        // Each message arrives whole, so an interrupted read can just retry
+        match channel.read_exact(&mut bytes) {
+            Err(Errno(err)) if err == -abi::EINTR => (),
+            result => return result.map(|()| u32::from_ne_bytes(bytes)),
+        }
+    }
+}
+
+fn run(channel: File, extra_passthrough: u64) -> ! {
+    seccomp::policy_for_spawner(extra_passthrough);
+    while let Ok(args_fd) = recv_u32(&channel) {
+        let args_fd = SysFd(args_fd);
+        let flags = abi::CLONE_PARENT | abi::SIGCHLD as usize;
+        let result = unsafe { syscall!(CLONE, flags, 0, 0, 0, 0) } as isize;
+        if result == 0 {
+            exec_loader(&args_fd);
+        }
+        channel
+            .write_all(&(result as i32).to_ne_bytes())
+            .expect("spawner reply");
+    }
+    exit(EXIT_OK)
+}
+
+fn exec_loader(args_fd: &SysFd) -> ! {
+    let mut fd_str = String::<U16>::from("FD=");
+    fd_str.push_str(&String::<U16>::from(args_fd.0)).unwrap();
+    fd_str.push('\0').unwrap();
+    let loader_argv = [STAGE_2_INIT_LOADER.as_ptr(), null()];
+    let loader_env = [fd_str.as_ptr(), null()];
+    let exec_args = unsafe { RawExecArgs::new(PROC_SELF_EXE, &loader_argv, &loader_env) };
+    unsafe { ptrace::be_the_child_process(&exec_args) }
+}
@@ -9,7 +9,7 @@ use crate::{
     },
     remote::{file::RemoteFd, trampoline::Trampoline},
     syscall,
-    syscall::result::SyscallResult,
+    syscall::{fs::XAttrTarget, result::SyscallResult, source::HostSource},
 };
 use plain::Plain;
 use sc::nr;
@@ -39,11 +39,12 @@ impl<'q, 's, 't> SyscallEmulator<'q, 's, 't> {
         let mut tr = Trampoline::new(self.stopped_task);
         let result = syscall::result::file(&mut tr, sys_fd).await;
         if let Ok(fd) = &result {
-            self.stopped_task
-                .task
-                .task_data
-                .file_table
-                .open(fd.clone(), vfile);
+            let task = &mut self.stopped_task.task;
+            task.task_data.file_table.open(fd.clone(), vfile.clone());
+            task.msg.send(FromTask::FdOpened {
+                fd: fd.0,
+                file: vfile,
+            });
         }
         result
     }
@@ -144,6 +145,104 @@ impl<'q, 's, 't> SyscallEmulator<'q, 's, 't> {
         Ok(actual_len)
     }
 
+    /// Like `return_bytes_result`, but the whole path has to fit
+    async fn return_cwd_result(
+        &mut self,
+        result: Result<(SysFd, usize), Errno>,
+        buffer: VPtr,
+        buffer_len: usize,
+    ) -> Result<usize, Errno> {
+        match result {
+            Ok((_, result_len)) if result_len > buffer_len => Err(Errno(-abi::ERANGE)),
+            result => self.return_bytes_result(result, buffer, buffer_len).await,
+        }
+    }
+
+    async fn openat(
+        &mut self,
+        dir: i32,
+        path: VString,
+        flags: i32,
+        mode: i32,
+    ) -> Result<RemoteFd, Errno> {
+        let dir = syscall::fs::at_dir(self.stopped_task, dir, &path)?;
+        let result = ipc_call!(
+            self.stopped_task.task,
+            FromTask::FileOpen {
+                dir,
+                path,
+                flags,
+                mode
+            },
+            ToTask::FileReply(result),
+            result
+        );
+        self.return_file_result(result).await
+    }
+
+    async fn openat2(
+        &mut self,
+        dir: i32,
+        path: VString,
+        how: VPtr,
+        size: usize,
+    ) -> Result<RemoteFd, Errno> {
+        let dir = syscall::fs::at_dir(self.stopped_task, dir, &path)?;
+        let result = ipc_call!(
+            self.stopped_task.task,
+            FromTask::FileOpenHow {
+                dir,
+                path,
+                how,
+                size
+            },
+            ToTask::FileReply(result),
+            result
+        );
+        self.return_file_result(result).await
+    }
+
+    fn xattr_target(&self) -> XAttrTarget {
+        // All three variants of each xattr call share an argument layout,
+        // differing only in how the first argument names a file.
+        let arg = self.call.args[0];
+        match self.call.nr as usize {
+            nr::FGETXATTR | nr::FSETXATTR | nr::FLISTXATTR | nr::FREMOVEXATTR => {
+                XAttrTarget::Fd(RemoteFd(arg as u32))
+            }
+            nr::LGETXATTR | nr::LSETXATTR | nr::LLISTXATTR | nr::LREMOVEXATTR => {
+                XAttrTarget::Path(VString(VPtr(arg as usize)), FollowLinks::NoFollow)
+            }
+            _ => XAttrTarget::Path(VString(VPtr(arg as usize)), FollowLinks::Follow),
+        }
+    }
+
+    /// Apply the kernel's restart rules to a call a signal interrupted
+    ///
+    /// A remote syscall can end early with one of the kernel's restart codes
+    /// when a signal arrives. That signal is delivered when the emulated call
+    /// returns, and its handler decides between `EINTR` and a restart just
+    /// like it would for a real syscall. Returns None to restart.
+    async fn interrupted_result(&mut self, result: isize) -> Option<isize> {
+        let is_code = |code: i32| result == -code as isize;
+        let obeys_sa_restart = if is_code(abi::ERESTARTNOINTR) {
+            return None;
+        } else if is_code(abi::ERESTARTSYS) {
+            true
+        } else if is_code(abi::ERESTARTNOHAND) || is_code(abi::ERESTART_RESTARTBLOCK) {
+            false
+        } else {
+            return Some(result);
+        };
+        // Without a handler to run, every interrupted call restarts
+        let signal = self.stopped_task.task.next_deferred_signal()?;
+        match syscall::user::signal_handler_flags(self.stopped_task, signal).await {
+            Ok(None) => None,
+            Ok(Some(flags)) if obeys_sa_restart && flags & abi::SA_RESTART != 0 => None,
+            _ => Some(-abi::EINTR as isize),
+        }
+    }
+
     pub async fn dispatch(&mut self) {
         let args = self.call.args;
         let arg_u32 = |idx| args[idx] as u32;
@@ -160,14 +259,44 @@ impl<'q, 's, 't> SyscallEmulator<'q, 's, 't> {
 
             nr::FORK => syscall::user::fork(self.stopped_task).await.into(),
 
-            nr::EXECVE => Exec {
-                filename: arg_string(0),
-                argv: VStringArray(arg_ptr(1)),
-                envp: VStringArray(arg_ptr(2)),
+            nr::MADVISE => {
+                syscall::user::madvise(self.stopped_task, arg_ptr(0), arg_usize(1), arg_i32(2))
+                    .await
+                    .into()
+            }
+
+            nr::EXECVE => {
+                let result = Exec {
+                    filename: arg_string(0),
+                    argv: VStringArray(arg_ptr(1)),
+                    envp: VStringArray(arg_ptr(2)),
+                }
+                .load(self.stopped_task)
+                .await;
+                if result.is_ok() {
+                    syscall::fs::close_on_exec(self.stopped_task).await;
+                }
+                let task = &mut self.stopped_task.task;
+                if task.task_data.loader_stage {
+                    // The loader has nothing to fall back on, so report why
+                    // the container couldn't start before it gives up
+                    match result {
+                        Ok(()) => task.task_data.loader_stage = false,
+                        Err(err) => task.msg.send(FromTask::LoaderFailed(err)),
+                    }
+                }
+                result.into()
+            }
+
+            nr::PRCTL => syscall::user::prctl(self.stopped_task, arg_usize(0), &args[1..])
+                .await
+                .into(),
+
+            nr::ARCH_PRCTL => {
+                syscall::user::arch_prctl(self.stopped_task, arg_usize(0), arg_usize(1))
+                    .await
+                    .into()
             }
-            .load(self.stopped_task)
-            .await
-            .into(),
 
             nr::UNAME => syscall::user::uname(self.stopped_task, arg_ptr(0))
                 .await
@@ -179,31 +308,134 @@ impl<'q, 's, 't> SyscallEmulator<'q, 's, 't> {
                 .await
                 .into(),
 
+            nr::DUP3 => syscall::fs::dup3(self.stopped_task, arg_fd(0), arg_fd(1), arg_usize(2))
+                .await
+                .into(),
+
+            nr::PIPE => syscall::fs::pipe2(self.stopped_task, arg_ptr(0), 0)
+                .await
+                .into(),
+
+            nr::PIPE2 => syscall::fs::pipe2(self.stopped_task, arg_ptr(0), arg_usize(1))
+                .await
+                .into(),
+
+            nr::SOCKET => {
+                syscall::socket::socket(self.stopped_task, arg_usize(0), arg_usize(1), arg_usize(2))
+                    .await
+                    .into()
+            }
+
+            nr::CONNECT => {
+                syscall::socket::connect(self.stopped_task, arg_fd(0), arg_ptr(1), arg_usize(2))
+                    .await
+                    .into()
+            }
+
+            nr::FCNTL => {
+                syscall::fs::fcntl(self.stopped_task, arg_fd(0), arg_usize(1), arg_usize(2))
+                    .await
+                    .into()
+            }
+
             nr::GETPID => self.stopped_task.task.task_data.vpid.into(),
             nr::GETTID => self.stopped_task.task.task_data.vpid.into(),
 
+            nr::KILL => syscall::user::kill(self.stopped_task, arg_i32(0), arg_i32(1))
+                .await
+                .into(),
+
+            nr::TKILL => syscall::user::tgkill(self.stopped_task, None, arg_i32(0), arg_i32(1))
+                .await
+                .into(),
+
+            nr::TGKILL => {
+                syscall::user::tgkill(self.stopped_task, Some(arg_i32(0)), arg_i32(1), arg_i32(2))
+                    .await
+                    .into()
+            }
+
             nr::GETPPID => SyscallResult(1),
-            nr::GETUID => SyscallResult(0),
-            nr::GETGID => SyscallResult(0),
-            nr::GETEUID => SyscallResult(0),
-            nr::GETEGID => SyscallResult(0),
+            nr::GETUID | nr::GETEUID => {
+                SyscallResult(self.stopped_task.task.task_data.tracer_settings.uid as isize)
+            }
+            nr::GETGID | nr::GETEGID => {
+                SyscallResult(self.stopped_task.task.task_data.tracer_settings.gid as isize)
+            }
             nr::GETPGRP => SyscallResult(0),
             nr::SETPGID => SyscallResult(0),
             nr::GETPGID => SyscallResult(0),
 
-            nr::SYSINFO => SyscallResult(0),
+            nr::SYSINFO => syscall::user::sysinfo(self.stopped_task, &HostSource, arg_ptr(0))
+                .await
+                .into(),
+
+            nr::GETPRIORITY => {
+                syscall::user::getpriority(self.stopped_task, arg_usize(0), arg_u32(1))
+                    .await
+                    .into()
+            }
+
+            nr::SCHED_GETAFFINITY => syscall::user::sched_getaffinity(
+                self.stopped_task,
+                arg_u32(0),
+                arg_usize(1),
+                arg_ptr(2),
+            )
+            .await
+            .into(),
+
+            nr::SCHED_SETAFFINITY => {
+                syscall::user::sched_setaffinity(self.stopped_task, arg_u32(0))
+                    .await
+                    .into()
+            }
+
+            nr::SCHED_GETPARAM => {
+                syscall::user::sched_getparam(self.stopped_task, arg_i32(0), arg_ptr(1))
+                    .await
+                    .into()
+            }
+
+            nr::SCHED_GETSCHEDULER => {
+                syscall::user::sched_getscheduler(self.stopped_task, arg_i32(0))
+                    .await
+                    .into()
+            }
+
+            nr::SCHED_SETSCHEDULER => syscall::user::sched_setscheduler(
+                self.stopped_task,
+                arg_i32(0),
+                arg_usize(1),
+                arg_ptr(2),
+            )
+            .await
+            .into(),
 
             nr::SET_TID_ADDRESS => SyscallResult(0),
 
+            nr::CLOCK_GETTIME => {
+                syscall::user::clock_gettime(self.stopped_task, &HostSource, args[0], arg_ptr(1))
+                    .await
+                    .into()
+            }
+
+            nr::GETRANDOM => syscall::user::getrandom(
+                self.stopped_task,
+                &mut HostSource,
+                arg_ptr(0),
+                arg_usize(1),
+                arg_usize(2),
+            )
+            .await
+            .into(),
+
             nr::WAIT4 => Errno(-abi::ECHILD).into(),
 
             nr::CLONE => panic!("clone"),
 
             nr::IOCTL => {
-                let _fd = arg_fd(0);
-                let _cmd = arg_i32(1);
-                let _arg = arg_usize(2);
-                SyscallResult(0)
+                syscall::fs::ioctl(self.stopped_task, arg_fd(0), arg_u32(1), arg_usize(2)).await
             }
 
             nr::STAT => ipc_call!(
@@ -233,6 +465,17 @@ impl<'q, 's, 't> SyscallEmulator<'q, 's, 't> {
                 self.return_stat_result(arg_ptr(1), result).await.into()
             ),
 
+            nr::STATX => syscall::fs::statx(
+                self.stopped_task,
+                arg_i32(0),
+                arg_string(1),
+                arg_i32(2),
+                arg_u32(3),
+                arg_ptr(4),
+            )
+            .await
+            .into(),
+
             nr::NEWFSTATAT => {
                 log_level = LogLevel::Warn;
                 let flags = arg_i32(3);
@@ -257,25 +500,110 @@ impl<'q, 's, 't> SyscallEmulator<'q, 's, 't> {
                 self.return_stat_result(arg_ptr(2), result).await.into()
             }
 
+            nr::GETXATTR | nr::LGETXATTR | nr::FGETXATTR => syscall::fs::getxattr(
+                self.stopped_task,
+                self.xattr_target(),
+                arg_string(1),
+                arg_ptr(2),
+                arg_usize(3),
+            )
+            .await
+            .into(),
+
+            nr::SETXATTR | nr::LSETXATTR | nr::FSETXATTR => syscall::fs::setxattr(
+                self.stopped_task,
+                self.xattr_target(),
+                arg_string(1),
+                arg_ptr(2),
+                arg_usize(3),
+                arg_i32(4),
+            )
+            .await
+            .into(),
+
+            nr::LISTXATTR | nr::LLISTXATTR | nr::FLISTXATTR => syscall::fs::listxattr(
+                self.stopped_task,
+                self.xattr_target(),
+                arg_ptr(1),
+                arg_usize(2),
+            )
+            .await
+            .into(),
+
+            nr::REMOVEXATTR | nr::LREMOVEXATTR | nr::FREMOVEXATTR => syscall::fs::removexattr(
+                self.stopped_task,
+                self.xattr_target(),
+                arg_string(1),
+            )
+            .await
+            .into(),
+
+            nr::MKDIR => {
+                syscall::fs::mkdirat(self.stopped_task, abi::AT_FDCWD, arg_string(0), arg_i32(1))
+                    .await
+                    .into()
+            }
+
+            nr::MKDIRAT => {
+                syscall::fs::mkdirat(self.stopped_task, arg_i32(0), arg_string(1), arg_i32(2))
+                    .await
+                    .into()
+            }
+
+            nr::UNLINK => syscall::fs::unlinkat(self.stopped_task, abi::AT_FDCWD, arg_string(0), 0)
+                .await
+                .into(),
+
+            nr::RMDIR => syscall::fs::unlinkat(
+                self.stopped_task,
+                abi::AT_FDCWD,
+                arg_string(0),
+                abi::AT_REMOVEDIR,
+            )
+            .await
+            .into(),
+
+            nr::UNLINKAT => {
+                syscall::fs::unlinkat(self.stopped_task, arg_i32(0), arg_string(1), arg_i32(2))
+                    .await
+                    .into()
+            }
+
             nr::STATFS => self.return_statfs(arg_ptr(1)).await.into(),
             nr::FSTATFS => self.return_statfs(arg_ptr(1)).await.into(),
 
-            nr::ACCESS => ipc_call!(
-                self.stopped_task.task,
-                FromTask::FileAccess {
-                    dir: None,
-                    path: arg_string(0),
-                    mode: arg_i32(1),
-                },
-                ToTask::Reply(result),
-                result.into()
-            ),
+            nr::ACCESS => syscall::fs::faccessat(
+                self.stopped_task,
+                abi::AT_FDCWD,
+                arg_string(0),
+                arg_i32(1),
+                0,
+            )
+            .await
+            .into(),
+
+            // The original faccessat has no flags argument
+            nr::FACCESSAT => {
+                syscall::fs::faccessat(self.stopped_task, arg_i32(0), arg_string(1), arg_i32(2), 0)
+                    .await
+                    .into()
+            }
+
+            nr::FACCESSAT2 => syscall::fs::faccessat(
+                self.stopped_task,
+                arg_i32(0),
+                arg_string(1),
+                arg_i32(2),
+                arg_i32(3),
+            )
+            .await
+            .into(),
 
             nr::GETCWD => ipc_call!(
                 self.stopped_task.task,
                 FromTask::GetWorkingDir,
                 ToTask::BytesReply(result),
-                self.return_bytes_result(result, arg_ptr(0), arg_usize(1))
+                self.return_cwd_result(result, arg_ptr(0), arg_usize(1))
                     .await
                     .into()
             ),
@@ -292,6 +620,7 @@ impl<'q, 's, 't> SyscallEmulator<'q, 's, 't> {
             nr::GETDENTS64 => {
                 syscall::fs::getdents(self.stopped_task, arg_fd(0), arg_ptr(1), arg_usize(2))
                     .await
+                    .into()
             }
 
             nr::CHDIR => ipc_call!(
@@ -319,28 +648,27 @@ impl<'q, 's, 't> SyscallEmulator<'q, 's, 't> {
                 .await
                 .into(),
 
-            nr::OPENAT if arg_i32(0) == abi::AT_FDCWD => {
-                let fd = arg_i32(0);
-                if fd != abi::AT_FDCWD {
-                    log_level = LogLevel::Error;
-                }
-                let result = ipc_call!(
-                    self.stopped_task.task,
-                    FromTask::FileOpen {
-                        dir: None,
-                        path: arg_string(1),
-                        flags: arg_i32(2),
-                        mode: arg_i32(3),
-                    },
-                    ToTask::FileReply(result),
-                    result
-                );
-                self.return_file_result(result).await.into()
-            }
+            nr::OPENAT => self
+                .openat(arg_i32(0), arg_string(1), arg_i32(2), arg_i32(3))
+                .await
+                .into(),
+
+            nr::OPENAT2 => self
+                .openat2(arg_i32(0), arg_string(1), arg_ptr(2), arg_usize(3))
+                .await
+                .into(),
 
             _ => panic!("unexpected {:?}", self.call),
         };
-        self.call.ret = result.0;
+        self.call.ret = match self.interrupted_result(result.0).await {
+            Some(ret) => ret,
+            None => {
+                // Back up to the syscall instruction, so the guest makes the
+                // same call again once any signal handler returns
+                self.stopped_task.regs.ip -= abi::SYSCALL_INSTRUCTION.len();
+                self.call.nr
+            }
+        };
         Syscall::ret_to_regs(self.call.ret, self.stopped_task.regs);
 
         if self.stopped_task.task.log_enabled(log_level) {
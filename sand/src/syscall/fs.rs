@@ -1,26 +1,90 @@
 use crate::{
+    abi,
+    mem::rw::{read_bytes, read_word},
     process::task::StoppedTask,
-    protocol::{Errno, FileStat, FollowLinks, FromTask, ToTask, VFile, VPtr},
+    protocol::{
+        abi::{DirentHeader, S_IFDIR, S_IFMT},
+        Errno, FileStat, FollowLinks, FromTask, SysFd, ToTask, VFile, VPtr, VString,
+    },
     remote::{file::RemoteFd, trampoline::Trampoline},
+    syscall,
     syscall::result::SyscallResult,
 };
+use plain::Plain;
 
+#[repr(C)]
+struct UserStatx(abi::Statx);
+
+unsafe impl Plain for UserStatx {}
+
+/// Directory fds are sealed memfds full of dirent records, so this reads
+/// them into the guest's buffer like read() would, but only keeps whole
+/// records and moves the fd's offset past just those.
 pub async fn getdents(
     stopped_task: &mut StoppedTask<'_, '_>,
     fd: RemoteFd,
     out_ptr: VPtr,
     len: usize,
-) -> SyscallResult {
+) -> Result<usize, Errno> {
+    // Pipes, sockets, and other fds that didn't come from the filesystem
+    // aren't in the file table, and they aren't directories either
+    let stat = match fstat(stopped_task, fd.clone()).await {
+        Err(Errno(err)) if err == -abi::EBADF => return Err(Errno(-abi::ENOTDIR)),
+        result => result?.1,
+    };
+    if stat.st_mode & S_IFMT != S_IFDIR {
+        return Err(Errno(-abi::ENOTDIR));
+    }
     let mut tr = Trampoline::new(stopped_task);
-    // Directory fds are implemented as sealed memfds full of direntries,
-    // so getdents64 simply redirects to read().
-    SyscallResult(
-        tr.syscall(
-            sc::nr::READ,
-            &[fd.0 as isize, out_ptr.0 as isize, len as isize],
-        )
-        .await,
-    )
+    let offset = lseek(&mut tr, &fd, 0, abi::SEEK_CUR).await?;
+    let filled = fd.pread_vptr(&mut tr, out_ptr, len, offset).await?;
+    let mut complete = 0;
+    while complete + offset_of!(DirentHeader, d_name) <= filled {
+        let mut reclen = [0u8; 2];
+        read_bytes(
+            tr.stopped_task,
+            out_ptr + complete + offset_of!(DirentHeader, d_reclen),
+            &mut reclen,
+        )?;
+        let reclen = u16::from_ne_bytes(reclen) as usize;
+        if reclen == 0 || complete + reclen > filled {
+            break;
+        }
+        complete += reclen;
+    }
+    if complete == 0 && filled > 0 {
+        // Not even one record fits
+        return Err(Errno(-abi::EINVAL));
+    }
+    lseek(&mut tr, &fd, (offset + complete) as isize, abi::SEEK_SET).await?;
+    Ok(complete)
+}
+
+async fn lseek(
+    tr: &mut Trampoline<'_, '_, '_>,
+    fd: &RemoteFd,
+    offset: isize,
+    whence: isize,
+) -> Result<usize, Errno> {
+    let result = tr
+        .syscall(sc::nr::LSEEK, &[fd.0 as isize, offset, whence])
+        .await;
+    if result < 0 {
+        Err(Errno(result as i32))
+    } else {
+        Ok(result as usize)
+    }
+}
+
+/// Tell the file table and `/proc` that `dest_fd` now refers to the same
+/// file as `src_fd`
+fn duplicated(stopped_task: &mut StoppedTask<'_, '_>, src_fd: &RemoteFd, dest_fd: &RemoteFd) {
+    let task = &mut stopped_task.task;
+    task.msg.send(FromTask::FdDuplicated {
+        src: src_fd.0,
+        dest: dest_fd.0,
+    });
+    task.task_data.file_table.dup(src_fd, dest_fd);
 }
 
 pub async fn dup(stopped_task: &mut StoppedTask<'_, '_>, src_fd: RemoteFd) -> Result<RemoteFd, Errno> {
@@ -29,26 +93,152 @@ pub async fn dup(stopped_task: &mut StoppedTask<'_, '_>, src_fd: RemoteFd) -> Re
     if result < 0 {
         Err(Errno(result as i32))
     } else {
-        let table = &mut stopped_task.task.task_data.file_table;
         let dest_fd = RemoteFd(result as u32);
-        table.dup(&src_fd, &dest_fd)?;
+        duplicated(stopped_task, &src_fd, &dest_fd);
         Ok(dest_fd)
     }
 }
 
 pub async fn dup2(stopped_task: &mut StoppedTask<'_, '_>, src_fd: RemoteFd, dest_fd: RemoteFd) -> Result<RemoteFd, Errno> {
+    // dup2 with equal fds only checks that the fd is open
     let mut tr = Trampoline::new(stopped_task);
     let result = tr.syscall(sc::nr::DUP2, &[src_fd.0 as isize, dest_fd.0 as isize]).await;
     if result < 0 {
         Err(Errno(result as i32))
     } else {
         assert_eq!(result, dest_fd.0 as isize);
-        let table = &mut stopped_task.task.task_data.file_table;
-        table.dup(&src_fd, &dest_fd)?;
+        if src_fd != dest_fd {
+            duplicated(stopped_task, &src_fd, &dest_fd);
+        }
         Ok(dest_fd)
     }
 }
 
+pub async fn dup3(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    src_fd: RemoteFd,
+    dest_fd: RemoteFd,
+    flags: usize,
+) -> Result<RemoteFd, Errno> {
+    if src_fd == dest_fd || (flags & !abi::O_CLOEXEC) != 0 {
+        return Err(Errno(-abi::EINVAL));
+    }
+    let mut tr = Trampoline::new(stopped_task);
+    let result = tr
+        .syscall(
+            sc::nr::DUP3,
+            &[src_fd.0 as isize, dest_fd.0 as isize, flags as isize],
+        )
+        .await;
+    if result < 0 {
+        Err(Errno(result as i32))
+    } else {
+        assert_eq!(result, dest_fd.0 as isize);
+        duplicated(stopped_task, &src_fd, &dest_fd);
+        Ok(dest_fd)
+    }
+}
+
+pub async fn fcntl(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    fd: RemoteFd,
+    cmd: usize,
+    arg: usize,
+) -> Result<usize, Errno> {
+    let mut tr = Trampoline::new(stopped_task);
+    let result = match cmd {
+        // Flags, locks, seals, and pipe sizes belong to the host fd, including
+        // the close-on-exec bit, so the kernel can keep track of those for us
+        abi::F_GETFD
+        | abi::F_SETFD
+        | abi::F_GETFL
+        | abi::F_SETFL
+        | abi::F_GETLK
+        | abi::F_SETLK
+        | abi::F_SETLKW
+        | abi::F_OFD_GETLK
+        | abi::F_OFD_SETLK
+        | abi::F_OFD_SETLKW
+        | abi::F_ADD_SEALS
+        | abi::F_GET_SEALS
+        | abi::F_SETPIPE_SZ
+        | abi::F_GETPIPE_SZ => {
+            tr.syscall(sc::nr::FCNTL, &[fd.0 as isize, cmd as isize, arg as isize])
+                .await
+        }
+        abi::F_DUPFD | abi::F_DUPFD_CLOEXEC => {
+            let result = tr
+                .syscall(sc::nr::FCNTL, &[fd.0 as isize, cmd as isize, arg as isize])
+                .await;
+            if result >= 0 {
+                duplicated(stopped_task, &fd, &RemoteFd(result as u32));
+            }
+            result
+        }
+        _ => return Err(Errno(-abi::EINVAL)),
+    };
+    if result < 0 {
+        Err(Errno(result as i32))
+    } else {
+        Ok(result as usize)
+    }
+}
+
+/// Pipes exist only on the host, so the kernel writes both fds straight
+/// into the guest's array and there's no VFS file to remember for either
+pub async fn pipe2(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    fds_ptr: VPtr,
+    flags: usize,
+) -> Result<(), Errno> {
+    let mut tr = Trampoline::new(stopped_task);
+    let result = tr
+        .syscall(sc::nr::PIPE2, &[fds_ptr.0 as isize, flags as isize])
+        .await;
+    if result < 0 {
+        return Err(Errno(result as i32));
+    }
+    let fds = read_word(stopped_task, fds_ptr)?;
+    let file_table = &mut stopped_task.task.task_data.file_table;
+    file_table.close(&RemoteFd(fds as u32));
+    file_table.close(&RemoteFd((fds >> 32) as u32));
+    Ok(())
+}
+
+/// The directory a `*at()` call's relative paths start from, or None for
+/// the working directory
+pub async fn mkdirat(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    dir_fd: i32,
+    path: VString,
+    mode: i32,
+) -> Result<(), Errno> {
+    let dir = at_dir(stopped_task, dir_fd, &path)?;
+    ipc_call!(
+        stopped_task.task,
+        FromTask::MakeDir { dir, path, mode },
+        ToTask::Reply(result),
+        result
+    )
+}
+
+/// Directory entries only exist in the runtime's VFS, so it does the
+/// removing, checking that the entry is in the writable overlay
+pub async fn unlinkat(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    dir_fd: i32,
+    path: VString,
+    flags: i32,
+) -> Result<(), Errno> {
+    let dir = at_dir(stopped_task, dir_fd, &path)?;
+    ipc_call!(
+        stopped_task.task,
+        FromTask::FileUnlink { dir, path, flags },
+        ToTask::Reply(result),
+        result
+    )
+}
+
 pub async fn fstat(
     stopped_task: &mut StoppedTask<'_, '_>,
     fd: RemoteFd,
@@ -66,10 +256,352 @@ pub async fn fstat(
     )
 }
 
+/// Real and effective IDs are always the same in the container, so
+/// `AT_EACCESS` makes no difference.
+pub async fn faccessat(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    dir: i32,
+    path: VString,
+    mode: i32,
+    flags: i32,
+) -> Result<(), Errno> {
+    let known_flags = abi::AT_EACCESS | abi::AT_SYMLINK_NOFOLLOW | abi::AT_EMPTY_PATH;
+    if flags & !known_flags != 0 || mode & !(abi::R_OK | abi::W_OK | abi::X_OK) != 0 {
+        return Err(Errno(-abi::EINVAL));
+    }
+    let (dir, path) = at_path(stopped_task, dir, path, flags)?;
+    ipc_call!(
+        stopped_task.task,
+        FromTask::FileAccess {
+            dir,
+            path,
+            mode,
+            follow_links: if flags & abi::AT_SYMLINK_NOFOLLOW != 0 {
+                FollowLinks::NoFollow
+            } else {
+                FollowLinks::Follow
+            },
+        },
+        ToTask::Reply(result),
+        result
+    )
+}
+
+/// Find the directory and path an `*at()` call refers to, as the host wants
+/// them for a lookup
+///
+/// An empty path refers to the directory itself, but only with
+/// `AT_EMPTY_PATH`.
+fn at_path(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    dir: i32,
+    path: VString,
+    flags: i32,
+) -> Result<(Option<VFile>, Option<VString>), Errno> {
+    let mut first = [0u8; 1];
+    if !(path.0 == VPtr::null() && flags & abi::AT_EMPTY_PATH != 0) {
+        read_bytes(stopped_task, path.0, &mut first)?;
+    }
+    let empty_path = first[0] == 0;
+    if empty_path && flags & abi::AT_EMPTY_PATH == 0 {
+        return Err(Errno(-abi::ENOENT));
+    }
+    if empty_path {
+        Ok((dir_file(stopped_task, dir)?, None))
+    } else {
+        Ok((at_dir(stopped_task, dir, &path)?, Some(path)))
+    }
+}
+
+/// Find the directory an `*at()` call starts from
+///
+/// Absolute paths ignore the directory, so it doesn't need to be valid.
+pub fn at_dir(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    dir: i32,
+    path: &VString,
+) -> Result<Option<VFile>, Errno> {
+    if dir == abi::AT_FDCWD {
+        return Ok(None);
+    }
+    let mut first = [0u8; 1];
+    read_bytes(stopped_task, path.0, &mut first)?;
+    if first[0] == b'/' {
+        Ok(None)
+    } else {
+        dir_file(stopped_task, dir)
+    }
+}
+
+fn dir_file(stopped_task: &mut StoppedTask<'_, '_>, dir: i32) -> Result<Option<VFile>, Errno> {
+    if dir == abi::AT_FDCWD {
+        Ok(None)
+    } else {
+        let file_table = &stopped_task.task.task_data.file_table;
+        file_table.get(&RemoteFd(dir as u32)).map(Some)
+    }
+}
+
+/// Only the basic stats are reported, whatever the mask asks for. The others,
+/// like birth time and mount ID, aren't tracked by our filesystem.
+pub async fn statx(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    dir: i32,
+    path: VString,
+    flags: i32,
+    mask: u32,
+    dest: VPtr,
+) -> Result<(), Errno> {
+    let known_flags = abi::AT_SYMLINK_NOFOLLOW
+        | abi::AT_NO_AUTOMOUNT
+        | abi::AT_EMPTY_PATH
+        | abi::AT_STATX_SYNC_TYPE;
+    if flags & !known_flags != 0
+        || flags & abi::AT_STATX_SYNC_TYPE == abi::AT_STATX_SYNC_TYPE
+        || mask & abi::STATX__RESERVED != 0
+    {
+        return Err(Errno(-abi::EINVAL));
+    }
+    let (file, path) = at_path(stopped_task, dir, path, flags)?;
+    let result: Result<(VFile, FileStat), Errno> = ipc_call!(
+        stopped_task.task,
+        FromTask::FileStat {
+            file,
+            path,
+            follow_links: if flags & abi::AT_SYMLINK_NOFOLLOW != 0 {
+                FollowLinks::NoFollow
+            } else {
+                FollowLinks::Follow
+            },
+        },
+        ToTask::FileStatReply(result),
+        result
+    );
+    let (vfile, stat) = result?;
+    let timestamp = |sec: u64, nsec: u64| abi::StatxTimestamp {
+        tv_sec: sec as i64,
+        tv_nsec: nsec as u32,
+        reserved: 0,
+    };
+    let result = UserStatx(abi::Statx {
+        stx_mask: abi::STATX_BASIC_STATS,
+        stx_blksize: 4096,
+        stx_nlink: stat.st_nlink as u32,
+        stx_uid: stat.st_uid,
+        stx_gid: stat.st_gid,
+        stx_mode: stat.st_mode as u16,
+        stx_ino: vfile.inode as u64,
+        stx_size: stat.st_size as u64,
+        stx_blocks: (stat.st_size as u64 + 511) / 512,
+        stx_atime: timestamp(stat.st_atime, stat.st_atime_nsec),
+        stx_ctime: timestamp(stat.st_ctime, stat.st_ctime_nsec),
+        stx_mtime: timestamp(stat.st_mtime, stat.st_mtime_nsec),
+        stx_rdev_major: dev_major(stat.st_rdev),
+        stx_rdev_minor: dev_minor(stat.st_rdev),
+        stx_dev_major: dev_major(stat.st_dev),
+        stx_dev_minor: dev_minor(stat.st_dev),
+        ..Default::default()
+    });
+    let mut tr = Trampoline::new(stopped_task);
+    syscall::result::local_bytes(&mut tr, unsafe { plain::as_bytes(&result) }, dest).await
+}
+
+/// linux/include/linux/kdev_t.h, `new_decode_dev()` with 64-bit numbers
+fn dev_major(dev: u64) -> u32 {
+    (((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff)) as u32
+}
+
+fn dev_minor(dev: u64) -> u32 {
+    ((dev & 0xff) | ((dev >> 12) & !0xff)) as u32
+}
+
 pub async fn close(stopped_task: &mut StoppedTask<'_, '_>, fd: RemoteFd) -> Result<(), Errno> {
     // Note that the fd will be closed even if close() also reports an error
-    let table = &mut stopped_task.task.task_data.file_table;
-    table.close(&fd);
+    let task = &mut stopped_task.task;
+    task.task_data.file_table.close(&fd);
+    task.msg.send(FromTask::FdClosed(fd.0));
     let mut tr = Trampoline::new(stopped_task);
     fd.close(&mut tr).await
 }
+
+/// Close the files marked close-on-exec, once an exec has succeeded
+///
+/// We load the new program ourselves, so the kernel never does this for us.
+/// Only files in the file table are checked; pipes and sockets are left open.
+pub async fn close_on_exec(stopped_task: &mut StoppedTask<'_, '_>) {
+    let fds = stopped_task.task.task_data.file_table.fds();
+    for fd in fds {
+        let fd_flags = Trampoline::new(stopped_task)
+            .syscall(sc::nr::FCNTL, &[fd.0 as isize, abi::F_GETFD as isize])
+            .await;
+        // An fd that's already gone only needs its entry cleaned up
+        if fd_flags < 0 || fd_flags as usize & abi::F_CLOEXEC != 0 {
+            let _ = close(stopped_task, fd).await;
+        }
+    }
+}
+
+pub async fn ioctl(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    fd: RemoteFd,
+    request: u32,
+    arg: usize,
+) -> SyscallResult {
+    // Only requests we know to be harmless on the real fd are passed through;
+    // everything else reports that the fd doesn't support it rather than
+    // pretending to succeed.
+    let allowed = match request {
+        abi::TCGETS
+        | abi::TCSETS
+        | abi::TCSETSW
+        | abi::TCSETSF
+        | abi::TIOCGWINSZ
+        | abi::TIOCSWINSZ
+        | abi::FIONREAD
+        | abi::FIONBIO => true,
+        other => stopped_task
+            .task
+            .task_data
+            .tracer_settings
+            .extra_ioctls
+            .contains(other),
+    };
+    if !allowed {
+        return Errno(-abi::ENOTTY).into();
+    }
+    let mut tr = Trampoline::new(stopped_task);
+    SyscallResult(
+        tr.syscall(
+            sc::nr::IOCTL,
+            &[fd.0 as isize, request as isize, arg as isize],
+        )
+        .await,
+    )
+}
+
+/// The file an xattr syscall operates on, either by path or by open fd
+pub enum XAttrTarget {
+    Path(VString, FollowLinks),
+    Fd(RemoteFd),
+}
+
+impl XAttrTarget {
+    fn resolve(
+        self,
+        stopped_task: &mut StoppedTask<'_, '_>,
+    ) -> Result<(Option<VFile>, Option<VString>, FollowLinks), Errno> {
+        match self {
+            XAttrTarget::Path(path, follow_links) => Ok((None, Some(path), follow_links)),
+            XAttrTarget::Fd(fd) => {
+                let file = stopped_task.task.task_data.file_table.get(&fd)?;
+                Ok((Some(file.clone()), None, FollowLinks::Follow))
+            }
+        }
+    }
+}
+
+async fn return_xattr_bytes(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    result: Result<(SysFd, usize), Errno>,
+    buffer: VPtr,
+    buffer_len: usize,
+) -> Result<usize, Errno> {
+    // A zero-length buffer asks only for the size; otherwise the whole value
+    // must fit, there is no partial read.
+    let (result_fd, result_len) = result?;
+    if buffer_len == 0 {
+        Ok(result_len)
+    } else if result_len > buffer_len {
+        Err(Errno(-abi::ERANGE))
+    } else {
+        let mut tr = Trampoline::new(stopped_task);
+        syscall::result::sysfd_bytes(&mut tr, &result_fd, buffer, result_len).await?;
+        Ok(result_len)
+    }
+}
+
+pub async fn getxattr(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    target: XAttrTarget,
+    name: VString,
+    value: VPtr,
+    size: usize,
+) -> Result<usize, Errno> {
+    let (file, path, follow_links) = target.resolve(stopped_task)?;
+    let result = ipc_call!(
+        stopped_task.task,
+        FromTask::GetXAttr {
+            file: file.clone(),
+            path,
+            follow_links,
+            name,
+        },
+        ToTask::BytesReply(result),
+        result
+    );
+    return_xattr_bytes(stopped_task, result, value, size).await
+}
+
+pub async fn listxattr(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    target: XAttrTarget,
+    list: VPtr,
+    size: usize,
+) -> Result<usize, Errno> {
+    let (file, path, follow_links) = target.resolve(stopped_task)?;
+    let result = ipc_call!(
+        stopped_task.task,
+        FromTask::ListXAttr {
+            file: file.clone(),
+            path,
+            follow_links,
+        },
+        ToTask::BytesReply(result),
+        result
+    );
+    return_xattr_bytes(stopped_task, result, list, size).await
+}
+
+pub async fn setxattr(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    target: XAttrTarget,
+    name: VString,
+    value: VPtr,
+    size: usize,
+    flags: i32,
+) -> Result<(), Errno> {
+    let (file, path, follow_links) = target.resolve(stopped_task)?;
+    ipc_call!(
+        stopped_task.task,
+        FromTask::SetXAttr {
+            file: file.clone(),
+            path,
+            follow_links,
+            name,
+            value,
+            size,
+            flags,
+        },
+        ToTask::Reply(result),
+        result
+    )
+}
+
+pub async fn removexattr(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    target: XAttrTarget,
+    name: VString,
+) -> Result<(), Errno> {
+    let (file, path, follow_links) = target.resolve(stopped_task)?;
+    ipc_call!(
+        stopped_task.task,
+        FromTask::RemoveXAttr {
+            file: file.clone(),
+            path,
+            follow_links,
+            name,
+        },
+        ToTask::Reply(result),
+        result
+    )
+}
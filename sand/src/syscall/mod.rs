@@ -1,6 +1,8 @@
 mod dispatch;
 mod fs;
 mod result;
+mod socket;
+mod source;
 mod user;
 
 pub use dispatch::SyscallEmulator;
@@ -1,4 +1,5 @@
 use crate::{
+    mem::{kernel::is_writable, rw::write_bytes},
     protocol::{Errno, SysFd, VPid, VPtr},
     remote::{
         file::{RemoteFd, TempRemoteFd},
@@ -93,6 +94,14 @@ pub async fn local_bytes(
     bytes: &[u8],
     to_ptr: VPtr,
 ) -> Result<(), Errno> {
+    // Poking the bytes into place takes a ptrace request per word, where
+    // going through a scratchpad and a temporary file takes six remote
+    // syscalls on top of that. Pokes ignore memory protection though, so
+    // anything the guest couldn't write goes the long way and faults the
+    // same as it would for the kernel.
+    if is_writable(trampoline.stopped_task, to_ptr, bytes.len()) {
+        return write_bytes(trampoline.stopped_task, to_ptr, bytes);
+    }
     let mut pad = Scratchpad::new(trampoline).await?;
     let main_result = local_bytes_with_scratchpad(&mut pad, bytes, to_ptr).await;
     let cleanup_result = pad.free().await;
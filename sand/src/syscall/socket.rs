@@ -0,0 +1,190 @@
+//! Unix sockets, which can only connect to streams mounted in the filesystem
+//!
+//! There's no network inside the sandbox. A guest can make an `AF_UNIX`
+//! stream socket, and connecting it to a path where the runtime mounted a
+//! shared stream swaps the unbound socket for that stream. Every connection
+//! to the same path shares the one stream.
+
+use crate::{
+    abi,
+    mem::rw::{read_bytes, write_padded_bytes},
+    process::task::StoppedTask,
+    protocol::{
+        abi::{S_IFMT, S_IFSOCK},
+        Errno, FileStat, FollowLinks, FromTask, SysFd, ToTask, VFile, VPtr, VString,
+    },
+    remote::{file::RemoteFd, scratchpad::Scratchpad, trampoline::Trampoline},
+    syscall,
+};
+
+pub async fn socket(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    domain: usize,
+    ty: usize,
+    protocol: usize,
+) -> Result<RemoteFd, Errno> {
+    if domain != abi::AF_UNIX {
+        return Err(Errno(-abi::EAFNOSUPPORT));
+    }
+    if ty & !(abi::SOCK_NONBLOCK | abi::SOCK_CLOEXEC) != abi::SOCK_STREAM {
+        return Err(Errno(-abi::ESOCKTNOSUPPORT));
+    }
+    if protocol != 0 {
+        return Err(Errno(-abi::EPROTONOSUPPORT));
+    }
+    let mut tr = Trampoline::new(stopped_task);
+    let result = tr
+        .syscall(sc::nr::SOCKET, &[domain as isize, ty as isize, 0])
+        .await;
+    if result < 0 {
+        return Err(Errno(result as i32));
+    }
+    // Like a pipe, the socket has no VFS file until it's connected
+    let fd = RemoteFd(result as u32);
+    stopped_task.task.task_data.file_table.close(&fd);
+    Ok(fd)
+}
+
+pub async fn connect(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    fd: RemoteFd,
+    addr: VPtr,
+    addr_len: usize,
+) -> Result<(), Errno> {
+    if let Ok((_, stat)) = syscall::fs::fstat(stopped_task, fd.clone()).await {
+        // Already connected to a stream, or some other kind of VFS file
+        return Err(Errno(if stat.st_mode & S_IFMT == S_IFSOCK {
+            -abi::EISCONN
+        } else {
+            -abi::ENOTSOCK
+        }));
+    }
+    if !is_host_socket(stopped_task, &fd).await? {
+        return Err(Errno(-abi::ENOTSOCK));
+    }
+    if addr_len <= abi::SUN_PATH_OFFSET || addr_len > abi::SUN_PATH_OFFSET + abi::SUN_PATH_LEN {
+        return Err(Errno(-abi::EINVAL));
+    }
+    // Only the addr_len bytes the guest gave us are part of the address
+    let mut sockaddr = [0u8; abi::SUN_PATH_OFFSET + abi::SUN_PATH_LEN];
+    let sockaddr = &mut sockaddr[..addr_len];
+    read_bytes(stopped_task, addr, sockaddr)?;
+    if u16::from_ne_bytes([sockaddr[0], sockaddr[1]]) as usize != abi::AF_UNIX {
+        return Err(Errno(-abi::EAFNOSUPPORT));
+    }
+    let sun_path = &sockaddr[abi::SUN_PATH_OFFSET..];
+    if sun_path[0] == 0 {
+        // Abstract addresses aren't paths, and nothing is ever listening there
+        return Err(Errno(-abi::ECONNREFUSED));
+    }
+    // The path needn't be terminated within addr_len, so the runtime gets a
+    // terminated copy rather than reading past the end of the address
+    let path_len = sun_path
+        .iter()
+        .position(|&b| b == 0)
+        .unwrap_or(sun_path.len());
+    let mut tr = Trampoline::new(stopped_task);
+    let mut pad = Scratchpad::new(&mut tr).await?;
+    let main_result = match write_padded_bytes(
+        pad.trampoline.stopped_task,
+        pad.ptr(),
+        &sun_path[..path_len],
+    ) {
+        Ok(()) => open_stream(pad.trampoline.stopped_task, VString(pad.ptr())).await,
+        Err(err) => Err(err),
+    };
+    let cleanup_result = pad.free().await;
+    let (vfile, sys_fd) = main_result?;
+    cleanup_result?;
+
+    // Move the stream into the socket's place, keeping its close-on-exec flag
+    let mut tr = Trampoline::new(stopped_task);
+    let fd_flags = tr
+        .syscall(sc::nr::FCNTL, &[fd.0 as isize, abi::F_GETFD as isize])
+        .await;
+    if fd_flags < 0 {
+        return Err(Errno(fd_flags as i32));
+    }
+    let stream_fd = syscall::result::file(&mut tr, &sys_fd).await?;
+    let dup_flags = if fd_flags as usize & abi::F_CLOEXEC != 0 {
+        abi::O_CLOEXEC
+    } else {
+        0
+    };
+    let result = tr
+        .syscall(
+            sc::nr::DUP3,
+            &[stream_fd.0 as isize, fd.0 as isize, dup_flags as isize],
+        )
+        .await;
+    stream_fd.close(&mut tr).await?;
+    if result < 0 {
+        return Err(Errno(result as i32));
+    }
+
+    let task = &mut stopped_task.task;
+    task.task_data.file_table.open(fd.clone(), vfile.clone());
+    task.msg.send(FromTask::FdOpened {
+        fd: fd.0,
+        file: vfile,
+    });
+    Ok(())
+}
+
+/// Open the shared stream mounted at a socket path
+async fn open_stream(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    path: VString,
+) -> Result<(VFile, SysFd), Errno> {
+    let stat: Result<(VFile, FileStat), Errno> = ipc_call!(
+        stopped_task.task,
+        FromTask::FileStat {
+            file: None,
+            path: Some(path.clone()),
+            follow_links: FollowLinks::Follow,
+        },
+        ToTask::FileStatReply(result),
+        result
+    );
+    if stat?.1.st_mode & S_IFMT != S_IFSOCK {
+        return Err(Errno(-abi::ECONNREFUSED));
+    }
+    ipc_call!(
+        stopped_task.task,
+        FromTask::FileOpen {
+            dir: None,
+            path,
+            flags: abi::O_RDONLY as i32,
+            mode: 0,
+        },
+        ToTask::FileReply(result),
+        result
+    )
+}
+
+/// Is this fd a socket the kernel knows about, rather than a VFS file?
+async fn is_host_socket(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    fd: &RemoteFd,
+) -> Result<bool, Errno> {
+    let mut tr = Trampoline::new(stopped_task);
+    let mut pad = Scratchpad::new(&mut tr).await?;
+    let result = pad
+        .trampoline
+        .syscall(sc::nr::FSTAT, &[fd.0 as isize, pad.ptr().0 as isize])
+        .await;
+    let mut mode = [0u8; 4];
+    let main_result = if result < 0 {
+        Err(Errno(result as i32))
+    } else {
+        read_bytes(
+            pad.trampoline.stopped_task,
+            pad.ptr() + offset_of!(abi::Stat, st_mode),
+            &mut mode,
+        )
+    };
+    let cleanup_result = pad.free().await;
+    main_result?;
+    cleanup_result?;
+    Ok(u32::from_ne_bytes(mode) & S_IFMT == S_IFSOCK)
+}
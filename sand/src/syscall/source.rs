@@ -0,0 +1,277 @@
+//! Sources of time and randomness for the emulated clock and getrandom
+//! syscalls
+//!
+//! Emulation logic takes these as traits so it can be exercised in unit tests
+//! without a traced process or the host's real clock.
+
+use crate::{abi, nolibc, protocol::Errno};
+
+/// Linux caps a single non-blocking getrandom() read at this length
+///
+/// linux/drivers/char/random.c, `INT_MAX >> (ENTROPY_SHIFT + 3)`
+pub const GETRANDOM_MAX_LEN: usize = 33554431;
+
+/// The distinct timelines visible through clock_gettime()
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clock {
+    Realtime,
+    Monotonic,
+    Boottime,
+    ProcessCpuTime,
+    ThreadCpuTime,
+}
+
+impl Clock {
+    /// Interpret a clock ID, collapsing the coarse, raw, and alarm variants
+    /// onto the clock they read from
+    pub fn from_id(id: isize) -> Result<Clock, Errno> {
+        match id {
+            abi::CLOCK_REALTIME | abi::CLOCK_REALTIME_COARSE | abi::CLOCK_REALTIME_ALARM => {
+                Ok(Clock::Realtime)
+            }
+            // we don't track leap seconds; TAI runs with realtime
+            abi::CLOCK_TAI => Ok(Clock::Realtime),
+            abi::CLOCK_MONOTONIC | abi::CLOCK_MONOTONIC_RAW | abi::CLOCK_MONOTONIC_COARSE => {
+                Ok(Clock::Monotonic)
+            }
+            abi::CLOCK_BOOTTIME | abi::CLOCK_BOOTTIME_ALARM => Ok(Clock::Boottime),
+            abi::CLOCK_PROCESS_CPUTIME_ID => Ok(Clock::ProcessCpuTime),
+            abi::CLOCK_THREAD_CPUTIME_ID => Ok(Clock::ThreadCpuTime),
+            // negative IDs name the cpu clocks of other processes and threads,
+            // which we can't see from here
+            _ => Err(Errno(-abi::EINVAL)),
+        }
+    }
+
+    fn host_id(self) -> isize {
+        match self {
+            Clock::Realtime => abi::CLOCK_REALTIME,
+            Clock::Monotonic => abi::CLOCK_MONOTONIC,
+            Clock::Boottime => abi::CLOCK_BOOTTIME,
+            Clock::ProcessCpuTime => abi::CLOCK_PROCESS_CPUTIME_ID,
+            Clock::ThreadCpuTime => abi::CLOCK_THREAD_CPUTIME_ID,
+        }
+    }
+}
+
+pub trait TimeSource {
+    fn now(&self, clock: Clock) -> Result<abi::TimeSpec, Errno>;
+}
+
+pub trait RandomSource {
+    /// Fill as much of the buffer as possible, returning the length filled
+    fn fill(&mut self, bytes: &mut [u8], nonblock: bool) -> Result<usize, Errno>;
+}
+
+/// Time and randomness straight from the host kernel
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HostSource;
+
+impl TimeSource for HostSource {
+    fn now(&self, clock: Clock) -> Result<abi::TimeSpec, Errno> {
+        nolibc::clock_gettime(clock.host_id())
+    }
+}
+
+impl RandomSource for HostSource {
+    fn fill(&mut self, bytes: &mut [u8], nonblock: bool) -> Result<usize, Errno> {
+        let flags = if nonblock { abi::GRND_NONBLOCK } else { 0 };
+        nolibc::getrandom(bytes, flags as isize)
+    }
+}
+
+/// Emulated clock_gettime(), for a clock ID provided by the guest
+pub fn clock_gettime<T: TimeSource>(source: &T, id: isize) -> Result<abi::TimeSpec, Errno> {
+    source.now(Clock::from_id(id)?)
+}
+
+/// Whole seconds since the container launched, rounded up like the kernel's
+/// uptime, given the monotonic time of the launch in nanoseconds
+pub fn uptime<T: TimeSource>(source: &T, launch_time: u64) -> Result<u64, Errno> {
+    let now = source.now(Clock::Monotonic)?;
+    let elapsed = (now.tv_sec * 1_000_000_000 + now.tv_nsec).saturating_sub(launch_time);
+    Ok((elapsed + 999_999_999) / 1_000_000_000)
+}
+
+/// Emulated getrandom(), into a local buffer of the length the guest
+/// requested
+///
+/// Flags are validated like linux does. GRND_RANDOM and GRND_INSECURE are
+/// accepted but there's only one pool, so they don't change the result.
+pub fn getrandom<R: RandomSource>(
+    source: &mut R,
+    bytes: &mut [u8],
+    flags: usize,
+) -> Result<usize, Errno> {
+    let known_flags = abi::GRND_NONBLOCK | abi::GRND_RANDOM | abi::GRND_INSECURE;
+    let insecure_random = abi::GRND_RANDOM | abi::GRND_INSECURE;
+    if flags & !known_flags != 0 || flags & insecure_random == insecure_random {
+        return Err(Errno(-abi::EINVAL));
+    }
+    let len = bytes.len().min(GETRANDOM_MAX_LEN);
+    source.fill(&mut bytes[..len], flags & abi::GRND_NONBLOCK != 0)
+}
+
+/// Deterministic sources for testing emulation logic
+#[cfg(test)]
+pub mod mock {
+    use super::{Clock, RandomSource, TimeSource};
+    use crate::{abi, protocol::Errno};
+    use core::cell::Cell;
+
+    /// A clock that only moves when told to
+    ///
+    /// All timelines start at the same instant, except realtime which is
+    /// offset by a fixed epoch.
+    #[derive(Debug)]
+    pub struct MockClock {
+        epoch: u64,
+        elapsed_nsec: Cell<u64>,
+    }
+
+    impl MockClock {
+        pub fn new(epoch: u64) -> Self {
+            MockClock {
+                epoch,
+                elapsed_nsec: Cell::new(0),
+            }
+        }
+
+        pub fn advance_nsec(&self, nsec: u64) {
+            self.elapsed_nsec.set(self.elapsed_nsec.get() + nsec);
+        }
+    }
+
+    impl TimeSource for MockClock {
+        fn now(&self, clock: Clock) -> Result<abi::TimeSpec, Errno> {
+            let elapsed = self.elapsed_nsec.get();
+            let sec = elapsed / 1_000_000_000;
+            let nsec = elapsed % 1_000_000_000;
+            Ok(abi::TimeSpec {
+                tv_sec: match clock {
+                    Clock::Realtime => self.epoch + sec,
+                    _ => sec,
+                },
+                tv_nsec: nsec,
+            })
+        }
+    }
+
+    /// A repeatable byte stream, optionally reporting that it would block
+    #[derive(Debug)]
+    pub struct MockRandom {
+        state: u64,
+        pub would_block: bool,
+    }
+
+    impl MockRandom {
+        pub fn new(seed: u64) -> Self {
+            MockRandom {
+                state: seed | 1,
+                would_block: false,
+            }
+        }
+
+        fn next_byte(&mut self) -> u8 {
+            // xorshift64
+            self.state ^= self.state << 13;
+            self.state ^= self.state >> 7;
+            self.state ^= self.state << 17;
+            self.state as u8
+        }
+    }
+
+    impl RandomSource for MockRandom {
+        fn fill(&mut self, bytes: &mut [u8], nonblock: bool) -> Result<usize, Errno> {
+            if self.would_block {
+                assert!(nonblock, "mock random source would block forever");
+                return Err(Errno(-abi::EAGAIN));
+            }
+            for byte in bytes.iter_mut() {
+                *byte = self.next_byte();
+            }
+            Ok(bytes.len())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{mock::*, *};
+
+    #[test]
+    fn clock_ids() {
+        let clock = MockClock::new(1_600_000_000);
+        clock.advance_nsec(2_500_000_000);
+        let realtime = clock_gettime(&clock, abi::CLOCK_REALTIME).unwrap();
+        assert_eq!(realtime.tv_sec, 1_600_000_002);
+        assert_eq!(realtime.tv_nsec, 500_000_000);
+        let coarse = clock_gettime(&clock, abi::CLOCK_REALTIME_COARSE).unwrap();
+        assert_eq!(coarse.tv_sec, realtime.tv_sec);
+        let monotonic = clock_gettime(&clock, abi::CLOCK_MONOTONIC_RAW).unwrap();
+        assert_eq!(monotonic.tv_sec, 2);
+        assert_eq!(clock_gettime(&clock, 10).unwrap_err(), Errno(-abi::EINVAL));
+        assert_eq!(clock_gettime(&clock, -6).unwrap_err(), Errno(-abi::EINVAL));
+    }
+
+    #[test]
+    fn clock_advances() {
+        let clock = MockClock::new(0);
+        let before = clock_gettime(&clock, abi::CLOCK_MONOTONIC).unwrap();
+        clock.advance_nsec(999_999_999);
+        clock.advance_nsec(2);
+        let after = clock_gettime(&clock, abi::CLOCK_BOOTTIME).unwrap();
+        assert_eq!((before.tv_sec, before.tv_nsec), (0, 0));
+        assert_eq!((after.tv_sec, after.tv_nsec), (1, 1));
+    }
+
+    #[test]
+    fn uptime_since_launch() {
+        let clock = MockClock::new(1_600_000_000);
+        clock.advance_nsec(5_000_000_000);
+        assert_eq!(uptime(&clock, 5_000_000_000), Ok(0));
+        clock.advance_nsec(1);
+        assert_eq!(uptime(&clock, 5_000_000_000), Ok(1));
+        clock.advance_nsec(2_000_000_000);
+        assert_eq!(uptime(&clock, 5_000_000_000), Ok(3));
+        assert_eq!(uptime(&clock, 10_000_000_000), Ok(0));
+    }
+
+    #[test]
+    fn random_is_repeatable() {
+        let mut first = [0u8; 16];
+        let mut second = [0u8; 16];
+        assert_eq!(getrandom(&mut MockRandom::new(7), &mut first, 0), Ok(16));
+        assert_eq!(getrandom(&mut MockRandom::new(7), &mut second, 0), Ok(16));
+        assert_eq!(first, second);
+        assert!(first.iter().any(|b| *b != 0));
+    }
+
+    #[test]
+    fn random_flags() {
+        let mut rng = MockRandom::new(1);
+        let mut buf = [0u8; 4];
+        assert_eq!(getrandom(&mut rng, &mut buf, abi::GRND_RANDOM), Ok(4));
+        assert_eq!(getrandom(&mut rng, &mut buf, abi::GRND_INSECURE), Ok(4));
+        assert_eq!(
+            getrandom(&mut rng, &mut buf, abi::GRND_RANDOM | abi::GRND_INSECURE),
+            Err(Errno(-abi::EINVAL))
+        );
+        assert_eq!(getrandom(&mut rng, &mut buf, 0x80), Err(Errno(-abi::EINVAL)));
+        rng.would_block = true;
+        assert_eq!(
+            getrandom(&mut rng, &mut buf, abi::GRND_NONBLOCK),
+            Err(Errno(-abi::EAGAIN))
+        );
+    }
+
+    #[test]
+    fn random_length_limit() {
+        let mut buf = vec![0u8; GETRANDOM_MAX_LEN + 100];
+        assert_eq!(
+            getrandom(&mut MockRandom::new(3), &mut buf, 0),
+            Ok(GETRANDOM_MAX_LEN)
+        );
+        assert_eq!(buf[GETRANDOM_MAX_LEN], 0);
+    }
+}
@@ -3,21 +3,442 @@ use crate::{
     mem::{
         maps::{MappedPages, MemFlags},
         page::VPage,
+        rw::{read_bytes, read_value},
+        string::VStringRange,
     },
     process::task::StoppedTask,
-    protocol::{Errno, VPtr},
+    protocol::{abi::CPU_SETSIZE, Errno, UtsString, VPtr, VString, UTS_STRING_CAPACITY},
     remote::{
         file::{RemoteFd, TempRemoteFd},
         scratchpad::Scratchpad,
         trampoline::Trampoline,
     },
-    syscall::result::SyscallResult,
+    syscall::{
+        result,
+        result::SyscallResult,
+        source,
+        source::{Clock, RandomSource, TimeSource, GETRANDOM_MAX_LEN},
+    },
 };
+use plain::Plain;
+
+#[repr(C)]
+struct UserSysInfo(abi::SysInfo);
+
+unsafe impl Plain for UserSysInfo {}
+
+/// Random bytes are generated in the tracer and copied into the guest this
+/// many at a time. Most requests are a few dozen bytes, and the buffer lives
+/// in every task's future.
+const GETRANDOM_CHUNK_LEN: usize = 256;
+
+pub async fn getpriority<'q, 's, 't>(
+    stopped_task: &'t mut StoppedTask<'q, 's>,
+    which: usize,
+    who: u32,
+) -> Result<usize, Errno> {
+    // Only our own priority is visible; other pids and groups are host
+    // concepts that don't translate into the container.
+    let vpid = stopped_task.task.task_data.vpid;
+    if which != abi::PRIO_PROCESS || (who != 0 && who != vpid.0) {
+        return Err(Errno(-abi::ESRCH));
+    }
+    let mut tr = Trampoline::new(stopped_task);
+    let result = tr
+        .syscall(sc::nr::GETPRIORITY, &[abi::PRIO_PROCESS as isize, 0])
+        .await;
+    if result < 0 {
+        Err(Errno(result as i32))
+    } else {
+        Ok(result as usize)
+    }
+}
+
+/// The container sees a fixed set of CPUs, numbered from zero, no matter
+/// which host CPUs it runs on. Masks are sized in whole longs like the
+/// kernel's, and any pid other than our own is treated as missing.
+pub async fn sched_getaffinity<'q, 's, 't>(
+    stopped_task: &'t mut StoppedTask<'q, 's>,
+    pid: u32,
+    len: usize,
+    dest: VPtr,
+) -> Result<usize, Errno> {
+    let vpid = stopped_task.task.task_data.vpid;
+    if pid != 0 && pid != vpid.0 {
+        return Err(Errno(-abi::ESRCH));
+    }
+    let cpus = stopped_task.task.task_data.tracer_settings.cpus as usize;
+    let cpus = cpus.max(1).min(CPU_SETSIZE);
+    let word = core::mem::size_of::<u64>();
+    if len < (cpus + 7) / 8 || len % word != 0 {
+        return Err(Errno(-abi::EINVAL));
+    }
+    let mut mask = [0u8; CPU_SETSIZE / 8];
+    for cpu in 0..cpus {
+        mask[cpu / 8] |= 1 << (cpu % 8);
+    }
+    let mask_len = (cpus + 63) / 64 * word;
+    let mut tr = Trampoline::new(stopped_task);
+    result::local_bytes(&mut tr, &mask[..mask_len], dest).await?;
+    Ok(mask_len)
+}
+
+/// The wall and monotonic clocks come from a [TimeSource] in the tracer. CPU
+/// time clocks belong to the guest process, so those are read by the guest
+/// itself.
+pub async fn clock_gettime<'q, 's, 't, T: TimeSource>(
+    stopped_task: &'t mut StoppedTask<'q, 's>,
+    time: &T,
+    id: isize,
+    dest: VPtr,
+) -> Result<(), Errno> {
+    let mut tr = Trampoline::new(stopped_task);
+    match Clock::from_id(id)? {
+        Clock::ProcessCpuTime | Clock::ThreadCpuTime => {
+            let result = tr
+                .syscall(sc::nr::CLOCK_GETTIME, &[id, dest.0 as isize])
+                .await;
+            if result < 0 {
+                Err(Errno(result as i32))
+            } else {
+                Ok(())
+            }
+        }
+        _ => {
+            let now = source::clock_gettime(time, id)?;
+            let mut bytes = [0u8; 16];
+            bytes[..8].copy_from_slice(&now.tv_sec.to_ne_bytes());
+            bytes[8..].copy_from_slice(&now.tv_nsec.to_ne_bytes());
+            result::local_bytes(&mut tr, &bytes, dest).await
+        }
+    }
+}
+
+/// Task names are kept in the tracer, so they never reach the host. Options
+/// that can only restrict the guest go through to the host, a few more that
+/// have no meaning in the sandbox are accepted and ignored, and anything else
+/// is rejected.
+pub async fn prctl<'q, 's, 't>(
+    stopped_task: &'t mut StoppedTask<'q, 's>,
+    option: usize,
+    args: &[isize],
+) -> Result<usize, Errno> {
+    match option {
+        abi::PR_SET_NAME => {
+            let ptr = VPtr(args[0] as usize);
+            let len = match VStringRange::parse(stopped_task, VString(ptr), abi::TASK_COMM_LEN)? {
+                Some(range) => range.range().end.0 - ptr.0 - 1,
+                None => abi::TASK_COMM_LEN - 1,
+            };
+            let mut name = [0; abi::TASK_COMM_LEN];
+            read_bytes(stopped_task, ptr, &mut name[..len])?;
+            stopped_task.task.task_data.name = name;
+            Ok(0)
+        }
+        abi::PR_GET_NAME => {
+            let name = stopped_task.task.task_data.name;
+            let mut tr = Trampoline::new(stopped_task);
+            result::local_bytes(&mut tr, &name, VPtr(args[0] as usize)).await?;
+            Ok(0)
+        }
+        abi::PR_SET_NO_NEW_PRIVS
+        | abi::PR_GET_NO_NEW_PRIVS
+        | abi::PR_SET_SECCOMP
+        | abi::PR_GET_SECCOMP => {
+            let mut call_args = [option as isize, 0, 0, 0, 0];
+            call_args[1..].copy_from_slice(&args[..4]);
+            let mut tr = Trampoline::new(stopped_task);
+            let result = tr.syscall(sc::nr::PRCTL, &call_args).await;
+            if result < 0 {
+                Err(Errno(result as i32))
+            } else {
+                Ok(result as usize)
+            }
+        }
+        abi::PR_SET_PDEATHSIG
+        | abi::PR_SET_DUMPABLE
+        | abi::PR_SET_TIMERSLACK
+        | abi::PR_SET_CHILD_SUBREAPER
+        | abi::PR_SET_VMA => Ok(0),
+        abi::PR_GET_DUMPABLE => Ok(1),
+        _ => Err(Errno(-abi::EINVAL)),
+    }
+}
+
+/// The fs and gs bases are real registers, so the host sets them. Our saved
+/// registers are written back when the emulated call returns, and they need
+/// to carry the new base along.
+pub async fn arch_prctl<'q, 's, 't>(
+    stopped_task: &'t mut StoppedTask<'q, 's>,
+    code: usize,
+    addr: usize,
+) -> Result<(), Errno> {
+    match code {
+        abi::ARCH_SET_FS | abi::ARCH_SET_GS | abi::ARCH_GET_FS | abi::ARCH_GET_GS => {
+            let mut tr = Trampoline::new(stopped_task);
+            let result = tr
+                .syscall(sc::nr::ARCH_PRCTL, &[code as isize, addr as isize])
+                .await;
+            if result < 0 {
+                return Err(Errno(result as i32));
+            }
+            match code {
+                abi::ARCH_SET_FS => stopped_task.regs.fs_base = addr,
+                abi::ARCH_SET_GS => stopped_task.regs.gs_base = addr,
+                _ => (),
+            }
+            Ok(())
+        }
+        _ => Err(Errno(-abi::EINVAL)),
+    }
+}
+
+/// Memory figures come from the container's budget rather than the host, and
+/// uptime counts from when the container launched. There's no swap, load, or
+/// process accounting, so those are all zero. If the clock can't be read,
+/// uptime is zero too rather than failing the whole call.
+pub async fn sysinfo<'q, 's, 't, T: TimeSource>(
+    stopped_task: &'t mut StoppedTask<'q, 's>,
+    time: &T,
+    dest: VPtr,
+) -> Result<(), Errno> {
+    let settings = &stopped_task.task.task_data.tracer_settings;
+    let info = UserSysInfo(abi::SysInfo {
+        uptime: source::uptime(time, settings.launch_time).unwrap_or(0) as i64,
+        totalram: settings.memory_limit,
+        freeram: settings.memory_limit,
+        mem_unit: 1,
+        ..Default::default()
+    });
+    let mut tr = Trampoline::new(stopped_task);
+    result::local_bytes(&mut tr, unsafe { plain::as_bytes(&info) }, dest).await
+}
+
+/// Randomness comes from a [RandomSource] in the tracer rather than the
+/// guest's own syscall, so we decide where the container's entropy comes
+/// from. A short read, or a fault after some bytes were written, returns the
+/// length written so far, like the kernel does.
+pub async fn getrandom<'q, 's, 't, R: RandomSource>(
+    stopped_task: &'t mut StoppedTask<'q, 's>,
+    random: &mut R,
+    dest: VPtr,
+    len: usize,
+    flags: usize,
+) -> Result<usize, Errno> {
+    // Flags are checked even when there's nothing to write
+    source::getrandom(random, &mut [], flags)?;
+    let len = len.min(GETRANDOM_MAX_LEN);
+    if len == 0 {
+        return Ok(0);
+    }
+    let mut tr = Trampoline::new(stopped_task);
+    let mut pad = Scratchpad::new(&mut tr).await?;
+    let main_result = getrandom_with_scratchpad(&mut pad, random, dest, len, flags).await;
+    let cleanup_result = pad.free().await;
+    let result = main_result?;
+    cleanup_result?;
+    Ok(result)
+}
+
+async fn getrandom_with_scratchpad<R: RandomSource>(
+    scratchpad: &mut Scratchpad<'_, '_, '_, '_>,
+    random: &mut R,
+    dest: VPtr,
+    len: usize,
+    flags: usize,
+) -> Result<usize, Errno> {
+    let remote_fd = TempRemoteFd::new(scratchpad).await?;
+    let mut chunk = [0u8; GETRANDOM_CHUNK_LEN];
+    let mut written = 0;
+    let mut main_result = Ok(());
+    while written < len {
+        let chunk_len = (len - written).min(GETRANDOM_CHUNK_LEN);
+        let filled = match source::getrandom(random, &mut chunk[..chunk_len], flags) {
+            Ok(filled) => filled,
+            Err(err) => {
+                main_result = Err(err);
+                break;
+            }
+        };
+        if let Err(err) = remote_fd
+            .mem_write_bytes_exact(scratchpad, dest + written, &chunk[..filled])
+            .await
+        {
+            main_result = Err(err);
+            break;
+        }
+        written += filled;
+        if filled < chunk_len {
+            break;
+        }
+    }
+    remote_fd.free(&mut scratchpad.trampoline).await?;
+    match main_result {
+        Err(err) if written == 0 => Err(err),
+        _ => Ok(written),
+    }
+}
+
+/// Setting affinity is accepted for our own pid and otherwise ignored, since
+/// the emulated CPUs don't correspond to host CPUs
+pub async fn sched_setaffinity<'q, 's, 't>(
+    stopped_task: &'t mut StoppedTask<'q, 's>,
+    pid: u32,
+) -> Result<(), Errno> {
+    let vpid = stopped_task.task.task_data.vpid;
+    if pid != 0 && pid != vpid.0 {
+        Err(Errno(-abi::ESRCH))
+    } else {
+        Ok(())
+    }
+}
+
+/// Every task runs under the normal time-sharing policy at priority zero,
+/// since the sandbox can't grant real-time scheduling on the host.
+pub async fn sched_getscheduler<'q, 's, 't>(
+    stopped_task: &'t mut StoppedTask<'q, 's>,
+    pid: i32,
+) -> Result<usize, Errno> {
+    let vpid = stopped_task.task.task_data.vpid;
+    if pid < 0 {
+        Err(Errno(-abi::EINVAL))
+    } else if pid != 0 && pid as u32 != vpid.0 {
+        Err(Errno(-abi::ESRCH))
+    } else {
+        Ok(abi::SCHED_OTHER)
+    }
+}
+
+/// Only the normal policies, at priority zero, are allowed. They're all the
+/// same to us, so [sched_getscheduler()] still reports `SCHED_OTHER`.
+/// Real-time policies need privileges we don't pretend to have.
+pub async fn sched_setscheduler<'q, 's, 't>(
+    stopped_task: &'t mut StoppedTask<'q, 's>,
+    pid: i32,
+    policy: usize,
+    param: VPtr,
+) -> Result<(), Errno> {
+    if param.0 == 0 {
+        return Err(Errno(-abi::EINVAL));
+    }
+    let priority: i32 = unsafe { read_value(stopped_task, param) }?;
+    sched_getscheduler(stopped_task, pid).await?;
+    match policy & !abi::SCHED_RESET_ON_FORK {
+        abi::SCHED_OTHER | abi::SCHED_BATCH | abi::SCHED_IDLE if priority == 0 => Ok(()),
+        abi::SCHED_FIFO | abi::SCHED_RR if priority >= 1 && priority <= 99 => {
+            Err(Errno(-abi::EPERM))
+        }
+        _ => Err(Errno(-abi::EINVAL)),
+    }
+}
+
+pub async fn sched_getparam<'q, 's, 't>(
+    stopped_task: &'t mut StoppedTask<'q, 's>,
+    pid: i32,
+    dest: VPtr,
+) -> Result<(), Errno> {
+    if dest.0 == 0 {
+        return Err(Errno(-abi::EINVAL));
+    }
+    sched_getscheduler(stopped_task, pid).await?;
+    let priority = 0i32;
+    let mut tr = Trampoline::new(stopped_task);
+    result::local_bytes(&mut tr, &priority.to_ne_bytes(), dest).await
+}
+
+/// Tasks can only signal themselves, which is enough for `raise()` and
+/// `abort()`. Any other pid is treated as missing, like with scheduling. The
+/// signal is sent for real, by the task itself.
+pub async fn kill<'q, 's, 't>(
+    stopped_task: &'t mut StoppedTask<'q, 's>,
+    pid: i32,
+    signal: i32,
+) -> Result<(), Errno> {
+    let vpid = stopped_task.task.task_data.vpid;
+    if pid != vpid.0 as i32 {
+        return Err(Errno(-abi::ESRCH));
+    }
+    signal_self(stopped_task, sc::nr::KILL, signal).await
+}
+
+/// Both `tkill` and `tgkill` come here, without and with a thread group.
+/// Every task is its own thread group, so they're the same as [kill()] apart
+/// from the argument checks.
+pub async fn tgkill<'q, 's, 't>(
+    stopped_task: &'t mut StoppedTask<'q, 's>,
+    tgid: Option<i32>,
+    tid: i32,
+    signal: i32,
+) -> Result<(), Errno> {
+    let vpid = stopped_task.task.task_data.vpid.0 as i32;
+    if tid <= 0 || tgid.map_or(false, |tgid| tgid <= 0) {
+        Err(Errno(-abi::EINVAL))
+    } else if tid != vpid || tgid.map_or(false, |tgid| tgid != vpid) {
+        Err(Errno(-abi::ESRCH))
+    } else {
+        signal_self(stopped_task, sc::nr::TKILL, signal).await
+    }
+}
+
+async fn signal_self<'q, 's, 't>(
+    stopped_task: &'t mut StoppedTask<'q, 's>,
+    nr: usize,
+    signal: i32,
+) -> Result<(), Errno> {
+    let sys_pid = stopped_task.task.task_data.sys_pid;
+    let mut tr = Trampoline::new(stopped_task);
+    let result = tr.syscall(nr, &[sys_pid.0 as isize, signal as isize]).await;
+    if result < 0 {
+        Err(Errno(result as i32))
+    } else {
+        Ok(())
+    }
+}
+
+/// Look up how the guest handles a signal
+///
+/// Returns the `sa_flags` of its handler, or None if the signal is ignored or
+/// has its default action.
+pub async fn signal_handler_flags<'q, 's, 't>(
+    stopped_task: &'t mut StoppedTask<'q, 's>,
+    signal: u8,
+) -> Result<Option<u32>, Errno> {
+    let mut tr = Trampoline::new(stopped_task);
+    let mut pad = Scratchpad::new(&mut tr).await?;
+    let old_action = pad.ptr();
+    let main_result = match pad
+        .trampoline
+        .syscall(
+            sc::nr::RT_SIGACTION,
+            &[
+                signal as isize,
+                0,
+                old_action.0 as isize,
+                core::mem::size_of::<abi::SigSet>() as isize,
+            ],
+        )
+        .await
+    {
+        // The kernel's sigaction starts with the handler, then the flags
+        0 => unsafe { read_value::<[usize; 2]>(pad.trampoline.stopped_task, old_action) },
+        err => Err(Errno(err as i32)),
+    };
+    let cleanup_result = pad.free().await;
+    let [handler, flags] = main_result?;
+    cleanup_result?;
+    Ok(match handler {
+        abi::SIG_DFL | abi::SIG_IGN => None,
+        _ => Some(flags as u32),
+    })
+}
 
 pub async fn uname<'q, 's, 't>(
     stopped_task: &'t mut StoppedTask<'q, 's>,
     dest: VPtr,
 ) -> Result<(), Errno> {
+    let settings = &stopped_task.task.task_data.tracer_settings;
+    let nodename = uts_field(&settings.hostname);
+    let release = uts_field(&settings.kernel_release);
     let mut tr = Trampoline::new(stopped_task);
     let mut pad = Scratchpad::new(&mut tr).await?;
     let main_result = match TempRemoteFd::new(&mut pad).await {
@@ -36,7 +457,7 @@ pub async fn uname<'q, 's, 't>(
                 temp.mem_write_bytes_exact(
                     &mut pad,
                     dest + offset_of!(abi::UtsName, nodename),
-                    b"host\0",
+                    &nodename,
                 )
                 .await,
             );
@@ -44,7 +465,7 @@ pub async fn uname<'q, 's, 't>(
                 temp.mem_write_bytes_exact(
                     &mut pad,
                     dest + offset_of!(abi::UtsName, release),
-                    b"4.0.0-bandsocks\0",
+                    &release,
                 )
                 .await,
             );
@@ -79,6 +500,13 @@ pub async fn uname<'q, 's, 't>(
     Ok(())
 }
 
+/// One nul-padded `uname()` field
+fn uts_field(s: &UtsString) -> [u8; UTS_STRING_CAPACITY + 1] {
+    let mut field = [0; UTS_STRING_CAPACITY + 1];
+    field[..s.as_bytes().len()].copy_from_slice(s.as_bytes());
+    field
+}
+
 /// brk() is emulated using mmap because we can't change the host kernel's per
 /// process brk pointer from our loader without extra privileges.
 pub async fn brk<'q, 's, 't>(
@@ -117,6 +545,65 @@ pub async fn brk<'q, 's, 't>(
     Ok(stopped_task.task.task_data.mm.brk)
 }
 
+/// madvise() hints are accepted and ignored, since the host kernel can manage
+/// our memory just fine without them. Advice that changes memory contents or
+/// what happens on fork is forwarded, and unknown advice is rejected like the
+/// kernel would.
+pub async fn madvise<'q, 's, 't>(
+    stopped_task: &'t mut StoppedTask<'q, 's>,
+    addr: VPtr,
+    length: usize,
+    advice: i32,
+) -> Result<(), Errno> {
+    if VPage::offset(addr) != 0 {
+        return Err(Errno(-abi::EINVAL));
+    }
+    match advice {
+        abi::MADV_NORMAL
+        | abi::MADV_RANDOM
+        | abi::MADV_SEQUENTIAL
+        | abi::MADV_WILLNEED
+        | abi::MADV_MERGEABLE
+        | abi::MADV_UNMERGEABLE
+        | abi::MADV_HUGEPAGE
+        | abi::MADV_NOHUGEPAGE
+        | abi::MADV_DONTDUMP
+        | abi::MADV_DODUMP
+        | abi::MADV_COLD
+        | abi::MADV_PAGEOUT
+        | abi::MADV_COLLAPSE => Ok(()),
+
+        abi::MADV_DONTNEED
+        | abi::MADV_FREE
+        | abi::MADV_REMOVE
+        | abi::MADV_DONTFORK
+        | abi::MADV_DOFORK
+        | abi::MADV_WIPEONFORK
+        | abi::MADV_KEEPONFORK
+        | abi::MADV_POPULATE_READ
+        | abi::MADV_POPULATE_WRITE
+        | abi::MADV_DONTNEED_LOCKED => {
+            let mut tr = Trampoline::new(stopped_task);
+            let result = tr
+                .syscall(
+                    sc::nr::MADVISE,
+                    &[addr.0 as isize, length as isize, advice as isize],
+                )
+                .await;
+            if result == 0 {
+                Ok(())
+            } else {
+                Err(Errno(result as i32))
+            }
+        }
+
+        // These need CAP_SYS_ADMIN on the host
+        abi::MADV_HWPOISON | abi::MADV_SOFT_OFFLINE => Err(Errno(-abi::EPERM)),
+
+        _ => Err(Errno(-abi::EINVAL)),
+    }
+}
+
 pub async fn fork(stopped_task: &mut StoppedTask<'_, '_>) -> SyscallResult {
     let mut tr = Trampoline::new(stopped_task);
     // to do:
@@ -2,27 +2,26 @@ use crate::{
     abi,
     ipc::Socket,
     mem::page::VPage,
-    nolibc::PROC_SELF_EXE,
+    nolibc::File,
     process::{
         table::{FileTable, ProcessTable},
         task::{TaskMemManagement, TaskSocketPair},
         Event, TaskFn,
     },
     protocol::{
-        LogLevel, MessageFromSand, MessageToSand, SysFd, SysPid, TracerSettings, VPid, VPtr,
+        abi::DEFAULT_ARG_MAX, IoctlList, LogLevel, MessageFromSand, MessageToSand, SysFd, SysPid,
+        TracerSettings, UtsString, VPid, VPtr,
     },
-    ptrace,
-    ptrace::RawExecArgs,
-    seccomp,
+    ptrace, seccomp,
+    spawner::Spawner,
 };
-use core::{future::Future, ptr::null, task::Poll};
-use heapless::{consts::*, String};
-use sc::syscall;
+use core::{future::Future, task::Poll};
 
 pub struct Tracer<'t, F: Future<Output = ()>> {
     ipc: Socket,
     settings: TracerSettings,
     process_table: ProcessTable<'t, F>,
+    spawner: Option<Spawner>,
 }
 
 impl<'t, F: Future<Output = ()>> Tracer<'t, F> {
@@ -31,8 +30,19 @@ impl<'t, F: Future<Output = ()>> Tracer<'t, F> {
             settings: TracerSettings {
                 max_log_level: LogLevel::Off,
                 instruction_trace: false,
+                extra_ioctls: IoctlList::default(),
+                arg_max: DEFAULT_ARG_MAX,
+                uid: 0,
+                gid: 0,
+                cpus: 1,
+                hostname: UtsString::default(),
+                kernel_release: UtsString::default(),
+                memory_limit: 0,
+                launch_time: 0,
+                extra_passthrough: 0,
             },
             process_table: ProcessTable::new(task_fn),
+            spawner: None,
             ipc,
         }
     }
@@ -52,33 +62,34 @@ impl<'t, F: Future<Output = ()>> Tracer<'t, F> {
         }
     }
 
-    fn init_loader(&mut self, args_fd: &SysFd) {
-        let mut fd_str = String::<U16>::from("FD=");
-        fd_str.push_str(&String::<U16>::from(args_fd.0)).unwrap();
-        fd_str.push('\0').unwrap();
-        let loader_argv = [crate::STAGE_2_INIT_LOADER.as_ptr(), null()];
-        let loader_env = [fd_str.as_ptr(), null()];
-        let exec_args = unsafe { RawExecArgs::new(PROC_SELF_EXE, &loader_argv, &loader_env) };
-        let socket_pair = TaskSocketPair::new_inheritable();
-        let settings = self.settings.clone();
-        match unsafe { syscall!(FORK) } as isize {
-            result if result == 0 => unsafe { ptrace::be_the_child_process(&exec_args) },
-            result if result < 0 => panic!("fork error"),
-            result => {
-                seccomp::policy_for_tracer_after_init();
-
-                let sys_pid = SysPid(result as u32);
-                let parent = None;
-                let mm = TaskMemManagement {
-                    brk: VPtr::null(),
-                    brk_start: VPage::null(),
-                };
-                let file_table = FileTable::new();
-                self.process_table
-                    .insert(settings, sys_pid, parent, socket_pair, mm, file_table)
-                    .expect("virtual process limit exceeded");
-            }
+    /// Start a loader, which execs the program described in an args file
+    ///
+    /// The first loader becomes the container's init, and any later ones run
+    /// alongside it.
+    fn start_loader(&mut self, args_fd: &SysFd) {
+        let args_file = File::new(*args_fd);
+        if let Some(spawner) = self.spawner.as_ref().filter(|spawner| spawner.is_running()) {
+            let socket_pair = TaskSocketPair::new_inheritable();
+            let sys_pid = spawner.spawn(args_fd);
+            let parent = None;
+            let mm = TaskMemManagement {
+                brk: VPtr::null(),
+                brk_start: VPage::null(),
+            };
+            let file_table = FileTable::new();
+            self.process_table
+                .insert(
+                    self.settings.clone(),
+                    sys_pid,
+                    parent,
+                    socket_pair,
+                    mm,
+                    file_table,
+                )
+                .expect("virtual process limit exceeded");
         }
+        // The loader has its own copy, if there is a loader
+        args_file.close().expect("args file leak");
     }
 
     fn message_event(&mut self, message: MessageToSand) {
@@ -88,14 +99,22 @@ impl<'t, F: Future<Output = ()>> Tracer<'t, F> {
                 args,
                 tracer_settings,
             } => {
+                assert!(self.spawner.is_none(), "container already initialized");
                 self.settings = tracer_settings;
-                self.init_loader(&args);
+                self.spawner = Some(Spawner::new(self.settings.extra_passthrough));
+                seccomp::policy_for_tracer_after_init();
+                self.start_loader(&args);
             }
+            MessageToSand::Spawn { args } => self.start_loader(&args),
         }
     }
 
     fn siginfo_event(&mut self, siginfo: &abi::SigInfo) {
         let sys_pid = SysPid(siginfo.si_pid);
+        if self.spawner.as_ref().map(Spawner::sys_pid) == Some(sys_pid) {
+            // The spawner only exits, after we're done with it
+            return;
+        }
         let vpid = self.process_table.syspid_to_v(sys_pid);
         match vpid {
             None => panic!("signal for unrecognized task, {:x?}", sys_pid),
@@ -138,6 +157,13 @@ impl<'t, F: Future<Output = ()>> Tracer<'t, F> {
             Poll::Ready(()) => {
                 // task exited normally, remove it from the process table
                 assert!(self.process_table.remove(task).is_some());
+                if self.process_table.is_empty() {
+                    // Nothing left to run alongside, and the tracer can exit
+                    // once the spawner does
+                    if let Some(spawner) = self.spawner.as_mut() {
+                        spawner.close();
+                    }
+                }
             }
         }
     }
@@ -1,17 +1,49 @@
 use crate::{
-    container::{Container, ExitStatus, Output},
+    container::{
+        cpus, network, seccomp, sysinfo, timezone, user, Container, ExecContext, ExitStatus,
+        Output, SeccompProfile,
+    },
     errors::{ImageError, RuntimeError, VFSError},
-    filesystem::{mount::Mount, socket::SharedStream, storage::FileStorage, vfs::Filesystem},
+    filesystem::{
+        host::HostDir,
+        mount::Mount,
+        socket::SharedStream,
+        storage::FileStorage,
+        tmpfs::Tmpfs,
+        vfs::{Filesystem, PathLimits},
+    },
+    manifest,
     manifest::ImageConfig,
     sand,
-    sand::protocol::{FollowLinks, TracerSettings},
+    sand::{
+        protocol::{
+            abi, IoctlList, LogLevel, LogMessage, SeccompRuleList, TracerSettings, UtsString, VPid,
+        },
+        LogHandler, ProcessPriority,
+    },
 };
 use std::{
-    ffi::{CString, NulError, OsStr},
-    os::unix::{ffi::OsStrExt, net::UnixStream},
+    ffi::{CStr, CString, NulError, OsStr, OsString},
+    fs::File,
+    io,
+    io::Read,
+    os::unix::ffi::{OsStrExt, OsStringExt},
     path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::Duration,
 };
 
+/// Callback that can rewrite or remove each environment variable
+type EnvFilter = Arc<dyn Fn(&OsStr, &OsStr) -> Option<OsString> + Send + Sync>;
+
+/// Data copied into the container's stdin once it starts
+#[derive(Clone)]
+enum StdinSource {
+    File(PathBuf),
+    Bytes(Vec<u8>),
+}
+
 /// Setup for containers, starting at [Container::new()] and ending with
 /// [ContainerBuilder::spawn()]
 #[derive(Clone)]
@@ -22,11 +54,33 @@ pub struct ContainerBuilder {
     entrypoint: Vec<CString>,
     cmd_default: Vec<CString>,
     cmd_override: Option<Vec<CString>>,
+    arg0: Option<CString>,
     env: Vec<CString>,
+    env_filters: Vec<EnvFilter>,
     arg_error: Result<(), NulError>,
     mount_error: Result<(), VFSError>,
+    ioctl_error: Result<(), u32>,
+    uts_error: Result<(), String>,
+    working_dir_error: Result<(), PathBuf>,
+    seccomp_profile: SeccompProfile,
+    seccomp_logging: Vec<u32>,
+    passthrough_syscalls: Option<Vec<u64>>,
+    extra_passthrough_syscalls: Vec<u64>,
+    read_only_paths: Vec<PathBuf>,
+    timezone: Option<String>,
+    network_files: bool,
+    priority: ProcessPriority,
+    stop_signal: i32,
+    timeout: Option<Duration>,
+    user: String,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    cpus: Option<u32>,
+    memory_limit: Option<u64>,
     stdio: [Option<SharedStream>; 3],
+    stdin_source: Option<StdinSource>,
     tracer_settings: TracerSettings,
+    log_handler: Option<LogHandler>,
 }
 
 impl ContainerBuilder {
@@ -41,11 +95,51 @@ impl ContainerBuilder {
             tracer_settings: TracerSettings {
                 max_log_level: sand::max_log_level(),
                 instruction_trace: false,
+                extra_ioctls: IoctlList::default(),
+                arg_max: abi::DEFAULT_ARG_MAX,
+                uid: 0,
+                gid: 0,
+                cpus: 1,
+                hostname: UtsString::new(b"host").unwrap(),
+                kernel_release: UtsString::new(b"4.0.0-bandsocks").unwrap(),
+                memory_limit: 0,
+                launch_time: 0,
+                extra_passthrough: 0,
             },
+            env_filters: Vec::new(),
+            log_handler: None,
             arg_error: Ok(()),
             mount_error: Ok(()),
+            ioctl_error: Ok(()),
+            uts_error: Ok(()),
+            working_dir_error: Ok(()),
+            seccomp_profile: SeccompProfile::new(),
+            seccomp_logging: Vec::new(),
+            passthrough_syscalls: None,
+            extra_passthrough_syscalls: Vec::new(),
+            read_only_paths: Vec::new(),
+            timezone: None,
+            network_files: false,
+            priority: Default::default(),
+            stop_signal: config
+                .stop_signal
+                .as_deref()
+                .and_then(manifest::parse_signal)
+                .unwrap_or(libc::SIGTERM),
+            timeout: None,
+            user: config.user.clone(),
+            uid: None,
+            gid: None,
+            cpus: None,
+            memory_limit: None,
             stdio: [None, None, None],
-            working_dir: CString::new(config.working_dir.as_bytes())?,
+            stdin_source: None,
+            working_dir: CString::new(
+                Path::new("/")
+                    .join(&config.working_dir)
+                    .into_os_string()
+                    .into_vec(),
+            )?,
             entrypoint: match &config.entrypoint {
                 None => Vec::new(),
                 Some(strs) => {
@@ -57,6 +151,7 @@ impl ContainerBuilder {
                 }
             },
             cmd_override: None,
+            arg0: None,
             cmd_default: {
                 let mut result = Vec::new();
                 for s in &config.cmd {
@@ -65,9 +160,12 @@ impl ContainerBuilder {
                 result
             },
             env: {
+                // Images can repeat a variable, and the last one wins
                 let mut result = Vec::new();
                 for s in &config.env {
-                    result.push(CString::new(s.as_bytes())?);
+                    let item = CString::new(s.as_bytes())?;
+                    let (key, value) = env::split(&item);
+                    env::set(&mut result, key, value.map(CStr::to_bytes))?;
                 }
                 result
             },
@@ -101,62 +199,99 @@ impl ContainerBuilder {
         self.spawn()?.interact().await
     }
 
+    /// Start a new [Container] that keeps running in the background
+    ///
+    /// Nothing needs to wait for a detached container or read its output.
+    /// Its stdin is closed, and its stdout and stderr are forwarded to their
+    /// real equivalents by background tasks unless they were overridden with
+    /// [ContainerBuilder::stdout()] or [ContainerBuilder::stderr()]. Hold on
+    /// to the [Container] to check on it with [Container::is_running()] and
+    /// end it with [Container::stop()].
+    ///
+    /// This must be called from within a tokio runtime.
+    pub fn detach(self) -> Result<Container, RuntimeError> {
+        let mut container = self.spawn()?;
+        container.forward_detached();
+        Ok(container)
+    }
+
     /// Start a new [Container] using the settings in this builder
     pub fn spawn(mut self) -> Result<Container, RuntimeError> {
+        let argv = self.command_line();
+        let env = self.environment();
+        let seccomp_rules = self.seccomp_rules();
         self.arg_error?;
+        let env = env?;
         self.mount_error?;
+        self.ioctl_error.map_err(RuntimeError::IoctlListFull)?;
+        self.uts_error.map_err(RuntimeError::InvalidUtsName)?;
+        self.working_dir_error
+            .map_err(ImageError::RelativeWorkingDir)?;
+        let seccomp_rules = seccomp_rules?;
+        let denied_passthrough = seccomp::denied_passthrough(self.passthrough_syscalls.as_deref())?;
+        let extra_passthrough = seccomp::extra_passthrough(&self.extra_passthrough_syscalls)?;
+        self.tracer_settings.extra_passthrough = extra_passthrough;
 
-        let mut local_stdio: [Option<UnixStream>; 3] = [None, None, None];
-        for fd in 0..3 {
-            let remote_stream = match self.stdio[fd].take() {
-                Some(stream) => stream,
-                None => {
-                    let (local, remote) = SharedStream::pair()?;
-                    local_stdio[fd] = Some(local);
-                    remote
-                }
-            };
-            remote_stream.mount(
-                &mut self.filesystem,
-                &Path::new(&format!("/proc/1/fd/{}", fd)),
-            )?;
+        if let Some(zone) = &self.timezone {
+            timezone::install(&mut self.filesystem, &self.storage, zone)?;
         }
-
-        let mut argv = self.entrypoint;
-        match self.cmd_override {
-            None => argv.extend(self.cmd_default),
-            Some(cmd) => argv.extend(cmd),
+        if self.network_files {
+            network::install(&mut self.filesystem, &self.storage)?;
+        }
+        for path in &self.read_only_paths {
+            self.filesystem.set_read_only(path)?;
+        }
+        let (uid, gid) = match (self.uid, self.gid) {
+            (Some(uid), Some(gid)) => (uid, gid),
+            (uid, gid) => {
+                let image_ids = user::resolve(&self.filesystem, &self.storage, &self.user)?;
+                (uid.unwrap_or(image_ids.0), gid.unwrap_or(image_ids.1))
+            }
         };
+        self.tracer_settings.uid = uid;
+        self.tracer_settings.gid = gid;
+        let cpus = cpus::clamp(self.cpus.unwrap_or_else(cpus::host_count));
+        if self.cpus.is_some() {
+            cpus::install(&mut self.filesystem, &self.storage, cpus)?;
+        }
+        self.tracer_settings.cpus = cpus;
+        self.tracer_settings.memory_limit =
+            self.memory_limit.unwrap_or_else(sysinfo::host_total_ram);
 
-        // be like execvpe(), doing path resolution if there are no slashes
-        let mut filename = argv.first().ok_or(RuntimeError::NoEntryPoint)?.to_owned();
-        if !filename.as_bytes().contains(&b'/') {
-            if let Some(Some(env_paths)) = env::get(&self.env, b"PATH") {
-                for env_path in env_paths.to_bytes().split(|c| *c == b':') {
-                    let mut buf = PathBuf::from(OsStr::from_bytes(self.working_dir.as_bytes()));
-                    buf.push(OsStr::from_bytes(env_path));
-                    buf.push(OsStr::from_bytes(filename.as_bytes()));
-                    if self
-                        .filesystem
-                        .lookup(&Filesystem::root(), &buf, &FollowLinks::Follow)
-                        .is_ok()
-                    {
-                        filename = CString::new(buf.into_os_string().as_bytes())?;
-                        break;
-                    }
-                }
-            }
+        if let Some(source) = self.stdin_source.take() {
+            let mut reader: Box<dyn Read + Send> = match source {
+                StdinSource::File(path) => Box::new(File::open(path)?),
+                StdinSource::Bytes(bytes) => Box::new(io::Cursor::new(bytes)),
+            };
+            let (mut local, remote) = SharedStream::pair()?;
+            self.stdio[0] = Some(remote);
+            thread::Builder::new()
+                .name("stdin source".to_string())
+                .spawn(move || {
+                    // Dropping our end at EOF closes the container's stdin
+                    let _ = io::copy(&mut reader, &mut local);
+                })?;
         }
 
+        self.tracer_settings.launch_time = sysinfo::monotonic_now();
         Container::exec(
-            self.filesystem,
-            self.storage,
-            filename,
-            self.working_dir,
+            ExecContext {
+                filesystem: self.filesystem,
+                storage: self.storage,
+                dir: self.working_dir,
+                env,
+                tracer_settings: self.tracer_settings,
+                seccomp_rules,
+                denied_passthrough,
+                extra_passthrough,
+                priority: self.priority,
+                stop_signal: self.stop_signal,
+                timeout: self.timeout,
+                log_handler: self.log_handler,
+            },
             argv,
-            self.env,
-            local_stdio,
-            self.tracer_settings,
+            self.arg0,
+            self.stdio,
         )
     }
 
@@ -175,9 +310,97 @@ impl ContainerBuilder {
         self
     }
 
+    /// Mount an empty, writable, in-memory directory
+    ///
+    /// The container can create, write, and remove files and directories in
+    /// it, replacing anything the image had at that path. With a size limit,
+    /// rounded up to whole pages, writes fail with `ENOSPC` once the files
+    /// add up to that many bytes. This needs unprivileged user namespaces on
+    /// the host. Contents are discarded when the container exits.
+    pub fn tmpfs<P: AsRef<Path>>(self, path: P, size_limit: Option<u64>) -> Self {
+        self.mount(path, &Tmpfs::new(size_limit))
+    }
+
+    /// Add a read-only file with the given contents to the container
+    ///
+    /// The file replaces anything the image had at that path, and any missing
+    /// parent directories are created. Its contents are copied into memory
+    /// now.
+    pub fn write_file<P, C>(mut self, path: P, contents: C, mode: u32) -> Self
+    where
+        P: AsRef<Path>,
+        C: Into<Vec<u8>>,
+    {
+        let result =
+            self.filesystem
+                .writer()
+                .write_memory_file(path.as_ref(), mode, &contents.into());
+        self.mount_error = self.mount_error.and(result);
+        self
+    }
+
+    /// Mount a read-only copy of a host directory
+    ///
+    /// The directory tree is walked now, and files are opened from the host
+    /// each time the container opens them. Symbolic links are kept, but one
+    /// leading outside the host directory fails the spawn. See [HostDir].
+    pub fn bind_mount<G, H>(self, guest_path: G, host_path: H) -> Self
+    where
+        G: AsRef<Path>,
+        H: AsRef<Path>,
+    {
+        self.mount(guest_path, &HostDir::new(host_path))
+    }
+
+    /// Make a subtree of the container's filesystem read-only
+    ///
+    /// The image itself is always read-only, but this also covers anything
+    /// mounted beneath the path. Attempts to open these files for writing or
+    /// modify their attributes fail with `EROFS`. The path must exist by the
+    /// time the container is spawned.
+    pub fn read_only_path<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.read_only_paths.push(path.as_ref().to_owned());
+        self
+    }
+
+    /// Limit the work done resolving each path in the container's filesystem
+    ///
+    /// Lookups that would traverse too many path segments fail with
+    /// `ENAMETOOLONG`, and those following too many symbolic links fail with
+    /// `ELOOP`. The defaults suit most
+    /// images; untrusted images may warrant stricter limits, and images with
+    /// unusually deep trees or long symlink chains may need looser ones.
+    pub fn path_limits(mut self, limits: PathLimits) -> Self {
+        self.filesystem.set_path_limits(limits);
+        self
+    }
+
     /// Attach stdin to a specific shared stream
     pub fn stdin(mut self, stream: SharedStream) -> Self {
         self.stdio[0] = Some(stream);
+        self.stdin_source = None;
+        self
+    }
+
+    /// Attach stdin to the contents of a file on the host
+    ///
+    /// The file is opened when the container starts, and copied into its
+    /// stdin from a separate thread. The container sees end-of-file on stdin
+    /// after the last byte. This replaces any stream set with
+    /// [ContainerBuilder::stdin()].
+    pub fn stdin_from_file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.stdio[0] = None;
+        self.stdin_source = Some(StdinSource::File(path.as_ref().to_owned()));
+        self
+    }
+
+    /// Attach stdin to a fixed buffer
+    ///
+    /// Like [ContainerBuilder::stdin_from_file()], but with data from memory.
+    /// The container reads these bytes from stdin and then sees end-of-file.
+    pub fn stdin_bytes<B: Into<Vec<u8>>>(mut self, data: B) -> Self {
+        self.stdio[0] = None;
+        self.stdin_source = Some(StdinSource::Bytes(data.into()));
         self
     }
 
@@ -193,7 +416,53 @@ impl ContainerBuilder {
         self
     }
 
+    /// The full command line, as Docker would build it
+    ///
+    /// This is the entrypoint followed by either the arguments from
+    /// [ContainerBuilder::arg()] or, if there were none, the image's default
+    /// command. See [ContainerBuilder::entrypoint()] for how overriding the
+    /// entrypoint affects the default command.
+    fn command_line(&self) -> Vec<CString> {
+        let mut argv = self.entrypoint.clone();
+        match &self.cmd_override {
+            None => argv.extend_from_slice(&self.cmd_default),
+            Some(cmd) => argv.extend_from_slice(cmd),
+        };
+        argv
+    }
+
+    /// The seccomp profile, with any syscalls to log added to it
+    fn seccomp_rules(&self) -> Result<SeccompRuleList, RuntimeError> {
+        self.seccomp_logging
+            .iter()
+            .fold(self.seccomp_profile.clone(), |profile, nr| profile.log(*nr))
+            .to_rule_list()
+    }
+
+    fn environment(&self) -> Result<Vec<CString>, NulError> {
+        let mut result = Vec::with_capacity(self.env.len());
+        'vars: for item in &self.env {
+            let (key, value) = env::split(item);
+            let key = OsStr::from_bytes(key);
+            let mut value = value.map(|v| OsStr::from_bytes(v.to_bytes()).to_os_string());
+            for filter in &self.env_filters {
+                match filter(key, value.as_deref().unwrap_or_default()) {
+                    None => continue 'vars,
+                    Some(filtered) => value = Some(filtered),
+                }
+            }
+            match value {
+                None => result.push(item.clone()),
+                Some(value) => result.push(env::join(key.as_bytes(), Some(&value.into_vec()))?),
+            }
+        }
+        Ok(result)
+    }
+
     /// Append arguments to the container's command line
+    ///
+    /// Any arguments given here replace the image's default command (`CMD`
+    /// in a Dockerfile), and are appended to the entrypoint.
     pub fn args<I, S>(mut self, args: I) -> Self
     where
         I: IntoIterator<Item = S>,
@@ -206,6 +475,9 @@ impl ContainerBuilder {
     }
 
     /// Append one argument to the container's command line
+    ///
+    /// Like [ContainerBuilder::args()], this replaces the image's default
+    /// command.
     pub fn arg<S>(mut self, arg: S) -> Self
     where
         S: AsRef<OsStr>,
@@ -222,14 +494,38 @@ impl ContainerBuilder {
         self
     }
 
+    /// Set the first argument the program sees, separately from the program
+    ///
+    /// The program to run is still the first word of the command line, found
+    /// by searching `PATH` if needed, but its `argv[0]` is replaced with this
+    /// name. Multicall binaries like busybox pick their behavior based on it.
+    pub fn arg0<S>(mut self, arg: S) -> Self
+    where
+        S: AsRef<OsStr>,
+    {
+        match CString::new(arg.as_ref().as_bytes()) {
+            Err(e) => self.arg_error = Err(e),
+            Ok(arg) => self.arg0 = Some(arg),
+        }
+        self
+    }
+
     /// Override the working directory the entrypoint will start in
+    ///
+    /// By default this is the image's `WorkingDir`, or `/` if the image
+    /// doesn't set one. The path must be absolute.
     pub fn working_dir<P>(mut self, dir: P) -> Self
     where
         P: AsRef<Path>,
     {
-        match CString::new(dir.as_ref().as_os_str().as_bytes()) {
-            Err(e) => self.arg_error = Err(e),
-            Ok(arg) => self.working_dir = arg,
+        let dir = dir.as_ref();
+        if dir.is_relative() {
+            self.working_dir_error = Err(dir.to_owned());
+        } else {
+            match CString::new(dir.as_os_str().as_bytes()) {
+                Err(e) => self.arg_error = Err(e),
+                Ok(arg) => self.working_dir = arg,
+            }
         }
         self
     }
@@ -237,7 +533,9 @@ impl ContainerBuilder {
     /// Override the container's entrypoint
     ///
     /// The entrypoint, if present, is prepended to the "args" to form
-    /// the container's full command line.
+    /// the container's full command line. Like `docker run --entrypoint`,
+    /// this also discards the image's default command, so only arguments
+    /// given with [ContainerBuilder::arg()] follow the new entrypoint.
     pub fn entrypoint<I, S>(mut self, args: I) -> Self
     where
         I: IntoIterator<Item = S>,
@@ -254,10 +552,15 @@ impl ContainerBuilder {
             }
         }
         self.entrypoint = collected;
+        self.cmd_default.clear();
         self
     }
 
     /// Add or replace one environment variable
+    ///
+    /// The container starts with the image's `Env` settings, and variables
+    /// set here replace any with the same key. Keys are case-sensitive, and
+    /// each key appears at most once, in the order it was first set.
     pub fn env<K, V>(mut self, key: K, val: V) -> Self
     where
         K: AsRef<OsStr>,
@@ -286,6 +589,45 @@ impl ContainerBuilder {
         self
     }
 
+    /// Set the container's local timezone, by tz database name
+    ///
+    /// This sets `TZ`, and copies the zone's file from the host's
+    /// `/usr/share/zoneinfo` to `/etc/localtime` and to the same path in the
+    /// container, unless the image already has those files. If the host
+    /// doesn't know the zone, spawning the container will fail.
+    pub fn timezone<S: AsRef<str>>(mut self, zone: S) -> Self {
+        let zone = zone.as_ref();
+        self.timezone = Some(zone.to_owned());
+        self.env("TZ", zone)
+    }
+
+    /// Provide stub networking configuration files if the image lacks them
+    ///
+    /// Containers have no network, but many programs read `/etc/hosts`,
+    /// `/etc/resolv.conf`, or `/etc/nsswitch.conf` at startup and fail
+    /// without them. Any that are missing are added as read-only files which
+    /// resolve `localhost` to the loopback address and name no DNS servers.
+    pub fn synthesize_network_files(mut self) -> Self {
+        self.network_files = true;
+        self
+    }
+
+    /// Rewrite or remove environment variables just before the container
+    /// starts
+    ///
+    /// The callback sees each variable from the image configuration and this
+    /// builder, as a key and value, and returns the value to use or None to
+    /// remove the variable. This can strip variables like `LD_PRELOAD` that
+    /// an untrusted image declares. Multiple filters run in the order they
+    /// were added.
+    pub fn filter_env<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&OsStr, &OsStr) -> Option<OsString> + Send + Sync + 'static,
+    {
+        self.env_filters.push(Arc::new(filter));
+        self
+    }
+
     /// Remove one environment variable entirely, leaving it unset
     pub fn env_remove<K: AsRef<OsStr>>(mut self, key: K) -> Self {
         env::remove(&mut self.env, key.as_ref().to_os_string().as_bytes());
@@ -299,6 +641,79 @@ impl ContainerBuilder {
         self
     }
 
+    /// Set the user ID the container's processes see
+    ///
+    /// By default this comes from the image's `User` setting, looked up in
+    /// the container's `/etc/passwd` if it's a name, or 0 if the image has no
+    /// user. This only changes what the container sees; the sandbox runs
+    /// with the same host privileges either way.
+    pub fn uid(mut self, uid: u32) -> Self {
+        self.uid = Some(uid);
+        self
+    }
+
+    /// Set the group ID the container's processes see
+    ///
+    /// By default this is the group from the image's `User` setting, or the
+    /// primary group of its user, or 0.
+    pub fn gid(mut self, gid: u32) -> Self {
+        self.gid = Some(gid);
+        self
+    }
+
+    /// Set the number of CPUs the container sees
+    ///
+    /// By default the container sees as many CPUs as the host lets us run on,
+    /// and the image's own CPU files. Setting this changes the mask
+    /// `sched_getaffinity()` reports, and adds the CPU lists in
+    /// `/proc/cpuinfo` and `/sys/devices/system/cpu` that `sysconf()` reads,
+    /// so thread pools can be sized for a smaller sandbox. It doesn't limit
+    /// which host CPUs actually run the container. Values are clamped between
+    /// 1 and 1024.
+    pub fn cpus(mut self, cpus: u32) -> Self {
+        self.cpus = Some(cpus);
+        self
+    }
+
+    /// Set the host name the container sees in `uname()`
+    ///
+    /// The default is `host`. Names are limited to 64 bytes with no nul; a
+    /// name that doesn't fit makes spawning the container fail.
+    pub fn hostname<S: AsRef<str>>(mut self, name: S) -> Self {
+        let name = name.as_ref();
+        match UtsString::new(name.as_bytes()) {
+            Some(s) => self.tracer_settings.hostname = s,
+            None => self.uts_error = Err(name.to_owned()),
+        }
+        self
+    }
+
+    /// Set the kernel release the container sees in `uname()`
+    ///
+    /// The default is `4.0.0-bandsocks`. Software that checks the kernel
+    /// version for features may want something that looks like the host's
+    /// release instead. The same limits apply as for
+    /// [ContainerBuilder::hostname()].
+    pub fn kernel_release<S: AsRef<str>>(mut self, release: S) -> Self {
+        let release = release.as_ref();
+        match UtsString::new(release.as_bytes()) {
+            Some(s) => self.tracer_settings.kernel_release = s,
+            None => self.uts_error = Err(release.to_owned()),
+        }
+        self
+    }
+
+    /// Set the amount of memory the container sees
+    ///
+    /// By default this is the host's total RAM. It's reported as both total
+    /// and free RAM by `sysinfo()`, for programs that size caches or buffers
+    /// from it. It doesn't limit how much memory the container can actually
+    /// allocate.
+    pub fn memory_limit(mut self, bytes: u64) -> Self {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
     /// Run the container in single-step mode
     ///
     /// This is extremely verbose, and intended only for debugging or reporting
@@ -307,9 +722,140 @@ impl ContainerBuilder {
         self.tracer_settings.instruction_trace = true;
         self
     }
+
+    /// Receive every log message from the tracer as it arrives
+    ///
+    /// The handler sees messages at all levels, in addition to the usual
+    /// output through the `log` crate. [LogMessage::Emulated] is sent for
+    /// each syscall the sandbox handles itself, so these can be collected
+    /// into a trace. [LogMessage::PtraceRequests] counts the tracer's ptrace
+    /// requests as each task exits, for benchmarking the emulation itself.
+    /// Messages the tracer would otherwise skip are all sent once there's a
+    /// handler, which slows the container down.
+    pub fn log_handler<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(VPid, LogLevel, LogMessage) + Send + Sync + 'static,
+    {
+        self.log_handler = Some(Arc::new(handler));
+        self.tracer_settings.max_log_level = LogLevel::Trace;
+        self
+    }
+
+    /// Limit the combined size of arguments and environment for each exec
+    ///
+    /// This counts each string including its nul terminator, plus one pointer
+    /// per string, like Linux. Exec calls over the limit fail with `E2BIG`.
+    /// The default is 2 MiB, what Linux allows with a typical stack size.
+    pub fn arg_max(mut self, bytes: usize) -> Self {
+        self.tracer_settings.arg_max = bytes;
+        self
+    }
+
+    /// Allow an additional ioctl request to reach the real file descriptor
+    ///
+    /// By default only a small set of terminal and fd status requests are
+    /// passed through, and any other ioctl fails with ENOTTY. Only a limited
+    /// number of extra requests may be allowed; past that, spawning the
+    /// container will fail.
+    pub fn allow_ioctl(mut self, request: u32) -> Self {
+        if !self.tracer_settings.extra_ioctls.insert(request) {
+            self.ioctl_error = Err(request);
+        }
+        self
+    }
+
+    /// Deny some host syscalls according to a [SeccompProfile]
+    ///
+    /// This replaces any profile set earlier. It only narrows the set of
+    /// syscalls a container may pass through to the host kernel, it has no
+    /// effect on emulated syscalls.
+    pub fn seccomp_profile(mut self, profile: SeccompProfile) -> Self {
+        self.seccomp_profile = profile;
+        self
+    }
+
+    /// Narrow the set of syscalls that pass through to the host kernel
+    ///
+    /// The list must be a subset of
+    /// [default_passthrough_syscalls()](crate::default_passthrough_syscalls);
+    /// any other syscall number makes [ContainerBuilder::spawn()] fail with
+    /// [RuntimeError::NotPassthroughSyscall]. Syscalls left out fail with
+    /// `ENOSYS`. This replaces any list set earlier, and combines with a
+    /// [SeccompProfile], which can restrict the remaining syscalls further.
+    pub fn passthrough_syscalls(mut self, syscalls: &[u64]) -> Self {
+        self.passthrough_syscalls = Some(syscalls.to_vec());
+        self
+    }
+
+    /// Pass some normally emulated syscalls straight through to the host kernel
+    ///
+    /// Each one must be from
+    /// [optional_passthrough_syscalls()](crate::optional_passthrough_syscalls);
+    /// any other syscall number makes [ContainerBuilder::spawn()] fail with
+    /// [RuntimeError::NotOptionalPassthroughSyscall]. These skip the sandbox
+    /// entirely, so clocks and random numbers come from the host, and files
+    /// they create aren't tracked. Calling this again adds to the list.
+    pub fn extra_passthrough_syscalls(mut self, syscalls: &[u64]) -> Self {
+        self.extra_passthrough_syscalls.extend_from_slice(syscalls);
+        self
+    }
+
+    /// Have the host kernel log each use of these passthrough syscalls
+    ///
+    /// These become [SeccompProfile::log()] rules on top of any
+    /// [ContainerBuilder::seccomp_profile()], whichever is called first.
+    /// Calling this again adds to the list. Emulated syscalls never reach the
+    /// host kernel and aren't logged.
+    pub fn seccomp_logging<I: IntoIterator<Item = u32>>(mut self, syscalls: I) -> Self {
+        self.seccomp_logging.extend(syscalls);
+        self
+    }
+
+    /// Override the signal [Container::terminate()] sends, like `docker run
+    /// --stop-signal`
+    ///
+    /// By default this is the image's `STOPSIGNAL`, or `SIGTERM` if it doesn't
+    /// have one.
+    pub fn stop_signal(mut self, signal: i32) -> Self {
+        self.stop_signal = signal;
+        self
+    }
+
+    /// Kill the container if it's still running this long after it starts
+    ///
+    /// When the time is up, every process in the container gets `SIGKILL`
+    /// and the container finishes with [RuntimeError::Timeout] instead of an
+    /// exit status. Probes from [Container::exec_probe()] run inside the
+    /// container, so they're killed along with it.
+    pub fn timeout(mut self, limit: Duration) -> Self {
+        self.timeout = Some(limit);
+        self
+    }
+
+    /// Set the scheduling niceness of the container's host process
+    ///
+    /// Values are clamped to the range -20 (highest priority) to 19 (lowest),
+    /// as with setpriority(2). Raising priority above the host process's own
+    /// requires privileges, and without them the container will fail to start.
+    pub fn nice(mut self, nice: i32) -> Self {
+        self.priority.nice = nice.max(-20).min(19);
+        self.priority.set_nice = true;
+        self
+    }
+
+    /// Set the OOM killer score adjustment of the container's host process
+    ///
+    /// Values are clamped to the range -1000 (never kill) to 1000 (kill
+    /// first). As with niceness, adjusting below the current value requires
+    /// privileges. The container sees the result in `/proc/1/oom_score_adj`.
+    pub fn oom_score_adj(mut self, adj: i32) -> Self {
+        self.priority.oom_score_adj = adj.max(-1000).min(1000);
+        self.priority.set_oom_score_adj = true;
+        self
+    }
 }
 
-mod env {
+pub(super) mod env {
     use std::ffi::{CStr, CString, NulError};
 
     pub fn split<'a>(env: &'a CStr) -> (&'a [u8], Option<&'a CStr>) {
@@ -362,3 +908,163 @@ mod env {
         Ok(env.push(joined))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::container::SeccompAction;
+
+    fn builder(entrypoint: Option<&[&str]>, cmd: &[&str]) -> ContainerBuilder {
+        let config = ImageConfig {
+            entrypoint: entrypoint.map(|e| e.iter().map(|s| s.to_string()).collect()),
+            cmd: cmd.iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        };
+        let storage = FileStorage::new(PathBuf::from("/nonexistent"), None);
+        ContainerBuilder::new(&config, Filesystem::new(), storage).unwrap()
+    }
+
+    fn command_line(builder: &ContainerBuilder) -> Vec<String> {
+        builder
+            .command_line()
+            .into_iter()
+            .map(|arg| arg.into_string().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn image_defaults() {
+        let b = builder(Some(&["/entry", "-x"]), &["cmd", "default"]);
+        assert_eq!(command_line(&b), ["/entry", "-x", "cmd", "default"]);
+        let b = builder(None, &["cmd", "default"]);
+        assert_eq!(command_line(&b), ["cmd", "default"]);
+    }
+
+    #[test]
+    fn args_replace_cmd() {
+        let b = builder(Some(&["/entry"]), &["cmd", "default"]).args(&["a", "b"]);
+        assert_eq!(command_line(&b), ["/entry", "a", "b"]);
+        let b = builder(None, &["cmd"]).arg("a");
+        assert_eq!(command_line(&b), ["a"]);
+    }
+
+    #[test]
+    fn entrypoint_discards_cmd() {
+        let b = builder(Some(&["/entry"]), &["cmd", "default"]).entrypoint(&["/other"]);
+        assert_eq!(command_line(&b), ["/other"]);
+        let b = builder(None, &["cmd"]).entrypoint(&["/other", "-y"]);
+        assert_eq!(command_line(&b), ["/other", "-y"]);
+    }
+
+    #[test]
+    fn entrypoint_and_args() {
+        let b = builder(Some(&["/entry"]), &["cmd"])
+            .arg("a")
+            .entrypoint(&["/other"])
+            .arg("b");
+        assert_eq!(command_line(&b), ["/other", "a", "b"]);
+        let b = builder(Some(&["/entry"]), &["cmd"])
+            .entrypoint(Vec::<&str>::new())
+            .args(&["sh", "-c", "true"]);
+        assert_eq!(command_line(&b), ["sh", "-c", "true"]);
+    }
+
+    #[test]
+    fn filter_image_env() {
+        let config = ImageConfig {
+            env: vec![
+                "PATH=/bin".to_string(),
+                "LD_PRELOAD=/lib/evil.so".to_string(),
+            ],
+            ..Default::default()
+        };
+        let storage = FileStorage::new(PathBuf::from("/nonexistent"), None);
+        let b = ContainerBuilder::new(&config, Filesystem::new(), storage)
+            .unwrap()
+            .env("HOME", "/root")
+            .filter_env(|key, value| match key.to_str() {
+                Some("LD_PRELOAD") => None,
+                Some("HOME") => Some("/home".into()),
+                _ => Some(value.to_owned()),
+            })
+            .filter_env(|_, value| Some(value.to_owned()));
+        let env: Vec<String> = b
+            .environment()
+            .unwrap()
+            .into_iter()
+            .map(|var| var.into_string().unwrap())
+            .collect();
+        assert_eq!(env, ["PATH=/bin", "HOME=/home"]);
+    }
+
+    #[test]
+    fn merge_env_last_wins() {
+        let config = ImageConfig {
+            env: vec![
+                "PATH=/bin".to_string(),
+                "LANG=C".to_string(),
+                "path=lower".to_string(),
+                "PATH=/usr/bin:/bin".to_string(),
+            ],
+            ..Default::default()
+        };
+        let storage = FileStorage::new(PathBuf::from("/nonexistent"), None);
+        let b = ContainerBuilder::new(&config, Filesystem::new(), storage)
+            .unwrap()
+            .env("LANG", "en_US.UTF-8")
+            .envs(vec![("HOME", "/root"), ("HOME", "/home")]);
+        let env: Vec<String> = b
+            .environment()
+            .unwrap()
+            .into_iter()
+            .map(|var| var.into_string().unwrap())
+            .collect();
+        assert_eq!(
+            env,
+            [
+                "PATH=/usr/bin:/bin",
+                "LANG=en_US.UTF-8",
+                "path=lower",
+                "HOME=/home"
+            ]
+        );
+    }
+
+    #[test]
+    fn image_stop_signal() {
+        let b = builder(None, &["cmd"]);
+        assert_eq!(b.stop_signal, libc::SIGTERM);
+        for name in &["SIGQUIT", "QUIT", "sigquit", "3"] {
+            let config = ImageConfig {
+                stop_signal: Some(name.to_string()),
+                ..Default::default()
+            };
+            let storage = FileStorage::new(PathBuf::from("/nonexistent"), None);
+            let b = ContainerBuilder::new(&config, Filesystem::new(), storage).unwrap();
+            assert_eq!(b.stop_signal, libc::SIGQUIT);
+        }
+        assert_eq!(manifest::parse_signal("SIGNOPE"), None);
+        assert_eq!(manifest::parse_signal("0"), None);
+        assert_eq!(manifest::parse_signal("65"), None);
+        assert_eq!(builder(None, &[]).stop_signal(9).stop_signal, libc::SIGKILL);
+    }
+
+    #[test]
+    fn seccomp_logging_with_profile() {
+        let write = libc::SYS_write as u32;
+        let read = libc::SYS_read as u32;
+        let profile = || SeccompProfile::new().deny(read, SeccompAction::Trap);
+        let before = builder(None, &[])
+            .seccomp_logging(vec![write, read])
+            .seccomp_profile(profile())
+            .seccomp_rules()
+            .unwrap();
+        let after = builder(None, &[])
+            .seccomp_profile(profile())
+            .seccomp_logging(vec![write, read])
+            .seccomp_rules()
+            .unwrap();
+        assert_eq!(before, after);
+        assert_eq!(before, profile().log(write).to_rule_list().unwrap(),);
+    }
+}
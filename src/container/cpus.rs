@@ -0,0 +1,102 @@
+use crate::{
+    errors::RuntimeError,
+    filesystem::{storage::FileStorage, vfs::Filesystem},
+    sand::protocol::{abi, FileStat, FollowLinks},
+};
+use std::{mem, path::Path};
+
+/// Directory where sysconf() looks for CPUs on glibc
+const SYS_CPU_DIR: &str = "/sys/devices/system/cpu";
+
+/// Count the CPUs this process is allowed to run on
+pub fn host_count() -> u32 {
+    let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+    let result = unsafe { libc::sched_getaffinity(0, mem::size_of_val(&set), &mut set) };
+    if result == 0 {
+        (unsafe { libc::CPU_COUNT(&set) } as u32).max(1)
+    } else {
+        1
+    }
+}
+
+/// Keep a CPU count within what the container's affinity mask can describe
+pub fn clamp(cpus: u32) -> u32 {
+    cpus.max(1).min(abi::CPU_SETSIZE as u32)
+}
+
+/// Linux's list format for a range of CPU numbers starting at zero
+fn cpu_list(cpus: u32) -> String {
+    match cpus {
+        1 => "0\n".to_string(),
+        n => format!("0-{}\n", n - 1),
+    }
+}
+
+fn cpuinfo(cpus: u32) -> String {
+    (0..cpus)
+        .map(|n| format!("processor\t: {}\n\n", n))
+        .collect()
+}
+
+/// Add CPU listings to the container to match the number of CPUs it sees
+///
+/// Files the image already has are left alone, and new files are read-only.
+pub fn install(
+    filesystem: &mut Filesystem,
+    storage: &FileStorage,
+    cpus: u32,
+) -> Result<(), RuntimeError> {
+    let list = cpu_list(cpus);
+    let files = [
+        ("/proc/cpuinfo".to_string(), cpuinfo(cpus)),
+        (format!("{}/online", SYS_CPU_DIR), list.clone()),
+        (format!("{}/possible", SYS_CPU_DIR), list.clone()),
+        (format!("{}/present", SYS_CPU_DIR), list),
+    ];
+    for (path, contents) in &files {
+        let path = Path::new(path);
+        if filesystem
+            .lookup(&Filesystem::root(), path, &FollowLinks::NoFollow)
+            .is_ok()
+        {
+            continue;
+        }
+        let stat = FileStat {
+            st_mode: abi::S_IFREG | 0o444,
+            st_size: contents.len() as i64,
+            ..Default::default()
+        };
+        let key = storage.insert_blob(contents.as_bytes())?;
+        filesystem
+            .writer()
+            .write_storage_file(path, stat, Some(key))?;
+        filesystem.set_read_only(path)?;
+    }
+    // Older glibc counts configured CPUs by listing these directories
+    for n in 0..cpus {
+        let path = format!("{}/cpu{}", SYS_CPU_DIR, n);
+        let stat = FileStat {
+            st_mode: abi::S_IFDIR | 0o555,
+            ..Default::default()
+        };
+        filesystem
+            .writer()
+            .write_directory_metadata(Path::new(&path), stat)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpu_listings() {
+        assert_eq!(cpu_list(1), "0\n");
+        assert_eq!(cpu_list(2), "0-1\n");
+        assert_eq!(cpuinfo(2), "processor\t: 0\n\nprocessor\t: 1\n\n");
+        assert_eq!(clamp(0), 1);
+        assert_eq!(clamp(100_000), 1024);
+        assert!(host_count() >= 1);
+    }
+}
@@ -0,0 +1,112 @@
+use crate::{
+    errors::VFSError,
+    filesystem::vfs::Filesystem,
+    sand::protocol::{abi, FileStat, FollowLinks},
+};
+use std::{ffi::CString, path::Path};
+
+/// Symbolic links that give names to the current process's open files
+///
+/// These all go through `/proc/self`, which leads to whichever task is
+/// looking it up.
+const LINKS: &[(&str, &str)] = &[
+    ("/dev/fd", "/proc/self/fd"),
+    ("/dev/stdin", "/proc/self/fd/0"),
+    ("/dev/stdout", "/proc/self/fd/1"),
+    ("/dev/stderr", "/proc/self/fd/2"),
+];
+
+/// Add links for `/dev/stdout` and friends, where the image doesn't have
+/// its own
+pub fn install(filesystem: &mut Filesystem) -> Result<(), VFSError> {
+    let exists = |filesystem: &Filesystem, path| {
+        filesystem
+            .lookup(&Filesystem::root(), path, &FollowLinks::NoFollow)
+            .is_ok()
+    };
+    let proc_self = Path::new("/proc/self");
+    if !exists(filesystem, proc_self) {
+        let stat = FileStat {
+            st_mode: abi::S_IFLNK | 0o777,
+            ..Default::default()
+        };
+        filesystem.writer().write_self_link(proc_self, stat)?;
+    }
+    for (path, target) in LINKS {
+        let path = Path::new(path);
+        if exists(filesystem, path) {
+            continue;
+        }
+        let stat = FileStat {
+            st_mode: abi::S_IFLNK | 0o777,
+            st_size: target.len() as i64,
+            ..Default::default()
+        };
+        let target = CString::new(*target).unwrap();
+        filesystem.writer().write_symlink(path, stat, target)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        filesystem::{mount::Mount, socket::SharedStream},
+        sand::protocol::VPid,
+    };
+
+    #[test]
+    fn stdio_links_reach_mounted_streams() {
+        let mut filesystem = Filesystem::new();
+        for fd in 0..3 {
+            let (_local, remote) = SharedStream::pair().unwrap();
+            let path = format!("/proc/1/fd/{}", fd);
+            remote.mount(&mut filesystem, Path::new(&path)).unwrap();
+        }
+        install(&mut filesystem).unwrap();
+        let lookup = |path| {
+            filesystem
+                .lookup(&Filesystem::root(), Path::new(path), &FollowLinks::Follow)
+                .unwrap()
+        };
+        assert_eq!(lookup("/dev/stdout"), lookup("/proc/1/fd/1"));
+        assert_eq!(lookup("/dev/stderr"), lookup("/proc/1/fd/2"));
+        assert_eq!(lookup("/dev/fd/0"), lookup("/proc/1/fd/0"));
+        assert_eq!(lookup("/dev/stdin"), lookup("/proc/self/fd/0"));
+    }
+
+    #[test]
+    fn proc_self_follows_current_task() {
+        let mut filesystem = Filesystem::new();
+        for task in 1..3 {
+            let (_local, remote) = SharedStream::pair().unwrap();
+            let path = format!("/proc/{}/fd/1", task);
+            remote.mount(&mut filesystem, Path::new(&path)).unwrap();
+        }
+        install(&mut filesystem).unwrap();
+        let lookup = |filesystem: &Filesystem, path| {
+            filesystem
+                .lookup(&Filesystem::root(), Path::new(path), &FollowLinks::Follow)
+                .unwrap()
+        };
+        let self_link = filesystem
+            .lookup(
+                &Filesystem::root(),
+                Path::new("/proc/self"),
+                &FollowLinks::NoFollow,
+            )
+            .unwrap();
+        assert_eq!(filesystem.readlink(&self_link).unwrap().to_bytes(), b"1");
+        assert_eq!(
+            lookup(&filesystem, "/dev/stdout"),
+            lookup(&filesystem, "/proc/1/fd/1")
+        );
+        filesystem.set_current_task(VPid(2));
+        assert_eq!(filesystem.readlink(&self_link).unwrap().to_bytes(), b"2");
+        assert_eq!(
+            lookup(&filesystem, "/dev/stdout"),
+            lookup(&filesystem, "/proc/2/fd/1")
+        );
+    }
+}
@@ -1,22 +1,47 @@
 //! Sandboxed subprocesses with a virtual filesystem
 
 mod builder;
+mod cpus;
+mod devices;
+mod network;
+mod seccomp;
+mod sysinfo;
+mod timezone;
+mod user;
 
 pub use builder::ContainerBuilder;
+pub use seccomp::{
+    default_passthrough_syscalls, optional_passthrough_syscalls, SeccompAction, SeccompProfile,
+};
 
 use crate::{
     errors::{ImageError, RuntimeError},
-    filesystem::{storage::FileStorage, vfs::Filesystem},
+    filesystem::{mount::Mount, socket::SharedStream, storage::FileStorage, vfs::Filesystem},
     image::{Image, ImageName},
-    ipcserver::IPCServer,
+    ipcserver::{IPCServer, LivePids, Probe},
     registry::RegistryClient,
-    sand::protocol::{InitArgsHeader, TracerSettings},
+    sand::{
+        protocol::{FollowLinks, InitArgsHeader, SeccompRuleList, TracerSettings},
+        LogHandler, ProcessPriority,
+    },
+};
+use futures_util::FutureExt;
+use std::{
+    borrow::Cow,
+    ffi::{CString, NulError, OsStr},
+    fmt, io,
+    os::unix::{ffi::OsStrExt, net::UnixStream},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::Duration,
 };
-use std::{borrow::Cow, ffi::CString, fmt, io, os::unix::net::UnixStream, sync::Arc, thread};
 use tokio::{
-    io::{AsyncWriteExt, BufWriter},
+    io::{AsyncBufRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
+    sync::{mpsc, oneshot},
     task,
     task::JoinHandle,
+    time,
 };
 
 /// A running container
@@ -28,6 +53,118 @@ pub struct Container {
     pub stdout: Option<UnixStream>,
     pub stderr: Option<UnixStream>,
     join: JoinHandle<Result<ExitStatus, RuntimeError>>,
+    exited: Option<Result<ExitStatus, RuntimeError>>,
+    signals: mpsc::UnboundedSender<i32>,
+    probes: mpsc::UnboundedSender<Probe>,
+    context: Arc<ExecContext>,
+    live_pids: LivePids,
+}
+
+/// Everything a container's processes have in common, besides their
+/// command line and stdio
+#[derive(Clone)]
+pub(crate) struct ExecContext {
+    pub(crate) filesystem: Filesystem,
+    pub(crate) storage: FileStorage,
+    pub(crate) dir: CString,
+    pub(crate) env: Vec<CString>,
+    pub(crate) tracer_settings: TracerSettings,
+    pub(crate) seccomp_rules: SeccompRuleList,
+    pub(crate) denied_passthrough: u64,
+    pub(crate) extra_passthrough: u64,
+    pub(crate) priority: ProcessPriority,
+    pub(crate) stop_signal: i32,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) log_handler: Option<LogHandler>,
+}
+
+impl fmt::Debug for ExecContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ExecContext")
+            .field("dir", &self.dir)
+            .field("env", &self.env)
+            .finish()
+    }
+}
+
+impl ExecContext {
+    /// Be like execvpe(), doing path resolution if there are no slashes
+    fn resolve_command(&self, name: &CString) -> Result<CString, NulError> {
+        if !name.as_bytes().contains(&b'/') {
+            if let Some(Some(env_paths)) = builder::env::get(&self.env, b"PATH") {
+                for env_path in env_paths.to_bytes().split(|c| *c == b':') {
+                    let mut buf = PathBuf::from(OsStr::from_bytes(self.dir.as_bytes()));
+                    buf.push(OsStr::from_bytes(env_path));
+                    buf.push(OsStr::from_bytes(name.as_bytes()));
+                    if self
+                        .filesystem
+                        .lookup(&Filesystem::root(), &buf, &FollowLinks::Follow)
+                        .is_ok()
+                    {
+                        return CString::new(buf.into_os_string().as_bytes());
+                    }
+                }
+            }
+        }
+        Ok(name.to_owned())
+    }
+
+    /// Encode the args file that sand's loader reads to start a process
+    fn loader_args(
+        &self,
+        mut argv: Vec<CString>,
+        arg0: Option<CString>,
+        env: &[CString],
+    ) -> Result<Vec<u8>, RuntimeError> {
+        let filename = self.resolve_command(argv.first().ok_or(RuntimeError::NoEntryPoint)?)?;
+        if let Some(arg0) = arg0 {
+            argv[0] = arg0;
+        }
+
+        log::debug!(
+            "exec file={:?} dir={:?} argv={:?} env={:?}",
+            filename,
+            self.dir,
+            argv,
+            env
+        );
+
+        let filename = filename.as_bytes_with_nul();
+        let dir = self.dir.as_bytes_with_nul();
+        let args_header = InitArgsHeader {
+            dir_len: dir.len(),
+            filename_len: filename.len(),
+            argv_len: argv
+                .iter()
+                .map(|arg| arg.as_bytes_with_nul().len())
+                .sum::<usize>()
+                + 1,
+            envp_len: env
+                .iter()
+                .map(|var| var.as_bytes_with_nul().len())
+                .sum::<usize>()
+                + 1,
+            arg_count: argv.len(),
+            env_count: env.len(),
+            seccomp_rules: self.seccomp_rules.clone(),
+            denied_passthrough: self.denied_passthrough,
+            extra_passthrough: self.extra_passthrough,
+        };
+
+        let mut args = Vec::new();
+        args.extend_from_slice(args_header.as_bytes());
+        args.extend_from_slice(dir);
+        args.extend_from_slice(filename);
+        for arg in &argv {
+            args.extend_from_slice(arg.as_bytes_with_nul());
+        }
+        args.push(0);
+        for var in env {
+            args.extend_from_slice(var.as_bytes_with_nul());
+        }
+        args.push(0);
+        Ok(args)
+    }
 }
 
 /// Status of an exited container
@@ -36,6 +173,7 @@ pub struct Container {
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct ExitStatus {
     pub(crate) code: i32,
+    pub(crate) signal: Option<i32>,
 }
 
 impl ExitStatus {
@@ -43,8 +181,20 @@ impl ExitStatus {
         self.code == 0
     }
 
+    /// Return the exit code, or `None` if the container was killed by a
+    /// signal
     pub fn code(&self) -> Option<i32> {
-        Some(self.code)
+        match self.signal {
+            None => Some(self.code),
+            Some(_) => None,
+        }
+    }
+
+    /// If the container was killed by a signal, return that signal number
+    ///
+    /// Like [std::os::unix::process::ExitStatusExt::signal()].
+    pub fn signal(&self) -> Option<i32> {
+        self.signal
     }
 }
 
@@ -92,6 +242,32 @@ fn expect_broken_pipe(result: io::Result<()>) -> io::Result<()> {
     }
 }
 
+fn take_async_reader(
+    stream: &mut Option<UnixStream>,
+) -> io::Result<Option<BufReader<tokio::net::UnixStream>>> {
+    match stream.take() {
+        None => Ok(None),
+        Some(stream) => Ok(Some(BufReader::new(tokio::net::UnixStream::from_std(
+            stream,
+        )?))),
+    }
+}
+
+/// Copy one of the container's output streams to a destination until it
+/// closes
+fn forward_task<W>(stream: Option<UnixStream>, mut dest: W) -> JoinHandle<io::Result<()>>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        if let Some(stream) = stream {
+            let mut stream = tokio::net::UnixStream::from_std(stream)?;
+            tokio::io::copy(&mut stream, &mut dest).await?;
+        }
+        Ok(())
+    })
+}
+
 impl Container {
     /// Prepare to run a new container, starting with an [Image] loaded
     pub fn new(image: Arc<Image>) -> Result<ContainerBuilder, ImageError> {
@@ -119,15 +295,200 @@ impl Container {
         Container::new(RegistryClient::new()?.pull(name).await?)
     }
 
+    /// Prepare to run a new container from an image in a local OCI image
+    /// layout directory
+    ///
+    /// This is equivalent to using [RegistryClient::from_oci_layout()] with
+    /// the default client settings, followed by [Container::new()].
+    pub async fn from_oci_layout(
+        dir: &Path,
+        tag: Option<&str>,
+    ) -> Result<ContainerBuilder, ImageError> {
+        Container::new(RegistryClient::new()?.from_oci_layout(dir, tag).await?)
+    }
+
+    /// Take the container's stdout stream, for incremental reading
+    ///
+    /// The stream is wrapped for asynchronous line-at-a-time reading. Once
+    /// taken, it's no longer forwarded by [Container::interact()] or captured
+    /// by [Container::output()]. Returns `Ok(None)` if the stream was already
+    /// taken or was overridden with [ContainerBuilder::stdout()].
+    ///
+    /// This must be called from within a tokio runtime.
+    pub fn take_stdout(&mut self) -> io::Result<Option<impl AsyncBufRead + Unpin + Send>> {
+        take_async_reader(&mut self.stdout)
+    }
+
+    /// Take the container's stderr stream, for incremental reading
+    ///
+    /// Works like [Container::take_stdout()].
+    pub fn take_stderr(&mut self) -> io::Result<Option<impl AsyncBufRead + Unpin + Send>> {
+        take_async_reader(&mut self.stderr)
+    }
+
+    /// Run an additional command alongside this container, like `docker exec`
+    ///
+    /// This is meant for health and readiness checks. The probe runs inside
+    /// the container, as another process next to its first one. It shares the
+    /// container's filesystem as it is now, and starts in the same working
+    /// directory with the container's environment extended by `env`. The
+    /// probe's output is discarded.
+    ///
+    /// If the container exits before the probe does, the probe is killed and
+    /// this returns [RuntimeError::ContainerExited].
+    pub async fn exec_probe<I, S, E, K, V>(
+        &self,
+        argv: I,
+        env: E,
+    ) -> Result<ExitStatus, RuntimeError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+        E: IntoIterator<Item = (K, V)>,
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        let mut probe_env = self.context.env.clone();
+        for (key, value) in env {
+            builder::env::set(
+                &mut probe_env,
+                key.as_ref().as_bytes(),
+                Some(value.as_ref().as_bytes()),
+            )?;
+        }
+        let mut probe_argv = Vec::new();
+        for arg in argv {
+            probe_argv.push(CString::new(arg.as_ref().as_bytes())?);
+        }
+        let args = self.context.loader_args(probe_argv, None, &probe_env)?;
+
+        let (stdin, remote_stdin) = SharedStream::pair()?;
+        let (stdout, remote_stdout) = SharedStream::pair()?;
+        let (stderr, remote_stderr) = SharedStream::pair()?;
+        drop(stdin);
+        forward_task(Some(stdout), tokio::io::sink());
+        forward_task(Some(stderr), tokio::io::sink());
+
+        let (mut args_local, args_remote) = fd_queue::tokio::UnixStream::pair()?;
+        let (reply, status) = oneshot::channel();
+        self.probes
+            .send(Probe {
+                args: args_remote,
+                stdio: [remote_stdin, remote_stdout, remote_stderr],
+                reply,
+            })
+            .map_err(|_| RuntimeError::ContainerExited)?;
+        args_local.write_all(&args).await?;
+        drop(args_local);
+        status.await.map_err(|_| RuntimeError::ContainerExited)?
+    }
+
+    /// Kill the container's first process with `SIGKILL`
+    ///
+    /// This can't be caught or ignored, so it ends a container that's stuck.
+    /// Killing a container that has already exited does nothing.
+    pub fn kill(&self) -> io::Result<()> {
+        self.signal(libc::SIGKILL);
+        Ok(())
+    }
+
+    /// Send the container its stop signal, asking it to exit
+    ///
+    /// This is the image's `STOPSIGNAL` if it has one, or `SIGTERM`, unless
+    /// overridden with [ContainerBuilder::stop_signal()].
+    pub fn terminate(&self) {
+        self.signal(self.context.stop_signal)
+    }
+
+    /// Send a signal to the container's first process, like `docker kill`
+    ///
+    /// Signals sent after the container exits are ignored. A container killed
+    /// by a signal has no exit code, only [ExitStatus::signal()].
+    pub fn signal(&self, signal: i32) {
+        // The receiver only goes away once the container has exited
+        let _ = self.signals.send(signal);
+    }
+
+    /// List the virtual PIDs of the container's processes, in order
+    ///
+    /// This is a snapshot which can be out of date as soon as it returns.
+    /// Once the container exits, the list is empty.
+    pub fn pids(&self) -> Vec<u32> {
+        let live_pids = self.live_pids.lock().unwrap();
+        live_pids.iter().map(|pid| pid.0).collect()
+    }
+
+    /// Check whether the container is still running, without waiting
+    ///
+    /// Once this sees the container has exited, its exit status is kept for
+    /// [Container::wait()] or whichever method finally consumes the
+    /// [Container].
+    pub fn is_running(&mut self) -> bool {
+        if self.exited.is_none() {
+            if let Some(joined) = (&mut self.join).now_or_never() {
+                self.exited = Some(joined.map_err(RuntimeError::from).and_then(|result| result));
+            }
+        }
+        self.exited.is_none()
+    }
+
     /// Wait for the container to finish running, if necessary, and return its
     /// exit status.
-    pub async fn wait(self) -> Result<ExitStatus, RuntimeError> {
+    pub async fn wait(mut self) -> Result<ExitStatus, RuntimeError> {
         log::trace!("wait starting");
-        let result = self.join.await?;
+        let result = self.join_status().await;
         log::trace!("wait complete -> {:?}", result);
         result
     }
 
+    async fn join_status(&mut self) -> Result<ExitStatus, RuntimeError> {
+        match self.exited.take() {
+            Some(result) => result,
+            None => (&mut self.join).await?,
+        }
+    }
+
+    /// Wait up to `timeout` for the container to finish, then send its stop
+    /// signal and wait for it to exit
+    ///
+    /// The stop signal is the same one [Container::terminate()] sends.
+    pub async fn wait_timeout(mut self, timeout: Duration) -> Result<ExitStatus, RuntimeError> {
+        if let Some(result) = self.join_timeout(timeout).await {
+            return result;
+        }
+        log::trace!("wait timed out, sending stop signal");
+        self.terminate();
+        self.wait().await
+    }
+
+    /// Stop the container like `docker stop`, returning its final exit status
+    ///
+    /// The container gets its stop signal first, as with
+    /// [Container::terminate()]. If it's still running after `grace`, it's
+    /// killed with `SIGKILL`, as with [Container::kill()].
+    pub async fn stop(mut self, grace: Duration) -> Result<ExitStatus, RuntimeError> {
+        self.terminate();
+        if let Some(result) = self.join_timeout(grace).await {
+            return result;
+        }
+        log::trace!("stop grace period expired, sending SIGKILL");
+        self.kill()?;
+        self.wait().await
+    }
+
+    async fn join_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> Option<Result<ExitStatus, RuntimeError>> {
+        if let Some(result) = self.exited.take() {
+            return Some(result);
+        }
+        match time::timeout(timeout, &mut self.join).await {
+            Err(_) => None,
+            Ok(joined) => Some(joined.map_err(RuntimeError::from).and_then(|result| result)),
+        }
+    }
+
     /// Wait for the container to finish running, while connecting it to stdio
     ///
     /// Any stdio streams which haven't been taken from the [Container] or
@@ -138,9 +499,9 @@ impl Container {
     /// the blocking stdin reads. This thread may continue running after
     /// the container itself exits, since `std`'s stdin reads cannot be
     /// cancelled.
-    pub async fn interact(self) -> Result<ExitStatus, RuntimeError> {
+    pub async fn interact(mut self) -> Result<ExitStatus, RuntimeError> {
         log::trace!("interact starting");
-        if let Some(mut stream) = self.stdin {
+        if let Some(mut stream) = self.stdin.take() {
             let _ = thread::Builder::new()
                 .name("stdin".to_string())
                 .spawn(move || {
@@ -148,24 +509,10 @@ impl Container {
                 });
         }
 
-        let stdout = self.stdout;
-        let stdout = tokio::spawn(async move {
-            if let Some(stream) = stdout {
-                let mut stream = tokio::net::UnixStream::from_std(stream)?;
-                tokio::io::copy(&mut stream, &mut tokio::io::stdout()).await?;
-            }
-            Ok::<(), tokio::io::Error>(())
-        });
-        let stderr = self.stderr;
-        let stderr = tokio::spawn(async move {
-            if let Some(stream) = stderr {
-                let mut stream = tokio::net::UnixStream::from_std(stream)?;
-                tokio::io::copy(&mut stream, &mut tokio::io::stderr()).await?;
-            }
-            Ok::<(), tokio::io::Error>(())
-        });
+        let stdout = forward_task(self.stdout.take(), tokio::io::stdout());
+        let stderr = forward_task(self.stderr.take(), tokio::io::stderr());
 
-        let status = self.join.await??;
+        let status = self.join_status().await?;
         log::trace!("interact waiting for stdout/stderr");
         let (stdout, stderr) = tokio::join!(stdout, stderr);
         expect_broken_pipe(stdout?)?;
@@ -180,8 +527,8 @@ impl Container {
     /// taken from the [Container] or overridden with [ContainerBuilder].
     ///
     /// If stdin has not been taken or overridden, it will be dropped.
-    pub async fn output(self) -> Result<Output, RuntimeError> {
-        drop(self.stdin);
+    pub async fn output(mut self) -> Result<Output, RuntimeError> {
+        drop(self.stdin.take());
 
         fn output_task(stream: Option<UnixStream>) -> JoinHandle<tokio::io::Result<Vec<u8>>> {
             task::spawn(async move {
@@ -193,11 +540,11 @@ impl Container {
                 Ok(buf)
             })
         }
-        let stdout = output_task(self.stdout);
-        let stderr = output_task(self.stderr);
+        let stdout = output_task(self.stdout.take());
+        let stderr = output_task(self.stderr.take());
 
         log::trace!("output wait starting");
-        let status = self.join.await??;
+        let status = self.join_status().await?;
         let stdout = stdout.await??;
         let stderr = stderr.await??;
         let result = Output {
@@ -210,67 +557,76 @@ impl Container {
         Ok(result)
     }
 
+    /// Close stdin and forward stdout and stderr in the background, for
+    /// [ContainerBuilder::detach()]
+    pub(crate) fn forward_detached(&mut self) {
+        drop(self.stdin.take());
+        forward_task(self.stdout.take(), tokio::io::stdout());
+        forward_task(self.stderr.take(), tokio::io::stderr());
+    }
+
     pub(crate) fn exec(
-        filesystem: Filesystem,
-        storage: FileStorage,
-        filename: CString,
-        dir: CString,
+        context: ExecContext,
         argv: Vec<CString>,
-        env: Vec<CString>,
-        stdio: [Option<UnixStream>; 3],
-        tracer_settings: TracerSettings,
+        arg0: Option<CString>,
+        mut stdio: [Option<SharedStream>; 3],
     ) -> Result<Container, RuntimeError> {
-        log::debug!(
-            "exec file={:?} dir={:?} argv={:?} env={:?}",
-            filename,
-            dir,
-            argv,
-            env
-        );
-
-        let filename = filename.into_bytes_with_nul();
-        let dir = dir.into_bytes_with_nul();
-        let argv: Vec<Vec<u8>> = argv.into_iter().map(CString::into_bytes_with_nul).collect();
-        let env: Vec<Vec<u8>> = env.into_iter().map(CString::into_bytes_with_nul).collect();
+        let args = context.loader_args(argv, arg0, &context.env)?;
 
-        let args_header = InitArgsHeader {
-            dir_len: dir.len(),
-            filename_len: filename.len(),
-            argv_len: argv.iter().map(Vec::len).sum::<usize>() + 1,
-            envp_len: env.iter().map(Vec::len).sum::<usize>() + 1,
-            arg_count: argv.len(),
-            env_count: env.len(),
-        };
+        let mut filesystem = context.filesystem.clone();
+        let mut local_stdio: [Option<UnixStream>; 3] = [None, None, None];
+        for fd in 0..3 {
+            let remote_stream = match stdio[fd].take() {
+                Some(stream) => stream,
+                None => {
+                    let (local, remote) = SharedStream::pair()?;
+                    local_stdio[fd] = Some(local);
+                    remote
+                }
+            };
+            remote_stream.mount(&mut filesystem, &Path::new(&format!("/proc/1/fd/{}", fd)))?;
+        }
+        devices::install(&mut filesystem)?;
 
-        let [stdin, stdout, stderr] = stdio;
+        let storage = context.storage.clone();
+        let tracer_settings = context.tracer_settings.clone();
+        let priority = context.priority;
+        let log_handler = context.log_handler.clone();
+        let [stdin, stdout, stderr] = local_stdio;
+        let (signals, signals_remote) = mpsc::unbounded_channel();
+        let (probes, probes_remote) = mpsc::unbounded_channel();
+        let deadline = context
+            .timeout
+            .map(|timeout| time::Instant::now() + timeout);
+        let live_pids = LivePids::default();
+        let server_pids = live_pids.clone();
 
         Ok(Container {
             stdin,
             stdout,
             stderr,
+            signals,
+            probes,
+            exited: None,
+            context: Arc::new(context),
+            live_pids,
             join: tokio::spawn(async move {
                 let ipc_task = {
                     let (args_local, args_remote) = fd_queue::tokio::UnixStream::pair()?;
                     let mut args_buf = BufWriter::new(args_local);
-                    let ipc_task =
-                        IPCServer::new(filesystem, storage, &args_remote, tracer_settings)
-                            .await?
-                            .task();
-
-                    args_buf.write_all(args_header.as_bytes()).await?;
-                    args_buf.write_all(&dir).await?;
-                    args_buf.write_all(&filename).await?;
-
-                    for bytes in argv {
-                        args_buf.write_all(&bytes).await?;
-                    }
-                    args_buf.write_all(b"\0").await?;
-
-                    for bytes in env {
-                        args_buf.write_all(&bytes).await?;
-                    }
-                    args_buf.write_all(b"\0").await?;
+                    let ipc_task = IPCServer::new(
+                        filesystem,
+                        storage,
+                        &args_remote,
+                        tracer_settings,
+                        &priority,
+                        server_pids,
+                        log_handler,
+                    )
+                    .await?
+                    .task(signals_remote, probes_remote, deadline);
 
+                    args_buf.write_all(&args).await?;
                     args_buf.flush().await?;
                     ipc_task
                 };
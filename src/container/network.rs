@@ -0,0 +1,45 @@
+use crate::{
+    errors::RuntimeError,
+    filesystem::{storage::FileStorage, vfs::Filesystem},
+    sand::protocol::{abi, FileStat, FollowLinks},
+};
+use std::path::Path;
+
+/// Name resolution files that C libraries read whether or not there's a
+/// network to use them with
+const STUB_FILES: &[(&str, &[u8])] = &[
+    ("/etc/hosts", b"127.0.0.1\tlocalhost\n::1\tlocalhost\n"),
+    ("/etc/resolv.conf", b""),
+    (
+        "/etc/nsswitch.conf",
+        b"hosts: files dns\nnetworks: files\npasswd: files\ngroup: files\n",
+    ),
+];
+
+/// Add minimal networking configuration files to the container
+///
+/// Only files missing from the image are added, and those are read-only.
+pub fn install(filesystem: &mut Filesystem, storage: &FileStorage) -> Result<(), RuntimeError> {
+    for (path, contents) in STUB_FILES {
+        let path = Path::new(path);
+        if filesystem
+            .lookup(&Filesystem::root(), path, &FollowLinks::NoFollow)
+            .is_ok()
+        {
+            continue;
+        }
+        let key = if contents.is_empty() {
+            None
+        } else {
+            Some(storage.insert_blob(contents)?)
+        };
+        let stat = FileStat {
+            st_mode: abi::S_IFREG | 0o444,
+            st_size: contents.len() as i64,
+            ..Default::default()
+        };
+        filesystem.writer().write_storage_file(path, stat, key)?;
+        filesystem.set_read_only(path)?;
+    }
+    Ok(())
+}
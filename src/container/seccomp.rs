@@ -0,0 +1,208 @@
+use crate::{
+    errors::RuntimeError,
+    sand::protocol::{
+        abi::{OPTIONAL_PASSTHROUGH_SYSCALLS, PASSTHROUGH_SYSCALLS},
+        SeccompRule, SeccompRuleList, SECCOMP_RULE_LOG,
+    },
+};
+
+/// The x86_64 syscall numbers a container may pass through to the host
+/// kernel by default
+///
+/// Every other syscall is emulated by the sandbox runtime or denied. The set
+/// can be narrowed with
+/// [ContainerBuilder::passthrough_syscalls()](crate::ContainerBuilder::passthrough_syscalls),
+/// or extended from [optional_passthrough_syscalls()].
+pub fn default_passthrough_syscalls() -> &'static [u64] {
+    PASSTHROUGH_SYSCALLS
+}
+
+/// The x86_64 syscall numbers a container may add to its passthrough set
+///
+/// These are normally emulated or denied, but none of them take a path or a
+/// pid. Passing them through with
+/// [ContainerBuilder::extra_passthrough_syscalls()](crate::ContainerBuilder::extra_passthrough_syscalls)
+/// trades the sandbox's view of clocks, randomness, and open files for speed.
+pub fn optional_passthrough_syscalls() -> &'static [u64] {
+    OPTIONAL_PASSTHROUGH_SYSCALLS
+}
+
+/// Bitmask of default passthrough syscalls missing from `allowed`
+pub(crate) fn denied_passthrough(allowed: Option<&[u64]>) -> Result<u64, RuntimeError> {
+    let allowed = match allowed {
+        None => return Ok(0),
+        Some(allowed) => allowed,
+    };
+    if let Some(nr) = allowed.iter().find(|nr| !PASSTHROUGH_SYSCALLS.contains(nr)) {
+        return Err(RuntimeError::NotPassthroughSyscall(*nr));
+    }
+    Ok(PASSTHROUGH_SYSCALLS
+        .iter()
+        .enumerate()
+        .filter(|(_, nr)| !allowed.contains(nr))
+        .fold(0, |mask, (index, _)| mask | (1 << index)))
+}
+
+/// Bitmask of optional passthrough syscalls in `extra`
+pub(crate) fn extra_passthrough(extra: &[u64]) -> Result<u64, RuntimeError> {
+    let mut mask = 0;
+    for nr in extra {
+        let index = OPTIONAL_PASSTHROUGH_SYSCALLS
+            .iter()
+            .position(|optional| optional == nr)
+            .ok_or(RuntimeError::NotOptionalPassthroughSyscall(*nr))?;
+        mask |= 1 << index;
+    }
+    Ok(mask)
+}
+
+/// What happens when a container attempts a syscall named in its
+/// [SeccompProfile]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SeccompAction {
+    /// Fail the syscall, returning this positive errno value
+    Errno(u16),
+    /// Deliver SIGSYS to the calling thread
+    Trap,
+    /// Allow the syscall, but record it in the host's kernel audit log
+    Log,
+}
+
+/// Extra restrictions on host syscalls made by a container
+///
+/// Most syscalls a container makes are emulated, but a set of fd and memory
+/// operations pass through to the host kernel. A profile can deny some of
+/// those passthrough syscalls outright. It's applied as an additional seccomp
+/// filter on top of the built-in one, so it can only ever tighten the rules;
+/// syscalls that are emulated or already denied are not affected.
+///
+/// The sandbox runtime issues some syscalls on the container's behalf from
+/// inside the container process, so denying memory or file calls it depends
+/// on may prevent the container from starting at all.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct SeccompProfile {
+    rules: Vec<(u32, SeccompAction)>,
+}
+
+impl SeccompProfile {
+    /// Start a new profile that denies nothing
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Deny a host syscall, by x86_64 syscall number
+    ///
+    /// Denying the same syscall again replaces the earlier action, except
+    /// that [SeccompAction::Log] never replaces an action that fails the
+    /// syscall.
+    pub fn deny(mut self, nr: u32, action: SeccompAction) -> Self {
+        if action == SeccompAction::Log && self.rules.iter().any(|(existing, _)| *existing == nr) {
+            return self;
+        }
+        self.rules.retain(|(existing, _)| *existing != nr);
+        self.rules.push((nr, action));
+        self
+    }
+
+    /// Audit a host syscall, by x86_64 syscall number
+    ///
+    /// The syscall still passes through, but the kernel logs each use, as
+    /// with `SECCOMP_RET_LOG`. Entries appear in the audit log or kernel log
+    /// depending on the host's configuration. This is shorthand for denying
+    /// with [SeccompAction::Log], so a syscall that's already denied stays
+    /// denied.
+    pub fn log(self, nr: u32) -> Self {
+        self.deny(nr, SeccompAction::Log)
+    }
+
+    pub(crate) fn to_rule_list(&self) -> Result<SeccompRuleList, RuntimeError> {
+        let mut list = SeccompRuleList::default();
+        for (nr, action) in &self.rules {
+            let errno = match action {
+                SeccompAction::Trap => 0,
+                SeccompAction::Log => SECCOMP_RULE_LOG,
+                SeccompAction::Errno(0) => return Err(RuntimeError::InvalidSeccompProfile),
+                SeccompAction::Errno(errno) => *errno as u32,
+            };
+            if !list.insert(SeccompRule { nr: *nr, errno }) {
+                return Err(RuntimeError::InvalidSeccompProfile);
+            }
+        }
+        Ok(list)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passthrough_subset() {
+        let read = libc::SYS_read as u64;
+        let write = libc::SYS_write as u64;
+        assert!(default_passthrough_syscalls().contains(&read));
+        assert_eq!(denied_passthrough(None).unwrap(), 0);
+        assert_eq!(
+            denied_passthrough(Some(default_passthrough_syscalls())).unwrap(),
+            0
+        );
+        let without_read: Vec<u64> = default_passthrough_syscalls()
+            .iter()
+            .cloned()
+            .filter(|nr| *nr != read)
+            .collect();
+        let mask = denied_passthrough(Some(&without_read)).unwrap();
+        assert_eq!(mask.count_ones(), 1);
+        let index = mask.trailing_zeros() as usize;
+        assert_eq!(default_passthrough_syscalls()[index], read);
+        assert_eq!(
+            denied_passthrough(Some(&[write])).unwrap().count_ones() as usize,
+            default_passthrough_syscalls().len() - 1
+        );
+        match denied_passthrough(Some(&[libc::SYS_openat as u64])) {
+            Err(RuntimeError::NotPassthroughSyscall(nr)) => {
+                assert_eq!(nr, libc::SYS_openat as u64)
+            }
+            other => panic!("unexpected result, {:?}", other),
+        }
+    }
+
+    #[test]
+    fn optional_passthrough() {
+        let getrandom = libc::SYS_getrandom as u64;
+        assert!(optional_passthrough_syscalls().contains(&getrandom));
+        assert_eq!(extra_passthrough(&[]).unwrap(), 0);
+        let mask = extra_passthrough(&[getrandom, getrandom]).unwrap();
+        assert_eq!(mask.count_ones(), 1);
+        let index = mask.trailing_zeros() as usize;
+        assert_eq!(optional_passthrough_syscalls()[index], getrandom);
+        for nr in &[libc::SYS_ptrace, libc::SYS_mount, libc::SYS_read] {
+            match extra_passthrough(&[getrandom, *nr as u64]) {
+                Err(RuntimeError::NotOptionalPassthroughSyscall(denied)) => {
+                    assert_eq!(denied, *nr as u64)
+                }
+                other => panic!("unexpected result, {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn log_action() {
+        let list = SeccompProfile::new()
+            .deny(1, SeccompAction::Trap)
+            .log(0)
+            .log(1)
+            .to_rule_list()
+            .unwrap();
+        assert_eq!(
+            list.as_slice(),
+            &[
+                SeccompRule { nr: 1, errno: 0 },
+                SeccompRule {
+                    nr: 0,
+                    errno: SECCOMP_RULE_LOG
+                },
+            ]
+        );
+    }
+}
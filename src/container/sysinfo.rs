@@ -0,0 +1,19 @@
+use std::mem;
+
+/// Total RAM on the host, the default memory budget the container sees
+pub fn host_total_ram() -> u64 {
+    let mut info: libc::sysinfo = unsafe { mem::zeroed() };
+    if unsafe { libc::sysinfo(&mut info) } == 0 {
+        info.totalram as u64 * info.mem_unit as u64
+    } else {
+        0
+    }
+}
+
+/// The host's monotonic clock, which the tracer also reads to work out
+/// the container's uptime
+pub fn monotonic_now() -> u64 {
+    let mut ts: libc::timespec = unsafe { mem::zeroed() };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
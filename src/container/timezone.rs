@@ -0,0 +1,73 @@
+use crate::{
+    errors::RuntimeError,
+    filesystem::{storage::FileStorage, vfs::Filesystem},
+    sand::protocol::{abi, FileStat, FollowLinks},
+};
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+/// Where the host keeps compiled tz database files
+const HOST_ZONEINFO: &str = "/usr/share/zoneinfo";
+
+/// Where C libraries look for zone files named by `TZ`
+const GUEST_ZONEINFO: &str = "/usr/share/zoneinfo";
+
+/// Where C libraries look for the default zone when `TZ` is unset
+const LOCALTIME: &str = "/etc/localtime";
+
+/// Zone names are relative paths under the zoneinfo directory, like
+/// `UTC` or `America/Los_Angeles`
+fn zone_path(zone: &str) -> Result<&Path, RuntimeError> {
+    let path = Path::new(zone);
+    let is_normal = |c| matches!(c, Component::Normal(_));
+    if zone.is_empty() || !path.components().all(is_normal) {
+        Err(RuntimeError::UnknownTimezone(zone.to_owned()))
+    } else {
+        Ok(path)
+    }
+}
+
+/// Copy a zone file from the host into the container
+///
+/// The zone is installed as `/etc/localtime` and under `/usr/share/zoneinfo`,
+/// so it's found whether or not `TZ` is set. Files the image already has are
+/// left alone.
+pub fn install(
+    filesystem: &mut Filesystem,
+    storage: &FileStorage,
+    zone: &str,
+) -> Result<(), RuntimeError> {
+    let zone_path = zone_path(zone)?;
+    let guest_paths = [
+        PathBuf::from(LOCALTIME),
+        Path::new(GUEST_ZONEINFO).join(zone_path),
+    ];
+    let missing: Vec<&PathBuf> = guest_paths
+        .iter()
+        .filter(|path| {
+            filesystem
+                .lookup(&Filesystem::root(), path, &FollowLinks::NoFollow)
+                .is_err()
+        })
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    let contents = fs::read(Path::new(HOST_ZONEINFO).join(zone_path))
+        .map_err(|_| RuntimeError::UnknownTimezone(zone.to_owned()))?;
+    let key = storage.insert_blob(&contents)?;
+
+    let stat = FileStat {
+        st_mode: abi::S_IFREG | 0o644,
+        st_size: contents.len() as i64,
+        ..Default::default()
+    };
+    let mut writer = filesystem.writer();
+    for path in missing {
+        writer.write_storage_file(path, stat.clone(), Some(key.clone()))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,111 @@
+use crate::{
+    errors::RuntimeError,
+    filesystem::{storage::FileStorage, vfs::Filesystem},
+    sand::protocol::FollowLinks,
+};
+use std::path::Path;
+
+const PASSWD: &str = "/etc/passwd";
+const GROUP: &str = "/etc/group";
+
+/// Read a text database from the container, treating a missing file as empty
+fn read_database(
+    filesystem: &Filesystem,
+    storage: &FileStorage,
+    path: &str,
+) -> Result<String, RuntimeError> {
+    let file = match filesystem.lookup(&Filesystem::root(), Path::new(path), &FollowLinks::Follow) {
+        Ok(file) => file,
+        Err(_) => return Ok(String::new()),
+    };
+    let contents = match filesystem.storage_key(&file)? {
+        None => Vec::new(),
+        Some(key) => storage.read_to_vec(key)?.unwrap_or_default(),
+    };
+    Ok(String::from_utf8_lossy(&contents).into_owned())
+}
+
+/// Find the line from a passwd or group database whose name or numeric ID
+/// matches, returning its ID and the field after it
+fn find_entry<'a>(database: &'a str, name: &str) -> Option<(u32, &'a str)> {
+    database.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        let entry_name = fields.next()?;
+        let id: u32 = fields.nth(1)?.parse().ok()?;
+        let next = fields.next().unwrap_or("");
+        if entry_name == name || name.parse() == Ok(id) {
+            Some((id, next))
+        } else {
+            None
+        }
+    })
+}
+
+/// Parse a `user[:group]` string against the contents of the passwd and
+/// group files
+///
+/// Either part may be a name or a numeric ID. Names must exist in the
+/// database, but numeric IDs don't need to. Without a group, the user's
+/// primary group is used, or group 0 if the user isn't in the passwd file.
+fn parse_user(spec: &str, passwd: &str, group: &str) -> Option<(u32, u32)> {
+    let mut parts = spec.splitn(2, ':');
+    let user = parts.next().unwrap_or("");
+    let (uid, primary_gid) = match (user.parse::<u32>(), find_entry(passwd, user)) {
+        (_, Some((uid, gid))) => (uid, gid.parse().ok()),
+        (Ok(uid), None) => (uid, None),
+        (Err(_), None) => return None,
+    };
+    let gid = match parts.next() {
+        None => primary_gid.unwrap_or(0),
+        Some(name) => match (name.parse::<u32>(), find_entry(group, name)) {
+            (_, Some((gid, _))) => gid,
+            (Ok(gid), None) => gid,
+            (Err(_), None) => return None,
+        },
+    };
+    Some((uid, gid))
+}
+
+/// Find the uid and gid named by an image's `User` setting
+///
+/// An empty setting means root. Names are looked up in the container's own
+/// `/etc/passwd` and `/etc/group`, like Docker does.
+pub fn resolve(
+    filesystem: &Filesystem,
+    storage: &FileStorage,
+    spec: &str,
+) -> Result<(u32, u32), RuntimeError> {
+    if spec.is_empty() {
+        return Ok((0, 0));
+    }
+    let passwd = read_database(filesystem, storage, PASSWD)?;
+    let group = if spec.contains(':') {
+        read_database(filesystem, storage, GROUP)?
+    } else {
+        String::new()
+    };
+    parse_user(spec, &passwd, &group).ok_or_else(|| RuntimeError::UnknownUser(spec.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSWD: &str = "root:x:0:0:root:/root:/bin/sh\n\
+                          daemon:x:1:1:daemon:/usr/sbin:/bin/false\n\
+                          nobody:x:65534:65534:nobody:/home:/bin/false\n";
+    const GROUP: &str = "root:x:0:\nwheel:x:10:root\nnogroup:x:65533:\n";
+
+    #[test]
+    fn user_names_and_ids() {
+        let parse = |spec| parse_user(spec, PASSWD, GROUP);
+        assert_eq!(parse("nobody"), Some((65534, 65534)));
+        assert_eq!(parse("1"), Some((1, 1)));
+        assert_eq!(parse("1000"), Some((1000, 0)));
+        assert_eq!(parse("nobody:wheel"), Some((65534, 10)));
+        assert_eq!(parse("daemon:nogroup"), Some((1, 65533)));
+        assert_eq!(parse("1000:1000"), Some((1000, 1000)));
+        assert_eq!(parse("somebody"), None);
+        assert_eq!(parse("nobody:staff"), None);
+    }
+}
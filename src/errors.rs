@@ -69,10 +69,18 @@ pub enum ImageError {
     #[error("can't determine where to cache image files")]
     NoDefaultCacheDir,
 
-    /// only v2 image manifests are supported
-    #[error("only v2 image manifests are supported")]
+    /// unsupported image manifest schema version
+    #[error("unsupported image manifest schema version")]
     UnsupportedManifestType,
 
+    /// registry sent a kind of manifest we can't pull images from
+    #[error("registry sent a kind of manifest we can't pull images from, {0:?}")]
+    UnsupportedManifestMediaType(String),
+
+    /// manifest list has no image for the platform we run on
+    #[error("manifest list has no image for linux/amd64")]
+    NoMatchingPlatform,
+
     /// unsupported type for runtime config
     #[error("unsupported type for runtime config, {0:?}")]
     UnsupportedRuntimeConfigType(String),
@@ -102,10 +110,38 @@ pub enum ImageError {
     #[error("insecure configuration; refusing to run a manifest downloaded over HTTP with no content digest")]
     InsecureManifest,
 
+    /// docker config file could not be used
+    #[error("docker config file could not be used, invalid {0}")]
+    InvalidDockerConfig(String),
+
+    /// credential helper program failed
+    #[error("credential helper {0} failed: {1}")]
+    CredentialHelper(String, String),
+
     /// registry server requested an unsupported type of authentication
     #[error("registry server requested an unsupported type of authentication: {0:?}")]
     UnsupportedAuthentication(String),
 
+    /// a content digest is required by the current configuration
+    #[error("a content digest is required by the current configuration, not found in {0}")]
+    DigestRequired(crate::image::ImageName),
+
+    /// an explicit registry is required by the current configuration
+    #[error("an explicit registry is required by the current configuration, not found in {0}")]
+    RegistryRequired(crate::image::ImageName),
+
+    /// OCI image layout has no single image matching the requested tag
+    #[error("OCI image layout has no single image matching tag {0:?}")]
+    OciLayoutImageNotFound(Option<String>),
+
+    /// image's entrypoint or command is not an executable file in the image
+    #[error("image's entrypoint or command {0:?} is not an executable file in the image")]
+    EntrypointMissing(String),
+
+    /// container working directory must be an absolute path
+    #[error("container working directory must be an absolute path: {0:?}")]
+    RelativeWorkingDir(std::path::PathBuf),
+
     /// calculated digest of downloaded content is not what we asked for
     #[error("calculated digest of downloaded content is not what we asked for, expected {expected}, found {found}")]
     ContentDigestMismatch {
@@ -145,6 +181,50 @@ pub enum RuntimeError {
     #[error("virtual filesystem error: {0}")]
     VFSError(#[from] VFSError),
 
+    /// too many extra ioctl requests were allowed
+    #[error("too many extra ioctl requests were allowed, can't add {0:#x}")]
+    IoctlListFull(u32),
+
+    /// uname field is too long or contains a nul
+    #[error("uname field is too long or contains a nul: {0:?}")]
+    InvalidUtsName(String),
+
+    /// seccomp profile has too many rules, or an errno of zero
+    #[error("seccomp profile has too many rules, or an errno of zero")]
+    InvalidSeccompProfile,
+
+    /// syscall is not in the default passthrough set, so it can't be allowed
+    #[error("syscall {0} is not in the default passthrough set, so it can't be allowed")]
+    NotPassthroughSyscall(u64),
+
+    /// syscall can't be added to the passthrough set
+    #[error("syscall {0} is not one of the optional passthrough syscalls, so it can't be added")]
+    NotOptionalPassthroughSyscall(u64),
+
+    /// container was killed for making a syscall denied by seccomp
+    #[error("container was killed for making a syscall denied by seccomp, nr {0}")]
+    SeccompViolation(isize),
+
+    /// container ran longer than its time limit and was killed
+    #[error("container ran longer than its time limit and was killed")]
+    Timeout,
+
+    /// container exited before a probe process finished
+    #[error("container exited before a probe process finished")]
+    ContainerExited,
+
+    /// sandbox loader couldn't start the container's program
+    #[error("sandbox loader couldn't start the container's program: {detail}")]
+    LoaderFailed { detail: String },
+
+    /// timezone is not available on the host
+    #[error("timezone {0:?} is not available on the host")]
+    UnknownTimezone(String),
+
+    /// user or group from the image configuration can't be found
+    #[error("user {0:?} is not in the container's passwd or group files")]
+    UnknownUser(String),
+
     /// container has no configured entry point
     #[error("container has no configured entry point")]
     NoEntryPoint,
@@ -231,12 +311,42 @@ pub enum VFSError {
     #[error("too many nested symbolic links")]
     SymbolicLinkLimitExceeded,
 
+    #[error("symbolic link loop")]
+    SymbolicLinkLoop,
+
+    #[error("symbolic link not allowed")]
+    SymbolicLinkNotAllowed,
+
+    #[error("path leaves the starting directory")]
+    PathNotBeneath,
+
     #[error("inode reference count error")]
     INodeRefCountError,
 
     #[error("name too long")]
     NameTooLong,
 
+    #[error("read-only filesystem")]
+    ReadOnly,
+
+    #[error("extended attribute not found")]
+    AttributeNotFound,
+
+    #[error("extended attribute already exists")]
+    AttributeExists,
+
+    #[error("file too large")]
+    FileTooLarge,
+
+    #[error("directory not empty")]
+    DirectoryNotEmpty,
+
+    #[error("file exists")]
+    FileExists,
+
+    #[error("no space left on device")]
+    NoSpace,
+
     #[error("utf8 path conversion error")]
     Utf8Error(#[from] std::str::Utf8Error),
 }
@@ -255,8 +365,18 @@ impl VFSError {
             VFSError::NotFound => libc::ENOENT,
             VFSError::PathSegmentLimitExceeded => libc::ENAMETOOLONG,
             VFSError::SymbolicLinkLimitExceeded => libc::ELOOP,
+            VFSError::SymbolicLinkLoop => libc::ELOOP,
+            VFSError::SymbolicLinkNotAllowed => libc::ELOOP,
+            VFSError::PathNotBeneath => libc::EXDEV,
             VFSError::INodeRefCountError => libc::ENOMEM,
             VFSError::NameTooLong => libc::ENAMETOOLONG,
+            VFSError::ReadOnly => libc::EROFS,
+            VFSError::AttributeNotFound => libc::ENODATA,
+            VFSError::AttributeExists => libc::EEXIST,
+            VFSError::FileTooLarge => libc::EFBIG,
+            VFSError::DirectoryNotEmpty => libc::ENOTEMPTY,
+            VFSError::FileExists => libc::EEXIST,
+            VFSError::NoSpace => libc::ENOSPC,
         }
     }
 }
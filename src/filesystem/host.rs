@@ -0,0 +1,142 @@
+use crate::{
+    errors::VFSError,
+    filesystem::{
+        mount::Mount,
+        vfs::{Filesystem, VFSWriter},
+    },
+    sand::protocol::FileStat,
+};
+use std::{
+    ffi::CString,
+    fs::Metadata,
+    io,
+    os::unix::{ffi::OsStringExt, fs::MetadataExt},
+    path::{Component, Path, PathBuf},
+};
+
+/// A read-only copy of a directory tree from the host
+///
+/// The tree's directories and symbolic links are copied into the container's
+/// filesystem when it's mounted, and each file is opened from the host again
+/// whenever the container opens it. Files added to the host directory later
+/// won't appear, and a file that's been replaced by anything other than a
+/// regular file can't be opened. Symbolic links have to stay within the
+/// directory; one that leads anywhere else fails the mount with
+/// [VFSError::PathNotBeneath]. Sockets, fifos, and devices are left out.
+#[derive(Debug, Clone)]
+pub struct HostDir {
+    path: PathBuf,
+}
+
+impl HostDir {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        HostDir {
+            path: path.as_ref().to_owned(),
+        }
+    }
+}
+
+impl Mount for HostDir {
+    fn mount(&self, fs: &mut Filesystem, path: &Path) -> Result<(), VFSError> {
+        let root = self.path.canonicalize().map_err(io_error)?;
+        let metadata = root.metadata().map_err(io_error)?;
+        if !metadata.is_dir() {
+            return Err(VFSError::DirectoryExpected);
+        }
+        let dir = fs
+            .writer()
+            .write_new_directory(path, host_stat(&metadata))?;
+        write_tree(&mut fs.writer_at(&dir), &root, Path::new(""))?;
+        fs.set_read_only(path)
+    }
+}
+
+fn write_tree(writer: &mut VFSWriter, root: &Path, dir: &Path) -> Result<(), VFSError> {
+    let mut entries = root
+        .join(dir)
+        .read_dir()
+        .and_then(|entries| entries.collect::<Result<Vec<_>, _>>())
+        .map_err(io_error)?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = dir.join(entry.file_name());
+        let host_path = entry.path();
+        let metadata = host_path.symlink_metadata().map_err(io_error)?;
+        let stat = host_stat(&metadata);
+        let file_type = metadata.file_type();
+        if file_type.is_dir() {
+            writer.write_new_directory(&path, stat)?;
+            write_tree(writer, root, &path)?;
+        } else if file_type.is_file() {
+            writer.write_host_file(&path, stat, host_path)?;
+        } else if file_type.is_symlink() {
+            let target = host_path.read_link().map_err(io_error)?;
+            if !link_stays_beneath(root, &root.join(dir), &target) {
+                return Err(VFSError::PathNotBeneath);
+            }
+            let target =
+                CString::new(target.into_os_string().into_vec()).map_err(|_| VFSError::IO)?;
+            writer.write_symlink(&path, stat, target)?;
+        } else {
+            log::warn!("not mounting special file {:?}", host_path);
+        }
+    }
+    Ok(())
+}
+
+/// Would following a link from `dir` to `target` stay beneath `root`?
+///
+/// Both directories are canonical host paths. Links met along the way are
+/// resolved on the host, and any that don't resolve count as leaving.
+fn link_stays_beneath(root: &Path, dir: &Path, target: &Path) -> bool {
+    let mut current = dir.to_owned();
+    for component in target.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                current.pop();
+            }
+            Component::Normal(name) => {
+                current.push(name);
+                let is_link = current
+                    .symlink_metadata()
+                    .map(|metadata| metadata.file_type().is_symlink())
+                    .unwrap_or(false);
+                if is_link {
+                    match current.canonicalize() {
+                        Ok(resolved) => current = resolved,
+                        Err(_) => return false,
+                    }
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return false,
+        }
+        if !current.starts_with(root) {
+            return false;
+        }
+    }
+    true
+}
+
+fn host_stat(metadata: &Metadata) -> FileStat {
+    FileStat {
+        st_mode: metadata.mode(),
+        st_uid: metadata.uid(),
+        st_gid: metadata.gid(),
+        st_size: metadata.size() as i64,
+        st_atime: metadata.atime() as u64,
+        st_atime_nsec: metadata.atime_nsec() as u64,
+        st_mtime: metadata.mtime() as u64,
+        st_mtime_nsec: metadata.mtime_nsec() as u64,
+        st_ctime: metadata.ctime() as u64,
+        st_ctime_nsec: metadata.ctime_nsec() as u64,
+        ..Default::default()
+    }
+}
+
+fn io_error(err: io::Error) -> VFSError {
+    match err.kind() {
+        io::ErrorKind::NotFound => VFSError::NotFound,
+        _ => VFSError::IO,
+    }
+}
@@ -1,5 +1,9 @@
+pub mod host;
 pub mod mount;
 pub mod socket;
 pub mod storage;
 pub mod tar;
+pub mod tmpfs;
 pub mod vfs;
+
+#[cfg(test)] mod tests;
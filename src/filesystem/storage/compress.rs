@@ -0,0 +1,178 @@
+//! Seekable zstd compression for cached objects
+//!
+//! Objects are split into fixed size chunks, each compressed as an
+//! independent zstd frame. A seek table at the end lists the size of each
+//! frame, so any byte range can be recovered by decompressing only the
+//! chunks that overlap it. The layout matches zstd's "seekable format"
+//! without checksums, and the table lives in a skippable frame so the whole
+//! file is still readable by an ordinary zstd decoder.
+
+use std::{
+    convert::TryInto,
+    io,
+    io::{Read, Write},
+    ops::Range,
+};
+
+/// Uncompressed size of each independently decompressable chunk
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+
+const COMPRESSION_LEVEL: i32 = 3;
+const SKIPPABLE_MAGIC: u32 = 0x184D2A5E;
+const SEEKABLE_MAGIC: u32 = 0x8F92EAB1;
+const SKIPPABLE_HEADER_LEN: usize = 8;
+const FOOTER_LEN: usize = 9;
+const ENTRY_LEN: usize = 8;
+const CHECKSUM_FLAG: u8 = 0x80;
+const RESERVED_FLAGS: u8 = 0x7c;
+
+#[derive(Debug, Clone)]
+struct Chunk {
+    compressed: Range<usize>,
+    decompressed: Range<usize>,
+}
+
+/// Location of every chunk in a compressed object
+#[derive(Debug, Clone)]
+pub struct SeekTable {
+    chunks: Vec<Chunk>,
+}
+
+fn corrupt() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "corrupt seekable zstd storage")
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// Read an entire uncompressed stream, writing it out in seekable form
+pub fn compress<R: Read, W: Write>(mut input: R, output: &mut W) -> io::Result<()> {
+    let mut sizes = Vec::new();
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    loop {
+        let mut len = 0;
+        while len < CHUNK_SIZE {
+            match input.read(&mut chunk[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        if len == 0 {
+            break;
+        }
+        let frame = zstd::stream::encode_all(&chunk[..len], COMPRESSION_LEVEL)?;
+        output.write_all(&frame)?;
+        sizes.push((frame.len() as u32, len as u32));
+        if len < CHUNK_SIZE {
+            break;
+        }
+    }
+
+    let table_len = sizes.len() * ENTRY_LEN + FOOTER_LEN;
+    output.write_all(&SKIPPABLE_MAGIC.to_le_bytes())?;
+    output.write_all(&(table_len as u32).to_le_bytes())?;
+    for (compressed, decompressed) in &sizes {
+        output.write_all(&compressed.to_le_bytes())?;
+        output.write_all(&decompressed.to_le_bytes())?;
+    }
+    output.write_all(&(sizes.len() as u32).to_le_bytes())?;
+    output.write_all(&[0u8])?;
+    output.write_all(&SEEKABLE_MAGIC.to_le_bytes())?;
+    Ok(())
+}
+
+impl SeekTable {
+    /// Locate and check the seek table at the end of a compressed object
+    pub fn parse(compressed: &[u8]) -> io::Result<SeekTable> {
+        if compressed.len() < SKIPPABLE_HEADER_LEN + FOOTER_LEN {
+            return Err(corrupt());
+        }
+        let footer = &compressed[compressed.len() - FOOTER_LEN..];
+        let num_chunks = u32_at(footer, 0) as usize;
+        let descriptor = footer[4];
+        if u32_at(footer, 5) != SEEKABLE_MAGIC || descriptor & (CHECKSUM_FLAG | RESERVED_FLAGS) != 0
+        {
+            return Err(corrupt());
+        }
+        let table_len = num_chunks
+            .checked_mul(ENTRY_LEN)
+            .and_then(|len| len.checked_add(FOOTER_LEN))
+            .ok_or_else(corrupt)?;
+        let frames_len = compressed
+            .len()
+            .checked_sub(SKIPPABLE_HEADER_LEN + table_len)
+            .ok_or_else(corrupt)?;
+        let header = &compressed[frames_len..frames_len + SKIPPABLE_HEADER_LEN];
+        if u32_at(header, 0) != SKIPPABLE_MAGIC || u32_at(header, 4) as usize != table_len {
+            return Err(corrupt());
+        }
+
+        let entries = &compressed[frames_len + SKIPPABLE_HEADER_LEN..];
+        let mut chunks = Vec::with_capacity(num_chunks);
+        let mut compressed_offset = 0;
+        let mut decompressed_offset = 0;
+        for index in 0..num_chunks {
+            let compressed_len = u32_at(entries, index * ENTRY_LEN) as usize;
+            let decompressed_len = u32_at(entries, index * ENTRY_LEN + 4) as usize;
+            let chunk = Chunk {
+                compressed: compressed_offset..compressed_offset + compressed_len,
+                decompressed: decompressed_offset..decompressed_offset + decompressed_len,
+            };
+            compressed_offset = chunk.compressed.end;
+            decompressed_offset = chunk.decompressed.end;
+            chunks.push(chunk);
+        }
+        if compressed_offset != frames_len {
+            return Err(corrupt());
+        }
+        Ok(SeekTable { chunks })
+    }
+
+    /// Total size of the object after decompression
+    pub fn decompressed_len(&self) -> usize {
+        match self.chunks.last() {
+            Some(chunk) => chunk.decompressed.end,
+            None => 0,
+        }
+    }
+
+    /// Decompress one byte range of the object, touching only the chunks
+    /// that overlap it
+    pub fn decompress_range<W: Write>(
+        &self,
+        compressed: &[u8],
+        range: Range<usize>,
+        output: &mut W,
+    ) -> io::Result<()> {
+        if range.start > range.end || range.end > self.decompressed_len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "range is outside compressed storage object",
+            ));
+        }
+        if range.is_empty() {
+            return Ok(());
+        }
+        let overlapping = self
+            .chunks
+            .iter()
+            .skip_while(|chunk| chunk.decompressed.end <= range.start)
+            .take_while(|chunk| chunk.decompressed.start < range.end);
+        for chunk in overlapping {
+            let frame = compressed
+                .get(chunk.compressed.clone())
+                .ok_or_else(corrupt)?;
+            let data = zstd::stream::decode_all(frame)?;
+            if data.len() != chunk.decompressed.len() {
+                return Err(corrupt());
+            }
+            let start = range.start.max(chunk.decompressed.start) - chunk.decompressed.start;
+            let end = range.end.min(chunk.decompressed.end) - chunk.decompressed.start;
+            output.write_all(&data[start..end])?;
+        }
+        Ok(())
+    }
+}
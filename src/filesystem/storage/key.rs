@@ -11,6 +11,9 @@ pub enum StorageKey {
     Blob(ContentDigest),
     BlobPart(ContentDigest, Range<usize>),
     Manifest(Registry, Repository, ImageVersion),
+    GzipLink(ContentDigest),
+    TarIndex(ContentDigest),
+    Token(Registry, Repository, ContentDigest),
 }
 
 impl StorageKey {
@@ -62,6 +65,29 @@ impl StorageKey {
                 path.set_extension("json");
                 path
             }
+            StorageKey::GzipLink(content_digest) => {
+                let mut path = base_dir.to_path_buf();
+                path.push("gzip");
+                path.push(path_encode(content_digest.as_str()));
+                path.set_extension("link");
+                path
+            }
+            StorageKey::TarIndex(content_digest) => {
+                let mut path = base_dir.to_path_buf();
+                path.push("index");
+                path.push(path_encode(content_digest.as_str()));
+                path.set_extension("tar");
+                path
+            }
+            StorageKey::Token(registry, repository, request_digest) => {
+                let mut path = base_dir.to_path_buf();
+                path.push("token");
+                path.push(path_encode(registry.as_str()));
+                path.push(path_encode(repository.as_str()));
+                path.push(path_encode(request_digest.as_str()));
+                path.set_extension("json");
+                path
+            }
         }
     }
 }
@@ -131,6 +157,63 @@ fn path_encode(input: &str) -> String {
     result
 }
 
+/// Recover the original string from the output of [path_encode()]
+///
+/// Returns None if the input isn't something path_encode() could produce.
+pub fn path_decode(encoded: &str) -> Option<String> {
+    let (body, changes) = match encoded.rfind('-') {
+        None => (encoded, ""),
+        Some(idx) => (&encoded[..idx], &encoded[idx + 1..]),
+    };
+    let mut body = body.chars().filter(|ch| *ch != '-');
+    let mut changes = changes.chars();
+    let mut result = String::with_capacity(encoded.len());
+    let mut idx = 0;
+    let mut idx_base = 0;
+
+    // Copy unchanged characters up to a byte index in the original string
+    let copy_to =
+        |result: &mut String, body: &mut dyn Iterator<Item = char>, idx: &mut usize, end| {
+            while *idx < end {
+                result.push(body.next()?);
+                *idx += 1;
+            }
+            Some(())
+        };
+
+    while let Some(op) = next_base18_varint(&mut changes) {
+        if op & 1 == 0 {
+            // Character replacement
+            let target = idx_base + (op >> 1);
+            copy_to(&mut result, &mut body, &mut idx, target)?;
+            let ch = std::char::from_u32(next_base18_varint(&mut changes)? as u32)?;
+            result.push(ch);
+            idx = target + ch.len_utf8();
+            idx_base = target + 1;
+        } else if op & 3 == 1 {
+            // Case conversion
+            let target = idx_base + (op >> 2);
+            copy_to(&mut result, &mut body, &mut idx, target)?;
+            result.push(body.next()?.to_ascii_uppercase());
+            idx = target + 1;
+            idx_base = target + 1;
+        } else if op == 3 {
+            // Placeholder for an empty string
+            if body.next()? != '0' {
+                return None;
+            }
+        } else {
+            return None;
+        }
+    }
+    result.extend(body);
+    if path_encode(&result) == encoded {
+        Some(result)
+    } else {
+        None
+    }
+}
+
 /// Variable length integer encoding using only lowercase alphanumeric chars
 fn push_base18_varint(buf: &mut String, mut value: usize) {
     loop {
@@ -158,6 +241,23 @@ fn push_base18_varint(buf: &mut String, mut value: usize) {
     }
 }
 
+/// Decode one integer written by [push_base18_varint()]
+fn next_base18_varint<I: Iterator<Item = char>>(chars: &mut I) -> Option<usize> {
+    let mut digits = Vec::new();
+    loop {
+        let base36_digit = chars.next()?.to_digit(36)? as usize;
+        if base36_digit < 18 {
+            digits.push(base36_digit);
+            break;
+        }
+        digits.push(base36_digit - 18);
+    }
+    let last = digits.pop()?;
+    digits.iter().rev().try_fold(last, |value, digit| {
+        value.checked_add(1)?.checked_mul(18)?.checked_add(*digit)
+    })
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -229,6 +329,35 @@ mod test {
         assert_eq!(path_encode("0ππ0"), "0-0-2oy12oy1");
     }
 
+    #[test]
+    fn decode_paths() {
+        for input in &[
+            "blah",
+            "--bl----ah",
+            "blAh",
+            "BLAH",
+            "foo::BAR!",
+            ".foo?",
+            "blah-4-9r1-8r12r1",
+            "",
+            "0",
+            "\x00",
+            "0\x00",
+            "\x00\x00",
+            "X\x00",
+            "🐱.m4v",
+            "💀💀💀",
+            "π\x000",
+            "0💀💀0",
+            "sha256:77af778b51abd4a3c51c5ddd97204a9c3ae614ebccb75a606c3b6865aed6744e",
+        ] {
+            assert_eq!(path_decode(&path_encode(input)).as_deref(), Some(*input));
+        }
+        assert_eq!(path_decode("blah-4"), None);
+        assert_eq!(path_decode("Blah"), None);
+        assert_eq!(path_decode("blah-zzz"), None);
+    }
+
     #[test]
     fn storage_paths() {
         assert_eq!(
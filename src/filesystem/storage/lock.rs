@@ -0,0 +1,173 @@
+//! Advisory locks that keep concurrent pulls from downloading the same object
+//!
+//! Locks are only an optimization. Objects are always committed with an
+//! atomic rename, so two writers that both think they hold a lock still leave
+//! a correct cache behind, they just do the work twice.
+
+use crate::{
+    errors::ImageError,
+    filesystem::storage::{FileStorage, StorageKey},
+};
+use memmap::Mmap;
+use std::{
+    fs,
+    fs::OpenOptions,
+    io,
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tokio::time::delay_for;
+
+/// How often to check whether someone else's lock was released
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Locks older than this are assumed to belong to a writer that's stuck
+const STALE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Held while writing one object, and released when dropped
+#[derive(Debug)]
+pub struct StorageLock {
+    path: PathBuf,
+}
+
+impl Drop for StorageLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Has the writer holding this lock gone away?
+///
+/// A lock is stale once it's older than [STALE_TIMEOUT], or when it names a
+/// process that no longer exists. Process IDs are only meaningful on the same
+/// host, so a lock from elsewhere can only go stale by timing out.
+fn is_stale(path: &Path) -> bool {
+    let age = fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok());
+    if let Some(age) = age {
+        if age > STALE_TIMEOUT {
+            return true;
+        }
+    }
+    let pid = fs::read_to_string(path)
+        .ok()
+        .and_then(|contents| contents.trim().parse::<libc::pid_t>().ok());
+    match pid {
+        Some(pid) if pid > 0 => {
+            let result = unsafe { libc::kill(pid, 0) };
+            result != 0 && io::Error::last_os_error().raw_os_error() == Some(libc::ESRCH)
+        }
+        _ => false,
+    }
+}
+
+impl FileStorage {
+    fn lock_path(&self, key: &StorageKey) -> PathBuf {
+        let mut path = key.to_path(&self.path.join("lock")).into_os_string();
+        path.push(".lock");
+        path.into()
+    }
+
+    fn try_lock(&self, key: &StorageKey) -> Result<Option<StorageLock>, ImageError> {
+        let path = self.lock_path(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o644)
+            .open(&path)
+        {
+            Ok(mut file) => {
+                let lock = StorageLock { path };
+                write!(file, "{}", std::process::id())?;
+                Ok(Some(lock))
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                if is_stale(&path) {
+                    log::warn!("taking over stale storage lock {:?}", path);
+                    // If another waiter already took over, we'll both try
+                    // the exclusive create again and only one will win
+                    match fs::remove_file(&path) {
+                        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                        _ => (),
+                    }
+                }
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Lock an object so that nobody else writes it at the same time,
+    /// waiting for any other writer to finish first
+    pub async fn lock(&self, key: &StorageKey) -> Result<StorageLock, ImageError> {
+        let mut waiting = false;
+        loop {
+            if let Some(lock) = self.try_lock(key)? {
+                return Ok(lock);
+            }
+            if !waiting {
+                log::debug!("waiting for another writer of {:?}", key);
+                waiting = true;
+            }
+            delay_for(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Map an object if it's stored, otherwise lock it so we can store it
+    ///
+    /// If someone else is already writing the object, this waits for them
+    /// and then maps what they wrote. The lock is only returned if the object
+    /// is still missing once we hold it.
+    pub async fn mmap_or_lock(
+        &self,
+        key: &StorageKey,
+    ) -> Result<Result<Mmap, StorageLock>, ImageError> {
+        if let Some(map) = self.mmap(key)? {
+            return Ok(Ok(map));
+        }
+        let lock = self.lock(key).await?;
+        match self.mmap(key)? {
+            Some(map) => Ok(Ok(map)),
+            None => Ok(Err(lock)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::ContentDigest;
+    use tempfile::TempDir;
+
+    #[test]
+    fn stale_lock_takeover() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let dir = TempDir::new().unwrap();
+        let storage = FileStorage::new(dir.path().to_owned(), None);
+        let key = StorageKey::Blob(ContentDigest::from_content(b"locked"));
+
+        let lock = rt.block_on(storage.lock(&key)).unwrap();
+        assert!(storage.try_lock(&key).unwrap().is_none());
+        drop(lock);
+
+        // A lock left behind by a process that has exited
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+        fs::write(storage.lock_path(&key), pid.to_string()).unwrap();
+        let lock = rt.block_on(storage.lock(&key)).unwrap();
+        assert_eq!(
+            fs::read_to_string(storage.lock_path(&key)).unwrap(),
+            std::process::id().to_string()
+        );
+        drop(lock);
+        assert!(!storage.lock_path(&key).exists());
+    }
+}
@@ -1,18 +1,27 @@
+mod compress;
 mod key;
+mod lock;
+mod prune;
 mod writer;
 
 pub use key::StorageKey;
 pub use writer::StorageWriter;
 
-use crate::errors::ImageError;
-use memmap::{Mmap, MmapOptions};
+use crate::{errors::ImageError, image::ContentDigest};
+use compress::SeekTable;
+use flate2::bufread::MultiGzDecoder;
+use memmap::{Mmap, MmapMut, MmapOptions};
+use sha2::{Digest, Sha256};
 use std::{
+    collections::HashMap,
     env, fs,
     fs::{File, OpenOptions},
     io,
-    os::unix::fs::OpenOptionsExt,
+    io::{BufReader, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{Arc, Mutex},
 };
 use tempfile::TempDir;
 use tokio::task;
@@ -36,6 +45,14 @@ pub fn default_cache_dir() -> Result<PathBuf, ImageError> {
     }
 }
 
+/// The key a layer's index would be stored at
+fn tar_index(key: &StorageKey) -> Option<StorageKey> {
+    match key {
+        StorageKey::Blob(digest) => Some(StorageKey::TarIndex(digest.clone())),
+        _ => None,
+    }
+}
+
 fn create_parent_dirs(path: &Path) {
     if let Some(parent) = path.parent() {
         if let Err(err) = fs::create_dir_all(parent) {
@@ -46,29 +63,182 @@ fn create_parent_dirs(path: &Path) {
     }
 }
 
+fn open_if_exists(path: &Path) -> Result<Option<File>, ImageError> {
+    match File::open(path) {
+        Err(e) => match e.kind() {
+            io::ErrorKind::NotFound => Ok(None),
+            _ => Err(e.into()),
+        },
+        Ok(f) => Ok(Some(f)),
+    }
+}
+
+/// Open a stored object we're about to use, marking it recently used
+///
+/// The access time is set explicitly, so pruning sees it even on
+/// filesystems mounted with `noatime` or `relatime`.
+fn open_for_use(path: &Path) -> Result<Option<File>, ImageError> {
+    let file = open_if_exists(path)?;
+    if let Some(file) = &file {
+        let times = [
+            libc::timespec {
+                tv_sec: 0,
+                tv_nsec: libc::UTIME_NOW,
+            },
+            libc::timespec {
+                tv_sec: 0,
+                tv_nsec: libc::UTIME_OMIT,
+            },
+        ];
+        unsafe { libc::futimens(file.as_raw_fd(), times.as_ptr()) };
+    }
+    Ok(file)
+}
+
 #[derive(Clone, Debug)]
 pub struct FileStorage {
     path: PathBuf,
     temp_dir: Option<Arc<TempDir>>,
+    compress: bool,
+    pins: Arc<Mutex<HashMap<StorageKey, usize>>>,
 }
 
 impl FileStorage {
     pub fn new(path: PathBuf, temp_dir: Option<Arc<TempDir>>) -> Self {
-        FileStorage { path, temp_dir }
+        FileStorage {
+            path,
+            temp_dir,
+            compress: false,
+            pins: Default::default(),
+        }
+    }
+
+    /// Store new blobs zstd-compressed
+    ///
+    /// Reads of compressed objects are decompressed transparently, and
+    /// objects written by an uncompressed storage are still used when
+    /// present. Blob parts are never compressed, since they're handed to the
+    /// sandbox as plain files.
+    pub fn compressed(mut self) -> Self {
+        self.compress = true;
+        self
+    }
+
+    fn compressed_path(&self, key: &StorageKey) -> PathBuf {
+        let mut path = key.to_path(&self.path).into_os_string();
+        path.push(".zst");
+        path.into()
+    }
+
+    fn mmap_compressed(&self, key: &StorageKey) -> Result<Option<(Mmap, SeekTable)>, ImageError> {
+        match open_for_use(&self.compressed_path(key))? {
+            None => Ok(None),
+            Some(file) => {
+                let map = unsafe { MmapOptions::new().map(&file) }?;
+                let table = SeekTable::parse(&map)?;
+                Ok(Some((map, table)))
+            }
+        }
     }
 
     /// Open one object from local storage, as a File
+    ///
+    /// Compressed objects are decompressed into an anonymous temporary file.
     pub fn open(&self, key: &StorageKey) -> Result<Option<File>, ImageError> {
-        let path = key.to_path(&self.path);
-        match File::open(path) {
-            Err(e) => match e.kind() {
-                io::ErrorKind::NotFound => Ok(None),
-                _ => Err(e.into()),
-            },
-            Ok(f) => Ok(Some(f)),
+        if let Some(file) = open_for_use(&key.to_path(&self.path))? {
+            return Ok(Some(file));
+        }
+        match self.mmap_compressed(key)? {
+            None => Ok(None),
+            Some((map, table)) => {
+                let mut file = tempfile::tempfile()?;
+                table.decompress_range(&map, 0..table.decompressed_len(), &mut file)?;
+                file.seek(SeekFrom::Start(0))?;
+                Ok(Some(file))
+            }
+        }
+    }
+
+    /// Copy a byte range from one stored object
+    ///
+    /// Returns false if the object doesn't exist. For compressed objects,
+    /// this only decompresses the chunks overlapping the range. Blobs only
+    /// stored as a [StorageKey::GzipLink] are decompressed from the start up
+    /// to the end of the range.
+    pub fn read_range<W: Write>(
+        &self,
+        key: &StorageKey,
+        range: Range<usize>,
+        output: &mut W,
+    ) -> Result<bool, ImageError> {
+        if let Some(file) = open_for_use(&key.to_path(&self.path))? {
+            let map = unsafe { MmapOptions::new().map(&file) }?;
+            match map.get(range) {
+                None => Err(ImageError::UnexpectedContentSize),
+                Some(mut slice) => {
+                    io::copy(&mut slice, output)?;
+                    Ok(true)
+                }
+            }
+        } else if let Some((map, table)) = self.mmap_compressed(key)? {
+            table.decompress_range(&map, range, output)?;
+            Ok(true)
+        } else if let Some(mut decoder) = self.open_gzip_link(key)? {
+            let skip = range.start as u64;
+            let len = (range.end - range.start) as u64;
+            if io::copy(&mut (&mut decoder).take(skip), &mut io::sink())? != skip
+                || io::copy(&mut decoder.take(len), output)? != len
+            {
+                return Err(ImageError::UnexpectedContentSize);
+            }
+            Ok(true)
+        } else {
+            Ok(false)
         }
     }
 
+    /// Record that a blob can be recovered by decompressing another, gzip
+    /// compressed, blob
+    ///
+    /// This lets a layer be used without storing it decompressed. The link is
+    /// not checked; the caller should already have hashed the decompressed
+    /// data.
+    pub fn insert_gzip_link(
+        &self,
+        decompressed: &ContentDigest,
+        compressed: &ContentDigest,
+    ) -> Result<(), ImageError> {
+        let mut writer = self.begin_write()?;
+        writer.write_all(compressed.as_str().as_bytes())?;
+        self.commit_write(writer, &StorageKey::GzipLink(decompressed.clone()))
+    }
+
+    /// Open a stream of decompressed data for a blob that's only stored as
+    /// a [StorageKey::GzipLink]
+    ///
+    /// Returns None if there's no link for this key, or if the compressed
+    /// blob it links to is missing.
+    pub fn open_gzip_link(
+        &self,
+        key: &StorageKey,
+    ) -> Result<Option<MultiGzDecoder<BufReader<File>>>, ImageError> {
+        let link = match key {
+            StorageKey::Blob(digest) => StorageKey::GzipLink(digest.clone()),
+            _ => return Ok(None),
+        };
+        let compressed = match open_for_use(&link.to_path(&self.path))? {
+            None => return Ok(None),
+            Some(mut file) => {
+                let mut digest = String::new();
+                file.read_to_string(&mut digest)?;
+                StorageKey::Blob(ContentDigest::parse(&digest)?)
+            }
+        };
+        Ok(self
+            .open(&compressed)?
+            .map(|file| MultiGzDecoder::new(BufReader::new(file))))
+    }
+
     /// Open an object, creating requested BlobParts on demand
     pub async fn open_part(&self, key: &StorageKey) -> Result<Option<File>, ImageError> {
         match self.open(key)? {
@@ -78,15 +248,21 @@ impl FileStorage {
                     let task_storage = self.clone();
                     let task_key = key.clone();
                     task::spawn_blocking(move || {
-                        match task_storage.mmap(&StorageKey::Blob(digest))? {
-                            None => Ok(None),
-                            Some(part_of) => {
-                                let mut part = &part_of[range];
-                                let mut writer = task_storage.begin_write()?;
-                                io::copy(&mut part, &mut writer)?;
+                        let mut writer = task_storage.begin_write()?;
+                        let blob = StorageKey::Blob(digest);
+                        match task_storage.read_range(&blob, range, &mut writer) {
+                            Ok(true) => {
                                 task_storage.commit_write(writer, &task_key)?;
                                 task_storage.open(&task_key)
                             }
+                            Ok(false) => {
+                                writer.remove_temp()?;
+                                Ok(None)
+                            }
+                            Err(e) => {
+                                writer.remove_temp()?;
+                                Err(e)
+                            }
                         }
                     })
                     .await?
@@ -101,11 +277,79 @@ impl FileStorage {
     /// Returns true if and only if the storage exists as a regular file. Any
     /// errors will cause this to return false.
     pub fn exists(&self, key: &StorageKey) -> bool {
-        let path = key.to_path(&self.path);
-        match fs::metadata(path) {
+        let is_file = |path| match fs::metadata(path) {
             Err(_) => false,
             Ok(metadata) => metadata.is_file(),
+        };
+        is_file(key.to_path(&self.path)) || is_file(self.compressed_path(key))
+    }
+
+    /// Delete an object, whether or not it's stored compressed
+    pub fn remove(&self, key: &StorageKey) -> Result<(), ImageError> {
+        for path in &[key.to_path(&self.path), self.compressed_path(key)] {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            }
         }
+        Ok(())
+    }
+
+    /// Check whether a layer is stored, either as a blob, as a
+    /// [StorageKey::GzipLink] to its compressed form, or as a
+    /// [StorageKey::TarIndex] of its separately stored files
+    pub fn layer_exists(&self, key: &StorageKey) -> bool {
+        self.exists(key)
+            || matches!(self.gzip_link_target(key), Some(blob) if self.exists(&blob))
+            || matches!(tar_index(key), Some(index) if self.exists(&index))
+    }
+
+    /// Write the index of a layer whose files are stored as separate parts
+    ///
+    /// The file is only kept if `write` succeeds. It isn't content addressed,
+    /// since an index is the layer's tar with holes where file contents were.
+    pub fn insert_tar_index<T, F>(&self, layer: &ContentDigest, write: F) -> Result<T, ImageError>
+    where
+        F: FnOnce(&mut File) -> Result<T, ImageError>,
+    {
+        let temp_path = StorageKey::temp().to_path(&self.path);
+        create_parent_dirs(&temp_path);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o440)
+            .open(&temp_path)?;
+        match write(&mut file) {
+            Ok(result) => {
+                let dest_path = StorageKey::TarIndex(layer.clone()).to_path(&self.path);
+                create_parent_dirs(&dest_path);
+                fs::rename(&temp_path, &dest_path)?;
+                Ok(result)
+            }
+            Err(e) => {
+                let _ = fs::remove_file(&temp_path);
+                Err(e)
+            }
+        }
+    }
+
+    /// Map the [StorageKey::TarIndex] for a layer, if it has one
+    pub fn mmap_tar_index(&self, key: &StorageKey) -> Result<Option<Mmap>, ImageError> {
+        match tar_index(key) {
+            Some(index) => self.mmap(&index),
+            None => Ok(None),
+        }
+    }
+
+    /// Find the compressed blob a [StorageKey::GzipLink] points to, without
+    /// checking whether it still exists
+    fn gzip_link_target(&self, key: &StorageKey) -> Option<StorageKey> {
+        let link = match key {
+            StorageKey::Blob(digest) => StorageKey::GzipLink(digest.clone()),
+            _ => return None,
+        };
+        let digest = fs::read_to_string(link.to_path(&self.path)).ok()?;
+        Some(StorageKey::Blob(ContentDigest::parse(&digest).ok()?))
     }
 
     /// Make a new storage object at `to_key` using the data from `from_key`
@@ -133,10 +377,46 @@ impl FileStorage {
     }
 
     /// Open a storage object and memory map it
+    ///
+    /// Compressed objects are decompressed into anonymous memory.
     pub fn mmap(&self, key: &StorageKey) -> Result<Option<Mmap>, ImageError> {
-        match self.open(key)? {
-            Some(file) => Ok(Some(unsafe { MmapOptions::new().map(&file) }?)),
+        if let Some(file) = open_for_use(&key.to_path(&self.path))? {
+            return Ok(Some(unsafe { MmapOptions::new().map(&file) }?));
+        }
+        match self.mmap_compressed(key)? {
             None => Ok(None),
+            Some((map, table)) => {
+                let mut decompressed = MmapMut::map_anon(table.decompressed_len())?;
+                table.decompress_range(
+                    &map,
+                    0..table.decompressed_len(),
+                    &mut &mut decompressed[..],
+                )?;
+                Ok(Some(decompressed.make_read_only()?))
+            }
+        }
+    }
+
+    /// Read a stored object's entire contents
+    ///
+    /// A [StorageKey::BlobPart] that was never opened on its own is read from
+    /// its parent blob without making a separate copy. Returns None if the
+    /// object doesn't exist.
+    pub fn read_to_vec(&self, key: &StorageKey) -> Result<Option<Vec<u8>>, ImageError> {
+        if let Some(map) = self.mmap(key)? {
+            return Ok(Some(map[..].to_vec()));
+        }
+        match key {
+            StorageKey::BlobPart(digest, range) => {
+                let mut contents = Vec::with_capacity(range.len());
+                let blob = StorageKey::Blob(digest.clone());
+                if self.read_range(&blob, range.clone(), &mut contents)? {
+                    Ok(Some(contents))
+                } else {
+                    Ok(None)
+                }
+            }
+            _ => Ok(None),
         }
     }
 
@@ -156,16 +436,321 @@ impl FileStorage {
     }
 
     /// Promote a temporary file into a StorageKey
+    ///
+    /// Temporary files live inside the storage directory, so this is a
+    /// rename within one filesystem, which replaces any existing object
+    /// atomically. Readers see either the old object or the whole new one.
     pub fn commit_write(
         &self,
         mut writer: StorageWriter,
         key: &StorageKey,
     ) -> Result<(), ImageError> {
         let content_digest = writer.finalize()?;
+        if self.compress {
+            if let StorageKey::Blob(_) = key {
+                return self.commit_compressed(writer, key, content_digest);
+            }
+        }
         let dest_path = key.to_path(&self.path);
         create_parent_dirs(&dest_path);
         writer.rename_temp(&dest_path)?;
         log::debug!("storage commit, {:?} -> {:?}", content_digest, dest_path);
         Ok(())
     }
+
+    /// Check every stored blob against the content digest it's stored under
+    ///
+    /// Returns the keys of blobs whose contents no longer match, a sign of
+    /// corruption in a long-lived cache. If `repair` is set, those blobs are
+    /// also deleted, so they'll be downloaded again when next needed. Blobs
+    /// with digest formats other than `sha256` are skipped.
+    pub async fn fsck(&self, repair: bool) -> Result<Vec<StorageKey>, ImageError> {
+        let storage = self.clone();
+        task::spawn_blocking(move || storage.fsck_blocking(repair)).await?
+    }
+
+    fn fsck_blocking(&self, repair: bool) -> Result<Vec<StorageKey>, ImageError> {
+        let entries = match fs::read_dir(self.path.join("blobs")) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut corrupt = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            let name = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) => name,
+                None => continue,
+            };
+            let (stem, compressed) = if let Some(stem) = name.strip_suffix(".blob.zst") {
+                (stem, true)
+            } else if let Some(stem) = name.strip_suffix(".blob") {
+                (stem, false)
+            } else {
+                continue;
+            };
+            let expected = match key::path_decode(stem)
+                .and_then(|digest| ContentDigest::parse(&digest).ok())
+            {
+                Some(digest) if digest.format_str() == "sha256" => digest,
+                _ => continue,
+            };
+            let file = match open_if_exists(&path)? {
+                Some(file) => file,
+                None => continue,
+            };
+            // Zero-length files can't be mapped, but they hash just fine
+            let map = match file.metadata()?.len() {
+                0 => None,
+                _ => Some(unsafe { MmapOptions::new().map(&file) }?),
+            };
+            let contents = map.as_deref().unwrap_or(&[]);
+            let mut hasher = HashWriter::default();
+            let readable = if compressed {
+                SeekTable::parse(contents)
+                    .and_then(|table| {
+                        table.decompress_range(contents, 0..table.decompressed_len(), &mut hasher)
+                    })
+                    .is_ok()
+            } else {
+                hasher.0.update(contents);
+                true
+            };
+            let found = hasher.finalize();
+            if !readable || found != expected {
+                log::warn!("storage fsck, {:?} doesn't match {}", path, expected);
+                if repair {
+                    fs::remove_file(&path)?;
+                }
+                corrupt.push(StorageKey::Blob(expected));
+            }
+        }
+        Ok(corrupt)
+    }
+
+    /// Store a complete object in memory as a content-addressed blob
+    ///
+    /// Returns the blob's key. Nothing is written if it's already stored.
+    pub fn insert_blob(&self, contents: &[u8]) -> Result<StorageKey, ImageError> {
+        let key = StorageKey::Blob(ContentDigest::from_content(contents));
+        if !self.exists(&key) {
+            let mut writer = self.begin_write()?;
+            writer.write_all(contents)?;
+            self.commit_write(writer, &key)?;
+        }
+        Ok(key)
+    }
+
+    /// Store a small object that only our own user may read, replacing any
+    /// earlier version
+    ///
+    /// This is for secrets like cached auth tokens. It's never compressed.
+    pub fn insert_private(&self, key: &StorageKey, contents: &[u8]) -> Result<(), ImageError> {
+        let temp_path = StorageKey::temp().to_path(&self.path);
+        create_parent_dirs(&temp_path);
+        let result = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&temp_path)
+            .and_then(|mut file| file.write_all(contents))
+            .and_then(|()| {
+                let dest_path = key.to_path(&self.path);
+                create_parent_dirs(&dest_path);
+                fs::rename(&temp_path, &dest_path)
+            });
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        result.map_err(ImageError::from)
+    }
+
+    fn commit_compressed(
+        &self,
+        mut writer: StorageWriter,
+        key: &StorageKey,
+        content_digest: ContentDigest,
+    ) -> Result<(), ImageError> {
+        let source = File::open(writer.key.to_path(&self.path))?;
+        let mut compressed = self.begin_write()?;
+        let result = compress::compress(BufReader::new(source), &mut compressed);
+        writer.remove_temp()?;
+        if let Err(e) = result {
+            compressed.remove_temp()?;
+            return Err(e.into());
+        }
+        compressed.finalize()?;
+        let dest_path = self.compressed_path(key);
+        create_parent_dirs(&dest_path);
+        compressed.rename_temp(&dest_path)?;
+        log::debug!("storage commit, {:?} -> {:?}", content_digest, dest_path);
+        Ok(())
+    }
+}
+
+/// Adapter for hashing data from APIs that expect a [Write]
+#[derive(Default)]
+pub struct HashWriter(Sha256);
+
+impl HashWriter {
+    pub fn finalize(self) -> ContentDigest {
+        ContentDigest::from_parts("sha256", &self.0.finalize()).unwrap()
+    }
+}
+
+impl Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn compressed_blob_range() {
+        let dir = TempDir::new().unwrap();
+        let storage = FileStorage::new(dir.path().to_owned(), None).compressed();
+        let data = pattern(compress::CHUNK_SIZE * 2 + 1000);
+        let mut writer = storage.begin_write().unwrap();
+        writer.write_all(&data).unwrap();
+        let key = StorageKey::Blob(writer.finalize().unwrap());
+        storage.commit_write(writer, &key).unwrap();
+
+        assert!(storage.exists(&key));
+        assert!(!key.to_path(dir.path()).exists());
+        assert!(storage.compressed_path(&key).exists());
+
+        let range = (compress::CHUNK_SIZE - 10)..(compress::CHUNK_SIZE * 2 + 10);
+        let mut part = Vec::new();
+        assert!(storage.read_range(&key, range.clone(), &mut part).unwrap());
+        assert_eq!(part, &data[range]);
+
+        let mut tail = Vec::new();
+        let range = (data.len() - 5)..data.len();
+        assert!(storage.read_range(&key, range.clone(), &mut tail).unwrap());
+        assert_eq!(tail, &data[range]);
+
+        let mut past_end = Vec::new();
+        let range = (data.len() - 5)..(data.len() + 1);
+        assert!(storage.read_range(&key, range, &mut past_end).is_err());
+
+        assert_eq!(&storage.mmap(&key).unwrap().unwrap()[..], &data[..]);
+    }
+
+    #[test]
+    fn uncompressed_blob_range() {
+        let dir = TempDir::new().unwrap();
+        let storage = FileStorage::new(dir.path().to_owned(), None);
+        let data = pattern(5000);
+        let mut writer = storage.begin_write().unwrap();
+        writer.write_all(&data).unwrap();
+        let key = StorageKey::Blob(writer.finalize().unwrap());
+        storage.commit_write(writer, &key).unwrap();
+
+        assert!(key.to_path(dir.path()).exists());
+        let mut part = Vec::new();
+        assert!(storage.read_range(&key, 100..200, &mut part).unwrap());
+        assert_eq!(part, &data[100..200]);
+
+        let missing = StorageKey::Blob(crate::image::ContentDigest::from_content(b"nope"));
+        assert!(!storage.read_range(&missing, 0..1, &mut part).unwrap());
+    }
+
+    fn store_blob(storage: &FileStorage, data: &[u8]) -> StorageKey {
+        let mut writer = storage.begin_write().unwrap();
+        writer.write_all(data).unwrap();
+        let key = StorageKey::Blob(writer.finalize().unwrap());
+        storage.commit_write(writer, &key).unwrap();
+        key
+    }
+
+    fn corrupt_file(path: &Path) {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o640)).unwrap();
+        let mut file = fs::OpenOptions::new().write(true).open(path).unwrap();
+        file.seek(SeekFrom::Start(1000)).unwrap();
+        file.write_all(b"bit rot").unwrap();
+    }
+
+    #[test]
+    fn fsck_finds_corrupt_blobs() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let dir = TempDir::new().unwrap();
+        let storage = FileStorage::new(dir.path().to_owned(), None);
+        assert_eq!(rt.block_on(storage.fsck(false)).unwrap(), vec![]);
+
+        let good = store_blob(&storage, &pattern(3000));
+        let empty = store_blob(&storage, b"");
+        let bad = store_blob(&storage, &pattern(5000));
+        let compressed = storage.clone().compressed();
+        let bad_compressed = store_blob(&compressed, &pattern(compress::CHUNK_SIZE + 7));
+        corrupt_file(&bad.to_path(dir.path()));
+        corrupt_file(&compressed.compressed_path(&bad_compressed));
+
+        let expected: HashSet<_> = vec![bad.clone(), bad_compressed.clone()]
+            .into_iter()
+            .collect();
+        let found: HashSet<_> = rt
+            .block_on(storage.fsck(false))
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(found, expected);
+        assert!(storage.exists(&bad));
+
+        let repaired: HashSet<_> = rt
+            .block_on(storage.fsck(true))
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(repaired, expected);
+        assert!(!storage.exists(&bad));
+        assert!(!storage.exists(&bad_compressed));
+        assert!(storage.exists(&good));
+        assert!(storage.exists(&empty));
+        assert_eq!(rt.block_on(storage.fsck(false)).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn prune_least_recently_used() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let dir = TempDir::new().unwrap();
+        let storage = FileStorage::new(dir.path().to_owned(), None);
+        let pause = || std::thread::sleep(std::time::Duration::from_millis(20));
+        let oldest = store_blob(&storage, &pattern(1000));
+        pause();
+        let pinned = store_blob(&storage, &pattern(1001));
+        pause();
+        let unused = store_blob(&storage, &pattern(1002));
+        pause();
+        let used = store_blob(&storage, &pattern(1003));
+        pause();
+        storage.mmap(&oldest).unwrap();
+        pause();
+        storage.mmap(&used).unwrap();
+
+        assert_eq!(rt.block_on(storage.prune(10000)).unwrap(), 0);
+        let pin = storage.pin(vec![pinned.clone()]);
+        assert_eq!(rt.block_on(storage.prune(2500)).unwrap(), 2002);
+        assert!(!storage.exists(&unused));
+        assert!(!storage.exists(&oldest));
+        assert!(storage.exists(&pinned));
+        assert!(storage.exists(&used));
+
+        drop(pin);
+        assert_eq!(rt.block_on(storage.prune(1003)).unwrap(), 1001);
+        assert!(storage.exists(&used));
+    }
 }
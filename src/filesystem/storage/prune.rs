@@ -0,0 +1,195 @@
+//! Keeping the cache under a size limit, by deleting what was used least
+//! recently
+
+use crate::{
+    errors::ImageError,
+    filesystem::storage::{FileStorage, StorageKey},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tokio::task;
+
+/// Directories with objects that pruning never touches: temporary files
+/// that may still be in the middle of a write, locks, and auth tokens
+const SKIPPED_DIRS: &[&str] = &["tmp", "lock", "token"];
+
+/// Keeps stored objects from being pruned until it's dropped
+#[derive(Debug)]
+pub struct StoragePin {
+    pins: Arc<Mutex<HashMap<StorageKey, usize>>>,
+    keys: Vec<StorageKey>,
+}
+
+impl Drop for StoragePin {
+    fn drop(&mut self) {
+        let mut pins = self.pins.lock().unwrap();
+        for key in &self.keys {
+            if let Some(count) = pins.get_mut(key) {
+                *count -= 1;
+                if *count == 0 {
+                    pins.remove(key);
+                }
+            }
+        }
+    }
+}
+
+struct Entry {
+    path: PathBuf,
+    size: u64,
+    accessed: (i64, i64),
+}
+
+fn walk(dir: &Path, entries: &mut Vec<Entry>) -> io::Result<()> {
+    let dir_entries = match fs::read_dir(dir) {
+        Ok(dir_entries) => dir_entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for entry in dir_entries {
+        let entry = entry?;
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            // Someone else may be pruning at the same time
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        if metadata.is_dir() {
+            walk(&entry.path(), entries)?;
+        } else if metadata.is_file() {
+            entries.push(Entry {
+                path: entry.path(),
+                size: metadata.len(),
+                accessed: (metadata.atime(), metadata.atime_nsec()),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn remove(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+impl FileStorage {
+    /// Keep objects from being pruned while the returned pin exists
+    ///
+    /// Pinning a blob also pins the compressed form it might be stored as.
+    /// Pins are shared by every clone of this storage, but not with other
+    /// processes using the same directory.
+    pub fn pin(&self, keys: Vec<StorageKey>) -> StoragePin {
+        let mut pins = self.pins.lock().unwrap();
+        for key in &keys {
+            *pins.entry(key.clone()).or_insert(0) += 1;
+        }
+        StoragePin {
+            pins: self.pins.clone(),
+            keys,
+        }
+    }
+
+    /// Every path that a pinned object might be stored at
+    fn pinned_paths(&self) -> HashSet<PathBuf> {
+        let keys: Vec<StorageKey> = self.pins.lock().unwrap().keys().cloned().collect();
+        let mut paths = HashSet::new();
+        for key in keys {
+            if let StorageKey::Blob(digest) = &key {
+                paths.insert(StorageKey::GzipLink(digest.clone()).to_path(&self.path));
+                paths.insert(StorageKey::TarIndex(digest.clone()).to_path(&self.path));
+                if let Some(blob) = self.gzip_link_target(&key) {
+                    paths.insert(blob.to_path(&self.path));
+                    paths.insert(self.compressed_path(&blob));
+                }
+            }
+            paths.insert(key.to_path(&self.path));
+            paths.insert(self.compressed_path(&key));
+        }
+        paths
+    }
+
+    /// The [StorageKey::TarIndex] that a stored part may belong to
+    ///
+    /// Parts are stored in a directory named after their layer, the same way
+    /// that layer's index is named.
+    fn part_index_path(&self, path: &Path) -> Option<PathBuf> {
+        let layer_dir = path.parent()?;
+        if layer_dir.parent()? != self.path.join("parts") {
+            return None;
+        }
+        let mut index = self.path.join("index").join(layer_dir.file_name()?);
+        index.set_extension("tar");
+        Some(index)
+    }
+
+    /// Delete the least recently used objects until the whole cache takes up
+    /// at most `max_bytes`, and return how many bytes were freed
+    ///
+    /// Blobs, blob parts, and manifests all count as objects, ordered by the
+    /// last time they were opened. Pinned objects, temporary files, locks,
+    /// and saved auth tokens are never deleted, so the cache can stay over the
+    /// limit if those alone exceed it.
+    ///
+    /// A layer stored as a [StorageKey::TarIndex] can't recreate its parts,
+    /// so deleting any of them deletes the index too, and the layer will be
+    /// downloaded again when it's next needed. Parts of a pinned index stay.
+    pub async fn prune(&self, max_bytes: u64) -> Result<u64, ImageError> {
+        let storage = self.clone();
+        task::spawn_blocking(move || storage.prune_blocking(max_bytes)).await?
+    }
+
+    fn prune_blocking(&self, max_bytes: u64) -> Result<u64, ImageError> {
+        let top = match fs::read_dir(&self.path) {
+            Ok(top) => top,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let mut entries = Vec::new();
+        for entry in top {
+            let entry = entry?;
+            let skipped = entry
+                .file_name()
+                .to_str()
+                .map(|name| SKIPPED_DIRS.contains(&name))
+                .unwrap_or(false);
+            if !skipped && entry.file_type()?.is_dir() {
+                walk(&entry.path(), &mut entries)?;
+            }
+        }
+        let mut total: u64 = entries.iter().map(|entry| entry.size).sum();
+        if total <= max_bytes {
+            return Ok(0);
+        }
+
+        let pinned = self.pinned_paths();
+        entries.sort_by_key(|entry| entry.accessed);
+        let mut freed = 0;
+        for entry in entries {
+            if total <= max_bytes {
+                break;
+            }
+            let index = self.part_index_path(&entry.path);
+            if pinned.contains(&entry.path)
+                || matches!(&index, Some(index) if pinned.contains(index))
+            {
+                continue;
+            }
+            log::debug!("storage prune, removing {:?}", entry.path);
+            remove(&entry.path)?;
+            if let Some(index) = index {
+                remove(&index)?;
+            }
+            total -= entry.size;
+            freed += entry.size;
+        }
+        log::info!("storage prune freed {} bytes, {} remain", freed, total);
+        Ok(freed)
+    }
+}
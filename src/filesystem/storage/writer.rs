@@ -1,5 +1,5 @@
 use crate::{errors::ImageError, filesystem::storage::StorageKey, image::ContentDigest};
-use pin_project::pin_project;
+use pin_project::{pin_project, pinned_drop};
 use sha2::{Digest, Sha256};
 use std::{
     fs,
@@ -7,9 +7,14 @@ use std::{
     io,
     io::Write,
     path::{Path, PathBuf},
+    pin::Pin,
 };
 
-#[pin_project]
+/// Writer for a new storage object, kept in a temporary file until it's
+/// committed
+///
+/// Dropping a writer that was never committed removes its temporary file.
+#[pin_project(PinnedDrop)]
 #[derive(Debug)]
 pub struct StorageWriter {
     #[pin]
@@ -67,6 +72,15 @@ impl StorageWriter {
     }
 }
 
+#[pinned_drop]
+impl PinnedDrop for StorageWriter {
+    fn drop(self: Pin<&mut Self>) {
+        if let Some(path) = self.project().temp_path.take() {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
 impl Write for StorageWriter {
     fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
         let result = self
@@ -1,18 +1,26 @@
 use crate::{
-    errors::ImageError,
+    errors::{ImageError, VFSError},
     filesystem::{
         storage::{FileStorage, StorageKey},
         vfs::Filesystem,
     },
-    sand::protocol::{abi, FileStat},
+    sand::protocol::{abi, FileStat, FollowLinks, INodeNum, VFile},
 };
 use std::{
+    cell::Cell,
+    collections::{HashMap, HashSet},
     convert::TryInto,
-    ffi::CString,
-    io::{Cursor, Read},
-    path::Path,
+    ffi::{CString, OsStr},
+    io,
+    io::{Cursor, Read, Seek, SeekFrom, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Component, Path, PathBuf},
 };
-use tar::{Archive, Entry, EntryType};
+use tar::{Archive, Builder, Entry, EntryType, Header};
+
+const PAX_XATTR_PREFIX: &[u8] = b"SCHILY.xattr.";
+const WHITEOUT_PREFIX: &[u8] = b".wh.";
+const OPAQUE_WHITEOUT: &[u8] = b".wh..wh..opq";
 
 pub fn extract(
     fs: &mut Filesystem,
@@ -22,8 +30,15 @@ pub fn extract(
     let mut offset: usize = 0;
     let archive_map = match storage.mmap(archive)? {
         Some(map) => map,
-        None => return Err(ImageError::TARFileError),
+        None => match storage.open_gzip_link(archive)? {
+            Some(stream) => return extract_stream(fs, archive, stream),
+            None => match storage.mmap_tar_index(archive)? {
+                Some(map) => map,
+                None => return Err(ImageError::TARFileError),
+            },
+        },
     };
+    let mut layer_paths = HashSet::new();
     while let Some(entry) = Archive::new(Cursor::new(&archive_map[offset..]))
         .entries()?
         .next()
@@ -31,23 +46,114 @@ pub fn extract(
         let entry = entry?;
         let entry_size = entry.size() as usize;
         let file_begin = offset + (entry.raw_file_position() as usize);
-        let file_range = file_begin..(file_begin + entry_size);
-        let file_key = if entry_size == 0 {
-            None
-        } else {
-            Some(
-                archive
-                    .clone()
-                    .range(file_range)
-                    .map_err(|_| ImageError::TARFileError)?,
-            )
-        };
+        let file_key = file_key(archive, file_begin, entry_size)?;
         offset = pad_to_block_multiple(file_begin + entry_size);
-        extract_file_metadata(fs, entry, file_key)?;
+        extract_entry(fs, &mut layer_paths, entry, file_key)?;
     }
     Ok(())
 }
 
+/// Extract an archive we can only read sequentially
+///
+/// File contents still refer to byte ranges in `archive`, the same as they
+/// would if we could map it.
+fn extract_stream<R: Read>(
+    fs: &mut Filesystem,
+    archive: &StorageKey,
+    stream: R,
+) -> Result<(), ImageError> {
+    let mut layer_paths = HashSet::new();
+    for entry in Archive::new(stream).entries()? {
+        let entry = entry?;
+        let entry_size = entry.size() as usize;
+        let file_begin = entry.raw_file_position() as usize;
+        let file_key = file_key(archive, file_begin, entry_size)?;
+        extract_entry(fs, &mut layer_paths, entry, file_key)?;
+    }
+    Ok(())
+}
+
+/// Store the contents of each regular file in a tar stream as its own
+/// [StorageKey::BlobPart] of `archive`, while reading the stream only once
+///
+/// These are the same keys [extract()] gives those files, so reading a file
+/// later opens its part directly. Everything else in the stream is copied to
+/// `index` at its original offset, with holes where the file contents were,
+/// so the index can stand in for the archive when extracting metadata. Keys
+/// are added to `parts` as they're committed, so a caller can remove them if
+/// the stream turns out to be bad.
+pub fn store_file_parts<R: Read, W: Write + Seek>(
+    storage: &FileStorage,
+    archive: &StorageKey,
+    stream: R,
+    index: &mut W,
+    parts: &mut Vec<StorageKey>,
+) -> Result<(), ImageError> {
+    let skipping = Cell::new(false);
+    let mut stream = IndexWriter {
+        stream,
+        index,
+        skipping: &skipping,
+    };
+    for entry in Archive::new(&mut stream).entries()? {
+        let mut entry = entry?;
+        if entry.header().entry_type() != EntryType::Regular {
+            continue;
+        }
+        let entry_size = entry.size() as usize;
+        let file_begin = entry.raw_file_position() as usize;
+        if let Some(key) = file_key(archive, file_begin, entry_size)? {
+            let mut writer = storage.begin_write()?;
+            skipping.set(true);
+            let copied = io::copy(&mut entry, &mut writer);
+            skipping.set(false);
+            copied?;
+            storage.commit_write(writer, &key)?;
+            parts.push(key);
+        }
+    }
+    // Keep the padding at the end, so the index is as long as the archive
+    io::copy(&mut stream, &mut io::sink())?;
+    Ok(())
+}
+
+/// Copies a stream to an index as it's read, except while `skipping`
+struct IndexWriter<'a, R, W> {
+    stream: R,
+    index: &'a mut W,
+    skipping: &'a Cell<bool>,
+}
+
+impl<'a, R: Read, W: Write + Seek> Read for IndexWriter<'a, R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.stream.read(buf)?;
+        if self.skipping.get() {
+            self.index.seek(SeekFrom::Current(size as i64))?;
+        } else {
+            self.index.write_all(&buf[..size])?;
+        }
+        Ok(size)
+    }
+}
+
+fn file_key(
+    archive: &StorageKey,
+    file_begin: usize,
+    entry_size: usize,
+) -> Result<Option<StorageKey>, ImageError> {
+    if entry_size == 0 {
+        Ok(None)
+    } else {
+        let file_range = file_begin..(file_begin + entry_size);
+        Ok(Some(
+            archive
+                .clone()
+                .range(file_range)
+                .map_err(|_| ImageError::TARFileError)?,
+        ))
+    }
+}
+
 fn pad_to_block_multiple(size: usize) -> usize {
     const BLOCK_LEN: usize = 512;
     let rem = size % BLOCK_LEN;
@@ -58,14 +164,55 @@ fn pad_to_block_multiple(size: usize) -> usize {
     }
 }
 
-fn extract_file_metadata<'a, R: Read>(
+/// Extract one entry from a layer, applying it on top of the layers below
+///
+/// Whiteout entries delete what lower layers left at their path, the way
+/// overlay filesystems and `docker` represent deletions. An opaque whiteout
+/// hides everything a directory had from lower layers, but not anything this
+/// layer puts there, so `layer_paths` collects every path the layer writes.
+fn extract_entry<'a, R: Read>(
     fs: &mut Filesystem,
+    layer_paths: &mut HashSet<PathBuf>,
     entry: Entry<'a, R>,
     data: Option<StorageKey>,
 ) -> Result<(), ImageError> {
+    let path: PathBuf = entry
+        .path()?
+        .components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect();
+    let name = path.file_name().map(OsStrExt::as_bytes).unwrap_or(b"");
+    let parent = path.parent().unwrap_or_else(|| Path::new(""));
+    if name == OPAQUE_WHITEOUT {
+        let mut writer = fs.writer();
+        writer.write_opaque_directory(parent, |child| layer_paths.contains(&parent.join(child)))?;
+    } else if name.starts_with(WHITEOUT_PREFIX) {
+        match &name[WHITEOUT_PREFIX.len()..] {
+            b"" | b"." | b".." => log::warn!("ignoring tar whiteout entry {:?}", path),
+            hidden => fs
+                .writer()
+                .write_whiteout(&parent.join(OsStr::from_bytes(hidden)))?,
+        }
+    } else {
+        for ancestor in path.ancestors() {
+            if !layer_paths.insert(ancestor.to_owned()) {
+                break;
+            }
+        }
+        extract_file_metadata(fs, entry, data)?;
+    }
+    Ok(())
+}
+
+fn extract_file_metadata<'a, R: Read>(
+    fs: &mut Filesystem,
+    mut entry: Entry<'a, R>,
+    data: Option<StorageKey>,
+) -> Result<(), ImageError> {
+    let xattrs = extract_xattrs(&mut entry)?;
     let mut fsw = fs.writer();
     let kind = entry.header().entry_type();
-    let path = entry.path()?;
+    let path = entry.path()?.into_owned();
     let link_name = entry.link_name_bytes();
     let device = (
         entry.header().device_major()?,
@@ -121,11 +268,189 @@ fn extract_file_metadata<'a, R: Read>(
             (Some(major), Some(minor)) => fsw.write_block_device(&path, stat, major, minor)?,
             _ => Err(ImageError::TARFileError)?,
         },
-        _ => log::error!(
-            "skipping unsupported tar file entry type {:?}, {:?}",
-            kind,
-            entry.header()
-        ),
+        _ => {
+            log::error!(
+                "skipping unsupported tar file entry type {:?}, {:?}",
+                kind,
+                entry.header()
+            );
+            return Ok(());
+        }
+    }
+    for (name, value) in xattrs {
+        fsw.write_xattr(&path, OsStr::from_bytes(&name), &value)?;
+    }
+    Ok(())
+}
+
+fn extract_xattrs<'a, R: Read>(
+    entry: &mut Entry<'a, R>,
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>, ImageError> {
+    let mut result = Vec::new();
+    if let Some(extensions) = entry.pax_extensions()? {
+        for extension in extensions {
+            let extension = extension?;
+            let key = extension.key_bytes();
+            if key.starts_with(PAX_XATTR_PREFIX) {
+                result.push((
+                    key[PAX_XATTR_PREFIX.len()..].to_vec(),
+                    extension.value_bytes().to_vec(),
+                ));
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Write the entire contents of a filesystem as a tar stream
+///
+/// Entries are written in sorted order, parents before children. Files
+/// reachable by more than one name are stored once, with hard links for the
+/// rest. Extended attributes are stored in PAX headers, the same way
+/// [extract()] reads them.
+pub fn export<W: Write>(
+    fs: &Filesystem,
+    storage: &FileStorage,
+    writer: W,
+) -> Result<W, ImageError> {
+    let mut builder = Builder::new(writer);
+    let mut links = HashMap::new();
+    export_directory(
+        fs,
+        storage,
+        &mut builder,
+        &mut links,
+        &Filesystem::root(),
+        Path::new(""),
+    )?;
+    Ok(builder.into_inner()?)
+}
+
+fn export_directory<W: Write>(
+    fs: &Filesystem,
+    storage: &FileStorage,
+    builder: &mut Builder<W>,
+    links: &mut HashMap<INodeNum, PathBuf>,
+    dir: &VFile,
+    dir_path: &Path,
+) -> Result<(), ImageError> {
+    for name in fs.list_dir(dir)? {
+        let file = fs.lookup(dir, Path::new(&name), &FollowLinks::NoFollow)?;
+        let path = dir_path.join(&name);
+        let stat = fs.stat(&file)?;
+        let is_dir = stat.st_mode & abi::S_IFMT == abi::S_IFDIR;
+        if !is_dir {
+            if let Some(link_to) = links.get(&file.inode) {
+                let mut header = export_header(&stat, EntryType::Link)?;
+                header.set_link_name(link_to)?;
+                builder.append_data(&mut header, &path, std::io::empty())?;
+                continue;
+            }
+            links.insert(file.inode, path.clone());
+        }
+        export_xattrs(fs, builder, &file)?;
+        export_file(fs, storage, builder, &file, &stat, &path)?;
+        if is_dir {
+            export_directory(fs, storage, builder, links, &file, &path)?;
+        }
+    }
+    Ok(())
+}
+
+fn export_file<W: Write>(
+    fs: &Filesystem,
+    storage: &FileStorage,
+    builder: &mut Builder<W>,
+    file: &VFile,
+    stat: &FileStat,
+    path: &Path,
+) -> Result<(), ImageError> {
+    let kind = match stat.st_mode & abi::S_IFMT {
+        abi::S_IFDIR => EntryType::Directory,
+        abi::S_IFLNK => EntryType::Symlink,
+        abi::S_IFCHR => EntryType::Char,
+        abi::S_IFBLK => EntryType::Block,
+        abi::S_IFIFO => EntryType::Fifo,
+        _ => EntryType::Regular,
+    };
+    let mut header = export_header(stat, kind)?;
+    match kind {
+        EntryType::Regular => match fs.storage_key(file) {
+            Ok(Some(key)) => {
+                let contents = storage
+                    .read_to_vec(key)?
+                    .ok_or(VFSError::ImageStorageError)?;
+                header.set_size(contents.len() as u64);
+                builder.append_data(&mut header, path, &contents[..])?;
+                return Ok(());
+            }
+            Ok(None) => (),
+            Err(VFSError::FileExpected) => {
+                log::warn!("skipping file with no stored contents, {:?}", path);
+                return Ok(());
+            }
+            Err(err) => return Err(err.into()),
+        },
+        EntryType::Symlink => {
+            header.set_link_name(OsStr::from_bytes(fs.readlink(file)?.to_bytes()))?
+        }
+        EntryType::Char | EntryType::Block => {
+            if let Some((major, minor)) = fs.device_numbers(file)? {
+                header.set_device_major(major)?;
+                header.set_device_minor(minor)?;
+            }
+        }
+        _ => (),
+    }
+    builder.append_data(&mut header, path, std::io::empty())?;
+    Ok(())
+}
+
+fn export_header(stat: &FileStat, kind: EntryType) -> Result<Header, ImageError> {
+    let mut header = Header::new_gnu();
+    header.set_entry_type(kind);
+    header.set_mode(stat.st_mode & !abi::S_IFMT);
+    header.set_uid(stat.st_uid.into());
+    header.set_gid(stat.st_gid.into());
+    header.set_mtime(stat.st_mtime);
+    header.set_size(0);
+    // Extraction expects device numbers on every entry, as other tools write
+    header.set_device_major(0)?;
+    header.set_device_minor(0)?;
+    Ok(header)
+}
+
+/// Write a PAX extended header holding a file's extended attributes, if it
+/// has any
+fn export_xattrs<W: Write>(
+    fs: &Filesystem,
+    builder: &mut Builder<W>,
+    file: &VFile,
+) -> Result<(), ImageError> {
+    let mut records = Vec::new();
+    for name in fs.list_xattr(file)?.split(|b| *b == 0) {
+        if name.is_empty() {
+            continue;
+        }
+        let value = fs.get_xattr(file, OsStr::from_bytes(name))?;
+        let mut record = [PAX_XATTR_PREFIX, name, b"=", value, b"\n"].concat();
+        // The length prefix counts its own digits, which may push it up a digit
+        let mut len = record.len() + 2;
+        while len != record.len() + 1 + len.to_string().len() {
+            len = record.len() + 1 + len.to_string().len();
+        }
+        records.extend_from_slice(format!("{} ", len).as_bytes());
+        records.append(&mut record);
+    }
+    if !records.is_empty() {
+        let mut header = Header::new_ustar();
+        header.set_entry_type(EntryType::XHeader);
+        header.set_mode(0o644);
+        header.set_size(records.len() as u64);
+        header.set_device_major(0)?;
+        header.set_device_minor(0)?;
+        header.set_cksum();
+        builder.append(&header, &records[..])?;
     }
     Ok(())
 }
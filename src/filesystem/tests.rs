@@ -0,0 +1,838 @@
+use super::{host::HostDir, mount::Mount, storage::FileStorage, tar, tmpfs::Tmpfs, vfs::*};
+use crate::{
+    errors::VFSError,
+    sand::protocol::{abi, FileStat, FollowLinks},
+};
+use std::{
+    ffi::{CString, OsStr},
+    fs::File,
+    io::{Read, Write},
+    os::unix::io::FromRawFd,
+    path::Path,
+};
+use tempfile::TempDir;
+
+#[test]
+fn xattr_on_overlay_file() {
+    let mut fs = Filesystem::new();
+    fs.writer()
+        .write_storage_file(Path::new("/etc/image"), Default::default(), None)
+        .unwrap();
+    fs.seal_image();
+    fs.writer()
+        .write_storage_file(Path::new("/etc/overlay"), Default::default(), None)
+        .unwrap();
+
+    let root = Filesystem::root();
+    let image = fs
+        .lookup(&root, Path::new("/etc/image"), &FollowLinks::Follow)
+        .unwrap();
+    let overlay = fs
+        .lookup(&root, Path::new("/etc/overlay"), &FollowLinks::Follow)
+        .unwrap();
+    let name = OsStr::new("user.comment");
+
+    assert!(matches!(
+        fs.writer()
+            .set_xattr(&image, name, b"nope", XAttrFlags::CreateOrReplace),
+        Err(VFSError::ReadOnly)
+    ));
+    assert!(matches!(
+        fs.get_xattr(&overlay, name),
+        Err(VFSError::AttributeNotFound)
+    ));
+    assert!(matches!(
+        fs.writer()
+            .set_xattr(&overlay, name, b"old", XAttrFlags::Replace),
+        Err(VFSError::AttributeNotFound)
+    ));
+    fs.writer()
+        .set_xattr(&overlay, name, b"hello", XAttrFlags::Create)
+        .unwrap();
+    assert!(matches!(
+        fs.writer()
+            .set_xattr(&overlay, name, b"again", XAttrFlags::Create),
+        Err(VFSError::AttributeExists)
+    ));
+    assert_eq!(fs.get_xattr(&overlay, name).unwrap(), b"hello");
+    assert_eq!(fs.list_xattr(&overlay).unwrap(), b"user.comment\0");
+    assert_eq!(fs.list_xattr(&image).unwrap(), b"");
+
+    fs.writer().remove_xattr(&overlay, name).unwrap();
+    assert!(matches!(
+        fs.get_xattr(&overlay, name),
+        Err(VFSError::AttributeNotFound)
+    ));
+}
+
+#[test]
+fn list_dir_and_readlink() {
+    let mut fs = Filesystem::new();
+    let mut writer = fs.writer();
+    writer
+        .write_storage_file(Path::new("/bin/busybox"), Default::default(), None)
+        .unwrap();
+    writer
+        .write_symlink(
+            Path::new("/bin/sh"),
+            Default::default(),
+            CString::new("busybox").unwrap(),
+        )
+        .unwrap();
+
+    let root = Filesystem::root();
+    let bin = fs
+        .lookup(&root, Path::new("/bin"), &FollowLinks::Follow)
+        .unwrap();
+    assert_eq!(fs.list_dir(&bin).unwrap(), vec!["busybox", "sh"]);
+    assert_eq!(fs.list_dir(&root).unwrap(), vec!["bin"]);
+
+    let sh = fs
+        .lookup(&root, Path::new("/bin/sh"), &FollowLinks::NoFollow)
+        .unwrap();
+    assert_eq!(fs.readlink(&sh).unwrap().to_bytes(), b"busybox");
+    assert!(matches!(fs.list_dir(&sh), Err(VFSError::DirectoryExpected)));
+}
+
+#[test]
+fn read_only_subtree() {
+    let mut fs = Filesystem::new();
+    fs.writer()
+        .write_storage_file(Path::new("/usr/bin/image"), Default::default(), None)
+        .unwrap();
+    fs.seal_image();
+    for path in &["/usr/share/overlay", "/tmp/overlay"] {
+        fs.writer()
+            .write_storage_file(Path::new(path), Default::default(), None)
+            .unwrap();
+    }
+    fs.set_read_only(Path::new("/usr")).unwrap();
+
+    let root = Filesystem::root();
+    let lookup = |path| {
+        fs.lookup(&root, Path::new(path), &FollowLinks::Follow)
+            .unwrap()
+    };
+    let usr = lookup("/usr/share/overlay");
+    let tmp = lookup("/tmp/overlay");
+    assert!(fs.is_read_only(&lookup("/usr")));
+    assert!(fs.is_read_only(&lookup("/usr/bin/image")));
+    assert!(fs.is_read_only(&usr));
+    assert!(!fs.is_read_only(&tmp));
+    assert!(!fs.is_read_only(&root));
+
+    let name = OsStr::new("user.comment");
+    assert!(matches!(
+        fs.writer()
+            .set_xattr(&usr, name, b"nope", XAttrFlags::CreateOrReplace),
+        Err(VFSError::ReadOnly)
+    ));
+    fs.writer()
+        .set_xattr(&tmp, name, b"ok", XAttrFlags::CreateOrReplace)
+        .unwrap();
+}
+
+#[test]
+fn configurable_symlink_limit() {
+    let mut fs = Filesystem::new();
+    let mut writer = fs.writer();
+    writer
+        .write_storage_file(Path::new("/target"), Default::default(), None)
+        .unwrap();
+    for index in 0..5 {
+        let next = if index == 4 {
+            "target".to_string()
+        } else {
+            format!("l{}", index + 1)
+        };
+        writer
+            .write_symlink(
+                Path::new(&format!("/l{}", index)),
+                Default::default(),
+                CString::new(next).unwrap(),
+            )
+            .unwrap();
+    }
+
+    let root = Filesystem::root();
+    let target = fs
+        .lookup(&root, Path::new("/target"), &FollowLinks::Follow)
+        .unwrap();
+    assert_eq!(
+        fs.lookup(&root, Path::new("/l0"), &FollowLinks::Follow)
+            .unwrap(),
+        target
+    );
+
+    fs.set_path_limits(PathLimits {
+        symbolic_links: 4,
+        ..Default::default()
+    });
+    assert!(matches!(
+        fs.lookup(&root, Path::new("/l0"), &FollowLinks::Follow),
+        Err(VFSError::SymbolicLinkLimitExceeded)
+    ));
+    fs.set_path_limits(PathLimits {
+        symbolic_links: 5,
+        ..Default::default()
+    });
+    assert_eq!(
+        fs.lookup(&root, Path::new("/l0"), &FollowLinks::Follow)
+            .unwrap(),
+        target
+    );
+}
+
+#[test]
+fn symlink_loop() {
+    let mut fs = Filesystem::new();
+    let mut writer = fs.writer();
+    for (path, target) in &[("/a", "b"), ("/b", "a")] {
+        writer
+            .write_symlink(
+                Path::new(path),
+                Default::default(),
+                CString::new(*target).unwrap(),
+            )
+            .unwrap();
+    }
+
+    let root = Filesystem::root();
+    let result = fs.lookup(&root, Path::new("/a"), &FollowLinks::Follow);
+    assert!(matches!(result, Err(VFSError::SymbolicLinkLoop)));
+    let result = fs.lookup(&root, Path::new("/a/file"), &FollowLinks::Follow);
+    assert!(matches!(result, Err(VFSError::SymbolicLinkLoop)));
+    assert!(fs
+        .lookup(&root, Path::new("/a"), &FollowLinks::NoFollow)
+        .is_ok());
+}
+
+#[test]
+fn export_tar_round_trip() {
+    let dir = TempDir::new().unwrap();
+    let storage = FileStorage::new(dir.path().to_owned(), None);
+    let contents = b"exported contents\n";
+    let stat = |mode| FileStat {
+        st_mode: mode,
+        st_uid: 12,
+        st_gid: 34,
+        st_mtime: 1600000000,
+        ..Default::default()
+    };
+
+    let mut fs = Filesystem::new();
+    let mut w = fs.writer();
+    w.write_directory_metadata(Path::new("/etc"), stat(abi::S_IFDIR | 0o755))
+        .unwrap();
+    w.write_storage_file(
+        Path::new("/etc/motd"),
+        FileStat {
+            st_size: contents.len() as i64,
+            ..stat(abi::S_IFREG | 0o640)
+        },
+        Some(storage.insert_blob(contents).unwrap()),
+    )
+    .unwrap();
+    w.write_storage_file(Path::new("/etc/empty"), stat(abi::S_IFREG | 0o600), None)
+        .unwrap();
+    w.write_hardlink(Path::new("/etc/motd.link"), Path::new("/etc/motd"))
+        .unwrap();
+    w.write_symlink(
+        Path::new("/etc/motd.sym"),
+        stat(abi::S_IFLNK | 0o777),
+        CString::new("motd").unwrap(),
+    )
+    .unwrap();
+    w.write_char_device(Path::new("/dev/null"), stat(abi::S_IFCHR | 0o666), 1, 3)
+        .unwrap();
+    w.write_fifo(Path::new("/run/pipe"), stat(abi::S_IFIFO | 0o600))
+        .unwrap();
+    w.write_xattr(Path::new("/etc/motd"), OsStr::new("user.note"), b"hi")
+        .unwrap();
+
+    let exported = tar::export(&fs, &storage, Vec::new()).unwrap();
+    let key = storage.insert_blob(&exported).unwrap();
+    let mut copy = Filesystem::new();
+    tar::extract(&mut copy, &storage, &key).unwrap();
+
+    let root = Filesystem::root();
+    let lookup = |path: &str| {
+        copy.lookup(&root, Path::new(path), &FollowLinks::NoFollow)
+            .unwrap()
+    };
+    let motd = lookup("/etc/motd");
+    let motd_stat = copy.stat(&motd).unwrap();
+    assert_eq!(motd_stat.st_mode, abi::S_IFREG | 0o640);
+    assert_eq!((motd_stat.st_uid, motd_stat.st_gid), (12, 34));
+    assert_eq!(motd_stat.st_mtime, 1600000000);
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    assert_eq!(
+        rt.block_on(copy.read_to_vec(&storage, &motd, 1024))
+            .unwrap(),
+        contents
+    );
+    assert_eq!(lookup("/etc/motd.link").inode, motd.inode);
+    assert_eq!(
+        copy.get_xattr(&motd, OsStr::new("user.note")).unwrap(),
+        b"hi"
+    );
+    assert!(copy.storage_key(&lookup("/etc/empty")).unwrap().is_none());
+    assert_eq!(
+        copy.readlink(&lookup("/etc/motd.sym")).unwrap().to_bytes(),
+        b"motd"
+    );
+    let null = lookup("/dev/null");
+    assert_eq!(copy.stat(&null).unwrap().st_mode, abi::S_IFCHR | 0o666);
+    assert_eq!(copy.device_numbers(&null).unwrap(), Some((1, 3)));
+    assert_eq!(
+        copy.stat(&lookup("/run/pipe")).unwrap().st_mode,
+        abi::S_IFIFO | 0o600
+    );
+    assert_eq!(
+        copy.stat(&lookup("/etc")).unwrap().st_mode,
+        abi::S_IFDIR | 0o755
+    );
+}
+
+/// A tar layer holding empty regular files at each path
+fn layer_tar(paths: &[&str]) -> Vec<u8> {
+    let mut builder = ::tar::Builder::new(Vec::new());
+    for path in paths {
+        let mut header = ::tar::Header::new_gnu();
+        header.set_size(0);
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        header.set_device_major(0).unwrap();
+        header.set_device_minor(0).unwrap();
+        header.set_cksum();
+        builder.append_data(&mut header, path, &[][..]).unwrap();
+    }
+    builder.into_inner().unwrap()
+}
+
+#[test]
+fn layer_whiteouts() {
+    let dir = TempDir::new().unwrap();
+    let storage = FileStorage::new(dir.path().to_owned(), None);
+    let lower = layer_tar(&[
+        "etc/motd",
+        "etc/hosts",
+        "opt/app/bin",
+        "var/cache/old",
+        "var/cache/sub/older",
+    ]);
+    let upper = layer_tar(&[
+        "etc/.wh.motd",
+        "./.wh.opt",
+        "var/cache/early",
+        "var/cache/.wh..wh..opq",
+        "var/cache/late",
+        "var/.wh.missing",
+    ]);
+    let mut fs = Filesystem::new();
+    for layer in &[lower, upper] {
+        let key = storage.insert_blob(layer).unwrap();
+        tar::extract(&mut fs, &storage, &key).unwrap();
+    }
+
+    let root = Filesystem::root();
+    let lookup = |path: &str| fs.lookup(&root, Path::new(path), &FollowLinks::NoFollow);
+    assert!(matches!(lookup("/etc/motd"), Err(VFSError::NotFound)));
+    assert!(matches!(lookup("/opt"), Err(VFSError::NotFound)));
+    assert_eq!(
+        fs.list_dir(&lookup("/etc").unwrap()).unwrap(),
+        vec!["hosts"]
+    );
+    assert_eq!(fs.list_dir(&root).unwrap(), vec!["etc", "var"]);
+    let cache = lookup("/var/cache").unwrap();
+    assert_eq!(fs.list_dir(&cache).unwrap(), vec!["early", "late"]);
+    // Only "." and the parent's entry are left linking to it
+    assert_eq!(fs.stat(&cache).unwrap().st_nlink, 2);
+}
+
+#[test]
+fn restricted_lookup() {
+    let mut fs = Filesystem::new();
+    let mut w = fs.writer();
+    let stat = FileStat {
+        st_mode: abi::S_IFDIR | 0o755,
+        ..Default::default()
+    };
+    w.write_directory_metadata(Path::new("/srv/data"), stat.clone())
+        .unwrap();
+    w.write_directory_metadata(Path::new("/etc"), stat.clone())
+        .unwrap();
+    w.write_symlink(
+        Path::new("/srv/data/up"),
+        stat.clone(),
+        CString::new("..").unwrap(),
+    )
+    .unwrap();
+    w.write_symlink(
+        Path::new("/srv/data/etc"),
+        stat,
+        CString::new("/etc").unwrap(),
+    )
+    .unwrap();
+
+    let root = Filesystem::root();
+    let data = fs
+        .lookup(&root, Path::new("/srv/data"), &FollowLinks::Follow)
+        .unwrap();
+    let lookup = |path: &str, no_symlinks, beneath| {
+        let flags = ResolveFlags {
+            no_symlinks,
+            beneath,
+        };
+        fs.lookup_restricted(&data, Path::new(path), &FollowLinks::Follow, &flags)
+    };
+
+    assert!(lookup("up", false, false).is_ok());
+    assert!(lookup(".", false, true).is_ok());
+    assert!(matches!(
+        lookup("up", true, false),
+        Err(VFSError::SymbolicLinkNotAllowed)
+    ));
+    assert!(matches!(
+        lookup("up", false, true),
+        Err(VFSError::PathNotBeneath)
+    ));
+    assert!(matches!(
+        lookup("../data", false, true),
+        Err(VFSError::PathNotBeneath)
+    ));
+    assert!(matches!(
+        lookup("etc", false, true),
+        Err(VFSError::PathNotBeneath)
+    ));
+    assert!(matches!(
+        lookup("/srv/data", false, true),
+        Err(VFSError::PathNotBeneath)
+    ));
+    assert!(lookup("etc", false, false).is_ok());
+    assert!(lookup("/srv", true, true).is_err());
+}
+
+#[test]
+fn remove_overlay_files() {
+    let mut fs = Filesystem::new();
+    fs.writer()
+        .write_storage_file(Path::new("/etc/image"), Default::default(), None)
+        .unwrap();
+    fs.seal_image();
+    let link = FileStat {
+        st_mode: abi::S_IFLNK | 0o777,
+        ..Default::default()
+    };
+    let mut w = fs.writer();
+    w.write_storage_file(Path::new("/proc/1/fd/0"), Default::default(), None)
+        .unwrap();
+    w.write_symlink(
+        Path::new("/proc/1/fd/3"),
+        link,
+        CString::new("/etc/image").unwrap(),
+    )
+    .unwrap();
+    w.write_hardlink(Path::new("/proc/1/fd/4"), Path::new("/proc/1/fd/3"))
+        .unwrap();
+
+    assert!(matches!(
+        w.remove_file(Path::new("/etc/image")),
+        Err(VFSError::ReadOnly)
+    ));
+    assert!(matches!(
+        w.remove_file(Path::new("/proc/1/fd")),
+        Err(VFSError::FileExpected)
+    ));
+    assert!(matches!(
+        w.remove_file(Path::new("/proc/1/fd/5")),
+        Err(VFSError::NotFound)
+    ));
+    w.remove_file(Path::new("/proc/1/fd/3")).unwrap();
+    w.remove_file(Path::new("/proc/1/fd/0")).unwrap();
+
+    let root = Filesystem::root();
+    let fd_dir = fs
+        .lookup(&root, Path::new("/proc/1/fd"), &FollowLinks::Follow)
+        .unwrap();
+    assert_eq!(fs.list_dir(&fd_dir).unwrap(), ["4"]);
+    let fd = fs
+        .lookup(&root, Path::new("/proc/1/fd/4"), &FollowLinks::NoFollow)
+        .unwrap();
+    assert_eq!(fs.readlink(&fd).unwrap().to_bytes(), b"/etc/image");
+    assert!(fs
+        .lookup(&root, Path::new("/proc/1/fd/4"), &FollowLinks::Follow)
+        .is_ok());
+}
+
+#[test]
+fn remove_tree() {
+    let mut fs = Filesystem::new();
+    fs.writer()
+        .write_directory_metadata(Path::new("/proc"), Default::default())
+        .unwrap();
+    fs.seal_image();
+    let root = Filesystem::root();
+    let proc_dir = fs
+        .lookup(&root, Path::new("/proc"), &FollowLinks::Follow)
+        .unwrap();
+    let links = fs.stat(&proc_dir).unwrap().st_nlink;
+
+    let mut w = fs.writer();
+    w.write_storage_file(Path::new("/proc/1/fd/0"), Default::default(), None)
+        .unwrap();
+    w.write_hardlink(Path::new("/proc/1/fd/1"), Path::new("/proc/1/fd/0"))
+        .unwrap();
+    w.write_storage_file(Path::new("/proc/2/fd/0"), Default::default(), None)
+        .unwrap();
+    let fd_dir = fs
+        .lookup(&root, Path::new("/proc/1/fd"), &FollowLinks::Follow)
+        .unwrap();
+
+    let mut w = fs.writer();
+    assert!(matches!(
+        w.remove_tree(Path::new("/proc/2/fd/0")),
+        Err(VFSError::DirectoryExpected)
+    ));
+    w.remove_tree(Path::new("/proc/1")).unwrap();
+    assert!(matches!(
+        w.remove_tree(Path::new("/proc/1")),
+        Err(VFSError::NotFound)
+    ));
+
+    assert_eq!(fs.list_dir(&proc_dir).unwrap(), ["2"]);
+    assert_eq!(fs.stat(&proc_dir).unwrap().st_nlink, links + 1);
+    assert!(fs.list_dir(&fd_dir).unwrap().is_empty());
+}
+
+#[test]
+fn directory_paths() {
+    let mut fs = Filesystem::new();
+    fs.writer()
+        .write_symlink(
+            Path::new("/usr/lib"),
+            Default::default(),
+            CString::new("../lib").unwrap(),
+        )
+        .unwrap();
+    fs.writer()
+        .write_storage_file(Path::new("/lib/modules/file"), Default::default(), None)
+        .unwrap();
+
+    let root = Filesystem::root();
+    assert_eq!(fs.dir_path(&root).unwrap(), Path::new("/"));
+    let dir = fs
+        .lookup(&root, Path::new("/usr/lib/modules"), &FollowLinks::Follow)
+        .unwrap();
+    assert_eq!(fs.dir_path(&dir).unwrap(), Path::new("/lib/modules"));
+    let dir = fs
+        .lookup(&dir, Path::new("../../usr"), &FollowLinks::Follow)
+        .unwrap();
+    assert_eq!(fs.dir_path(&dir).unwrap(), Path::new("/usr"));
+
+    let dir = fs
+        .lookup(&root, Path::new("/lib/modules"), &FollowLinks::Follow)
+        .unwrap();
+    let mut w = fs.writer();
+    w.remove_file(Path::new("/lib/modules/file")).unwrap();
+    w.remove_dir(Path::new("/lib/modules")).unwrap();
+    assert!(fs.dir_path(&dir).is_err());
+}
+
+#[test]
+fn remove_overlay_directories() {
+    let mut fs = Filesystem::new();
+    fs.writer()
+        .write_storage_file(Path::new("/etc/image"), Default::default(), None)
+        .unwrap();
+    fs.seal_image();
+    fs.writer()
+        .write_storage_file(Path::new("/run/a/file"), Default::default(), None)
+        .unwrap();
+
+    let root = Filesystem::root();
+    let nlink = |fs: &Filesystem, path| {
+        let file = fs.lookup(&root, Path::new(path), &FollowLinks::Follow);
+        fs.stat(&file.unwrap()).unwrap().st_nlink
+    };
+    assert_eq!(nlink(&fs, "/run"), 3);
+
+    let mut w = fs.writer();
+    assert!(matches!(
+        w.remove_dir(Path::new("/etc")),
+        Err(VFSError::ReadOnly)
+    ));
+    assert!(matches!(
+        w.remove_dir(Path::new("/run/a")),
+        Err(VFSError::DirectoryNotEmpty)
+    ));
+    assert!(matches!(
+        w.remove_dir(Path::new("/run/a/file")),
+        Err(VFSError::DirectoryExpected)
+    ));
+
+    let run = fs
+        .lookup(&root, Path::new("/run"), &FollowLinks::Follow)
+        .unwrap();
+    let mut w = fs.writer_at(&run);
+    w.remove_file(Path::new("a/file")).unwrap();
+    w.remove_dir(Path::new("a")).unwrap();
+    assert!(fs.list_dir(&run).unwrap().is_empty());
+    assert_eq!(nlink(&fs, "/run"), 2);
+}
+
+#[test]
+fn make_overlay_directories() {
+    let mut fs = Filesystem::new();
+    fs.writer()
+        .write_storage_file(Path::new("/etc/image"), Default::default(), None)
+        .unwrap();
+    fs.seal_image();
+    fs.writer()
+        .write_storage_file(Path::new("/run/file"), Default::default(), None)
+        .unwrap();
+
+    let mut w = fs.writer();
+    assert!(matches!(
+        w.make_dir(Path::new("/etc/new"), 0o755),
+        Err(VFSError::ReadOnly)
+    ));
+    assert!(matches!(
+        w.make_dir(Path::new("/etc/image"), 0o755),
+        Err(VFSError::FileExists)
+    ));
+    assert!(matches!(
+        w.make_dir(Path::new("/run/a/b"), 0o755),
+        Err(VFSError::NotFound)
+    ));
+    assert!(matches!(
+        w.make_dir(Path::new("/run/file/a"), 0o755),
+        Err(VFSError::DirectoryExpected)
+    ));
+    w.make_dir(Path::new("/run/a"), 0o700).unwrap();
+    fs.writer_at(&Filesystem::root())
+        .make_dir(Path::new("run/a/b"), 0o750)
+        .unwrap();
+
+    let root = Filesystem::root();
+    let dir = fs
+        .lookup(&root, Path::new("/run/a/b"), &FollowLinks::Follow)
+        .unwrap();
+    assert_eq!(fs.stat(&dir).unwrap().st_mode, abi::S_IFDIR | 0o750);
+    let dir = fs
+        .lookup(&root, Path::new("/run/a"), &FollowLinks::Follow)
+        .unwrap();
+    assert_eq!(fs.stat(&dir).unwrap().st_mode, abi::S_IFDIR | 0o700);
+    assert_eq!(fs.list_dir(&dir).unwrap(), ["b"]);
+}
+
+#[test]
+fn tmpfs_files() {
+    let dir = TempDir::new().unwrap();
+    let storage = FileStorage::new(dir.path().to_owned(), None);
+    let mut fs = Filesystem::new();
+    fs.writer()
+        .write_storage_file(Path::new("/tmp/image"), Default::default(), None)
+        .unwrap();
+    fs.seal_image();
+    Tmpfs::new(Some(8))
+        .mount(&mut fs, Path::new("/tmp"))
+        .unwrap();
+
+    let root = Filesystem::root();
+    let tmp = fs
+        .lookup(&root, Path::new("/tmp"), &FollowLinks::Follow)
+        .unwrap();
+    assert!(fs.list_dir(&tmp).unwrap().is_empty());
+    assert_eq!(fs.stat(&tmp).unwrap().st_mode, abi::S_IFDIR | 0o1777);
+    assert!(matches!(
+        fs.writer().create_file(Path::new("/new"), 0o644),
+        Err(VFSError::ReadOnly)
+    ));
+
+    let mut w = fs.writer_at(&tmp);
+    w.make_dir(Path::new("dir"), 0o755).unwrap();
+    let file = w.create_file(Path::new("dir/file"), 0o640).unwrap();
+    assert!(matches!(
+        w.create_file(Path::new("dir/file"), 0o640),
+        Err(VFSError::FileExists)
+    ));
+    assert_eq!(fs.stat(&file).unwrap().st_mode, abi::S_IFREG | 0o640);
+
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    let write = |fs: &Filesystem, rt: &mut tokio::runtime::Runtime, flags, data: &[u8]| {
+        let fd = rt.block_on(fs.open_storage(&storage, &file, flags))?;
+        let mut writer = unsafe { File::from_raw_fd(libc::dup(fd.as_raw_fd())) };
+        writer.write_all(data).unwrap();
+        Ok::<_, VFSError>(())
+    };
+    write(&fs, &mut rt, libc::O_WRONLY, b"hello").unwrap();
+    write(&fs, &mut rt, libc::O_WRONLY | libc::O_APPEND, b" there").unwrap();
+    assert_eq!(fs.stat(&file).unwrap().st_size, 11);
+    assert!(matches!(
+        fs.writer().create_file(Path::new("/tmp/more"), 0o644),
+        Err(VFSError::NoSpace)
+    ));
+
+    let fd = rt
+        .block_on(fs.open_storage(&storage, &file, libc::O_RDONLY))
+        .unwrap();
+    let mut contents = String::new();
+    unsafe { File::from_raw_fd(libc::dup(fd.as_raw_fd())) }
+        .read_to_string(&mut contents)
+        .unwrap();
+    assert_eq!(contents, "hello there");
+
+    write(&fs, &mut rt, libc::O_WRONLY | libc::O_TRUNC, b"new").unwrap();
+    assert_eq!(fs.stat(&file).unwrap().st_size, 3);
+    fs.writer().remove_file(Path::new("/tmp/dir/file")).unwrap();
+    drop(fd);
+    fs.writer()
+        .create_file(Path::new("/tmp/more"), 0o644)
+        .unwrap();
+}
+
+#[test]
+fn tmpfs_write_past_limit() {
+    let dir = TempDir::new().unwrap();
+    let storage = FileStorage::new(dir.path().to_owned(), None);
+    let mut fs = Filesystem::new();
+    fs.seal_image();
+    Tmpfs::new(Some(4096))
+        .mount(&mut fs, Path::new("/tmp"))
+        .unwrap();
+    let file = fs
+        .writer()
+        .create_file(Path::new("/tmp/big"), 0o644)
+        .unwrap();
+
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    let fd = rt
+        .block_on(fs.open_storage(&storage, &file, libc::O_WRONLY))
+        .unwrap();
+    let mut writer = unsafe { File::from_raw_fd(libc::dup(fd.as_raw_fd())) };
+    writer.write_all(&[0x55; 4096]).unwrap();
+    let err = writer.write_all(&[0x55; 4096]).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOSPC));
+    assert_eq!(fs.stat(&file).unwrap().st_size, 4096);
+}
+
+#[test]
+fn host_dir_mount() {
+    let host = TempDir::new().unwrap();
+    std::fs::create_dir(host.path().join("sub")).unwrap();
+    std::fs::write(host.path().join("sub/data"), b"host data").unwrap();
+    std::os::unix::fs::symlink("sub/data", host.path().join("link")).unwrap();
+
+    let dir = TempDir::new().unwrap();
+    let storage = FileStorage::new(dir.path().to_owned(), None);
+    let mut fs = Filesystem::new();
+    fs.writer()
+        .write_storage_file(Path::new("/mnt/image"), Default::default(), None)
+        .unwrap();
+    fs.seal_image();
+    HostDir::new(host.path())
+        .mount(&mut fs, Path::new("/mnt"))
+        .unwrap();
+
+    let root = Filesystem::root();
+    let mnt = fs
+        .lookup(&root, Path::new("/mnt"), &FollowLinks::Follow)
+        .unwrap();
+    assert_eq!(fs.list_dir(&mnt).unwrap(), vec!["link", "sub"]);
+    let file = fs
+        .lookup(&root, Path::new("/mnt/link"), &FollowLinks::Follow)
+        .unwrap();
+    assert_eq!(fs.stat(&file).unwrap().st_size, 9);
+    assert!(fs.is_read_only(&file));
+    assert!(matches!(
+        fs.writer().create_file(Path::new("/mnt/sub/new"), 0o644),
+        Err(VFSError::ReadOnly)
+    ));
+
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    std::fs::write(host.path().join("sub/data"), b"changed").unwrap();
+    let fd = rt
+        .block_on(fs.open_storage(&storage, &file, libc::O_RDONLY))
+        .unwrap();
+    let mut contents = String::new();
+    unsafe { File::from_raw_fd(libc::dup(fd.as_raw_fd())) }
+        .read_to_string(&mut contents)
+        .unwrap();
+    assert_eq!(contents, "changed");
+    assert_eq!(fs.stat(&file).unwrap().st_size, 7);
+
+    std::fs::remove_file(host.path().join("sub/data")).unwrap();
+    std::os::unix::fs::symlink("/etc/passwd", host.path().join("sub/data")).unwrap();
+    assert!(rt
+        .block_on(fs.open_storage(&storage, &file, libc::O_RDONLY))
+        .is_err());
+    std::fs::remove_file(host.path().join("sub/data")).unwrap();
+    let fifo = CString::new(host.path().join("sub/data").to_str().unwrap()).unwrap();
+    assert_eq!(0, unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) });
+    assert!(matches!(
+        rt.block_on(fs.open_storage(&storage, &file, libc::O_RDONLY)),
+        Err(VFSError::FileExpected)
+    ));
+
+    std::os::unix::fs::symlink("../..", host.path().join("sub/up")).unwrap();
+    assert!(matches!(
+        HostDir::new(host.path()).mount(&mut fs, Path::new("/mnt2")),
+        Err(VFSError::PathNotBeneath)
+    ));
+    std::fs::remove_file(host.path().join("sub/up")).unwrap();
+    std::os::unix::fs::symlink("/etc", host.path().join("sub/abs")).unwrap();
+    assert!(matches!(
+        HostDir::new(host.path()).mount(&mut fs, Path::new("/mnt2")),
+        Err(VFSError::PathNotBeneath)
+    ));
+}
+
+#[test]
+fn memory_files() {
+    let dir = TempDir::new().unwrap();
+    let storage = FileStorage::new(dir.path().to_owned(), None);
+    let mut fs = Filesystem::new();
+    fs.writer()
+        .write_storage_file(Path::new("/etc/hosts"), Default::default(), None)
+        .unwrap();
+    fs.seal_image();
+    let mut w = fs.writer();
+    w.write_memory_file(Path::new("/etc/hosts"), 0o644, b"127.0.0.1 localhost\n")
+        .unwrap();
+    w.write_memory_file(Path::new("/opt/app/config"), 0o100600, b"")
+        .unwrap();
+
+    let root = Filesystem::root();
+    let lookup = |path: &str| fs.lookup(&root, Path::new(path), &FollowLinks::Follow);
+    let hosts = lookup("/etc/hosts").unwrap();
+    let stat = fs.stat(&hosts).unwrap();
+    assert_eq!(stat.st_mode, abi::S_IFREG | 0o644);
+    assert_eq!(stat.st_size, 20);
+    let config = lookup("/opt/app/config").unwrap();
+    assert_eq!(fs.stat(&config).unwrap().st_mode, abi::S_IFREG | 0o600);
+
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    let read = |rt: &mut tokio::runtime::Runtime| {
+        let fd = rt
+            .block_on(fs.open_storage(&storage, &hosts, libc::O_RDONLY))
+            .unwrap();
+        let mut contents = String::new();
+        unsafe { File::from_raw_fd(libc::dup(fd.as_raw_fd())) }
+            .read_to_string(&mut contents)
+            .unwrap();
+        contents
+    };
+    assert_eq!(read(&mut rt), "127.0.0.1 localhost\n");
+    assert_eq!(read(&mut rt), "127.0.0.1 localhost\n");
+    assert!(matches!(
+        rt.block_on(fs.open_storage(&storage, &hosts, libc::O_RDWR)),
+        Err(VFSError::ReadOnly)
+    ));
+    assert_eq!(
+        rt.block_on(fs.read_to_vec(&storage, &hosts, 1024)).unwrap(),
+        b"127.0.0.1 localhost\n"
+    );
+}
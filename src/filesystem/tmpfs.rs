@@ -0,0 +1,247 @@
+use crate::{
+    errors::VFSError,
+    filesystem::{mount::Mount, vfs::Filesystem},
+};
+use std::{
+    ffi::CString,
+    fmt,
+    fs::{File, OpenOptions},
+    io, mem,
+    os::unix::{
+        ffi::OsStrExt,
+        io::{AsRawFd, FromRawFd, RawFd},
+    },
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+/// A writable directory whose files live in memory
+///
+/// Each file's contents are in host memory, which the container reads and
+/// writes directly. Everything is discarded once the container and any other
+/// users of the filesystem are gone.
+///
+/// Without a size limit, files are memfds. With one, they live on a private
+/// host tmpfs mounted with that size, so the kernel fails any write past it
+/// with `ENOSPC`, even through a file that's already open. The limit is
+/// rounded up to whole pages. Making that mount needs unprivileged user
+/// namespaces; where they aren't available, mounting a size-limited [Tmpfs]
+/// fails.
+#[derive(Clone)]
+pub struct Tmpfs {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    size_limit: Option<u64>,
+    host_dir: Mutex<Option<Arc<File>>>,
+}
+
+impl Tmpfs {
+    pub fn new(size_limit: Option<u64>) -> Self {
+        Tmpfs {
+            inner: Arc::new(Inner {
+                size_limit,
+                host_dir: Mutex::new(None),
+            }),
+        }
+    }
+
+    /// The root of the host tmpfs holding our files, if there's a size limit
+    ///
+    /// It's mounted on first use, and shared by every copy of this [Tmpfs].
+    fn host_dir(&self) -> Result<Option<Arc<File>>, VFSError> {
+        let size_limit = match self.inner.size_limit {
+            None => return Ok(None),
+            Some(limit) => limit,
+        };
+        let mut host_dir = self.inner.host_dir.lock().unwrap();
+        if host_dir.is_none() {
+            let dir = mount_host_tmpfs(size_limit).map_err(|err| {
+                log::warn!("can't mount a size-limited tmpfs, {}", err);
+                VFSError::IO
+            })?;
+            *host_dir = Some(Arc::new(dir));
+        }
+        Ok(host_dir.clone())
+    }
+
+    /// Fail with `ENOSPC` if there's no room left to write
+    ///
+    /// Writes are limited by the kernel, this only keeps new files from
+    /// being created once every page is in use.
+    fn check_space(&self) -> Result<(), VFSError> {
+        if let Some(dir) = self.host_dir()? {
+            let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+            if unsafe { libc::fstatvfs(dir.as_raw_fd(), &mut stat) } != 0 {
+                return Err(VFSError::IO);
+            }
+            if stat.f_bavail == 0 {
+                return Err(VFSError::NoSpace);
+            }
+        }
+        Ok(())
+    }
+
+    /// Make an empty file, with no name on the host
+    pub(crate) fn create_file(&self) -> Result<Arc<File>, VFSError> {
+        self.check_space()?;
+        let file = match self.host_dir()? {
+            None => memfd::MemfdOptions::default()
+                .create("bandsocks-tmpfs")
+                .map_err(|_| VFSError::IO)?
+                .into_file(),
+            Some(dir) => {
+                let fd = unsafe {
+                    libc::openat(
+                        dir.as_raw_fd(),
+                        b".\0".as_ptr() as *const libc::c_char,
+                        libc::O_TMPFILE | libc::O_RDWR | libc::O_CLOEXEC,
+                        0o600,
+                    )
+                };
+                if fd < 0 {
+                    return Err(VFSError::IO);
+                }
+                unsafe { File::from_raw_fd(fd) }
+            }
+        };
+        Ok(Arc::new(file))
+    }
+
+    /// Open a file again with its own offset and access mode
+    pub(crate) fn open_file(&self, file: &File, flags: i32) -> Result<File, VFSError> {
+        let access = flags & libc::O_ACCMODE;
+        let writing = access != libc::O_RDONLY;
+        let truncating = writing && flags & libc::O_TRUNC != 0;
+        let reopened = OpenOptions::new()
+            .read(access != libc::O_WRONLY)
+            .write(writing)
+            .append(flags & libc::O_APPEND != 0)
+            .open(format!("/proc/self/fd/{}", file.as_raw_fd()))
+            .map_err(|_| VFSError::IO)?;
+        if truncating {
+            reopened.set_len(0).map_err(|_| VFSError::IO)?;
+        }
+        Ok(reopened)
+    }
+}
+
+impl fmt::Debug for Tmpfs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Tmpfs")
+            .field("size_limit", &self.inner.size_limit)
+            .finish()
+    }
+}
+
+impl Mount for Tmpfs {
+    fn mount(&self, fs: &mut Filesystem, path: &Path) -> Result<(), VFSError> {
+        // Any trouble with the host mount shows up now rather than on first use
+        self.host_dir()?;
+        fs.writer().write_tmpfs(path, self.clone())
+    }
+}
+
+/// Everything the mount helper needs, prepared before it starts
+struct MountArgs {
+    setgroups: CString,
+    uid_map: CString,
+    gid_map: CString,
+    target: CString,
+    options: CString,
+    result_fd: RawFd,
+}
+
+/// Mount a host tmpfs limited to `size_limit` bytes, returning its root
+///
+/// A child in new user and mount namespaces makes the mount, and leaves its
+/// root open in the file table we share. The directory stays usable after the
+/// child exits and its namespace goes away, and nothing else can reach it.
+fn mount_host_tmpfs(size_limit: u64) -> io::Result<File> {
+    // The child's fd replaces this one, so we know where to find it
+    let placeholder = File::open("/dev/null")?;
+    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    let args = MountArgs {
+        setgroups: CString::new("deny")?,
+        uid_map: CString::new(format!("0 {} 1", uid))?,
+        gid_map: CString::new(format!("0 {} 1", gid))?,
+        target: CString::new(std::env::temp_dir().as_os_str().as_bytes())?,
+        options: CString::new(format!("size={},mode=700", size_limit))?,
+        result_fd: placeholder.as_raw_fd(),
+    };
+
+    let mut stack = vec![0u8; 64 * 1024];
+    let flags = libc::CLONE_NEWUSER
+        | libc::CLONE_NEWNS
+        | libc::CLONE_FILES
+        | libc::CLONE_VFORK
+        | libc::SIGCHLD;
+    let pid = unsafe {
+        libc::clone(
+            mount_host_tmpfs_child,
+            stack.as_mut_ptr().add(stack.len()) as *mut libc::c_void,
+            flags,
+            &args as *const MountArgs as *mut libc::c_void,
+        )
+    };
+    if pid < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut status = 0;
+    if unsafe { libc::waitpid(pid, &mut status, 0) } != pid {
+        return Err(io::Error::last_os_error());
+    }
+    match libc::WIFEXITED(status).then(|| libc::WEXITSTATUS(status)) {
+        Some(0) => Ok(placeholder),
+        Some(errno) => Err(io::Error::from_raw_os_error(errno)),
+        None => Err(io::Error::from_raw_os_error(libc::EIO)),
+    }
+}
+
+/// Runs in the mount helper, which may only make system calls
+extern "C" fn mount_host_tmpfs_child(args: *mut libc::c_void) -> libc::c_int {
+    let args = unsafe { &*(args as *const MountArgs) };
+    let steps = || unsafe {
+        write_proc_self(b"/proc/self/setgroups\0", &args.setgroups)?;
+        write_proc_self(b"/proc/self/uid_map\0", &args.uid_map)?;
+        write_proc_self(b"/proc/self/gid_map\0", &args.gid_map)?;
+        check(libc::mount(
+            b"tmpfs\0".as_ptr() as *const libc::c_char,
+            args.target.as_ptr(),
+            b"tmpfs\0".as_ptr() as *const libc::c_char,
+            libc::MS_NOSUID | libc::MS_NODEV,
+            args.options.as_ptr() as *const libc::c_void,
+        ))?;
+        let dir = check(libc::open(
+            args.target.as_ptr(),
+            libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC,
+        ))?;
+        check(libc::dup3(dir, args.result_fd, libc::O_CLOEXEC))?;
+        check(libc::close(dir))
+    };
+    match steps() {
+        Ok(_) => 0,
+        Err(errno) => errno.min(255),
+    }
+}
+
+unsafe fn write_proc_self(path: &[u8], contents: &CString) -> Result<libc::c_int, libc::c_int> {
+    let fd = check(libc::open(
+        path.as_ptr() as *const libc::c_char,
+        libc::O_WRONLY | libc::O_CLOEXEC,
+    ))?;
+    let bytes = contents.as_bytes();
+    let result = libc::write(fd, bytes.as_ptr() as *const libc::c_void, bytes.len());
+    libc::close(fd);
+    check(result as libc::c_int)
+}
+
+/// Turn a libc result into the errno it set
+unsafe fn check(result: libc::c_int) -> Result<libc::c_int, libc::c_int> {
+    if result < 0 {
+        Err(*libc::__errno_location())
+    } else {
+        Ok(result)
+    }
+}
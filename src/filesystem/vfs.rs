@@ -3,24 +3,62 @@ use crate::{
     filesystem::{
         socket::SharedStream,
         storage::{FileStorage, StorageKey},
+        tmpfs::Tmpfs,
     },
-    sand::protocol::{abi, abi::DirentHeader, FileStat, FollowLinks, INodeNum, VFile},
+    sand::protocol::{abi, abi::DirentHeader, FileStat, FollowLinks, INodeNum, VFile, VPid},
 };
 use plain::Plain;
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     convert::TryInto,
     ffi::{CStr, CString, OsStr, OsString},
-    fs::File,
-    io::{BufWriter, Seek, SeekFrom, Write},
-    os::unix::{ffi::OsStrExt, io::AsRawFd},
-    path::Path,
+    fs::{File, OpenOptions},
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    os::unix::{ffi::OsStrExt, fs::OpenOptionsExt, io::AsRawFd},
+    path::{Path, PathBuf},
     sync::Arc,
 };
 
 #[derive(Clone)]
 pub struct Filesystem {
     inodes: Vec<Option<Arc<INode>>>,
+    first_overlay_inode: INodeNum,
+    read_only: BTreeSet<INodeNum>,
+    tmpfs_dirs: BTreeMap<INodeNum, Tmpfs>,
+    path_limits: PathLimits,
+    current_task: CString,
+    generation: u64,
+}
+
+/// Limits on the work done resolving any one path
+///
+/// These bound the cost of lookups in untrusted images. Every directory
+/// traversed, including those in symlink targets, counts as a path segment.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct PathLimits {
+    /// Most path components visited during one lookup
+    pub path_segments: usize,
+    /// Most symbolic links followed during one lookup
+    pub symbolic_links: usize,
+}
+
+impl Default for PathLimits {
+    fn default() -> Self {
+        PathLimits {
+            path_segments: 1000,
+            symbolic_links: 50,
+        }
+    }
+}
+
+/// Restrictions on one path lookup, like the `openat2` resolve flags
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct ResolveFlags {
+    /// Fail on any symbolic link, including in the last component
+    pub no_symlinks: bool,
+    /// Fail on absolute paths, including symlink targets, and on `..` out of
+    /// the starting directory
+    pub beneath: bool,
 }
 
 pub struct VFSWriter<'f> {
@@ -32,6 +70,7 @@ pub struct VFSWriter<'f> {
 struct INode {
     stat: FileStat,
     data: Node,
+    xattrs: Option<BTreeMap<OsString, Vec<u8>>>,
 }
 
 #[derive(Debug, Clone)]
@@ -45,13 +84,26 @@ enum Node {
     NormalDirectory(BTreeMap<OsString, INodeNum>),
     FileStorage(StorageKey),
     SharedStream(SharedStream),
+    Tmpfs(Arc<File>, Tmpfs),
+    HostFile(PathBuf),
+    MemoryFile(Arc<File>),
     EmptyFile,
     SymbolicLink(CString),
+    /// Link to the current task's pid, like the kernel's `/proc/self`
+    SelfLink,
     Char(u32, u32),
     Block(u32, u32),
     Fifo,
 }
 
+/// How [VFSWriter::set_xattr()] treats an existing attribute
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum XAttrFlags {
+    CreateOrReplace,
+    Create,
+    Replace,
+}
+
 #[repr(C)]
 struct PlainDirentHeader(DirentHeader);
 
@@ -61,6 +113,8 @@ unsafe impl Plain for PlainDirentHeader {}
 struct Limits {
     path_segment: usize,
     symbolic_link: usize,
+    no_symlinks: bool,
+    beneath: Option<INodeNum>,
 }
 
 impl DirEntryRef {
@@ -73,13 +127,23 @@ impl DirEntryRef {
 }
 
 impl Limits {
-    fn reset() -> Self {
+    fn reset(path_limits: &PathLimits) -> Self {
         Limits {
-            path_segment: 1000,
-            symbolic_link: 50,
+            path_segment: path_limits.path_segments,
+            symbolic_link: path_limits.symbolic_links,
+            no_symlinks: false,
+            beneath: None,
         }
     }
 
+    fn restrict(mut self, dir: INodeNum, flags: &ResolveFlags) -> Self {
+        self.no_symlinks = flags.no_symlinks;
+        if flags.beneath {
+            self.beneath = Some(dir);
+        }
+        self
+    }
+
     fn take_path_segment(&mut self) -> Result<(), VFSError> {
         if self.path_segment > 0 {
             self.path_segment -= 1;
@@ -90,7 +154,9 @@ impl Limits {
     }
 
     fn take_symbolic_link(&mut self) -> Result<(), VFSError> {
-        if self.symbolic_link > 0 {
+        if self.no_symlinks {
+            Err(VFSError::SymbolicLinkNotAllowed)
+        } else if self.symbolic_link > 0 {
             self.symbolic_link -= 1;
             Ok(())
         } else {
@@ -101,17 +167,85 @@ impl Limits {
 
 impl<'s> Filesystem {
     pub fn new() -> Self {
-        let mut fs = Filesystem { inodes: vec![None] };
+        let mut fs = Filesystem {
+            inodes: vec![None],
+            first_overlay_inode: 0,
+            read_only: BTreeSet::new(),
+            tmpfs_dirs: BTreeMap::new(),
+            path_limits: Default::default(),
+            current_task: CString::new("1").unwrap(),
+            generation: 0,
+        };
         let root = Filesystem::root().inode;
         fs.writer().put_directory(root);
         fs
     }
 
     pub fn writer<'f>(&'f mut self) -> VFSWriter<'f> {
-        let workdir = Filesystem::root();
+        self.writer_at(&Filesystem::root())
+    }
+
+    /// A writer that resolves relative paths from `dir` instead of the root
+    pub fn writer_at<'f>(&'f mut self, dir: &VFile) -> VFSWriter<'f> {
+        self.generation += 1;
+        let workdir = dir.clone();
         VFSWriter { workdir, fs: self }
     }
 
+    /// Change the limits applied to every later path lookup
+    pub fn set_path_limits(&mut self, path_limits: PathLimits) {
+        self.generation += 1;
+        self.path_limits = path_limits;
+    }
+
+    /// A number that changes whenever lookups might start giving different
+    /// results, because there's been a writer or new limits
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Mark all inodes so far as part of the read-only image
+    ///
+    /// Inodes created afterward, by mounts for example, are part of the
+    /// writable overlay.
+    pub fn seal_image(&mut self) {
+        self.first_overlay_inode = self.inodes.len();
+    }
+
+    fn is_overlay(&self, num: INodeNum) -> bool {
+        num >= self.first_overlay_inode
+    }
+
+    /// Make everything currently reachable under a path immutable
+    ///
+    /// This includes overlay inodes, which would otherwise be writable. Hard
+    /// links to the same inodes from elsewhere become read-only too.
+    pub fn set_read_only(&mut self, path: &Path) -> Result<(), VFSError> {
+        let top = self.lookup(&Filesystem::root(), path, &FollowLinks::Follow)?;
+        let mut pending = vec![top.inode];
+        while let Some(num) = pending.pop() {
+            if self.read_only.insert(num) {
+                if let Node::NormalDirectory(map) = &self.get_inode(num)?.data {
+                    pending.extend(
+                        map.iter()
+                            .filter(|(name, _)| *name != "." && *name != "..")
+                            .map(|(_, child)| *child),
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Is this file part of a subtree marked with [Filesystem::set_read_only()]
+    pub fn is_read_only(&self, f: &VFile) -> bool {
+        self.read_only.contains(&f.inode)
+    }
+
+    fn is_writable(&self, num: INodeNum) -> bool {
+        self.is_overlay(num) && !self.read_only.contains(&num)
+    }
+
     fn get_inode(&self, num: INodeNum) -> Result<&INode, VFSError> {
         match self.inodes.get(num) {
             None => Err(VFSError::UnallocNode),
@@ -127,18 +261,28 @@ impl<'s> Filesystem {
         mut limits: &mut Limits,
         mut entry: DirEntryRef,
     ) -> Result<DirEntryRef, VFSError> {
-        while let Node::SymbolicLink(cstr) = &self.get_inode(entry.child)?.data {
+        // relative targets depend on the parent directory too, so a link is
+        // identified by its directory entry rather than its inode alone
+        let mut visited = BTreeSet::new();
+        while let Some(cstr) = self.link_target(&self.get_inode(entry.child)?.data) {
             log::trace!("following symlink, {:?} -> {:?}", entry, cstr);
+            if !visited.insert((entry.parent, entry.child)) {
+                return Err(VFSError::SymbolicLinkLoop);
+            }
             limits.take_symbolic_link()?;
-            entry = self.resolve_path(
-                &mut limits,
-                entry.parent,
-                Path::new(cstr.as_c_str().to_str()?),
-            )?;
+            entry = self.resolve_path(&mut limits, entry.parent, Path::new(cstr.to_str()?))?;
         }
         Ok(entry)
     }
 
+    fn link_target<'a>(&'a self, node: &'a Node) -> Option<&'a CStr> {
+        match node {
+            Node::SymbolicLink(cstr) => Some(cstr.as_c_str()),
+            Node::SelfLink => Some(self.current_task.as_c_str()),
+            _ => None,
+        }
+    }
+
     fn resolve_path_segment(
         &self,
         limits: &mut Limits,
@@ -146,7 +290,11 @@ impl<'s> Filesystem {
         part: &OsStr,
     ) -> Result<DirEntryRef, VFSError> {
         limits.take_path_segment()?;
-        if part == "/" {
+        if limits.beneath.is_some()
+            && (part == "/" || (part == ".." && limits.beneath == Some(parent)))
+        {
+            Err(VFSError::PathNotBeneath)
+        } else if part == "/" {
             Ok(DirEntryRef::root())
         } else {
             match &self.get_inode(parent)?.data {
@@ -195,6 +343,12 @@ impl<'s> Filesystem {
         result
     }
 
+    /// Set the task whose request is being handled, which `/proc/self`
+    /// links to
+    pub fn set_current_task(&mut self, task: VPid) {
+        self.current_task = CString::new(task.0.to_string()).unwrap();
+    }
+
     pub fn root() -> VFile {
         VFile { inode: 0 }
     }
@@ -205,7 +359,18 @@ impl<'s> Filesystem {
         path: &Path,
         follow_links: &FollowLinks,
     ) -> Result<VFile, VFSError> {
-        let mut limits = Limits::reset();
+        self.lookup_restricted(dir, path, follow_links, &ResolveFlags::default())
+    }
+
+    /// Look up a path with extra restrictions, for `openat2`
+    pub fn lookup_restricted(
+        &self,
+        dir: &VFile,
+        path: &Path,
+        follow_links: &FollowLinks,
+        flags: &ResolveFlags,
+    ) -> Result<VFile, VFSError> {
+        let mut limits = Limits::reset(&self.path_limits).restrict(dir.inode, flags);
         let entry = self.resolve_path(&mut limits, dir.inode, path)?;
         let entry = match follow_links {
             FollowLinks::NoFollow => entry,
@@ -221,25 +386,41 @@ impl<'s> Filesystem {
         Ok(VFile { inode: entry.child })
     }
 
-    pub fn stat(&self, f: &VFile) -> Result<&FileStat, VFSError> {
-        let stat = &self.get_inode(f.inode)?.stat;
+    /// Get a file's metadata, with the current size of tmpfs files
+    pub fn stat(&self, f: &VFile) -> Result<FileStat, VFSError> {
+        let node = self.get_inode(f.inode)?;
+        let mut stat = node.stat.clone();
+        match &node.data {
+            Node::Tmpfs(file, _) => {
+                stat.st_size = file.metadata().map_err(|_| VFSError::IO)?.len() as i64;
+            }
+            Node::HostFile(path) => {
+                let file = open_host_file(path)?;
+                stat.st_size = file.metadata().map_err(|_| VFSError::IO)?.len() as i64;
+            }
+            _ => (),
+        }
         log::debug!("stat({:?}) -> {:?}", f, stat);
         Ok(stat)
     }
 
     pub fn readlink(&self, f: &VFile) -> Result<&CStr, VFSError> {
-        let cstr = match &self.get_inode(f.inode)?.data {
-            Node::SymbolicLink(path) => path.as_c_str(),
-            _ => return Err(VFSError::LinkExpected),
-        };
+        let cstr = self
+            .link_target(&self.get_inode(f.inode)?.data)
+            .ok_or(VFSError::LinkExpected)?;
         log::debug!("readlink({:?}) -> {:?}", f, cstr);
         Ok(cstr)
     }
 
+    /// Open a file's contents for the container
+    ///
+    /// Only tmpfs files are writable, and only they look at the `open()`
+    /// flags.
     pub async fn open_storage(
         &self,
         storage: &FileStorage,
         f: &VFile,
+        flags: i32,
     ) -> Result<Arc<dyn AsRawFd + Sync + Send>, VFSError> {
         let node = self.get_inode(f.inode)?;
         match &node.data {
@@ -247,8 +428,103 @@ impl<'s> Filesystem {
             Node::NormalDirectory(dir) => self.open_directory(dir),
             Node::SharedStream(stream) => stream.vfile_open(),
             Node::FileStorage(key) => open_storage_part(storage, key).await,
+            Node::Tmpfs(file, tmpfs) => Ok(Arc::new(tmpfs.open_file(file, flags)?)),
+            Node::HostFile(path) => Ok(Arc::new(open_host_file(path)?)),
+            Node::MemoryFile(_) if flags & libc::O_ACCMODE != libc::O_RDONLY => {
+                Err(VFSError::ReadOnly)
+            }
+            Node::MemoryFile(file) => Ok(Arc::new(reopen_memory_file(file)?)),
+            _ => return Err(VFSError::FileExpected),
+        }
+    }
+
+    /// Read a regular file's entire contents, if it's no longer than `limit`
+    pub async fn read_to_vec(
+        &self,
+        storage: &FileStorage,
+        f: &VFile,
+        limit: u64,
+    ) -> Result<Vec<u8>, VFSError> {
+        let node = self.get_inode(f.inode)?;
+        if node.stat.st_size as u64 > limit {
+            return Err(VFSError::FileTooLarge);
+        }
+        let file = match &node.data {
+            Node::EmptyFile => return Ok(Vec::new()),
+            Node::FileStorage(key) => storage
+                .open_part(key)
+                .await
+                .ok()
+                .flatten()
+                .ok_or(VFSError::ImageStorageError)?,
+            Node::HostFile(path) => open_host_file(path)?,
+            Node::MemoryFile(file) => reopen_memory_file(file)?,
             _ => return Err(VFSError::FileExpected),
+        };
+        let mut contents = Vec::with_capacity(node.stat.st_size as usize);
+        file.take(limit + 1)
+            .read_to_end(&mut contents)
+            .map_err(|_| VFSError::IO)?;
+        if contents.len() as u64 > limit {
+            return Err(VFSError::FileTooLarge);
+        }
+        log::debug!("read_to_vec({:?}) -> {} bytes", f, contents.len());
+        Ok(contents)
+    }
+
+    /// Find the storage holding a regular file's contents, or None if the
+    /// file is empty
+    pub fn storage_key(&self, f: &VFile) -> Result<Option<&StorageKey>, VFSError> {
+        match &self.get_inode(f.inode)?.data {
+            Node::EmptyFile => Ok(None),
+            Node::FileStorage(key) => Ok(Some(key)),
+            _ => Err(VFSError::FileExpected),
+        }
+    }
+
+    /// Major and minor numbers for a character or block device, or None for
+    /// any other kind of file
+    pub fn device_numbers(&self, f: &VFile) -> Result<Option<(u32, u32)>, VFSError> {
+        match &self.get_inode(f.inode)?.data {
+            Node::Char(major, minor) | Node::Block(major, minor) => Ok(Some((*major, *minor))),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn get_xattr(&self, f: &VFile, name: &OsStr) -> Result<&[u8], VFSError> {
+        let node = self.get_inode(f.inode)?;
+        let value = match node.xattrs.as_ref().and_then(|map| map.get(name)) {
+            Some(value) => value,
+            None => return Err(VFSError::AttributeNotFound),
+        };
+        log::debug!("get_xattr({:?}, {:?}) -> {:?}", f, name, value);
+        Ok(value)
+    }
+
+    /// List extended attribute names, each followed by a nul byte
+    pub fn list_xattr(&self, f: &VFile) -> Result<Vec<u8>, VFSError> {
+        let node = self.get_inode(f.inode)?;
+        let mut list = Vec::new();
+        for name in node.xattrs.iter().flat_map(|map| map.keys()) {
+            list.extend_from_slice(name.as_bytes());
+            list.push(0);
         }
+        log::debug!("list_xattr({:?}) -> {:?}", f, list);
+        Ok(list)
+    }
+
+    /// List the names in a directory, not including "." and ".."
+    pub fn list_dir(&self, f: &VFile) -> Result<Vec<OsString>, VFSError> {
+        let names = match &self.get_inode(f.inode)?.data {
+            Node::NormalDirectory(map) => map
+                .keys()
+                .filter(|name| *name != "." && *name != "..")
+                .cloned()
+                .collect(),
+            _ => return Err(VFSError::DirectoryExpected),
+        };
+        log::debug!("list_dir({:?}) -> {:?}", f, names);
+        Ok(names)
     }
 
     pub fn is_directory(&self, f: &VFile) -> Result<bool, VFSError> {
@@ -259,6 +535,38 @@ impl<'s> Filesystem {
         }
     }
 
+    /// Find the absolute path of a directory, by following ".." up to the
+    /// root and looking for each directory's name in its parent
+    ///
+    /// A directory that has been removed, or whose parent has, isn't found.
+    pub fn dir_path(&self, f: &VFile) -> Result<PathBuf, VFSError> {
+        let mut limits = Limits::reset(&self.path_limits);
+        let mut names = Vec::new();
+        let mut child = f.inode;
+        while child != Filesystem::root().inode {
+            limits.take_path_segment()?;
+            let parent = match &self.get_inode(child)?.data {
+                Node::NormalDirectory(map) => {
+                    *map.get(OsStr::new("..")).ok_or(VFSError::NotFound)?
+                }
+                _ => return Err(VFSError::DirectoryExpected),
+            };
+            let name = match &self.get_inode(parent)?.data {
+                Node::NormalDirectory(map) => map
+                    .iter()
+                    .find(|(name, num)| **num == child && *name != "." && *name != "..")
+                    .map(|(name, _)| name),
+                _ => None,
+            };
+            names.push(name.ok_or(VFSError::NotFound)?);
+            child = parent;
+        }
+        let mut path = PathBuf::from("/");
+        path.extend(names.into_iter().rev());
+        log::debug!("dir_path({:?}) -> {:?}", f, path);
+        Ok(path)
+    }
+
     fn dir_entry_type(&self, inode: INodeNum) -> Result<u8, VFSError> {
         let stat = &self.get_inode(inode)?.stat;
         Ok(match stat.st_mode & abi::S_IFMT {
@@ -321,6 +629,7 @@ impl<'f> VFSWriter<'f> {
                     ..Default::default()
                 },
                 data: Node::NormalDirectory(map),
+                xattrs: None,
             },
         );
     }
@@ -399,7 +708,7 @@ impl<'f> VFSWriter<'f> {
         path: &Path,
         stat: FileStat,
     ) -> Result<(), VFSError> {
-        let mut limits = Limits::reset();
+        let mut limits = Limits::reset(&self.fs.path_limits);
         let entry = self.resolve_or_create_path(&mut limits, self.workdir.inode, path)?;
         let entry = self.fs.resolve_symlinks(&mut limits, entry)?;
         let inode = self.get_inode_mut(entry.child)?;
@@ -412,10 +721,17 @@ impl<'f> VFSWriter<'f> {
     }
 
     fn write_node_file(&mut self, path: &Path, stat: FileStat, data: Node) -> Result<(), VFSError> {
-        let mut limits = Limits::reset();
+        let mut limits = Limits::reset(&self.fs.path_limits);
         let (dir, name) = self.resolve_or_create_parent(&mut limits, path)?;
         let num = self.alloc_inode_number();
-        self.put_inode(num, INode { stat, data });
+        self.put_inode(
+            num,
+            INode {
+                stat,
+                data,
+                xattrs: None,
+            },
+        );
         self.add_child_to_directory(dir, name, num)?;
         Ok(())
     }
@@ -445,6 +761,40 @@ impl<'f> VFSWriter<'f> {
         self.write_node_file(path, stat, Node::SharedStream(stream))
     }
 
+    /// Add a file whose contents are opened from the host each time
+    pub fn write_host_file(
+        &mut self,
+        path: &Path,
+        stat: FileStat,
+        host_path: PathBuf,
+    ) -> Result<(), VFSError> {
+        self.write_node_file(path, stat, Node::HostFile(host_path))
+    }
+
+    /// Add a read-only file holding a copy of `contents`, in a sealed memfd
+    pub fn write_memory_file(
+        &mut self,
+        path: &Path,
+        mode: u32,
+        contents: &[u8],
+    ) -> Result<(), VFSError> {
+        let memfd = memfd::MemfdOptions::default()
+            .allow_sealing(true)
+            .create("bandsocks-file")
+            .map_err(|_| VFSError::IO)?;
+        memfd
+            .as_file()
+            .write_all(contents)
+            .map_err(|_| VFSError::IO)?;
+        seal_memfd(&memfd).map_err(|_| VFSError::IO)?;
+        let stat = FileStat {
+            st_mode: abi::S_IFREG | (mode & 0o7777),
+            st_size: contents.len() as i64,
+            ..Default::default()
+        };
+        self.write_node_file(path, stat, Node::MemoryFile(Arc::new(memfd.into_file())))
+    }
+
     pub fn write_symlink(
         &mut self,
         path: &Path,
@@ -454,8 +804,14 @@ impl<'f> VFSWriter<'f> {
         self.write_node_file(path, stat, Node::SymbolicLink(link_to))
     }
 
+    /// Write a link that always leads to the current task's pid, for
+    /// `/proc/self`
+    pub fn write_self_link(&mut self, path: &Path, stat: FileStat) -> Result<(), VFSError> {
+        self.write_node_file(path, stat, Node::SelfLink)
+    }
+
     pub fn write_hardlink(&mut self, path: &Path, link_to: &Path) -> Result<(), VFSError> {
-        let mut limits = Limits::reset();
+        let mut limits = Limits::reset(&self.fs.path_limits);
         let link_to_node = self
             .fs
             .resolve_path(&mut limits, self.workdir.inode, link_to)?
@@ -489,6 +845,330 @@ impl<'f> VFSWriter<'f> {
         self.write_node_file(path, stat, Node::Block(major, minor))
     }
 
+    /// Remove anything at a path while building the image, for a whiteout in
+    /// an upper layer
+    ///
+    /// Unlike [VFSWriter::remove_file()] this skips the overlay check, and
+    /// takes whole directories along with their contents. A path that doesn't
+    /// exist is left alone.
+    pub fn write_whiteout(&mut self, path: &Path) -> Result<(), VFSError> {
+        let mut limits = Limits::reset(&self.fs.path_limits);
+        let entry = match self.fs.resolve_path(&mut limits, self.workdir.inode, path) {
+            Ok(entry) => entry,
+            Err(VFSError::NotFound) => return Ok(()),
+            Err(other) => return Err(other),
+        };
+        let name = path.file_name().ok_or(VFSError::NotFound)?;
+        self.unlink_child(entry.parent, name, entry.child)
+    }
+
+    /// Remove the contents a directory had from lower image layers, for an
+    /// opaque whiteout
+    ///
+    /// Entries that `keep` accepts by name were written by the current layer
+    /// and stay. The directory itself is created if it's missing.
+    pub fn write_opaque_directory<F: Fn(&OsStr) -> bool>(
+        &mut self,
+        path: &Path,
+        keep: F,
+    ) -> Result<(), VFSError> {
+        let mut limits = Limits::reset(&self.fs.path_limits);
+        let entry = self.resolve_or_create_path(&mut limits, self.workdir.inode, path)?;
+        let dir = self.fs.resolve_symlinks(&mut limits, entry)?.child;
+        let hidden: Vec<(OsString, INodeNum)> = match &self.fs.get_inode(dir)?.data {
+            Node::NormalDirectory(map) => map
+                .iter()
+                .filter(|(name, _)| *name != "." && *name != ".." && !keep(name))
+                .map(|(name, child)| (name.clone(), *child))
+                .collect(),
+            _ => return Err(VFSError::DirectoryExpected),
+        };
+        for (name, child) in hidden {
+            self.unlink_child(dir, &name, child)?;
+        }
+        Ok(())
+    }
+
+    /// Take one entry out of a directory, including the link a child
+    /// directory's `..` held on its parent
+    fn unlink_child(
+        &mut self,
+        parent: INodeNum,
+        name: &OsStr,
+        child: INodeNum,
+    ) -> Result<(), VFSError> {
+        match &mut self.get_inode_mut(parent)?.data {
+            Node::NormalDirectory(map) => map.remove(name),
+            _ => Err(VFSError::DirectoryExpected)?,
+        };
+        self.inode_decref(child)?;
+        if let Node::NormalDirectory(_) = self.fs.get_inode(child)?.data {
+            self.inode_decref(parent)?;
+        }
+        Ok(())
+    }
+
+    /// Set an extended attribute while building the image, bypassing the
+    /// overlay check
+    pub fn write_xattr(&mut self, path: &Path, name: &OsStr, value: &[u8]) -> Result<(), VFSError> {
+        let mut limits = Limits::reset(&self.fs.path_limits);
+        let entry = self
+            .fs
+            .resolve_path(&mut limits, self.workdir.inode, path)?;
+        let inode = self.get_inode_mut(entry.child)?;
+        inode
+            .xattrs
+            .get_or_insert_with(BTreeMap::new)
+            .insert(name.to_os_string(), value.to_vec());
+        Ok(())
+    }
+
+    /// Set an extended attribute at runtime, only allowed on writable overlay
+    /// inodes
+    pub fn set_xattr(
+        &mut self,
+        f: &VFile,
+        name: &OsStr,
+        value: &[u8],
+        flags: XAttrFlags,
+    ) -> Result<(), VFSError> {
+        log::debug!("set_xattr({:?}, {:?}, {:?}, {:?})", f, name, value, flags);
+        if !self.fs.is_writable(f.inode) {
+            return Err(VFSError::ReadOnly);
+        }
+        let inode = self.get_inode_mut(f.inode)?;
+        let map = inode.xattrs.get_or_insert_with(BTreeMap::new);
+        match (flags, map.contains_key(name)) {
+            (XAttrFlags::Create, true) => Err(VFSError::AttributeExists),
+            (XAttrFlags::Replace, false) => Err(VFSError::AttributeNotFound),
+            _ => {
+                map.insert(name.to_os_string(), value.to_vec());
+                Ok(())
+            }
+        }
+    }
+
+    /// Remove an extended attribute at runtime, only allowed on writable
+    /// overlay inodes
+    pub fn remove_xattr(&mut self, f: &VFile, name: &OsStr) -> Result<(), VFSError> {
+        log::debug!("remove_xattr({:?}, {:?})", f, name);
+        if !self.fs.is_writable(f.inode) {
+            return Err(VFSError::ReadOnly);
+        }
+        let inode = self.get_inode_mut(f.inode)?;
+        match inode.xattrs.as_mut().and_then(|map| map.remove(name)) {
+            Some(_) => Ok(()),
+            None => Err(VFSError::AttributeNotFound),
+        }
+    }
+
+    /// Remove a directory entry at runtime, only allowed in writable overlay
+    /// directories
+    ///
+    /// The last component isn't followed, so this removes links rather than
+    /// their targets. Directories can't be removed.
+    pub fn remove_file(&mut self, path: &Path) -> Result<(), VFSError> {
+        log::debug!("remove_file({:?})", path);
+        let mut limits = Limits::reset(&self.fs.path_limits);
+        let entry = self
+            .fs
+            .resolve_path(&mut limits, self.workdir.inode, path)?;
+        let name = path.file_name().ok_or(VFSError::NotFound)?;
+        if !self.fs.is_writable(entry.parent) {
+            return Err(VFSError::ReadOnly);
+        }
+        if let Node::NormalDirectory(_) = self.fs.get_inode(entry.child)?.data {
+            return Err(VFSError::FileExpected);
+        }
+        match &mut self.get_inode_mut(entry.parent)?.data {
+            Node::NormalDirectory(map) => map.remove(name),
+            _ => Err(VFSError::DirectoryExpected)?,
+        };
+        self.inode_decref(entry.child)?;
+        self.release_unlinked_file(entry.child)
+    }
+
+    /// Without any links left, drop our reference to a tmpfs file so its
+    /// space is freed once the container closes it too
+    fn release_unlinked_file(&mut self, num: INodeNum) -> Result<(), VFSError> {
+        let inode = self.get_inode_mut(num)?;
+        if inode.stat.st_nlink == 0 {
+            if let Node::Tmpfs(..) = inode.data {
+                inode.data = Node::EmptyFile;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove a directory and everything under it, writable or not
+    ///
+    /// This is for our own bookkeeping, like a process's `/proc/<pid>`
+    /// directory, rather than anything the container asks for.
+    pub fn remove_tree(&mut self, path: &Path) -> Result<(), VFSError> {
+        log::debug!("remove_tree({:?})", path);
+        let mut limits = Limits::reset(&self.fs.path_limits);
+        let entry = self
+            .fs
+            .resolve_path(&mut limits, self.workdir.inode, path)?;
+        let name = path.file_name().ok_or(VFSError::NotFound)?;
+        match &self.fs.get_inode(entry.child)?.data {
+            Node::NormalDirectory(_) => (),
+            _ => return Err(VFSError::DirectoryExpected),
+        }
+        match &mut self.get_inode_mut(entry.parent)?.data {
+            Node::NormalDirectory(map) => map.remove(name),
+            _ => Err(VFSError::DirectoryExpected)?,
+        };
+        self.unlink_tree(entry.parent, entry.child)
+    }
+
+    /// Drop one link to `child` from `parent`, and if it's a directory, all
+    /// of its own entries too
+    fn unlink_tree(&mut self, parent: INodeNum, child: INodeNum) -> Result<(), VFSError> {
+        self.inode_decref(child)?;
+        let entries: Vec<INodeNum> = match &mut self.get_inode_mut(child)?.data {
+            Node::NormalDirectory(map) => {
+                let names: Vec<OsString> = map
+                    .keys()
+                    .filter(|name| *name != "." && *name != "..")
+                    .cloned()
+                    .collect();
+                names.iter().filter_map(|name| map.remove(name)).collect()
+            }
+            _ => return self.release_unlinked_file(child),
+        };
+        for entry in entries {
+            self.unlink_tree(child, entry)?;
+        }
+        self.fs.tmpfs_dirs.remove(&child);
+        // The directory's ".." was a link to its parent
+        self.inode_decref(parent)
+    }
+
+    /// Find the directory a new entry goes in, and the entry's name
+    ///
+    /// The entry must not exist yet, and its directory must be writable.
+    /// Unlike the image-building writes, missing parents aren't created.
+    fn resolve_new_entry<'p>(&self, path: &'p Path) -> Result<(INodeNum, &'p OsStr), VFSError> {
+        let mut limits = Limits::reset(&self.fs.path_limits);
+        let (parent, name) = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => (parent, name),
+            _ => return Err(VFSError::FileExists),
+        };
+        let entry = self
+            .fs
+            .resolve_path(&mut limits, self.workdir.inode, parent)?;
+        let dir = self.fs.resolve_symlinks(&mut limits, entry)?.child;
+        match self.fs.resolve_path_segment(&mut limits, dir, name) {
+            Ok(_) => return Err(VFSError::FileExists),
+            Err(VFSError::NotFound) => (),
+            Err(other) => return Err(other),
+        }
+        if !self.fs.is_writable(dir) {
+            return Err(VFSError::ReadOnly);
+        }
+        Ok((dir, name))
+    }
+
+    /// Create a directory at runtime, only allowed in writable overlay
+    /// directories
+    pub fn make_dir(&mut self, path: &Path, mode: u32) -> Result<(), VFSError> {
+        log::debug!("make_dir({:?}, {:o})", path, mode);
+        let (dir, name) = self.resolve_new_entry(path)?;
+        let num = self.alloc_child_directory(dir, name)?;
+        self.get_inode_mut(num)?.stat.st_mode = abi::S_IFDIR | (mode & 0o7777);
+        if let Some(tmpfs) = self.fs.tmpfs_dirs.get(&dir).cloned() {
+            self.fs.tmpfs_dirs.insert(num, tmpfs);
+        }
+        Ok(())
+    }
+
+    /// Create an empty regular file at runtime
+    ///
+    /// Files need somewhere to keep their contents, so this only works in a
+    /// [Tmpfs]. Elsewhere it fails with [VFSError::ReadOnly].
+    pub fn create_file(&mut self, path: &Path, mode: u32) -> Result<VFile, VFSError> {
+        log::debug!("create_file({:?}, {:o})", path, mode);
+        let (dir, name) = self.resolve_new_entry(path)?;
+        let tmpfs = match self.fs.tmpfs_dirs.get(&dir) {
+            Some(tmpfs) => tmpfs.clone(),
+            None => return Err(VFSError::ReadOnly),
+        };
+        let file = tmpfs.create_file()?;
+        let num = self.alloc_inode_number();
+        self.put_inode(
+            num,
+            INode {
+                stat: FileStat {
+                    st_mode: abi::S_IFREG | (mode & 0o7777),
+                    ..Default::default()
+                },
+                data: Node::Tmpfs(file, tmpfs),
+                xattrs: None,
+            },
+        );
+        self.add_child_to_directory(dir, name, num)?;
+        Ok(VFile { inode: num })
+    }
+
+    /// Mount an empty [Tmpfs] directory, replacing anything at the path
+    pub fn write_tmpfs(&mut self, path: &Path, tmpfs: Tmpfs) -> Result<(), VFSError> {
+        let mut limits = Limits::reset(&self.fs.path_limits);
+        let (parent, name) = self.resolve_or_create_parent(&mut limits, path)?;
+        let num = self.alloc_child_directory(parent, name)?;
+        self.get_inode_mut(num)?.stat.st_mode = abi::S_IFDIR | 0o1777;
+        self.fs.tmpfs_dirs.insert(num, tmpfs);
+        Ok(())
+    }
+
+    /// Make a new empty directory, replacing anything at the path
+    ///
+    /// The new directory keeps its own link count; `st_nlink` in `stat` is
+    /// ignored.
+    pub fn write_new_directory(&mut self, path: &Path, stat: FileStat) -> Result<VFile, VFSError> {
+        let mut limits = Limits::reset(&self.fs.path_limits);
+        let (parent, name) = self.resolve_or_create_parent(&mut limits, path)?;
+        let num = self.alloc_child_directory(parent, name)?;
+        let inode = self.get_inode_mut(num)?;
+        inode.stat = FileStat {
+            st_nlink: inode.stat.st_nlink,
+            ..stat
+        };
+        Ok(VFile { inode: num })
+    }
+
+    /// Remove an empty directory at runtime, only allowed in writable overlay
+    /// directories
+    ///
+    /// The directory's `..` entry goes with it, so the parent loses a link
+    /// too.
+    pub fn remove_dir(&mut self, path: &Path) -> Result<(), VFSError> {
+        log::debug!("remove_dir({:?})", path);
+        let mut limits = Limits::reset(&self.fs.path_limits);
+        let entry = self
+            .fs
+            .resolve_path(&mut limits, self.workdir.inode, path)?;
+        let name = path.file_name().ok_or(VFSError::NotFound)?;
+        if !self.fs.is_writable(entry.parent) {
+            return Err(VFSError::ReadOnly);
+        }
+        match &self.fs.get_inode(entry.child)?.data {
+            Node::NormalDirectory(map) => {
+                if map.keys().any(|name| name != "." && name != "..") {
+                    return Err(VFSError::DirectoryNotEmpty);
+                }
+            }
+            _ => return Err(VFSError::DirectoryExpected),
+        }
+        match &mut self.get_inode_mut(entry.parent)?.data {
+            Node::NormalDirectory(map) => map.remove(name),
+            _ => Err(VFSError::DirectoryExpected)?,
+        };
+        self.inode_decref(entry.child)?;
+        self.fs.tmpfs_dirs.remove(&entry.child);
+        self.inode_decref(entry.parent)
+    }
+
     fn resolve_or_create_path_segment(
         &mut self,
         mut limits: &mut Limits,
@@ -535,6 +1215,49 @@ fn open_null() -> Result<Arc<dyn AsRawFd + Sync + Send>, VFSError> {
     ))
 }
 
+/// Open a file from a host directory mount, if it's still a regular file
+///
+/// The host side may have changed since the mount, so this won't follow a
+/// link or wait on a fifo that has taken the file's place.
+fn open_host_file(path: &Path) -> Result<File, VFSError> {
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+        .open(path)
+        .map_err(|err| match err.kind() {
+            std::io::ErrorKind::NotFound => VFSError::NotFound,
+            _ => VFSError::IO,
+        })?;
+    if !file.metadata().map_err(|_| VFSError::IO)?.is_file() {
+        return Err(VFSError::FileExpected);
+    }
+    // Regular files ignore O_NONBLOCK, but the container would still see it
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETFL, 0) } != 0 {
+        return Err(VFSError::IO);
+    }
+    Ok(file)
+}
+
+/// Open a memory file again, read-only and with its own offset
+fn reopen_memory_file(file: &File) -> Result<File, VFSError> {
+    File::open(format!("/proc/self/fd/{}", file.as_raw_fd())).map_err(|_| VFSError::IO)
+}
+
+/// Seal a memfd so its contents can never change
+fn seal_memfd(memfd: &memfd::Memfd) -> Result<(), memfd::Error> {
+    memfd.add_seals(
+        &[
+            memfd::FileSeal::SealWrite,
+            memfd::FileSeal::SealShrink,
+            memfd::FileSeal::SealGrow,
+            memfd::FileSeal::SealSeal,
+        ]
+        .iter()
+        .cloned()
+        .collect(),
+    )
+}
+
 async fn open_storage_part(
     storage: &FileStorage,
     key: &StorageKey,
@@ -570,19 +1293,7 @@ impl DirectoryFileBuilder {
             .into_inner()
             .map_err(|_| VFSError::ImageStorageError)?;
         let memfd = memfd::Memfd::try_from_file(memfd).left().unwrap();
-        memfd
-            .add_seals(
-                &[
-                    memfd::FileSeal::SealWrite,
-                    memfd::FileSeal::SealShrink,
-                    memfd::FileSeal::SealGrow,
-                    memfd::FileSeal::SealSeal,
-                ]
-                .iter()
-                .cloned()
-                .collect(),
-            )
-            .map_err(|_| VFSError::ImageStorageError)?;
+        seal_memfd(&memfd).map_err(|_| VFSError::ImageStorageError)?;
         let mut memfd = memfd.into_file();
         memfd
             .seek(SeekFrom::Start(0))
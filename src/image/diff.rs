@@ -0,0 +1,150 @@
+use crate::{
+    errors::VFSError,
+    filesystem::vfs::Filesystem,
+    image::{Image, LayerInfo},
+    sand::protocol::{abi, FollowLinks, VFile},
+};
+use std::{
+    ffi::OsStr,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+/// Differences between two [Image]s, from [Image::diff_against()]
+///
+/// Paths are absolute and sorted. "Added" and "removed" are from the point of
+/// view of going from the other image to this one, and a directory that was
+/// added or removed is listed without its contents.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ImageDiff {
+    /// Lowest layers both images have in common, in order
+    pub common_layers: Vec<LayerInfo>,
+    /// Layers above the common ones that only this image has
+    pub added_layers: Vec<LayerInfo>,
+    /// Layers above the common ones that only the other image has
+    pub removed_layers: Vec<LayerInfo>,
+    /// Files only this image has
+    pub added: Vec<PathBuf>,
+    /// Files only the other image has
+    pub removed: Vec<PathBuf>,
+    /// Files both images have, with different type, metadata, or contents
+    pub changed: Vec<PathBuf>,
+}
+
+impl ImageDiff {
+    /// Do both images have the same layers and the same files?
+    pub fn is_empty(&self) -> bool {
+        self.added_layers.is_empty()
+            && self.removed_layers.is_empty()
+            && self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+    }
+}
+
+/// Compare the layer lists and then the merged filesystems of two images
+pub fn diff(image: &Image, other: &Image) -> Result<ImageDiff, VFSError> {
+    let common = image
+        .layers
+        .iter()
+        .zip(&other.layers)
+        .take_while(|(a, b)| a.diff_id == b.diff_id)
+        .count();
+    let mut result = ImageDiff {
+        common_layers: image.layers[..common].to_vec(),
+        added_layers: image.layers[common..].to_vec(),
+        removed_layers: other.layers[common..].to_vec(),
+        ..Default::default()
+    };
+    // Layers fully determine the filesystem, so there's nothing to walk when
+    // they all match
+    if !result.added_layers.is_empty() || !result.removed_layers.is_empty() {
+        let trees = Trees {
+            ours: &image.filesystem,
+            theirs: &other.filesystem,
+        };
+        let root = Filesystem::root();
+        trees.diff_dir(&mut result, &root, &root, Path::new("/"))?;
+        result.added.sort();
+        result.removed.sort();
+        result.changed.sort();
+    }
+    Ok(result)
+}
+
+struct Trees<'a> {
+    ours: &'a Filesystem,
+    theirs: &'a Filesystem,
+}
+
+impl<'a> Trees<'a> {
+    fn diff_dir(
+        &self,
+        result: &mut ImageDiff,
+        ours: &VFile,
+        theirs: &VFile,
+        dir_path: &Path,
+    ) -> Result<(), VFSError> {
+        let our_names = self.ours.list_dir(ours)?;
+        let their_names = self.theirs.list_dir(theirs)?;
+        for name in &their_names {
+            if our_names.binary_search(name).is_err() {
+                result.removed.push(dir_path.join(name));
+            }
+        }
+        for name in &our_names {
+            let path = dir_path.join(name);
+            if their_names.binary_search(name).is_err() {
+                result.added.push(path);
+                continue;
+            }
+            let name = Path::new(name);
+            let our_file = self.ours.lookup(ours, name, &FollowLinks::NoFollow)?;
+            let their_file = self.theirs.lookup(theirs, name, &FollowLinks::NoFollow)?;
+            if !self.same_file(&our_file, &their_file)? {
+                result.changed.push(path.clone());
+            }
+            if self.ours.is_directory(&our_file)? && self.theirs.is_directory(&their_file)? {
+                self.diff_dir(result, &our_file, &their_file, &path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Compare one file's own metadata and contents, not including any
+    /// directory entries
+    ///
+    /// Regular files compare by where their contents are stored, so a file
+    /// that a layer rewrites counts as changed even if its bytes didn't.
+    fn same_file(&self, ours: &VFile, theirs: &VFile) -> Result<bool, VFSError> {
+        let (a, b) = (self.ours.stat(ours)?, self.theirs.stat(theirs)?);
+        if (a.st_mode, a.st_uid, a.st_gid, a.st_size, a.st_mtime)
+            != (b.st_mode, b.st_uid, b.st_gid, b.st_size, b.st_mtime)
+        {
+            return Ok(false);
+        }
+        let xattrs = self.ours.list_xattr(ours)?;
+        if xattrs != self.theirs.list_xattr(theirs)? {
+            return Ok(false);
+        }
+        for name in xattrs.split(|b| *b == 0) {
+            if !name.is_empty() {
+                let name = OsStr::from_bytes(name);
+                if self.ours.get_xattr(ours, name)? != self.theirs.get_xattr(theirs, name)? {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(match a.st_mode & abi::S_IFMT {
+            abi::S_IFREG => match (self.ours.storage_key(ours), self.theirs.storage_key(theirs)) {
+                (Ok(a), Ok(b)) => a == b,
+                _ => false,
+            },
+            abi::S_IFLNK => self.ours.readlink(ours)? == self.theirs.readlink(theirs)?,
+            abi::S_IFCHR | abi::S_IFBLK => {
+                self.ours.device_numbers(ours)? == self.theirs.device_numbers(theirs)?
+            }
+            _ => true,
+        })
+    }
+}
@@ -0,0 +1,88 @@
+use crate::{
+    image::{ContentDigest, ImageName, LayerInfo},
+    manifest::{parse_signal, RuntimeConfig},
+};
+
+/// Metadata for a container image, without its filesystem
+///
+/// Returned by [crate::RegistryClient::inspect()], from nothing but the
+/// image's manifest and config. The name includes the manifest's content
+/// digest, like [crate::Image::name()].
+#[derive(Debug, Clone)]
+pub struct ImageInspect {
+    pub(crate) name: ImageName,
+    pub(crate) config: RuntimeConfig,
+    pub(crate) layers: Vec<LayerInfo>,
+}
+
+impl ImageInspect {
+    /// Get the content digest of the image's manifest
+    pub fn content_digest(&self) -> ContentDigest {
+        self.name()
+            .content_digest()
+            .expect("inspected images must always have a digest")
+    }
+
+    /// Get the name of this image, including its content digest
+    pub fn name(&self) -> &ImageName {
+        &self.name
+    }
+
+    /// Get the layers making up this image's filesystem, lowest layer first
+    pub fn layers(&self) -> &[LayerInfo] {
+        &self.layers
+    }
+
+    /// Get the CPU architecture the image was built for, like `amd64`
+    pub fn architecture(&self) -> &str {
+        &self.config.architecture
+    }
+
+    /// Get the operating system the image was built for, like `linux`
+    pub fn os(&self) -> &str {
+        &self.config.os
+    }
+
+    /// Get the image's `ENTRYPOINT`, if it has one
+    pub fn entrypoint(&self) -> Option<&[String]> {
+        self.config.config.entrypoint.as_deref()
+    }
+
+    /// Get the image's default command, from `CMD`
+    ///
+    /// With an entrypoint, these are its default arguments instead.
+    pub fn cmd(&self) -> &[String] {
+        &self.config.config.cmd
+    }
+
+    /// Get the image's environment variables, as `KEY=value` strings
+    pub fn env(&self) -> &[String] {
+        &self.config.config.env
+    }
+
+    /// Get the image's working directory, empty if it doesn't set one
+    pub fn working_dir(&self) -> &str {
+        &self.config.config.working_dir
+    }
+
+    /// Get the user the image runs as, empty for root
+    pub fn user(&self) -> &str {
+        &self.config.config.user
+    }
+
+    /// Get the command this image's `SHELL` instruction set, if any
+    pub fn shell(&self) -> Option<&[String]> {
+        self.config.config.shell.as_deref()
+    }
+
+    /// Get the signal number this image's `STOPSIGNAL` instruction set, if any
+    ///
+    /// Signals given by unrecognized names are treated as absent.
+    pub fn stop_signal(&self) -> Option<i32> {
+        self.config
+            .config
+            .stop_signal
+            .as_deref()
+            .and_then(parse_signal)
+    }
+}
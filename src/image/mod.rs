@@ -2,14 +2,18 @@
 
 #[cfg(test)] mod tests;
 
+mod diff;
 mod digest;
+mod inspect;
 mod name;
 mod registry;
 mod repository;
 mod tag;
 mod version;
 
+pub use diff::ImageDiff;
 pub use digest::ContentDigest;
+pub use inspect::ImageInspect;
 pub use name::ImageName;
 pub use registry::Registry;
 pub use repository::{Repository, RepositoryIter};
@@ -17,10 +21,31 @@ pub use tag::Tag;
 pub use version::ImageVersion;
 
 use crate::{
-    filesystem::{storage::FileStorage, vfs::Filesystem},
-    manifest::RuntimeConfig,
+    errors::{ImageError, VFSError},
+    filesystem::{storage::FileStorage, tar, vfs::Filesystem},
+    manifest::{parse_signal, RuntimeConfig},
+    sand::protocol::{abi, FileStat, FollowLinks, VFile},
 };
-use std::fmt;
+use std::{
+    ffi::{OsStr, OsString},
+    fmt,
+    io::Write,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+/// Identity of one filesystem layer in an [Image]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LayerInfo {
+    /// Digest of the compressed layer, as listed in the image manifest
+    pub digest: ContentDigest,
+    /// Digest of the uncompressed layer tar, as listed in the image config
+    pub diff_id: ContentDigest,
+    /// Size of the compressed layer in bytes, if the manifest records it
+    ///
+    /// Legacy schema1 manifests don't.
+    pub size: Option<u64>,
+}
 
 /// Loaded data for a container image
 ///
@@ -33,11 +58,15 @@ use std::fmt;
 pub struct Image {
     pub(crate) name: ImageName,
     pub(crate) config: RuntimeConfig,
+    pub(crate) layers: Vec<LayerInfo>,
     pub(crate) filesystem: Filesystem,
     pub(crate) storage: FileStorage,
 }
 
 impl Image {
+    /// Largest file [Image::read()] will return without an explicit limit
+    pub const DEFAULT_READ_LIMIT: u64 = 16 * 1024 * 1024;
+
     /// Get the digest identifying this image's content and configuration
     pub fn content_digest(&self) -> ContentDigest {
         self.name()
@@ -49,6 +78,126 @@ impl Image {
     pub fn name(&self) -> &ImageName {
         &self.name
     }
+
+    /// Get the layers making up this image's filesystem, lowest layer first
+    pub fn layers(&self) -> &[LayerInfo] {
+        &self.layers
+    }
+
+    /// Get the command this image's `SHELL` instruction set, if any
+    ///
+    /// This is the shell used for the shell form of `RUN`, `CMD`, and
+    /// `ENTRYPOINT` when building the image.
+    pub fn shell(&self) -> Option<&[String]> {
+        self.config.config.shell.as_deref()
+    }
+
+    /// Get the signal number this image's `STOPSIGNAL` instruction set, if any
+    ///
+    /// Signals given by unrecognized names are treated as absent.
+    pub fn stop_signal(&self) -> Option<i32> {
+        self.config
+            .config
+            .stop_signal
+            .as_deref()
+            .and_then(parse_signal)
+    }
+
+    /// Get metadata for a file in this image, following symbolic links
+    ///
+    /// Paths are resolved starting at the image's root directory.
+    pub fn stat(&self, path: &Path) -> Result<FileStat, VFSError> {
+        let file = self.lookup(path, &FollowLinks::Follow)?;
+        self.filesystem.stat(&file)
+    }
+
+    /// Read the target of a symbolic link in this image
+    pub fn read_link(&self, path: &Path) -> Result<PathBuf, VFSError> {
+        let file = self.lookup(path, &FollowLinks::NoFollow)?;
+        let target = self.filesystem.readlink(&file)?;
+        Ok(OsStr::from_bytes(target.to_bytes()).into())
+    }
+
+    /// Read the contents of a file in this image, following symbolic links
+    ///
+    /// Files larger than [Image::DEFAULT_READ_LIMIT] are refused with
+    /// [VFSError::FileTooLarge]; use [Image::read_with_limit()] to choose a
+    /// different size. Directories and special files can't be read this way.
+    pub async fn read(&self, path: &Path) -> Result<Vec<u8>, VFSError> {
+        self.read_with_limit(path, Image::DEFAULT_READ_LIMIT).await
+    }
+
+    /// Read the contents of a file in this image, up to `limit` bytes long
+    pub async fn read_with_limit(&self, path: &Path, limit: u64) -> Result<Vec<u8>, VFSError> {
+        let file = self.lookup(path, &FollowLinks::Follow)?;
+        self.filesystem
+            .read_to_vec(&self.storage, &file, limit)
+            .await
+    }
+
+    /// List the names in one of this image's directories, in sorted order
+    pub fn list_dir(&self, path: &Path) -> Result<Vec<OsString>, VFSError> {
+        let file = self.lookup(path, &FollowLinks::Follow)?;
+        self.filesystem.list_dir(&file)
+    }
+
+    /// Write this image's entire root filesystem to `writer` as a tar stream
+    ///
+    /// The tar holds the merged result of every layer, with file contents
+    /// read back from storage. This does blocking I/O, and returns the writer
+    /// once the archive is finished.
+    pub fn export_tar<W: Write>(&self, writer: W) -> Result<W, ImageError> {
+        tar::export(&self.filesystem, &self.storage, writer)
+    }
+
+    /// Compare this image with an older or newer version of it
+    ///
+    /// Layers are compared by digest first. Images that share every layer
+    /// are identical, otherwise both merged filesystems are walked to find
+    /// which paths were added, removed, or changed going from `other` to
+    /// this image.
+    pub fn diff_against(&self, other: &Image) -> Result<ImageDiff, VFSError> {
+        diff::diff(self, other)
+    }
+
+    /// Check that the image's default command names an executable file
+    ///
+    /// Commands without a slash are searched for in the `PATH` from the
+    /// image's environment, and relative paths start at its working
+    /// directory, the same way a container would find them.
+    pub(crate) fn check_entrypoint(&self) -> Result<(), ImageError> {
+        let config = &self.config.config;
+        let command = match config.entrypoint.iter().flatten().chain(&config.cmd).next() {
+            Some(command) => command,
+            None => return Err(ImageError::EntrypointMissing(String::new())),
+        };
+        let dir = Path::new("/").join(&config.working_dir);
+        let candidates: Vec<PathBuf> = if command.contains('/') {
+            vec![dir.join(command)]
+        } else {
+            config
+                .env
+                .iter()
+                .filter_map(|var| var.strip_prefix("PATH="))
+                .flat_map(|paths| paths.split(':'))
+                .map(|path| dir.join(path).join(command))
+                .collect()
+        };
+        let is_executable = |path: &PathBuf| match self.stat(path) {
+            Ok(stat) => stat.st_mode & abi::S_IFMT == abi::S_IFREG && stat.st_mode & 0o111 != 0,
+            Err(_) => false,
+        };
+        if candidates.iter().any(is_executable) {
+            Ok(())
+        } else {
+            Err(ImageError::EntrypointMissing(command.clone()))
+        }
+    }
+
+    fn lookup(&self, path: &Path, follow_links: &FollowLinks) -> Result<VFile, VFSError> {
+        self.filesystem
+            .lookup(&Filesystem::root(), path, follow_links)
+    }
 }
 
 impl fmt::Debug for Image {
@@ -186,3 +186,118 @@ fn parse_repository_name() {
     assert!(Repository::parse("boring/strings").is_ok());
     assert!(Repository::parse("a").is_ok());
 }
+
+#[test]
+fn check_entrypoint() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let mut filesystem = Filesystem::new();
+    let mut writer = filesystem.writer();
+    let file = |mode| FileStat {
+        st_mode: abi::S_IFREG | mode,
+        ..Default::default()
+    };
+    writer
+        .write_storage_file(Path::new("/bin/tool"), file(0o755), None)
+        .unwrap();
+    writer
+        .write_storage_file(Path::new("/bin/data"), file(0o644), None)
+        .unwrap();
+    let image = |entrypoint: Option<&[&str]>, cmd: &[&str]| {
+        let strings = |s: &[&str]| s.iter().map(|s| s.to_string()).collect();
+        let mut config = RuntimeConfig::default();
+        config.config.env = vec!["PATH=/usr/bin:/bin".to_string()];
+        config.config.entrypoint = entrypoint.map(strings);
+        config.config.cmd = strings(cmd);
+        Image {
+            name: "busybox".parse().unwrap(),
+            config,
+            layers: Vec::new(),
+            filesystem: filesystem.clone(),
+            storage: FileStorage::new(dir.path().to_owned(), None),
+        }
+        .check_entrypoint()
+    };
+
+    assert!(image(None, &["tool", "-x"]).is_ok());
+    assert!(image(Some(&["/bin/tool"]), &["data"]).is_ok());
+    assert!(image(Some(&["bin/tool"]), &[]).is_ok());
+    for (entrypoint, cmd, missing) in &[
+        (None, &["data"][..], "data"),
+        (None, &["/bin/data"][..], "/bin/data"),
+        (Some(&["missing"][..]), &["tool"][..], "missing"),
+        (None, &["/bin"][..], "/bin"),
+        (None, &[][..], ""),
+    ] {
+        match image(*entrypoint, cmd) {
+            Err(ImageError::EntrypointMissing(name)) => assert_eq!(&name, missing),
+            other => panic!("unexpected result, {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn diff_against_shared_base() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let storage = FileStorage::new(dir.path().to_owned(), None);
+    let layer = |content: &[u8]| LayerInfo {
+        digest: ContentDigest::from_content(content),
+        diff_id: ContentDigest::from_content(content),
+        size: None,
+    };
+    let file = FileStat {
+        st_mode: abi::S_IFREG | 0o644,
+        ..Default::default()
+    };
+    let mut base = Filesystem::new();
+    let mut writer = base.writer();
+    for path in &["/bin/sh", "/etc/passwd", "/etc/motd"] {
+        writer
+            .write_storage_file(Path::new(path), file.clone(), None)
+            .unwrap();
+    }
+    let link = FileStat {
+        st_mode: abi::S_IFLNK | 0o777,
+        ..Default::default()
+    };
+    writer
+        .write_symlink(
+            Path::new("/bin/ash"),
+            link,
+            std::ffi::CString::new("sh").unwrap(),
+        )
+        .unwrap();
+
+    let mut top = base.clone();
+    let mut writer = top.writer();
+    let motd = storage.insert_blob(b"hello\n").unwrap();
+    writer
+        .write_storage_file(Path::new("/etc/motd"), file.clone(), Some(motd))
+        .unwrap();
+    writer
+        .write_storage_file(Path::new("/usr/share/doc/readme"), file, None)
+        .unwrap();
+
+    let image = |filesystem, layers| Image {
+        name: "busybox".parse().unwrap(),
+        config: RuntimeConfig::default(),
+        layers,
+        filesystem,
+        storage: storage.clone(),
+    };
+    let old = image(base, vec![layer(b"base")]);
+    let new = image(top, vec![layer(b"base"), layer(b"top")]);
+
+    let diff = new.diff_against(&old).unwrap();
+    assert_eq!(diff.common_layers, [layer(b"base")]);
+    assert_eq!(diff.added_layers, [layer(b"top")]);
+    assert!(diff.removed_layers.is_empty());
+    assert_eq!(diff.added, [Path::new("/usr")]);
+    assert!(diff.removed.is_empty());
+    assert_eq!(diff.changed, [Path::new("/etc/motd")]);
+
+    let diff = old.diff_against(&new).unwrap();
+    assert_eq!(diff.removed_layers, [layer(b"top")]);
+    assert_eq!(diff.removed, [Path::new("/usr")]);
+    assert_eq!(diff.changed, [Path::new("/etc/motd")]);
+    assert!(old.diff_against(&old).unwrap().is_empty());
+}
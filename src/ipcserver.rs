@@ -1,31 +1,41 @@
 use crate::{
     container::ExitStatus,
-    errors::RuntimeError,
-    filesystem::{storage::FileStorage, vfs::Filesystem},
+    errors::{RuntimeError, VFSError},
+    filesystem::{mount::Mount, socket::SharedStream, storage::FileStorage, vfs::Filesystem},
     process::{Process, ProcessStatus},
     sand,
-    sand::protocol::{
-        buffer, buffer::IPCBuffer, exit::*, Errno, FileStat, FromTask, MessageFromSand,
-        MessageToSand, SysFd, ToTask, TracerSettings, VFile, VPid, MEMFD_TEMP_NAME,
+    sand::{
+        protocol::{
+            buffer, buffer::IPCBuffer, exit::*, Errno, FileStat, FromTask, MessageFromSand,
+            MessageToSand, SysFd, ToTask, TracerSettings, VFile, VPid, MEMFD_TEMP_NAME,
+        },
+        LogHandler, ProcessPriority,
     },
     taskcall,
 };
 use fd_queue::{tokio::UnixStream, EnqueueFd};
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap, VecDeque},
     ffi::{CStr, CString},
+    fs,
     fs::File,
+    io,
     io::Write,
     os::{
         raw::c_int,
         unix::{io::AsRawFd, prelude::RawFd},
     },
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
 };
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     process::{Child, Command},
+    sync::{mpsc::UnboundedReceiver, oneshot},
     task,
     task::JoinHandle,
+    time,
+    time::Instant,
 };
 
 pub struct IPCServer {
@@ -34,8 +44,38 @@ pub struct IPCServer {
     tracer: Child,
     stream: UnixStream,
     process_table: HashMap<VPid, Process>,
+    live_pids: LivePids,
+    log_handler: Option<LogHandler>,
+    pending_signals: Vec<i32>,
+    pending_probes: VecDeque<PendingProbe>,
+    probes: HashMap<VPid, Option<ProbeReply>>,
 }
 
+/// Where a probe's exit status goes
+type ProbeReply = oneshot::Sender<Result<ExitStatus, RuntimeError>>;
+
+/// A request to start another process alongside the container's first one
+pub struct Probe {
+    /// Socket the loader reads its args from
+    pub args: fd_queue::tokio::UnixStream,
+    /// Streams to mount as the new process's stdin, stdout, and stderr
+    pub stdio: [SharedStream; 3],
+    pub reply: ProbeReply,
+}
+
+/// A probe that sand is starting, and which hasn't asked for its process yet
+struct PendingProbe {
+    stdio: [SharedStream; 3],
+    reply: ProbeReply,
+}
+
+/// Virtual PIDs with an open process, shared with the [crate::Container]
+pub type LivePids = Arc<Mutex<BTreeSet<VPid>>>;
+
+/// The first process in the container, which receives signals sent to the
+/// container as a whole
+const INIT_TASK: VPid = VPid(1);
+
 struct SysFdStd(SysFd);
 
 impl AsRawFd for SysFdStd {
@@ -68,12 +108,32 @@ fn memfd_from_bytes(bytes: &[u8]) -> Result<File, RuntimeError> {
     Ok(file)
 }
 
+/// Show the sand process's OOM score adjustment, which the container's tasks
+/// inherit, at `/proc/1/oom_score_adj`
+fn install_oom_score_adj(filesystem: &mut Filesystem, sys_pid: u32) {
+    let result = fs::read(format!("/proc/{}/oom_score_adj", sys_pid))
+        .map_err(|_| VFSError::IO)
+        .and_then(|contents| {
+            filesystem.writer().write_memory_file(
+                Path::new("/proc/1/oom_score_adj"),
+                0o444,
+                &contents,
+            )
+        });
+    if let Err(err) = result {
+        log::warn!("can't show oom_score_adj in the container, {:?}", err);
+    }
+}
+
 impl IPCServer {
     pub async fn new<T: AsRawFd>(
-        filesystem: Filesystem,
+        mut filesystem: Filesystem,
         storage: FileStorage,
         args_socket: &T,
         tracer_settings: TracerSettings,
+        priority: &ProcessPriority,
+        live_pids: LivePids,
+        log_handler: Option<LogHandler>,
     ) -> Result<Self, RuntimeError> {
         let (mut server_socket, child_socket) = UnixStream::pair()?;
         clear_close_on_exec_flag(child_socket.as_raw_fd());
@@ -93,8 +153,9 @@ impl IPCServer {
         )
         .await?;
 
-        let mut command: Command = sand::command(child_socket.as_raw_fd())?.into();
+        let mut command: Command = sand::command(child_socket.as_raw_fd(), priority)?.into();
         let tracer = command.spawn()?;
+        install_oom_score_adj(&mut filesystem, tracer.id());
 
         Ok(IPCServer {
             filesystem,
@@ -102,23 +163,61 @@ impl IPCServer {
             tracer,
             stream: server_socket,
             process_table: HashMap::new(),
+            live_pids,
+            log_handler,
+            pending_signals: Vec::new(),
+            pending_probes: VecDeque::new(),
+            probes: HashMap::new(),
         })
     }
 
-    pub fn task(mut self) -> JoinHandle<Result<ExitStatus, RuntimeError>> {
+    pub fn task(
+        mut self,
+        mut signals: UnboundedReceiver<i32>,
+        mut probes: UnboundedReceiver<Probe>,
+        deadline: Option<Instant>,
+    ) -> JoinHandle<Result<ExitStatus, RuntimeError>> {
         task::spawn(async move {
-            let result = self.task_message_loop().await;
+            let result = self
+                .task_message_loop(&mut signals, &mut probes, deadline)
+                .await;
             log::trace!("task_message_loop -> {:?}", result);
+            self.live_pids.lock().unwrap().clear();
             self.task_finalize().await?;
             result
         })
     }
 
-    pub async fn task_message_loop(&mut self) -> Result<ExitStatus, RuntimeError> {
+    pub async fn task_message_loop(
+        &mut self,
+        signals: &mut UnboundedReceiver<i32>,
+        probes: &mut UnboundedReceiver<Probe>,
+        mut deadline: Option<Instant>,
+    ) -> Result<ExitStatus, RuntimeError> {
         let mut buffer = IPCBuffer::new();
+        let mut timed_out = false;
         loop {
             let available = buffer.begin_fill();
-            match self.stream.read(available.bytes).await? {
+            let read_result = tokio::select! {
+                result = self.stream.read(available.bytes) => result,
+                Some(signal) = signals.recv() => {
+                    self.signal_init_task(signal);
+                    continue;
+                }
+                Some(probe) = probes.recv() => {
+                    self.start_probe(probe).await?;
+                    continue;
+                }
+                _ = time::delay_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    // Keep handling messages until the sand reports the exit
+                    log::trace!("time limit reached, killing all tasks");
+                    deadline = None;
+                    timed_out = true;
+                    self.kill_all_tasks();
+                    continue;
+                }
+            };
+            match read_result? {
                 len if len > 0 => {
                     log::trace!("available={} len={}", available.bytes.len(), len);
                     buffer.commit_fill(len, 0)
@@ -136,6 +235,7 @@ impl IPCServer {
                         log::error!("{:?} while handling {:?}", err, message);
                         return Err(err);
                     }
+                    Ok(Some(_)) if timed_out => return Err(RuntimeError::Timeout),
                     Ok(Some(exit)) => return Ok(exit),
                     Ok(None) => (),
                 }
@@ -168,6 +268,96 @@ impl IPCServer {
         }
     }
 
+    /// Send a signal to the container's first process, or save it until that
+    /// process starts
+    fn signal_init_task(&mut self, signal: i32) {
+        match self.process_table.get(&INIT_TASK) {
+            None => self.pending_signals.push(signal),
+            Some(process) => {
+                if let Err(err) = process.send_signal(signal) {
+                    log::warn!("failed to send signal {}, {:?}", signal, err);
+                }
+            }
+        }
+    }
+
+    /// Ask sand to start a probe, which gets its stdio once its process opens
+    async fn start_probe(&mut self, probe: Probe) -> Result<(), RuntimeError> {
+        let args_fd = probe.args.as_raw_fd();
+        assert_eq!(0, unsafe { libc::fcntl(args_fd, libc::F_SETFL, 0) });
+        self.send_message(&MessageToSand::Spawn {
+            args: SysFd(args_fd as u32),
+        })
+        .await?;
+        self.pending_probes.push_back(PendingProbe {
+            stdio: probe.stdio,
+            reply: probe.reply,
+        });
+        Ok(())
+    }
+
+    /// Give a newly opened probe process its stdio, in its own `/proc`
+    /// directory
+    fn open_probe(&mut self, task: VPid) -> Result<(), RuntimeError> {
+        let probe = self
+            .pending_probes
+            .pop_front()
+            .ok_or(RuntimeError::WrongProcessState)?;
+        for (fd, stream) in probe.stdio.iter().enumerate() {
+            stream.mount(&mut self.filesystem, &probe_stdio_path(task, fd))?;
+        }
+        self.probes.insert(task, Some(probe.reply));
+        Ok(())
+    }
+
+    /// Report a probe's exit, and close its stdio
+    fn probe_exited(&mut self, task: VPid, result: Result<ExitStatus, RuntimeError>) {
+        self.process_table.remove(&task);
+        if let Some(Some(reply)) = self.probes.remove(&task) {
+            let _ = reply.send(result);
+        }
+    }
+
+    /// Fail a probe without ending the container, or fail the container if
+    /// this isn't a probe
+    fn task_failed(&mut self, task: VPid, err: RuntimeError) -> Result<(), RuntimeError> {
+        match self.probes.get_mut(&task) {
+            None => Err(err),
+            Some(reply) => {
+                // The process still exits later, and only the error is reported
+                if let Some(reply) = reply.take() {
+                    let _ = reply.send(Err(err));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Kill any probes still running once the container's first process
+    /// exits
+    fn kill_probes(&mut self) {
+        for task in self.probes.keys() {
+            if let Some(process) = self.process_table.get(task) {
+                if let Err(err) = process.send_signal(libc::SIGKILL) {
+                    log::warn!("failed to kill probe {:?}, {:?}", task, err);
+                }
+            }
+        }
+    }
+
+    /// Kill every process in the container, including the first one if it
+    /// hasn't started yet
+    fn kill_all_tasks(&mut self) {
+        if !self.process_table.contains_key(&INIT_TASK) {
+            self.pending_signals.push(libc::SIGKILL);
+        }
+        for (task, process) in &self.process_table {
+            if let Err(err) = process.send_signal(libc::SIGKILL) {
+                log::warn!("failed to kill {:?}, {:?}", task, err);
+            }
+        }
+    }
+
     pub async fn send_message(&mut self, message: &MessageToSand) -> Result<(), RuntimeError> {
         send_message(&mut self.stream, message).await
     }
@@ -211,13 +401,17 @@ impl IPCServer {
     async fn task_file_reply(
         &mut self,
         task: VPid,
-        result: Result<VFile, Errno>,
+        result: Result<(VFile, i32), Errno>,
     ) -> Result<Option<ExitStatus>, RuntimeError> {
         // SysFd does not own the underlying file, which must remain allocated until the
         // outgoing message has been flushed.
         let (_storage, reply) = match result {
             Err(e) => (None, Err(e)),
-            Ok(vfile) => match self.filesystem.open_storage(&self.storage, &vfile).await {
+            Ok((vfile, flags)) => match self
+                .filesystem
+                .open_storage(&self.storage, &vfile, flags)
+                .await
+            {
                 Err(e) => (None, Err(e.into())),
                 Ok(file) => {
                     let sys_fd = SysFd(file.as_raw_fd() as u32);
@@ -273,9 +467,37 @@ impl IPCServer {
         task: VPid,
         op: &FromTask,
     ) -> Result<Option<ExitStatus>, RuntimeError> {
+        // Log messages follow the request they describe, and can reuse the
+        // memory it read. Anything else means the task stopped again.
+        if !matches!(op, FromTask::Log(..)) {
+            if let Some(process) = self.process_table.get(&task) {
+                process.mem.reset_cache();
+            }
+        }
+        self.filesystem.set_current_task(task);
         match op {
             FromTask::Log(level, message) => {
-                sand::task_log(task, *level, message.clone());
+                if let Some(handler) = &self.log_handler {
+                    handler(task, *level, message.clone());
+                }
+                let mem = self.process_table.get(&task).map(|process| &process.mem);
+                sand::task_log(task, *level, message.clone(), mem);
+                Ok(None)
+            }
+
+            FromTask::SeccompViolation(nr) => {
+                self.task_failed(task, RuntimeError::SeccompViolation(*nr))?;
+                Ok(None)
+            }
+
+            FromTask::LoaderFailed(errno) => {
+                let err = RuntimeError::LoaderFailed {
+                    detail: format!(
+                        "initial exec failed, {}",
+                        io::Error::from_raw_os_error(-errno.0)
+                    ),
+                };
+                self.task_failed(task, err)?;
                 Ok(None)
             }
 
@@ -288,15 +510,25 @@ impl IPCServer {
                         &self.tracer,
                         ProcessStatus {
                             current_dir: Filesystem::root().clone(),
+                            last_open: None,
                         },
                     )?;
                     let handle = process.to_handle();
                     assert!(self.process_table.insert(task, process).is_none());
+                    self.live_pids.lock().unwrap().insert(task);
+                    if task != INIT_TASK {
+                        self.open_probe(task)?;
+                    }
                     self.send_message(&MessageToSand::Task {
                         task,
                         op: ToTask::OpenProcessReply(handle),
                     })
                     .await?;
+                    if task == INIT_TASK {
+                        for signal in std::mem::take(&mut self.pending_signals) {
+                            self.signal_init_task(signal);
+                        }
+                    }
                     Ok(None)
                 }
             }
@@ -340,13 +572,23 @@ impl IPCServer {
                 }
             },
 
-            FromTask::FileAccess { dir, path, mode } => match self.process_table.get_mut(&task) {
+            FromTask::FileAccess {
+                dir,
+                path,
+                mode,
+                follow_links,
+            } => match self.process_table.get_mut(&task) {
                 None => Err(RuntimeError::WrongProcessState)?,
                 Some(process) => {
-                    let result =
-                        taskcall::file_open(process, &self.filesystem, dir, path, 0, *mode)
-                            .await
-                            .map(|_| ());
+                    let result = taskcall::file_access(
+                        process,
+                        &self.filesystem,
+                        dir,
+                        path,
+                        *mode,
+                        follow_links,
+                    )
+                    .await;
                     self.task_reply(task, result).await
                 }
             },
@@ -359,9 +601,36 @@ impl IPCServer {
             } => match self.process_table.get_mut(&task) {
                 None => Err(RuntimeError::WrongProcessState)?,
                 Some(process) => {
-                    let result =
-                        taskcall::file_open(process, &self.filesystem, dir, path, *flags, *mode)
-                            .await;
+                    let result = taskcall::file_open(
+                        process,
+                        &mut self.filesystem,
+                        dir,
+                        path,
+                        *flags,
+                        *mode,
+                    )
+                    .await;
+                    self.task_file_reply(task, result).await
+                }
+            },
+
+            FromTask::FileOpenHow {
+                dir,
+                path,
+                how,
+                size,
+            } => match self.process_table.get_mut(&task) {
+                None => Err(RuntimeError::WrongProcessState)?,
+                Some(process) => {
+                    let result = taskcall::file_open_how(
+                        process,
+                        &mut self.filesystem,
+                        dir,
+                        path,
+                        *how,
+                        *size,
+                    )
+                    .await;
                     self.task_file_reply(task, result).await
                 }
             },
@@ -371,11 +640,185 @@ impl IPCServer {
                 Some(_process) => self.task_reply(task, Ok(())).await,
             },
 
-            FromTask::Exited(exit_code) => Ok(Some(ExitStatus { code: *exit_code })),
+            FromTask::GetXAttr {
+                file,
+                path,
+                follow_links,
+                name,
+            } => match self.process_table.get_mut(&task) {
+                None => Err(RuntimeError::WrongProcessState)?,
+                Some(process) => {
+                    let result = taskcall::get_xattr(
+                        process,
+                        &self.filesystem,
+                        file,
+                        path,
+                        follow_links,
+                        name,
+                    )
+                    .await;
+                    let result = match &result {
+                        Err(e) => Err(*e),
+                        Ok(value) => Ok(&value[..]),
+                    };
+                    self.task_bytes_reply(task, result).await
+                }
+            },
+
+            FromTask::ListXAttr {
+                file,
+                path,
+                follow_links,
+            } => match self.process_table.get_mut(&task) {
+                None => Err(RuntimeError::WrongProcessState)?,
+                Some(process) => {
+                    let result =
+                        taskcall::list_xattr(process, &self.filesystem, file, path, follow_links)
+                            .await;
+                    let result = match &result {
+                        Err(e) => Err(*e),
+                        Ok(list) => Ok(&list[..]),
+                    };
+                    self.task_bytes_reply(task, result).await
+                }
+            },
+
+            FromTask::SetXAttr {
+                file,
+                path,
+                follow_links,
+                name,
+                value,
+                size,
+                flags,
+            } => match self.process_table.get_mut(&task) {
+                None => Err(RuntimeError::WrongProcessState)?,
+                Some(process) => {
+                    let result = taskcall::set_xattr(
+                        process,
+                        &mut self.filesystem,
+                        file,
+                        path,
+                        follow_links,
+                        name,
+                        *value,
+                        *size,
+                        *flags,
+                    )
+                    .await;
+                    self.task_reply(task, result).await
+                }
+            },
+
+            FromTask::RemoveXAttr {
+                file,
+                path,
+                follow_links,
+                name,
+            } => match self.process_table.get_mut(&task) {
+                None => Err(RuntimeError::WrongProcessState)?,
+                Some(process) => {
+                    let result = taskcall::remove_xattr(
+                        process,
+                        &mut self.filesystem,
+                        file,
+                        path,
+                        follow_links,
+                        name,
+                    )
+                    .await;
+                    self.task_reply(task, result).await
+                }
+            },
+
+            FromTask::MakeDir { dir, path, mode } => match self.process_table.get_mut(&task) {
+                None => Err(RuntimeError::WrongProcessState)?,
+                Some(process) => {
+                    let result =
+                        taskcall::make_dir(process, &mut self.filesystem, dir, path, *mode).await;
+                    self.task_reply(task, result).await
+                }
+            },
+
+            FromTask::FileUnlink { dir, path, flags } => match self.process_table.get_mut(&task) {
+                None => Err(RuntimeError::WrongProcessState)?,
+                Some(process) => {
+                    let result =
+                        taskcall::file_unlink(process, &mut self.filesystem, dir, path, *flags)
+                            .await;
+                    self.task_reply(task, result).await
+                }
+            },
+
+            FromTask::FdOpened { fd, file } => match self.process_table.get_mut(&task) {
+                None => Err(RuntimeError::WrongProcessState)?,
+                Some(process) => {
+                    let result =
+                        taskcall::fd_opened(process, &mut self.filesystem, task, *fd, file).await;
+                    fd_table_result(task, result)
+                }
+            },
+
+            FromTask::FdDuplicated { src, dest } => match self.process_table.get_mut(&task) {
+                None => Err(RuntimeError::WrongProcessState)?,
+                Some(process) => {
+                    let result =
+                        taskcall::fd_duplicated(process, &mut self.filesystem, task, *src, *dest)
+                            .await;
+                    fd_table_result(task, result)
+                }
+            },
+
+            FromTask::FdClosed(fd) => match self.process_table.get_mut(&task) {
+                None => Err(RuntimeError::WrongProcessState)?,
+                Some(process) => {
+                    let result =
+                        taskcall::fd_closed(process, &mut self.filesystem, task, *fd).await;
+                    fd_table_result(task, result)
+                }
+            },
+
+            FromTask::Exited { code, signal } => {
+                self.live_pids.lock().unwrap().remove(&task);
+                // Everything under /proc/<pid> goes too, including any probe
+                // stdio mounted in its fd directory
+                let proc_dir = PathBuf::from(format!("/proc/{}", task.0));
+                match self.filesystem.writer().remove_tree(&proc_dir) {
+                    Ok(()) | Err(VFSError::NotFound) => (),
+                    Err(err) => log::warn!("{:?} couldn't remove {:?}, {:?}", task, proc_dir, err),
+                }
+                let status = ExitStatus {
+                    code: *code,
+                    signal: signal.map(|signal| signal.0 as i32),
+                };
+                if self.probes.contains_key(&task) {
+                    self.probe_exited(task, Ok(status));
+                    Ok(None)
+                } else {
+                    self.kill_probes();
+                    Ok(Some(status))
+                }
+            }
         }
     }
 }
 
+/// Changes to the file table have no reply, and the task has already moved
+/// on, so a failure only leaves `/proc/<pid>/fd` out of date
+fn fd_table_result(
+    task: VPid,
+    result: Result<(), Errno>,
+) -> Result<Option<ExitStatus>, RuntimeError> {
+    if let Err(err) = result {
+        log::warn!("{:?} couldn't update its fd directory, {:?}", task, err);
+    }
+    Ok(None)
+}
+
+fn probe_stdio_path(task: VPid, fd: usize) -> PathBuf {
+    PathBuf::from(format!("/proc/{}/fd/{}", task.0, fd))
+}
+
 fn clear_close_on_exec_flag(fd: RawFd) {
     let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
     assert!(flags >= 0);
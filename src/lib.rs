@@ -21,8 +21,9 @@
 //! Let's make it easy to run somewhat-untrusted computational workloads
 //! like media codecs from inside an existing async rust app. There is
 //! no networking or traditional storage support inside containers. The
-//! container uses a virtual filesystem backed by read-only image contents and
-//! mounted I/O channels.
+//! container uses a virtual filesystem backed by read-only image contents,
+//! mounted I/O channels, and optional in-memory [Tmpfs] scratch directories
+//! that are discarded when the container exits.
 //!
 //! Getting Started
 //! ===============
@@ -83,12 +84,14 @@ mod manifest;
 mod process;
 mod registry;
 mod sand;
+mod strace;
 mod taskcall;
 
 pub use crate::{
     container::*,
     errors::*,
-    filesystem::{mount::*, socket::*},
+    filesystem::{host::HostDir, mount::*, socket::*, tmpfs::Tmpfs, vfs::PathLimits},
     image::*,
     registry::*,
+    sand::protocol::{abi::Syscall, FileStat, LogLevel, LogMessage, VPid},
 };
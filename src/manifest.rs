@@ -1,4 +1,115 @@
-use serde::{Deserialize, Serialize};
+use crate::errors::ImageError;
+use serde::{de::IgnoredAny, Deserialize, Serialize};
+
+/// Any manifest format we know how to pull an image from
+#[derive(Clone, Debug)]
+pub enum AnyManifest {
+    V2(Manifest),
+    V1(ManifestV1),
+}
+
+#[derive(Deserialize)]
+struct SchemaVersion {
+    #[serde(rename = "schemaVersion")]
+    schema_version: u32,
+    #[serde(rename = "mediaType")]
+    media_type: Option<String>,
+    manifests: Option<IgnoredAny>,
+}
+
+impl SchemaVersion {
+    /// Does this look like a manifest list or image index?
+    ///
+    /// The `mediaType` field is optional in an OCI index, so without one we
+    /// go by whether it has a list of manifests.
+    fn is_list(&self) -> bool {
+        match self.media_type.as_deref().map(media_type_essence) {
+            Some(media_types::MANIFEST_LIST) | Some(media_types::OCI_INDEX) => true,
+            Some(_) => false,
+            None => self.manifests.is_some(),
+        }
+    }
+}
+
+/// Media type without any parameters
+fn media_type_essence(media_type: &str) -> &str {
+    media_type.split(';').next().unwrap_or("").trim()
+}
+
+impl AnyManifest {
+    /// Parse a manifest, detecting its schema version
+    ///
+    /// Manifest lists are an error here, they're parsed as a [ManifestList]
+    /// instead.
+    pub fn parse(slice: &[u8]) -> Result<Self, ImageError> {
+        let version: SchemaVersion = serde_json::from_slice(slice)?;
+        if version.is_list() {
+            let media_type = version
+                .media_type
+                .as_deref()
+                .unwrap_or(media_types::OCI_INDEX);
+            return Err(ImageError::UnsupportedManifestMediaType(
+                media_type_essence(media_type).to_owned(),
+            ));
+        }
+        match version.schema_version {
+            2 => Ok(AnyManifest::V2(serde_json::from_slice(slice)?)),
+            1 => Ok(AnyManifest::V1(serde_json::from_slice(slice)?)),
+            _ => Err(ImageError::UnsupportedManifestType),
+        }
+    }
+}
+
+/// Manifest list or OCI image index, pointing to one manifest per platform
+///
+/// Reference: https://docs.docker.com/registry/spec/manifest-v2-2/#manifest-list
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ManifestList {
+    pub manifests: Vec<PlatformLink>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PlatformLink {
+    #[serde(flatten)]
+    pub link: Link,
+    pub platform: Option<Platform>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Platform {
+    pub architecture: String,
+    pub os: String,
+}
+
+/// The only platform we can run images for
+pub const PLATFORM_OS: &str = "linux";
+pub const PLATFORM_ARCHITECTURE: &str = "amd64";
+
+impl ManifestList {
+    /// Parse a manifest list, or return `None` for any other kind of manifest
+    pub fn parse(slice: &[u8]) -> Result<Option<Self>, ImageError> {
+        let version: SchemaVersion = serde_json::from_slice(slice)?;
+        if version.is_list() {
+            Ok(Some(serde_json::from_slice(slice)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Choose the first manifest for our platform, ignoring any CPU variant
+    pub fn find_platform(&self) -> Result<&Link, ImageError> {
+        self.manifests
+            .iter()
+            .find(|entry| match &entry.platform {
+                Some(platform) => {
+                    platform.os == PLATFORM_OS && platform.architecture == PLATFORM_ARCHITECTURE
+                }
+                None => false,
+            })
+            .map(|entry| &entry.link)
+            .ok_or(ImageError::NoMatchingPlatform)
+    }
+}
 
 /// Partial implementation of the manifest v2 schema2 spec.
 ///
@@ -13,17 +124,146 @@ pub struct Manifest {
 pub struct Link {
     #[serde(rename = "mediaType")]
     pub media_type: String,
-    pub size: u64,
+    /// Expected size in bytes, not known for legacy schema1 layers
+    pub size: Option<u64>,
     pub digest: String,
 }
 
+/// Legacy implementation of the deprecated manifest v2 schema1 format
+///
+/// Some older registries can only serve this format. It has no separate
+/// runtime config blob, so we synthesize one from the image history, and the
+/// uncompressed layer digests are only known after downloading every layer.
+///
+/// Signed schema1 manifests are accepted, but the signatures are NOT checked.
+/// These images are only as trustworthy as the content digest they were
+/// requested by, or the HTTPS connection they arrived over.
+///
+/// Reference: https://docs.docker.com/registry/spec/manifest-v2-1/
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ManifestV1 {
+    #[serde(default)]
+    pub architecture: String,
+    #[serde(rename = "fsLayers")]
+    pub fs_layers: Vec<FsLayerV1>,
+    pub history: Vec<HistoryV1>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct FsLayerV1 {
+    #[serde(rename = "blobSum")]
+    pub blob_sum: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct HistoryV1 {
+    #[serde(rename = "v1Compatibility")]
+    pub v1_compatibility: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+struct V1Compatibility {
+    architecture: Option<String>,
+    os: Option<String>,
+    created: Option<String>,
+    docker_version: Option<String>,
+    config: Option<V1ImageConfig>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+struct V1ImageConfig {
+    #[serde(rename = "User")]
+    user: Option<String>,
+    #[serde(rename = "Env")]
+    env: Option<Vec<String>>,
+    #[serde(rename = "Cmd")]
+    cmd: Option<Vec<String>>,
+    #[serde(rename = "Image")]
+    image: Option<String>,
+    #[serde(rename = "WorkingDir")]
+    working_dir: Option<String>,
+    #[serde(rename = "Entrypoint")]
+    entrypoint: Option<Vec<String>>,
+    #[serde(rename = "Shell")]
+    shell: Option<Vec<String>>,
+    #[serde(rename = "StopSignal")]
+    stop_signal: Option<String>,
+}
+
+impl ManifestV1 {
+    /// Links to each compressed layer, lowest layer first
+    ///
+    /// Schema1 lists layers from the top down, and it doesn't record their
+    /// size or media type. Layers are always tar+gzip in this format.
+    pub fn layer_links(&self) -> Vec<Link> {
+        self.fs_layers
+            .iter()
+            .rev()
+            .map(|layer| Link {
+                media_type: media_types::LAYER_TAR_GZIP.to_owned(),
+                size: None,
+                digest: layer.blob_sum.clone(),
+            })
+            .collect()
+    }
+
+    /// Build a runtime config equivalent from the most recent history entry
+    ///
+    /// The diff_ids must be supplied separately, in the same order as
+    /// [ManifestV1::layer_links()].
+    pub fn runtime_config(&self, diff_ids: Vec<String>) -> Result<RuntimeConfig, ImageError> {
+        let compat: V1Compatibility = match self.history.first() {
+            None => V1Compatibility::default(),
+            Some(history) => serde_json::from_str(&history.v1_compatibility)?,
+        };
+        let config = compat.config.unwrap_or_default();
+        Ok(RuntimeConfig {
+            architecture: compat
+                .architecture
+                .unwrap_or_else(|| self.architecture.clone()),
+            config: ImageConfig {
+                user: config.user.unwrap_or_default(),
+                env: config.env.unwrap_or_default(),
+                cmd: config.cmd.unwrap_or_default(),
+                image: config.image.unwrap_or_default(),
+                working_dir: config.working_dir.unwrap_or_default(),
+                entrypoint: config.entrypoint,
+                shell: config.shell,
+                stop_signal: config.stop_signal,
+            },
+            created: compat.created.unwrap_or_default(),
+            docker_version: compat.docker_version.unwrap_or_default(),
+            os: compat.os.unwrap_or_default(),
+            rootfs: Filesystem {
+                fs_type: FS_TYPE.to_owned(),
+                diff_ids,
+            },
+        })
+    }
+}
+
 pub mod media_types {
     pub const MANIFEST: &str = "application/vnd.docker.distribution.manifest.v2+json";
+    pub const MANIFEST_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+    pub const MANIFEST_V1: &str = "application/vnd.docker.distribution.manifest.v1+json";
+    pub const MANIFEST_V1_SIGNED: &str =
+        "application/vnd.docker.distribution.manifest.v1+prettyjws";
     pub const RUNTIME_CONFIG: &str = "application/vnd.docker.container.image.v1+json";
     pub const LAYER_TAR_GZIP: &str = "application/vnd.docker.image.rootfs.diff.tar.gzip";
+    pub const OCI_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+    pub const OCI_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+    pub const OCI_CONFIG: &str = "application/vnd.oci.image.config.v1+json";
+    pub const OCI_LAYER_TAR: &str = "application/vnd.oci.image.layer.v1.tar";
+    pub const OCI_LAYER_TAR_GZIP: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+    pub const OCI_LAYER_TAR_ZSTD: &str = "application/vnd.oci.image.layer.v1.tar+zstd";
 }
 
+/// Image config, from the docker runtime config or OCI image config
+///
+/// OCI configs leave out fields docker always includes, like
+/// `docker_version`, so missing fields are left at their defaults.
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
 pub struct RuntimeConfig {
     pub architecture: String,
     pub config: ImageConfig,
@@ -34,6 +274,7 @@ pub struct RuntimeConfig {
 }
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
 pub struct ImageConfig {
     #[serde(rename = "User")]
     pub user: String,
@@ -47,6 +288,60 @@ pub struct ImageConfig {
     pub working_dir: String,
     #[serde(rename = "Entrypoint")]
     pub entrypoint: Option<Vec<String>>,
+    #[serde(rename = "Shell")]
+    pub shell: Option<Vec<String>>,
+    #[serde(rename = "StopSignal")]
+    pub stop_signal: Option<String>,
+}
+
+/// Parse a signal as written in `STOPSIGNAL`
+///
+/// Accepts a number or a name, with or without the `SIG` prefix.
+pub fn parse_signal(signal: &str) -> Option<i32> {
+    if let Ok(number) = signal.parse::<i32>() {
+        // Linux has 64 signals, counting the real-time ones
+        return if number > 0 && number <= 64 {
+            Some(number)
+        } else {
+            None
+        };
+    }
+    let name = signal.to_ascii_uppercase();
+    let name = name.strip_prefix("SIG").unwrap_or(&name);
+    Some(match name {
+        "HUP" => libc::SIGHUP,
+        "INT" => libc::SIGINT,
+        "QUIT" => libc::SIGQUIT,
+        "ILL" => libc::SIGILL,
+        "TRAP" => libc::SIGTRAP,
+        "ABRT" | "IOT" => libc::SIGABRT,
+        "BUS" => libc::SIGBUS,
+        "FPE" => libc::SIGFPE,
+        "KILL" => libc::SIGKILL,
+        "USR1" => libc::SIGUSR1,
+        "SEGV" => libc::SIGSEGV,
+        "USR2" => libc::SIGUSR2,
+        "PIPE" => libc::SIGPIPE,
+        "ALRM" => libc::SIGALRM,
+        "TERM" => libc::SIGTERM,
+        "STKFLT" => libc::SIGSTKFLT,
+        "CHLD" => libc::SIGCHLD,
+        "CONT" => libc::SIGCONT,
+        "STOP" => libc::SIGSTOP,
+        "TSTP" => libc::SIGTSTP,
+        "TTIN" => libc::SIGTTIN,
+        "TTOU" => libc::SIGTTOU,
+        "URG" => libc::SIGURG,
+        "XCPU" => libc::SIGXCPU,
+        "XFSZ" => libc::SIGXFSZ,
+        "VTALRM" => libc::SIGVTALRM,
+        "PROF" => libc::SIGPROF,
+        "WINCH" => libc::SIGWINCH,
+        "IO" | "POLL" => libc::SIGIO,
+        "PWR" => libc::SIGPWR,
+        "SYS" => libc::SIGSYS,
+        _ => return None,
+    })
 }
 
 pub const FS_TYPE: &str = "layers";
@@ -1,13 +1,21 @@
 use crate::{
     errors::RuntimeError,
-    sand::protocol::{Errno, ProcessHandle, SysFd, SysPid, VFile, VPtr, VString},
+    sand::protocol::{abi, Errno, ProcessHandle, SysFd, SysPid, VFile, VPtr, VString},
+    taskcall::LookupCache,
 };
 use regex::Regex;
 use std::{
+    collections::BTreeMap,
     ffi::{OsStr, OsString},
     fs::File,
+    io,
     io::Read,
     os::unix::{ffi::OsStrExt, fs::FileExt, io::AsRawFd},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
 };
 use tokio::process::Child;
 
@@ -33,19 +41,38 @@ fn page_remaining(vptr: VPtr) -> usize {
 pub struct ProcessStatus {
     // todo: uid, gid, loads of other stuff here.
     pub current_dir: VFile,
+    /// The file most recently opened, with its absolute path if we know it,
+    /// waiting for the task to report which fd it landed on
+    pub last_open: Option<(VFile, Option<PathBuf>)>,
 }
 
+/// Guest memory, read through `/proc/pid/mem`
+///
+/// Reads can optionally go through a cache of whole pages, which lasts until
+/// the next [MemFile::reset_cache()]. This saves a `pread` each time the same
+/// page is read again while the guest is stopped, like when a path is read
+/// once to emulate a syscall and again to log it, or when argv strings
+/// packed into one page are read one by one.
 #[derive(Debug)]
-pub struct MemFile(File);
+pub struct MemFile {
+    file: File,
+    cache: Mutex<Option<BTreeMap<usize, Vec<u8>>>>,
+    preads: AtomicUsize,
+}
+
+/// Most pages kept in a [MemFile] cache before it starts over
+const MAX_CACHED_PAGES: usize = 64;
 
 #[derive(Debug)]
 pub struct MapsFile(File);
 
 #[derive(Debug)]
 pub struct Process {
+    pub sys_pid: SysPid,
     pub mem: MemFile,
     pub maps: MapsFile,
     pub status: ProcessStatus,
+    pub lookups: LookupCache,
 }
 
 impl Process {
@@ -59,12 +86,41 @@ impl Process {
         let mem = MemFile::open(sys_pid)?;
         let maps = MapsFile::open(sys_pid)?;
         check_can_open(sys_pid, tracer)?;
-        Ok(Process { mem, maps, status })
+        Ok(Process {
+            sys_pid,
+            mem,
+            maps,
+            status,
+            lookups: Default::default(),
+        })
+    }
+
+    /// Send a real signal to the process
+    ///
+    /// The tracer sees it first, and passes it along to the process.
+    pub fn send_signal(&self, signal: i32) -> Result<(), RuntimeError> {
+        match unsafe { libc::kill(self.sys_pid.0 as libc::pid_t, signal) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error().into()),
+        }
+    }
+
+    /// The process's file mode creation mask
+    ///
+    /// `umask()` passes through, so the kernel keeps track of this for us.
+    pub fn umask(&self) -> Result<u32, RuntimeError> {
+        lazy_static! {
+            static ref RE: Regex = Regex::new(r"\nUmask:\t([0-7]+)\n").unwrap();
+        }
+        let status = read_proc_status(self.sys_pid)?;
+        RE.captures(&status)
+            .and_then(|captures| u32::from_str_radix(captures.get(1)?.as_str(), 8).ok())
+            .ok_or(RuntimeError::InvalidPid)
     }
 
     pub fn to_handle(&self) -> ProcessHandle {
         ProcessHandle {
-            mem: SysFd(self.mem.0.as_raw_fd() as u32),
+            mem: SysFd(self.mem.file.as_raw_fd() as u32),
             maps: SysFd(self.maps.0.as_raw_fd() as u32),
         }
     }
@@ -72,27 +128,87 @@ impl Process {
 
 impl MemFile {
     pub fn read_bytes(&self, vptr: VPtr, buf: &mut [u8]) -> Result<(), RuntimeError> {
-        self.0
+        let mut cache = self.cache.lock().unwrap();
+        let pages = match cache.as_mut() {
+            None => return self.pread(vptr, buf),
+            Some(pages) => pages,
+        };
+        let mut ptr = vptr;
+        let mut done = 0;
+        while done < buf.len() {
+            let page_start = ptr.0 - page_offset(ptr);
+            if !pages.contains_key(&page_start) {
+                if pages.len() >= MAX_CACHED_PAGES {
+                    pages.clear();
+                }
+                let mut page = vec![0u8; *PAGE_SIZE];
+                self.pread(VPtr(page_start), &mut page)?;
+                pages.insert(page_start, page);
+            }
+            let len = page_remaining(ptr).min(buf.len() - done);
+            let offset = page_offset(ptr);
+            buf[done..done + len].copy_from_slice(&pages[&page_start][offset..offset + len]);
+            done += len;
+            ptr = ptr + len;
+        }
+        Ok(())
+    }
+
+    fn pread(&self, vptr: VPtr, buf: &mut [u8]) -> Result<(), RuntimeError> {
+        self.preads.fetch_add(1, Ordering::Relaxed);
+        self.file
             .read_exact_at(buf, vptr.0 as u64)
             .map_err(|_| RuntimeError::MemAccess)
     }
 
-    pub fn read_user_string(&self, vstr: &VString) -> Result<String, Errno> {
-        self.read_string(vstr).map_err(|_| Errno(-libc::EFAULT))
+    /// Forget any cached pages, and cache reads from now on
+    ///
+    /// This must be called whenever the guest may have run since the last
+    /// read, so the IPC server calls it as each new request arrives from a
+    /// stopped task.
+    pub fn reset_cache(&self) {
+        *self.cache.lock().unwrap() = Some(BTreeMap::new());
+    }
+
+    /// Count the reads from `/proc/pid/mem` so far
+    #[cfg(test)]
+    fn pread_count(&self) -> usize {
+        self.preads.load(Ordering::Relaxed)
     }
 
-    pub fn read_string(&self, vstr: &VString) -> Result<String, RuntimeError> {
-        self.read_string_os(vstr)?
-            .into_string()
-            .map_err(|_| RuntimeError::StringDecoding)
+    /// Read a path from the guest, failing with `ENAMETOOLONG` if it's
+    /// longer than `PATH_MAX` including the nul terminator
+    pub fn read_user_path(&self, vstr: &VString) -> Result<String, Errno> {
+        match self.read_string_os_bounded(vstr, abi::PATH_MAX) {
+            Err(_) => Err(Errno(-libc::EFAULT)),
+            Ok(None) => Err(Errno(-libc::ENAMETOOLONG)),
+            Ok(Some(s)) => s.into_string().map_err(|_| Errno(-libc::EFAULT)),
+        }
     }
 
     pub fn read_string_os(&self, vstr: &VString) -> Result<OsString, RuntimeError> {
+        self.read_string_os_bounded(vstr, usize::MAX)?
+            .ok_or(RuntimeError::MemAccess)
+    }
+
+    /// Read a nul-terminated string, without reading past `max_len` bytes
+    ///
+    /// Returns None if the string and its terminator don't fit in
+    /// `max_len`.
+    pub fn read_string_os_bounded(
+        &self,
+        vstr: &VString,
+        max_len: usize,
+    ) -> Result<Option<OsString>, RuntimeError> {
         let mut ptr = vstr.0;
         let mut result = OsString::new();
         let mut page_buffer = Vec::with_capacity(*PAGE_SIZE);
         loop {
-            page_buffer.resize(page_remaining(ptr), 0u8);
+            let remaining = max_len.saturating_sub(result.len());
+            if remaining == 0 {
+                break Ok(None);
+            }
+            page_buffer.resize(page_remaining(ptr).min(remaining), 0u8);
             self.read_bytes(ptr, &mut page_buffer[..])?;
             match page_buffer.iter().position(|i| *i == 0) {
                 None => {
@@ -101,16 +217,20 @@ impl MemFile {
                 }
                 Some(index) => {
                     result.push(OsStr::from_bytes(&page_buffer[0..index]));
-                    break Ok(result);
+                    break Ok(Some(result));
                 }
             }
         }
     }
 
-    fn open(sys_pid: SysPid) -> Result<Self, RuntimeError> {
+    pub(crate) fn open(sys_pid: SysPid) -> Result<Self, RuntimeError> {
         // open for read only, write is not portable enough
         let path = format!("/proc/{}/mem", sys_pid.0);
-        Ok(MemFile(File::open(path)?))
+        Ok(MemFile {
+            file: File::open(path)?,
+            cache: Mutex::new(None),
+            preads: AtomicUsize::new(0),
+        })
     }
 }
 
@@ -193,22 +313,22 @@ mod tests {
 
         // First test a few edge cases around the memory hole, with all zeroes in the
         // mapping still
-        assert_eq!(self_mem.read_string(&VString(map_addr)).unwrap(), "");
+        assert_eq!(self_mem.read_string_os(&VString(map_addr)).unwrap(), "");
         assert_eq!(
             self_mem
-                .read_string(&VString(map_addr + (hole_offset - 1)))
+                .read_string_os(&VString(map_addr + (hole_offset - 1)))
                 .unwrap(),
             ""
         );
         assert!(is_memaccess_err(
-            self_mem.read_string(&VString(map_addr + hole_offset))
+            self_mem.read_string_os(&VString(map_addr + hole_offset))
         ));
-        assert!(is_memaccess_err(self_mem.read_string(&VString(
+        assert!(is_memaccess_err(self_mem.read_string_os(&VString(
             map_addr + (hole_offset + hole_size - 1)
         ))));
         assert_eq!(
             self_mem
-                .read_string(&VString(map_addr + (hole_offset + hole_size)))
+                .read_string_os(&VString(map_addr + (hole_offset + hole_size)))
                 .unwrap(),
             ""
         );
@@ -223,11 +343,71 @@ mod tests {
                 let offset_end = offset + test_str.len();
                 map_slice[offset..offset_end].copy_from_slice(test_str.as_bytes());
                 map_slice[offset_end] = b'\0';
-                let readback = self_mem.read_string(&VString(map_addr + offset)).unwrap();
-                assert_eq!(test_str, readback);
+                let readback = self_mem
+                    .read_string_os(&VString(map_addr + offset))
+                    .unwrap();
+                assert_eq!(readback, test_str.as_str());
             }
         }
 
+        // Bounded reads count the terminator, and stop without reading further
+        map_slice[hole_offset - 5..hole_offset].copy_from_slice(b"abcd\0");
+        let vstr = VString(map_addr + (hole_offset - 5));
+        assert_eq!(
+            self_mem.read_string_os_bounded(&vstr, 5).unwrap(),
+            Some(OsString::from("abcd"))
+        );
+        assert_eq!(self_mem.read_string_os_bounded(&vstr, 4).unwrap(), None);
+        map_slice[hole_offset - 1] = b'x';
+        assert_eq!(self_mem.read_string_os_bounded(&vstr, 5).unwrap(), None);
+        assert!(is_memaccess_err(self_mem.read_string_os_bounded(&vstr, 6)));
+
         unsafe { libc::munmap(map_addr.0 as *mut libc::c_void, map_total_size) };
     }
+
+    #[test]
+    fn cached_reads_count() {
+        let self_mem = MemFile::open(SysPid(unsafe { libc::getpid() as u32 })).unwrap();
+
+        // Like an argv array, many short strings packed into a few pages
+        let mut packed = Vec::new();
+        let mut offsets = Vec::new();
+        for i in 0..200 {
+            offsets.push(packed.len());
+            packed.extend_from_slice(format!("--argument-{}\0", i).as_bytes());
+        }
+        let base = VPtr(packed.as_ptr() as usize);
+        let last = base + (packed.len() - 1);
+        let pages = last.0 / *PAGE_SIZE - base.0 / *PAGE_SIZE + 1;
+
+        // Read each string twice, once to emulate a syscall and once to log it
+        let workload = |mem: &MemFile| {
+            let before = mem.pread_count();
+            for _ in 0..2 {
+                for (i, offset) in offsets.iter().enumerate() {
+                    let s = mem.read_string_os(&VString(base + *offset)).unwrap();
+                    assert_eq!(s, format!("--argument-{}", i).as_str());
+                }
+            }
+            mem.pread_count() - before
+        };
+
+        // Uncached, every string costs at least one read each time
+        assert!(workload(&self_mem) >= 2 * offsets.len());
+        // Cached, each page is read once
+        self_mem.reset_cache();
+        assert_eq!(workload(&self_mem), pages);
+
+        // Resetting forgets what was read
+        packed[0] = b'X';
+        assert_eq!(
+            self_mem.read_string_os(&VString(base)).unwrap(),
+            "--argument-0"
+        );
+        self_mem.reset_cache();
+        assert_eq!(
+            self_mem.read_string_os(&VString(base)).unwrap(),
+            "X-argument-0"
+        );
+    }
 }
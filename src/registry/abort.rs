@@ -0,0 +1,52 @@
+//! Spawned tasks that stop when nothing is waiting for them
+
+use crate::errors::ImageError;
+use futures_util::future::{AbortHandle, Abortable, Aborted};
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::task::{self, JoinHandle};
+
+/// Handle for a task started with [spawn()]
+///
+/// Dropping the handle aborts the task at its next await point, dropping its
+/// future. Anything it owned, like a half-written storage file, is cleaned up
+/// the same way as if it had returned early.
+pub struct AbortOnDrop<T> {
+    join: JoinHandle<Result<T, Aborted>>,
+    abort: AbortHandle,
+}
+
+/// Spawn a task that lives only as long as its handle
+pub fn spawn<F>(future: F) -> AbortOnDrop<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let (abort, registration) = AbortHandle::new_pair();
+    AbortOnDrop {
+        join: task::spawn(Abortable::new(future, registration)),
+        abort,
+    }
+}
+
+impl<T> Future for AbortOnDrop<T> {
+    type Output = Result<T, ImageError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match Pin::new(&mut self.get_mut().join).poll(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Ok(Ok(output))) => Poll::Ready(Ok(output)),
+            Poll::Ready(Ok(Err(Aborted))) => Poll::Ready(Err(ImageError::PullTaskError)),
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err.into())),
+        }
+    }
+}
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.abort.abort();
+    }
+}
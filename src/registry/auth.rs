@@ -1,12 +1,25 @@
-use crate::{errors::ImageError, image::Registry};
+use crate::{
+    errors::ImageError,
+    filesystem::storage::{FileStorage, StorageKey},
+    image::{ContentDigest, Registry, Repository},
+};
 use regex::Regex;
 use reqwest::{header, RequestBuilder, Response, StatusCode, Url};
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    process::Stdio,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{io::AsyncWriteExt, process::Command};
 
 #[derive(Clone)]
 pub struct Auth {
     logins: HashMap<Registry, Login>,
-    tokens: HashMap<Registry, Token>,
+    tokens: HashMap<(Registry, Repository), Token>,
+    basic: HashSet<Registry>,
+    helpers: HashMap<Registry, CredentialHelper>,
+    default_helper: Option<String>,
+    token_cache: Option<FileStorage>,
 }
 
 #[derive(Clone)]
@@ -15,11 +28,29 @@ struct Login {
     password: Option<String>,
 }
 
+#[derive(Clone)]
+struct CredentialHelper {
+    name: String,
+    server_url: String,
+}
+
+#[derive(Deserialize)]
+struct HelperCredentials {
+    #[serde(rename = "Username")]
+    username: String,
+    #[serde(rename = "Secret")]
+    secret: String,
+}
+
 impl Auth {
     pub fn new() -> Self {
         Auth {
             logins: HashMap::new(),
             tokens: HashMap::new(),
+            basic: HashSet::new(),
+            helpers: HashMap::new(),
+            default_helper: None,
+            token_cache: None,
         }
     }
 
@@ -27,35 +58,143 @@ impl Auth {
         self.logins.insert(registry, Login { username, password });
     }
 
-    fn include_token(&self, registry: &Registry, req: RequestBuilder) -> RequestBuilder {
-        match self.tokens.get(registry) {
+    /// Add all settings from another Auth, which take precedence over ours
+    pub fn merge(&mut self, other: Auth) {
+        self.logins.extend(other.logins);
+        self.tokens.extend(other.tokens);
+        self.basic.extend(other.basic);
+        self.helpers.extend(other.helpers);
+        if other.default_helper.is_some() {
+            self.default_helper = other.default_helper;
+        }
+    }
+
+    /// Use an external `docker-credential-*` program for one registry
+    pub fn credential_helper(&mut self, registry: Registry, name: String, server_url: String) {
+        self.helpers
+            .insert(registry, CredentialHelper { name, server_url });
+    }
+
+    /// Use an external `docker-credential-*` program for any registry without
+    /// a more specific login or helper
+    pub fn default_credential_helper(&mut self, name: String) {
+        self.default_helper = Some(name);
+    }
+
+    /// Save bearer tokens in storage, to reuse them from other clients until
+    /// they expire
+    pub fn token_cache(&mut self, storage: FileStorage) {
+        self.token_cache = Some(storage);
+    }
+
+    /// Look for an unexpired token from an earlier client
+    ///
+    /// The cache is only an optimization, so any problem reading it just
+    /// means we'll ask for a new token.
+    fn load_cached_token(&self, key: &StorageKey) -> Option<Token> {
+        let data = self.token_cache.as_ref()?.read_to_vec(key).ok()??;
+        let cached: CachedToken = serde_json::from_slice(&data).ok()?;
+        if cached.expires > unix_time() + TOKEN_EXPIRY_MARGIN {
+            Some(Token {
+                token: cached.token,
+                expires_in: None,
+            })
+        } else {
+            None
+        }
+    }
+
+    fn save_cached_token(&self, key: &StorageKey, token: &Token) {
+        if let Some(storage) = &self.token_cache {
+            let cached = CachedToken {
+                token: token.token.clone(),
+                expires: unix_time() + token.expires_in.unwrap_or(DEFAULT_TOKEN_EXPIRY),
+            };
+            let result = serde_json::to_vec(&cached)
+                .map_err(ImageError::from)
+                .and_then(|data| storage.insert_private(key, &data));
+            if let Err(err) = result {
+                log::warn!("couldn't save token, {}", err);
+            }
+        }
+    }
+
+    fn include_token(
+        &self,
+        registry: &Registry,
+        repository: &Repository,
+        req: RequestBuilder,
+    ) -> RequestBuilder {
+        match self.tokens.get(&(registry.clone(), repository.clone())) {
             Some(token_struct) => {
                 log::debug!("using token for {}", registry);
                 req.bearer_auth(&token_struct.token)
             }
-            None => req,
+            None => match (self.basic.contains(registry), self.logins.get(registry)) {
+                (true, Some(login)) => {
+                    log::debug!("using basic auth for {}", registry);
+                    req.basic_auth(&login.username, login.password.as_ref())
+                }
+                _ => req,
+            },
         }
     }
 
+    /// Find the login for a registry, asking a credential helper if necessary
+    async fn get_login(&mut self, registry: &Registry) -> Result<Option<Login>, ImageError> {
+        if let Some(login) = self.logins.get(registry) {
+            return Ok(Some(login.clone()));
+        }
+        let helper = match (self.helpers.get(registry), &self.default_helper) {
+            (Some(helper), _) => helper.clone(),
+            (None, Some(name)) => CredentialHelper {
+                name: name.clone(),
+                server_url: registry.to_string(),
+            },
+            (None, None) => return Ok(None),
+        };
+        let login = run_credential_helper(&helper).await?;
+        if let Some(login) = &login {
+            self.logins.insert(registry.clone(), login.clone());
+        }
+        Ok(login)
+    }
+
     /// Reference: <https://docs.docker.com/registry/spec/auth/token/>
     async fn authenticate_for(
         &mut self,
         registry: &Registry,
+        repository: &Repository,
         req: &reqwest::Client,
         auth_header: &str,
     ) -> Result<(), ImageError> {
         let challenge = BearerChallenge::parse(auth_header)?;
         log::debug!("login challenge for {}, {:?}", registry, challenge);
-        let req = req
-            .get(challenge.realm)
-            .query(&[("service", challenge.service), ("scope", challenge.scope)]);
-        let req = match self.logins.get(registry) {
-            Some(login) => req.basic_auth(&login.username, login.password.as_ref()),
-            None => req,
+        let login = self.get_login(registry).await?;
+        let cache_key = challenge.cache_key(registry, repository, login.as_ref());
+        // A cached token we just sent was rejected, so don't try it again
+        let key = (registry.clone(), repository.clone());
+        let rejected = self.tokens.remove(&key).map(|token| token.token);
+        let token = match self.load_cached_token(&cache_key) {
+            Some(token) if Some(&token.token) != rejected.as_ref() => {
+                log::debug!("using cached token for {}/{}", registry, repository);
+                token
+            }
+            _ => {
+                let req = req
+                    .get(challenge.realm)
+                    .query(&[("service", challenge.service), ("scope", challenge.scope)]);
+                let req = match &login {
+                    Some(login) => req.basic_auth(&login.username, login.password.as_ref()),
+                    None => req,
+                };
+                let response: Token = req.send().await?.error_for_status()?.json().await?;
+                log::debug!("received token for {}", registry);
+                self.save_cached_token(&cache_key, &response);
+                response
+            }
         };
-        let response: Token = req.send().await?.error_for_status()?.json().await?;
-        log::debug!("received token for {}", registry);
-        self.tokens.insert(registry.clone(), response);
+        self.tokens.insert(key, token);
         Ok(())
     }
 
@@ -66,12 +205,14 @@ impl Auth {
     pub async fn request(
         &mut self,
         registry: &Registry,
+        repository: &Repository,
         client: &reqwest::Client,
         req: RequestBuilder,
     ) -> Result<Response, ImageError> {
         let response = self
             .include_token(
                 registry,
+                repository,
                 req.try_clone()
                     .expect("not expecting unclonable requests here"),
             )
@@ -85,9 +226,20 @@ impl Auth {
             {
                 None => Ok(response),
                 Some(Err(_bad_string)) => Ok(response),
+                Some(Ok(auth_header)) if is_basic_challenge(auth_header) => {
+                    // Basic auth has no token exchange; retry with the login
+                    // itself, if we have one.
+                    if self.get_login(registry).await?.is_none() {
+                        return Ok(response);
+                    }
+                    log::debug!("basic auth challenge for {}", registry);
+                    self.basic.insert(registry.clone());
+                    Ok(self.include_token(registry, repository, req).send().await?)
+                }
                 Some(Ok(auth_header)) => {
-                    self.authenticate_for(registry, client, auth_header).await?;
-                    Ok(self.include_token(registry, req).send().await?)
+                    self.authenticate_for(registry, repository, client, auth_header)
+                        .await?;
+                    Ok(self.include_token(registry, repository, req).send().await?)
                 }
             }
         } else {
@@ -96,6 +248,52 @@ impl Auth {
     }
 }
 
+fn is_basic_challenge(auth_header: &str) -> bool {
+    auth_header
+        .trim_start()
+        .get(..5)
+        .map(|scheme| scheme.eq_ignore_ascii_case("basic"))
+        .unwrap_or(false)
+}
+
+/// Reference: <https://github.com/docker/docker-credential-helpers>
+async fn run_credential_helper(helper: &CredentialHelper) -> Result<Option<Login>, ImageError> {
+    let program = format!("docker-credential-{}", helper.name);
+    log::debug!("asking {} for {} credentials", program, helper.server_url);
+    let helper_error =
+        |e: std::io::Error| ImageError::CredentialHelper(program.clone(), e.to_string());
+    let mut child = Command::new(&program)
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(helper_error)?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(helper.server_url.as_bytes())
+            .await
+            .map_err(helper_error)?;
+    }
+    let output = child.wait_with_output().await.map_err(helper_error)?;
+    if !output.status.success() {
+        // helpers report a missing entry with a message on stdout
+        let message = String::from_utf8_lossy(&output.stdout);
+        if message.contains("credentials not found") {
+            return Ok(None);
+        }
+        return Err(ImageError::CredentialHelper(
+            program,
+            message.trim().to_string(),
+        ));
+    }
+    let credentials: HelperCredentials = serde_json::from_slice(&output.stdout)?;
+    Ok(Some(Login {
+        username: credentials.username,
+        password: Some(credentials.secret),
+    }))
+}
+
 #[derive(Debug, Clone)]
 struct BearerChallenge {
     realm: Url,
@@ -103,12 +301,56 @@ struct BearerChallenge {
     scope: String,
 }
 
+/// Token lifetime in seconds, for servers that don't say
+const DEFAULT_TOKEN_EXPIRY: u64 = 60;
+
+/// Cached tokens aren't used if they'd expire within this many seconds
+const TOKEN_EXPIRY_MARGIN: u64 = 10;
+
 #[derive(Clone, Deserialize)]
 struct Token {
     token: String,
+    expires_in: Option<u64>,
+}
+
+/// A token saved to disk, expiring at a time in seconds since the epoch
+#[derive(Serialize, Deserialize)]
+struct CachedToken {
+    token: String,
+    expires: u64,
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
 }
 
 impl BearerChallenge {
+    /// Where a token for this challenge is cached
+    ///
+    /// Tokens are only reused for the same login asking the same server for
+    /// the same scope, so the key includes a digest of all of those.
+    fn cache_key(
+        &self,
+        registry: &Registry,
+        repository: &Repository,
+        login: Option<&Login>,
+    ) -> StorageKey {
+        let request = serde_json::json!([
+            self.realm.as_str(),
+            self.service,
+            self.scope,
+            login.map(|login| &login.username),
+        ]);
+        StorageKey::Token(
+            registry.clone(),
+            repository.clone(),
+            ContentDigest::from_content(request.to_string().as_bytes()),
+        )
+    }
+
     fn parse(auth_header: &str) -> Result<Self, ImageError> {
         lazy_static! {
             static ref RE: Regex = Regex::new(concat!(
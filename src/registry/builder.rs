@@ -4,7 +4,10 @@ use crate::{
     errors::ImageError,
     filesystem::storage::FileStorage,
     image::Registry,
-    registry::{auth::Auth, DefaultRegistry, RegistryClient},
+    registry::{
+        auth::Auth, docker_config, retry::RetryPolicy, throttle::Throttle, DefaultRegistry,
+        RegistryClient,
+    },
 };
 
 use reqwest::{
@@ -26,14 +29,31 @@ enum CacheOption {
     Ephemeral,
 }
 
+enum DockerConfigOption {
+    None,
+    Default,
+    Path(PathBuf),
+}
+
 /// Builder for configuring custom [RegistryClient] instances
 pub struct RegistryClientBuilder {
     auth: Auth,
     cache_option: CacheOption,
+    compress_cache: bool,
+    docker_config: DockerConfigOption,
     network: Option<ClientBuilder>,
     default_registry: Option<DefaultRegistry>,
     allowed_registries: Option<HashSet<Registry>>,
     allow_http_registries: bool,
+    require_digest: bool,
+    require_registry: bool,
+    max_download_rate: Option<u64>,
+    retry: RetryPolicy,
+    cache_size_limit: Option<u64>,
+    token_cache: bool,
+    decompressed_cache: bool,
+    streaming_extract: bool,
+    validate_entrypoint: bool,
 }
 
 impl RegistryClientBuilder {
@@ -42,10 +62,21 @@ impl RegistryClientBuilder {
         RegistryClientBuilder {
             network: Some(Client::builder().user_agent(RegistryClient::default_user_agent())),
             cache_option: CacheOption::Default,
+            compress_cache: false,
+            docker_config: DockerConfigOption::None,
             default_registry: None,
             auth: Auth::new(),
             allowed_registries: None,
             allow_http_registries: true,
+            require_digest: false,
+            require_registry: false,
+            max_download_rate: None,
+            retry: RetryPolicy::default(),
+            cache_size_limit: None,
+            token_cache: true,
+            decompressed_cache: true,
+            streaming_extract: true,
+            validate_entrypoint: false,
         }
     }
 
@@ -59,6 +90,27 @@ impl RegistryClientBuilder {
         self
     }
 
+    /// Refuse to pull any image that isn't named by its content digest
+    ///
+    /// Tags are mutable, and an image pulled by tag is only as trustworthy as
+    /// the server it came from. With this setting, pulling an [ImageName]
+    /// without an `@sha256:` digest fails with [ImageError::DigestRequired].
+    pub fn require_digest(mut self) -> Self {
+        self.require_digest = true;
+        self
+    }
+
+    /// Refuse to pull images that don't name a registry
+    ///
+    /// Normally a name like `busybox` implies the default registry. With this
+    /// setting, pulling an [ImageName] without an explicit registry fails
+    /// with [ImageError::RegistryRequired], so every reference says which
+    /// server it trusts.
+    pub fn no_default_registry(mut self) -> Self {
+        self.require_registry = true;
+        self
+    }
+
     /// Only use images already in the local cache
     pub fn offline(mut self) -> Self {
         self.network = None;
@@ -97,6 +149,86 @@ impl RegistryClientBuilder {
         self
     }
 
+    /// Limit the size of the cache directory, in bytes
+    ///
+    /// After each pull, the least recently used objects in the cache are
+    /// deleted until it's back under the limit. Layers of the image that was
+    /// just pulled are kept even if they're larger than the limit on their
+    /// own, but images pulled earlier may lose layers they'd need to read
+    /// files later.
+    /// By default the cache can grow without limit.
+    pub fn cache_size_limit(mut self, max_bytes: u64) -> Self {
+        self.cache_size_limit = Some(max_bytes);
+        self
+    }
+
+    /// Keep cached blobs zstd-compressed on disk
+    ///
+    /// Decompressed layers take up most of the cache. With this option they
+    /// are stored compressed in independently decompressable chunks, at the
+    /// cost of some CPU time when images are loaded and when files are first
+    /// read from a layer.
+    pub fn compress_cache(mut self) -> Self {
+        self.compress_cache = true;
+        self
+    }
+
+    /// Keep only the compressed form of each image layer
+    ///
+    /// Normally each file in a gzip layer is decompressed once and stored on
+    /// its own, which needs roughly twice the disk space of the compressed
+    /// download but lets any file be read directly. With this option, the
+    /// gzip compressed layer is stored as-is and decompressed again each time
+    /// the image is loaded. Gzip streams can't be read from the middle, so the
+    /// first read of each file decompresses the layer up to that file, and
+    /// that file's contents are kept in the cache afterward. Layers in other
+    /// formats, like zstd, are still stored decompressed.
+    pub fn no_decompressed_cache(mut self) -> Self {
+        self.decompressed_cache = false;
+        self
+    }
+
+    /// Store each layer as one decompressed blob instead of extracting its
+    /// files while it downloads
+    ///
+    /// Normally a gzip layer is decompressed straight into separate cached
+    /// files, plus an index holding just the tar headers, and the whole
+    /// decompressed tar is never written out. This option goes back to storing
+    /// that tar and copying files out of it the first time they're read.
+    /// It's slower and writes more, but the stored layer can be inspected
+    /// directly, which helps when debugging. Layers are only extracted
+    /// while downloading when the image config lists their digests, so
+    /// older image formats always work this way.
+    pub fn no_streaming_extract(mut self) -> Self {
+        self.streaming_extract = false;
+        self
+    }
+
+    /// Don't save registry auth tokens in the cache directory
+    ///
+    /// Normally the bearer tokens a registry gives us are saved, readable
+    /// only by the current user, so later clients can reuse them until they
+    /// expire instead of logging in again. With this option, every client
+    /// starts without a token.
+    pub fn no_token_cache(mut self) -> Self {
+        self.token_cache = false;
+        self
+    }
+
+    /// Check each loaded image for an executable entrypoint
+    ///
+    /// The first word of the image's entrypoint and command must name an
+    /// executable file in its filesystem, searching the image's `PATH` if
+    /// needed. Images that fail the check are refused with
+    /// [ImageError::EntrypointMissing] when they're pulled, instead of when a
+    /// container first tries to run them. Commands overridden with
+    /// [crate::ContainerBuilder] aren't known yet, so only the image's own
+    /// configuration is checked.
+    pub fn validate_entrypoint(mut self) -> Self {
+        self.validate_entrypoint = true;
+        self
+    }
+
     /// Set a timeout for each network request
     ///
     /// This timeout applies from the beginning of a (GET) request until the
@@ -119,6 +251,46 @@ impl RegistryClientBuilder {
         self
     }
 
+    /// Limit the total download rate, in bytes per second
+    ///
+    /// The limit is shared by every download this client makes, including
+    /// layers downloaded concurrently. Short bursts above the rate are
+    /// allowed, up to a quarter second's worth of data. By default there is
+    /// no limit.
+    pub fn max_download_rate(mut self, bytes_per_sec: u64) -> Self {
+        self.max_download_rate = Some(bytes_per_sec);
+        self
+    }
+
+    /// Set how many times a failed download is retried
+    ///
+    /// Downloads are retried after the registry responds with a 429 or 5xx
+    /// status, or when the connection fails or drops partway through. Each
+    /// retry starts the download over. The default is 3 retries.
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.retry.max_retries = max_retries;
+        self
+    }
+
+    /// Set the delay before the first retry of a failed download
+    ///
+    /// The delay doubles with each further retry of the same download. A
+    /// `Retry-After` header from the registry, in seconds, takes precedence.
+    /// The default is one second.
+    pub fn retry_backoff(mut self, backoff: Duration) -> Self {
+        self.retry.backoff = backoff;
+        self
+    }
+
+    /// Set the longest delay before any retry of a failed download
+    ///
+    /// This caps both the doubling backoff and any `Retry-After` header from
+    /// the registry. The default is 30 seconds.
+    pub fn max_retry_backoff(mut self, max_backoff: Duration) -> Self {
+        self.retry.max_backoff = max_backoff;
+        self
+    }
+
     /// Sets the `User-Agent` header used by this client
     ///
     /// By default, the value returened by
@@ -168,15 +340,25 @@ impl RegistryClientBuilder {
     /// The default value if unset can be determined with
     /// [RegistryClient::default_registry()]
     ///
-    /// The parameter is a [DefaultRegistry], which provides a few additional
-    /// options for emulating registry quirks. When those aren't needed,
-    /// a [Registry] can be converted directly into a [DefaultRegistry] by
-    /// calling its `into()`.
-    pub fn registry(mut self, default_registry: &DefaultRegistry) -> Self {
-        self.default_registry = Some(default_registry.clone());
+    /// The parameter is a [DefaultRegistry], which also controls the
+    /// `library_prefix` added to single-component names like `busybox`, and
+    /// the other names this registry is `also_known_as`. When those aren't
+    /// needed, a plain [Registry] can be passed instead.
+    pub fn default_registry<R>(mut self, default_registry: R) -> Self
+    where
+        R: Into<DefaultRegistry>,
+    {
+        self.default_registry = Some(default_registry.into());
         self
     }
 
+    /// Change the default registry server
+    ///
+    /// Equivalent to [RegistryClientBuilder::default_registry()]
+    pub fn registry(self, default_registry: &DefaultRegistry) -> Self {
+        self.default_registry(default_registry.clone())
+    }
+
     /// Store a username and password for use with a particular registry on this
     /// client
     pub fn login(mut self, registry: Registry, username: String, password: Option<String>) -> Self {
@@ -184,8 +366,40 @@ impl RegistryClientBuilder {
         self
     }
 
+    /// Use logins stored by `docker login`
+    ///
+    /// Static credentials from the config file's `auths` section are added as
+    /// if by [RegistryClientBuilder::login()], and registries named under
+    /// `credHelpers` or `credsStore` will run the corresponding external
+    /// `docker-credential-*` program when a server asks us to authenticate.
+    /// Logins set directly on the builder take precedence.
+    ///
+    /// With a path, that file must exist. With `None`, the file is
+    /// `$DOCKER_CONFIG/config.json` or `~/.docker/config.json`, and it's fine
+    /// if it doesn't exist. Either way, it's read when the client is built.
+    pub fn docker_config(mut self, path: Option<&Path>) -> Self {
+        self.docker_config = match path {
+            Some(path) => DockerConfigOption::Path(path.to_owned()),
+            None => DockerConfigOption::Default,
+        };
+        self
+    }
+
     /// Construct a RegistryClient using the parameters from this Builder
     pub fn build(self) -> Result<RegistryClient, ImageError> {
+        let default_registry = self
+            .default_registry
+            .unwrap_or_else(RegistryClient::default_registry);
+        let mut auth = match self.docker_config {
+            DockerConfigOption::None => self.auth,
+            DockerConfigOption::Default => match docker_config::default_path() {
+                None => self.auth,
+                Some(path) => with_docker_config(self.auth, &default_registry, &path, false)?,
+            },
+            DockerConfigOption::Path(path) => {
+                with_docker_config(self.auth, &default_registry, &path, true)?
+            }
+        };
         let (cache_dir, temp_dir) = match self.cache_option {
             CacheOption::Dir(dir) => (dir, None),
             CacheOption::Default => (RegistryClient::default_cache_dir()?, None),
@@ -201,17 +415,44 @@ impl RegistryClientBuilder {
             }
         };
         log::debug!("using cache directory {:?}", cache_dir);
+        let mut storage = FileStorage::new(cache_dir, temp_dir);
+        if self.compress_cache {
+            storage = storage.compressed();
+        }
+        if self.token_cache {
+            auth.token_cache(storage.clone());
+        }
         Ok(RegistryClient::from_parts(
-            FileStorage::new(cache_dir, temp_dir),
-            self.auth,
+            storage,
+            auth,
             match self.network {
                 Some(n) => Some(n.build()?),
                 None => None,
             },
-            self.default_registry
-                .unwrap_or_else(RegistryClient::default_registry),
+            default_registry,
             self.allowed_registries,
             self.allow_http_registries,
+            self.require_digest,
+            self.require_registry,
+            self.max_download_rate.map(Throttle::new),
+            self.retry,
+            self.cache_size_limit,
+            self.decompressed_cache,
+            self.streaming_extract,
+            self.validate_entrypoint,
         ))
     }
 }
+
+/// Load a docker config underneath the logins already set on the builder
+fn with_docker_config(
+    builder_auth: Auth,
+    default_registry: &DefaultRegistry,
+    path: &Path,
+    required: bool,
+) -> Result<Auth, ImageError> {
+    let mut auth = Auth::new();
+    docker_config::load(&mut auth, default_registry, path, required)?;
+    auth.merge(builder_auth);
+    Ok(auth)
+}
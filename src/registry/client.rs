@@ -4,13 +4,19 @@ use crate::{
     errors::ImageError,
     filesystem::{
         storage,
-        storage::{FileStorage, StorageKey, StorageWriter},
+        storage::{FileStorage, HashWriter, StorageKey, StorageWriter},
         tar,
         vfs::Filesystem,
     },
-    image::{ContentDigest, Image, ImageName, ImageVersion, Registry, Repository},
-    manifest::{media_types, Link, Manifest, RuntimeConfig, FS_TYPE},
-    registry::{auth::Auth, progress::*, DefaultRegistry, RegistryClientBuilder},
+    image::{
+        ContentDigest, Image, ImageInspect, ImageName, ImageVersion, LayerInfo, Registry,
+        Repository,
+    },
+    manifest::{media_types, AnyManifest, Link, ManifestList, ManifestV1, RuntimeConfig, FS_TYPE},
+    registry::{
+        abort, auth::Auth, oci_layout, progress::*, retry, retry::RetryPolicy, throttle::Throttle,
+        DefaultRegistry, RegistryClientBuilder,
+    },
 };
 
 use futures_util::{stream::FuturesUnordered, StreamExt};
@@ -20,11 +26,13 @@ use std::{
     collections::HashSet,
     env,
     fmt::Display,
-    io::{Read, Write},
-    path::PathBuf,
+    fs::File,
+    io,
+    io::{Cursor, Read, Write},
+    path::{Path, PathBuf},
     sync::Arc,
 };
-use tokio::{sync::mpsc, task};
+use tokio::{sync::mpsc, task, time::delay_for};
 
 /// Registry clients can download and store data from an image registry
 ///
@@ -39,6 +47,14 @@ pub struct RegistryClient {
     default_registry: DefaultRegistry,
     allowed_registries: Option<HashSet<Registry>>,
     allow_http_registries: bool,
+    require_digest: bool,
+    require_registry: bool,
+    throttle: Option<Throttle>,
+    retry: RetryPolicy,
+    cache_size_limit: Option<u64>,
+    decompressed_cache: bool,
+    streaming_extract: bool,
+    validate_entrypoint: bool,
 }
 
 impl RegistryClient {
@@ -59,6 +75,14 @@ impl RegistryClient {
         default_registry: DefaultRegistry,
         allowed_registries: Option<HashSet<Registry>>,
         allow_http_registries: bool,
+        require_digest: bool,
+        require_registry: bool,
+        throttle: Option<Throttle>,
+        retry: RetryPolicy,
+        cache_size_limit: Option<u64>,
+        decompressed_cache: bool,
+        streaming_extract: bool,
+        validate_entrypoint: bool,
     ) -> Self {
         RegistryClient {
             storage,
@@ -67,6 +91,14 @@ impl RegistryClient {
             default_registry,
             allowed_registries,
             allow_http_registries,
+            require_digest,
+            require_registry,
+            throttle,
+            retry,
+            cache_size_limit,
+            decompressed_cache,
+            streaming_extract,
+            validate_entrypoint,
         }
     }
 
@@ -140,7 +172,9 @@ impl RegistryClient {
         .parse()
         .expect("url components already validated");
 
-        let req = network.get(url);
+        // Content digests cover the registry's bytes exactly, so any transfer
+        // compression would only have to be undone before checking them
+        let req = network.get(url).header(header::ACCEPT_ENCODING, "identity");
         Ok((network, &mut self.auth, req))
     }
 
@@ -155,10 +189,11 @@ impl RegistryClient {
         let storage = self.storage.clone();
         let mut progress = progress.clone();
         let progress_resource = progress_resource.clone();
+        let throttle = self.throttle.clone();
 
         // Send blocks from the async reactor to a sync thread pool for hashing
         let (send_channel, recv_channel) = std::sync::mpsc::channel::<bytes::Bytes>();
-        let send_task = task::spawn(async move {
+        let send_task = abort::spawn(async move {
             progress
                 .send(PullProgress::Update(ProgressUpdate {
                     resource: progress_resource.clone(),
@@ -175,6 +210,9 @@ impl RegistryClient {
             loop {
                 match response.chunk().await? {
                     Some(chunk) => {
+                        if let Some(throttle) = &throttle {
+                            throttle.consume(chunk.len()).await;
+                        }
                         progress_counter += chunk.len() as u64;
                         send_channel.send(chunk)?;
                         progress
@@ -232,22 +270,23 @@ impl RegistryClient {
         }
     }
 
-    async fn download_manifest(
+    /// Send a GET request and download its response into storage
+    ///
+    /// Requests that fail with a 429 or 5xx status, or that lose their
+    /// connection, start over from the beginning after a delay, up to the
+    /// client's retry limit.
+    async fn download_object<T: Display>(
         &mut self,
         progress: &mut mpsc::Sender<PullProgress>,
+        progress_resource: &Arc<ProgressResource>,
         registry: &Registry,
         repository: &Repository,
-        version: &ImageVersion,
+        bucket: &'static str,
+        object: &T,
+        accept: &[HeaderValue],
     ) -> Result<(StorageWriter, ContentDigest), ImageError> {
-        if !(registry.is_https() || version.is_content_digest()) {
-            Err(ImageError::InsecureManifest)
-        } else {
-            let progress_resource = Arc::new(ProgressResource::Manifest(
-                registry.clone(),
-                repository.clone(),
-                version.clone(),
-            ));
-
+        let mut attempt = 0;
+        loop {
             progress
                 .send(PullProgress::Update(ProgressUpdate {
                     resource: progress_resource.clone(),
@@ -257,15 +296,11 @@ impl RegistryClient {
                 .await
                 .map_err(|_| ImageError::PullTaskError)?;
 
-            let (network, auth, request) =
-                self.begin_get(registry, repository, "manifests", version)?;
-            let response = auth
-                .request(
-                    registry,
-                    network,
-                    request.header(header::ACCEPT, media_types::MANIFEST),
-                )
-                .await;
+            let (network, auth, request) = self.begin_get(registry, repository, bucket, object)?;
+            let request = accept.iter().fold(request, |request, value| {
+                request.header(header::ACCEPT, value.clone())
+            });
+            let response = auth.request(registry, repository, network, request).await;
 
             progress
                 .send(PullProgress::Update(ProgressUpdate {
@@ -276,8 +311,68 @@ impl RegistryClient {
                 .await
                 .map_err(|_| ImageError::PullTaskError)?;
 
-            self.download_response(progress, &progress_resource, response?)
-                .await
+            let (retry_after, result) = match response {
+                Err(err) => (None, Err(err)),
+                Ok(response) => (
+                    retry::retry_after(&response),
+                    self.download_response(progress, progress_resource, response)
+                        .await,
+                ),
+            };
+            match result {
+                Err(err) if attempt < self.retry.max_retries && retry::is_transient(&err) => {
+                    let delay = self.retry.delay(attempt, retry_after);
+                    log::warn!(
+                        "{}/{} failed ({}), retrying in {:?}",
+                        repository,
+                        object,
+                        err,
+                        delay
+                    );
+                    delay_for(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn download_manifest(
+        &mut self,
+        progress: &mut mpsc::Sender<PullProgress>,
+        registry: &Registry,
+        repository: &Repository,
+        version: &ImageVersion,
+    ) -> Result<(StorageWriter, ContentDigest), ImageError> {
+        if !(registry.is_https() || version.is_content_digest()) {
+            Err(ImageError::InsecureManifest)
+        } else {
+            let progress_resource = Arc::new(ProgressResource::Manifest(
+                registry.clone(),
+                repository.clone(),
+                version.clone(),
+            ));
+            let accept = [
+                media_types::MANIFEST,
+                media_types::MANIFEST_LIST,
+                media_types::OCI_MANIFEST,
+                media_types::OCI_INDEX,
+                media_types::MANIFEST_V1_SIGNED,
+                media_types::MANIFEST_V1,
+            ]
+            .iter()
+            .map(|media_type| HeaderValue::from_static(media_type))
+            .collect::<Vec<_>>();
+            self.download_object(
+                progress,
+                &progress_resource,
+                registry,
+                repository,
+                "manifests",
+                version,
+                &accept,
+            )
+            .await
         }
     }
 
@@ -290,37 +385,17 @@ impl RegistryClient {
         content_digest: &ContentDigest,
         content_type: &HeaderValue,
     ) -> Result<StorageWriter, ImageError> {
-        progress
-            .send(PullProgress::Update(ProgressUpdate {
-                resource: progress_resource.clone(),
-                phase: ProgressPhase::Connect,
-                event: ProgressEvent::Begin,
-            }))
-            .await
-            .map_err(|_| ImageError::PullTaskError)?;
-
-        let (network, auth, request) =
-            self.begin_get(registry, repository, "blobs", content_digest)?;
-        let response = auth
-            .request(
+        let (mut writer, found_digest) = self
+            .download_object(
+                progress,
+                progress_resource,
                 registry,
-                network,
-                request.header(header::ACCEPT, content_type),
+                repository,
+                "blobs",
+                content_digest,
+                std::slice::from_ref(content_type),
             )
             .await?;
-
-        progress
-            .send(PullProgress::Update(ProgressUpdate {
-                resource: progress_resource.clone(),
-                phase: ProgressPhase::Connect,
-                event: ProgressEvent::Complete,
-            }))
-            .await
-            .map_err(|_| ImageError::PullTaskError)?;
-
-        let (mut writer, found_digest) = self
-            .download_response(progress, &progress_resource, response)
-            .await?;
         if &found_digest == content_digest {
             Ok(writer)
         } else {
@@ -332,15 +407,47 @@ impl RegistryClient {
         }
     }
 
+    /// Get the manifest for an image, following a manifest list to the
+    /// image for our platform
+    ///
+    /// The name returned has the content digest of the manifest we chose,
+    /// not the list.
     async fn pull_manifest(
         &mut self,
         progress: &mut mpsc::Sender<PullProgress>,
         image: &ImageName,
-    ) -> Result<(ImageName, Manifest), ImageError> {
+    ) -> Result<(ImageName, AnyManifest), ImageError> {
+        let (specific_image, map) = self.pull_manifest_data(progress, image).await?;
+        let list = match ManifestList::parse(&map[..])? {
+            None => return Ok((specific_image, AnyManifest::parse(&map[..])?)),
+            Some(list) => list,
+        };
+        let link = list.find_platform()?;
+        log::debug!(
+            "{} is a manifest list, choosing {}",
+            specific_image,
+            link.digest
+        );
+        let platform_image = ImageName::from_parts(
+            specific_image.registry_str(),
+            specific_image.repository_str(),
+            specific_image.tag_str(),
+            Some(&link.digest),
+        )?;
+        let (platform_image, map) = self.pull_manifest_data(progress, &platform_image).await?;
+        Ok((platform_image, AnyManifest::parse(&map[..])?))
+    }
+
+    /// Get the raw data for one manifest, from the cache or the registry
+    async fn pull_manifest_data(
+        &mut self,
+        progress: &mut mpsc::Sender<PullProgress>,
+        image: &ImageName,
+    ) -> Result<(ImageName, Mmap), ImageError> {
         let (registry, repository) = self.default_registry.resolve_image_name(image);
         let key = StorageKey::Manifest(registry, repository, image.version());
-        let (specific_image, map) = match self.storage.mmap(&key)? {
-            Some(map) => {
+        let (specific_image, map) = match self.storage.mmap_or_lock(&key).await? {
+            Ok(map) => {
                 // If the manifest is cached, still verify its content digest and annotate the
                 // ImageName with that digest
                 let found_digest = ContentDigest::from_content(&map[..]);
@@ -348,7 +455,7 @@ impl RegistryClient {
                 log::debug!("{} manifest in cache is good", specific_image);
                 (specific_image, map)
             }
-            None => match &key {
+            Err(_lock) => match &key {
                 StorageKey::Manifest(registry, repository, version) => {
                     let (mut writer, found_digest) = self
                         .download_manifest(progress, registry, repository, version)
@@ -393,21 +500,19 @@ impl RegistryClient {
             },
         };
 
-        let slice = &map[..];
         log::trace!(
             "raw json manifest for {}: {:?}",
             specific_image,
-            String::from_utf8_lossy(slice)
+            String::from_utf8_lossy(&map[..])
         );
-        Ok((specific_image, serde_json::from_slice(slice)?))
+        Ok((specific_image, map))
     }
 
     fn check_mmap_for_link(link: &Link, mmap: Mmap) -> Result<Mmap, ImageError> {
         log::trace!("{:?} mapped {} bytes", link, mmap.len());
-        if mmap.len() as u64 == link.size {
-            Ok(mmap)
-        } else {
-            Err(ImageError::UnexpectedContentSize)
+        match link.size {
+            Some(size) if mmap.len() as u64 != size => Err(ImageError::UnexpectedContentSize),
+            _ => Ok(mmap),
         }
     }
 
@@ -429,12 +534,12 @@ impl RegistryClient {
         let content_type = RegistryClient::content_type_for_link(link)?;
         let mmap = RegistryClient::check_mmap_for_link(
             link,
-            match self.storage.mmap(&key)? {
-                Some(map) => {
+            match self.storage.mmap_or_lock(&key).await? {
+                Ok(map) => {
                     log::debug!("{} blob {} is already cached", image, link.digest);
                     map
                 }
-                None => match &key {
+                Err(_lock) => match &key {
                     StorageKey::Blob(content_digest) => {
                         let writer = self
                             .download_blob(
@@ -515,9 +620,16 @@ impl RegistryClient {
         image: &ImageName,
         link: &Link,
     ) -> Result<RuntimeConfig, ImageError> {
-        if link.media_type == media_types::RUNTIME_CONFIG {
+        if let media_types::RUNTIME_CONFIG | media_types::OCI_CONFIG = link.media_type.as_str() {
             let (mapref, _progress_resource) = self.pull_blob(progress, image, link).await?;
             let slice = &mapref[..];
+            // Downloads are verified as they arrive, but check the config
+            // against the manifest every time in case it came from the cache
+            let expected = ContentDigest::parse(&link.digest)?;
+            let found = ContentDigest::from_content(slice);
+            if found != expected {
+                return Err(ImageError::ContentDigestMismatch { expected, found });
+            }
             log::trace!(
                 "raw json runtime config, {}",
                 String::from_utf8_lossy(slice)
@@ -530,26 +642,42 @@ impl RegistryClient {
         }
     }
 
+    /// Download and decompress layers, returning the digest of each
+    /// decompressed layer in the same order as the links
+    ///
+    /// If we know the `diff_ids` the layers should decompress to, layers
+    /// that someone else stores while we wait for them aren't downloaded
+    /// again.
     async fn pull_layers(
         &mut self,
         progress: &mut mpsc::Sender<PullProgress>,
         image: &ImageName,
         links: &[Link],
-    ) -> Result<(), ImageError> {
+        diff_ids: Option<&[String]>,
+    ) -> Result<Vec<ContentDigest>, ImageError> {
         let mut tasks = FuturesUnordered::new();
-        for link in links {
+        for (index, link) in links.iter().enumerate() {
             let mut client = self.clone();
             let mut progress = progress.clone();
             let image = image.clone();
             let link = link.clone();
-            tasks.push(task::spawn(async move {
-                client.pull_layer(&mut progress, &image, &link).await
+            let diff_id = match diff_ids {
+                Some(diff_ids) => Some(ContentDigest::parse(&diff_ids[index])?),
+                None => None,
+            };
+            tasks.push(abort::spawn(async move {
+                let result = client
+                    .pull_layer(&mut progress, &image, &link, diff_id)
+                    .await;
+                result.map(|digest| (index, digest))
             }));
         }
+        let mut digests = vec![None; links.len()];
         while let Some(result) = tasks.next().await {
-            result??;
+            let (index, digest) = result??;
+            digests[index] = Some(digest);
         }
-        Ok(())
+        Ok(digests.into_iter().map(Option::unwrap).collect())
     }
 
     async fn pull_layer(
@@ -557,21 +685,66 @@ impl RegistryClient {
         progress: &mut mpsc::Sender<PullProgress>,
         image: &ImageName,
         link: &Link,
-    ) -> Result<(), ImageError> {
-        if link.media_type == media_types::LAYER_TAR_GZIP {
-            self.pull_gzip_layer(progress, image, link).await
-        } else {
-            Err(ImageError::UnsupportedLayerType(link.media_type.clone()))
-        }
+        diff_id: Option<ContentDigest>,
+    ) -> Result<ContentDigest, ImageError> {
+        let compression = match link.media_type.as_str() {
+            media_types::LAYER_TAR_GZIP | media_types::OCI_LAYER_TAR_GZIP => Compression::Gzip,
+            media_types::OCI_LAYER_TAR_ZSTD => Compression::Zstd,
+            media_types::OCI_LAYER_TAR => {
+                // Already decompressed, so the blob is the layer itself
+                self.pull_blob(progress, image, link).await?;
+                return ContentDigest::parse(&link.digest);
+            }
+            other => return Err(ImageError::UnsupportedLayerType(other.to_owned())),
+        };
+        // Another puller may be decompressing the same layer
+        let _lock = match &diff_id {
+            None => None,
+            Some(diff_id) => {
+                let key = StorageKey::Blob(diff_id.clone());
+                let lock = self.storage.lock(&key).await?;
+                if self.storage.layer_exists(&key) {
+                    log::debug!("{} layer {} was stored while we waited", image, diff_id);
+                    return Ok(diff_id.clone());
+                }
+                Some(lock)
+            }
+        };
+        self.pull_compressed_layer(progress, image, link, compression, diff_id)
+            .await
     }
 
-    async fn pull_gzip_layer(
+    /// Download and decompress one layer
+    ///
+    /// Only gzip layers can be kept compressed behind a
+    /// [StorageKey::GzipLink], so other formats are always stored
+    /// decompressed. When we know a gzip layer's `diff_id` ahead of time, its
+    /// files are stored individually as they're decompressed.
+    async fn pull_compressed_layer(
         &mut self,
         progress: &mut mpsc::Sender<PullProgress>,
         image: &ImageName,
         link: &Link,
-    ) -> Result<(), ImageError> {
-        let (source, progress_resource) = self.pull_blob_uncached(progress, image, link).await?;
+        compression: Compression,
+        diff_id: Option<ContentDigest>,
+    ) -> Result<ContentDigest, ImageError> {
+        let streaming_extract = match diff_id {
+            Some(diff_id)
+                if self.streaming_extract
+                    && self.decompressed_cache
+                    && compression == Compression::Gzip =>
+            {
+                Some(diff_id)
+            }
+            _ => None,
+        };
+        let decompressed_cache = self.decompressed_cache || compression != Compression::Gzip;
+        let (source, progress_resource) = if decompressed_cache {
+            self.pull_blob_uncached(progress, image, link).await?
+        } else {
+            self.pull_blob(progress, image, link).await?
+        };
+        let compressed_digest = ContentDigest::parse(&link.digest)?;
         let task_storage = self.storage.clone();
         let mut task_progress = progress.clone();
         let task_progress_resource = progress_resource.clone();
@@ -585,38 +758,69 @@ impl RegistryClient {
             .await
             .map_err(|_| ImageError::PullTaskError)?;
 
-        task::spawn_blocking(move || -> Result<(), ImageError> {
-            let mut writer = task_storage.begin_write()?;
-            let mut decoder = flate2::bufread::GzDecoder::new(std::io::Cursor::new(&*source));
-            let mut buffer = [0u8; 256 * 1024];
+        let content_digest = task::spawn_blocking(move || -> Result<ContentDigest, ImageError> {
             log::info!("decompressing {} bytes", source.len());
-
-            let result: std::io::Result<()> = loop {
-                match decoder.read(&mut buffer) {
-                    Err(err) => break Err(err),
-                    Ok(size) if size == 0 => break Ok(()),
-                    Ok(size) => match writer.write_all(&buffer[..size]) {
-                        Err(err) => break Err(err),
-                        Ok(()) => {
-                            let _ = task_progress.try_send(PullProgress::Update(ProgressUpdate {
-                                resource: task_progress_resource.clone(),
-                                phase: ProgressPhase::Decompress,
-                                event: ProgressEvent::Progress(decoder.get_ref().position()),
-                            }));
-                        }
-                    },
+            if let Some(diff_id) = streaming_extract {
+                let archive = StorageKey::Blob(diff_id.clone());
+                let mut parts = Vec::new();
+                let result = task_storage.insert_tar_index(&diff_id, |index| {
+                    let found = extract_gzip_layer_parts(
+                        &source,
+                        &task_storage,
+                        &archive,
+                        index,
+                        &mut parts,
+                        &mut task_progress,
+                        &task_progress_resource,
+                    )?;
+                    if found == diff_id {
+                        Ok(found)
+                    } else {
+                        Err(ImageError::ContentDigestMismatch {
+                            expected: diff_id.clone(),
+                            found,
+                        })
+                    }
+                });
+                if result.is_err() {
+                    // Parts are named after the layer we expected, so they
+                    // can't be left behind if the layer was anything else
+                    for part in &parts {
+                        task_storage.remove(part)?;
+                    }
                 }
-            };
-            match result {
+                return result;
+            }
+            if !decompressed_cache {
+                let mut hasher = HashWriter::default();
+                decompress_layer(
+                    compression,
+                    &source,
+                    &mut hasher,
+                    &mut task_progress,
+                    &task_progress_resource,
+                )?;
+                let content_digest = hasher.finalize();
+                task_storage.insert_gzip_link(&content_digest, &compressed_digest)?;
+                return Ok(content_digest);
+            }
+            let mut writer = task_storage.begin_write()?;
+            match decompress_layer(
+                compression,
+                &source,
+                &mut writer,
+                &mut task_progress,
+                &task_progress_resource,
+            ) {
                 Err(err) => {
                     writer.remove_temp()?;
                     Err(err.into())
                 }
                 Ok(()) => {
                     let content_digest = writer.finalize()?;
-                    let key = StorageKey::Blob(content_digest);
+                    let key = StorageKey::Blob(content_digest.clone());
                     task_storage.commit_write(writer, &key)?;
-                    Ok(())
+                    Ok(content_digest)
                 }
             }
         })
@@ -630,7 +834,7 @@ impl RegistryClient {
             }))
             .await
             .map_err(|_| ImageError::PullTaskError)?;
-        Ok(())
+        Ok(content_digest)
     }
 
     /// Resolve an [ImageName] into an [Image] if possible
@@ -650,16 +854,83 @@ impl RegistryClient {
         self.pull_progress(image).wait().await
     }
 
+    /// Load an [Image] from a local OCI image layout directory
+    ///
+    /// The layout's `index.json` and `blobs` are read from `dir` without
+    /// using the network, and the parts the image needs are imported into
+    /// this client's cache. With a `tag`, the image is chosen by its
+    /// `org.opencontainers.image.ref.name` annotation, otherwise the layout
+    /// must hold exactly one image.
+    ///
+    /// The resulting image is named `oci-layout`, with the tag if it's valid
+    /// and the manifest's content digest.
+    pub async fn from_oci_layout(
+        &self,
+        dir: &Path,
+        tag: Option<&str>,
+    ) -> Result<Arc<Image>, ImageError> {
+        let storage = self.storage.clone();
+        let dir = dir.to_owned();
+        let tag = tag.map(str::to_owned);
+        let image = task::spawn_blocking(move || oci_layout::load(&storage, &dir, tag.as_deref()))
+            .await??;
+        self.validate(image)
+    }
+
     /// Start to pull an image, and return progress updates
     pub fn pull_progress(&self, image: &ImageName) -> Pull {
         let (mut sender, receiver) = mpsc::channel(128);
         let image = image.clone();
         let mut client = self.clone();
-        let _ = task::spawn(async move {
+        let task = abort::spawn(async move {
             let result = client.pull_with_progress_channel(&mut sender, &image).await;
             let _ = sender.send(PullProgress::Done(result)).await;
         });
-        Pull { receiver }
+        Pull {
+            receiver,
+            _task: task,
+        }
+    }
+
+    /// Fetch only an image's manifest and config, to examine it without
+    /// downloading any layers
+    ///
+    /// Names are resolved and content digests checked the same way as
+    /// [RegistryClient::pull()], and the manifest and config are cached for a
+    /// later pull. Images with a legacy schema1 manifest can't be inspected,
+    /// since their config can only be built after decompressing every layer.
+    pub async fn inspect(&self, image: &ImageName) -> Result<ImageInspect, ImageError> {
+        let (mut sender, mut receiver) = mpsc::channel(128);
+        // Nobody is watching this progress, but it still has to go somewhere
+        task::spawn(async move { while receiver.recv().await.is_some() {} });
+        let mut client = self.clone();
+        client.check_name_allowed(image)?;
+        let (name, manifest) = client.pull_manifest(&mut sender, image).await?;
+        let manifest = match manifest {
+            AnyManifest::V2(manifest) => manifest,
+            AnyManifest::V1(_) => return Err(ImageError::UnsupportedManifestType),
+        };
+        let config = client
+            .pull_runtime_config(&mut sender, image, &manifest.config)
+            .await?;
+        let layers = RegistryClient::layer_info(&manifest.layers, &config.rootfs.diff_ids)?;
+        Ok(ImageInspect {
+            name,
+            config,
+            layers,
+        })
+    }
+
+    fn check_name_allowed(&self, image: &ImageName) -> Result<(), ImageError> {
+        if self.require_digest && image.content_digest().is_none() {
+            log::warn!("{} has no content digest, refusing to pull", image);
+            return Err(ImageError::DigestRequired(image.clone()));
+        }
+        if self.require_registry && image.registry().is_none() {
+            log::warn!("{} has no registry, refusing to pull", image);
+            return Err(ImageError::RegistryRequired(image.clone()));
+        }
+        Ok(())
     }
 
     async fn pull_with_progress_channel(
@@ -667,37 +938,109 @@ impl RegistryClient {
         progress: &mut mpsc::Sender<PullProgress>,
         image: &ImageName,
     ) -> Result<Arc<Image>, ImageError> {
+        self.check_name_allowed(image)?;
         let (specific_image, manifest) = self.pull_manifest(progress, image).await?;
-        let config = self
-            .pull_runtime_config(progress, image, &manifest.config)
-            .await?;
-        let decompressed_layers = match self.check_local_rootfs_layers(&config).await? {
-            Some(layers) => layers,
-            None => {
-                self.pull_layers(progress, image, &manifest.layers).await?;
-                self.check_local_rootfs_layers(&config)
-                    .await?
-                    .ok_or(ImageError::UnexpectedDecompressedLayerContent)?
+        let (config, decompressed_layers, layers) = match manifest {
+            AnyManifest::V1(manifest) => {
+                self.pull_legacy_layers(progress, image, &manifest).await?
+            }
+            AnyManifest::V2(manifest) => {
+                let config = self
+                    .pull_runtime_config(progress, image, &manifest.config)
+                    .await?;
+                let layers = RegistryClient::layer_info(&manifest.layers, &config.rootfs.diff_ids)?;
+                let decompressed_layers = match self.check_local_rootfs_layers(&config).await? {
+                    Some(layers) => layers,
+                    None => {
+                        let diff_ids = Some(&config.rootfs.diff_ids[..]);
+                        self.pull_layers(progress, image, &manifest.layers, diff_ids)
+                            .await?;
+                        self.check_local_rootfs_layers(&config)
+                            .await?
+                            .ok_or(ImageError::UnexpectedDecompressedLayerContent)?
+                    }
+                };
+                (config, decompressed_layers, layers)
             }
         };
 
         let storage = self.storage.clone();
         let task_storage = self.storage.clone();
+        let _pin = self.storage.pin(decompressed_layers.clone());
         let filesystem = task::spawn_blocking(move || -> Result<Filesystem, ImageError> {
             let mut filesystem = Filesystem::new();
             for layer in &decompressed_layers {
                 tar::extract(&mut filesystem, &task_storage, layer)?;
             }
+            filesystem.seal_image();
             Ok(filesystem)
         })
         .await??;
 
-        Ok(Arc::new(Image {
+        if let Some(limit) = self.cache_size_limit {
+            if let Err(err) = self.storage.prune(limit).await {
+                log::warn!("couldn't prune cache, {}", err);
+            }
+        }
+
+        self.validate(Image {
             name: specific_image,
             config,
+            layers,
             filesystem,
             storage,
-        }))
+        })
+    }
+
+    /// Apply the optional checks for newly loaded images
+    fn validate(&self, image: Image) -> Result<Arc<Image>, ImageError> {
+        if self.validate_entrypoint {
+            image.check_entrypoint()?;
+        }
+        Ok(Arc::new(image))
+    }
+
+    /// Best-effort support for images with a deprecated schema1 manifest
+    ///
+    /// There's no runtime config listing the decompressed layer digests, so
+    /// every layer must be fetched before we know which blobs make up the
+    /// filesystem. Manifest signatures are not checked.
+    async fn pull_legacy_layers(
+        &mut self,
+        progress: &mut mpsc::Sender<PullProgress>,
+        image: &ImageName,
+        manifest: &ManifestV1,
+    ) -> Result<(RuntimeConfig, Vec<StorageKey>, Vec<LayerInfo>), ImageError> {
+        log::warn!(
+            "{} has a legacy schema1 manifest, its signature will not be verified",
+            image
+        );
+        let links = manifest.layer_links();
+        let digests = self.pull_layers(progress, image, &links, None).await?;
+        let diff_ids: Vec<String> = digests.iter().map(|d| d.as_str().to_owned()).collect();
+        let layer_info = RegistryClient::layer_info(&links, &diff_ids)?;
+        let config = manifest.runtime_config(diff_ids)?;
+        let layers = digests.into_iter().map(StorageKey::Blob).collect();
+        Ok((config, layers, layer_info))
+    }
+
+    /// Pair each compressed layer link with the digest of its decompressed
+    /// contents
+    fn layer_info(links: &[Link], diff_ids: &[String]) -> Result<Vec<LayerInfo>, ImageError> {
+        if links.len() != diff_ids.len() {
+            return Err(ImageError::UnexpectedDecompressedLayerContent);
+        }
+        links
+            .iter()
+            .zip(diff_ids)
+            .map(|(link, diff_id)| {
+                Ok(LayerInfo {
+                    digest: ContentDigest::parse(&link.digest)?,
+                    diff_id: ContentDigest::parse(diff_id)?,
+                    size: link.size,
+                })
+            })
+            .collect()
     }
 
     async fn check_local_rootfs_layers(
@@ -714,7 +1057,7 @@ impl RegistryClient {
             for digest_str in layer_ids {
                 layers.push(StorageKey::Blob(ContentDigest::parse(digest_str)?));
             }
-            if layers.iter().all(|layer| self.storage.exists(layer)) {
+            if layers.iter().all(|layer| self.storage.layer_exists(layer)) {
                 Ok(Some(layers))
             } else {
                 Ok(None)
@@ -722,3 +1065,113 @@ impl RegistryClient {
         }
     }
 }
+
+/// Compression formats we can decompress layers from
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Compression {
+    Gzip,
+    Zstd,
+}
+
+/// Decompress one layer into `writer`, with progress updates based on how
+/// much of the compressed `source` has been read
+fn decompress_layer<W: Write>(
+    compression: Compression,
+    source: &[u8],
+    writer: &mut W,
+    progress: &mut mpsc::Sender<PullProgress>,
+    progress_resource: &Arc<ProgressResource>,
+) -> std::io::Result<()> {
+    let source = Cursor::new(source);
+    match compression {
+        // Layers may be several gzip members back to back, which decompress
+        // to one continuous tar
+        Compression::Gzip => copy_with_progress(
+            flate2::bufread::MultiGzDecoder::new(source),
+            |decoder| decoder.get_ref().position(),
+            writer,
+            progress,
+            progress_resource,
+        ),
+        Compression::Zstd => copy_with_progress(
+            zstd::stream::read::Decoder::with_buffer(source)?,
+            |decoder| decoder.get_ref().position(),
+            writer,
+            progress,
+            progress_resource,
+        ),
+    }
+}
+
+/// Decompress a gzip layer into separate parts for each file, returning the
+/// digest of the whole decompressed tar
+fn extract_gzip_layer_parts(
+    source: &[u8],
+    storage: &FileStorage,
+    archive: &StorageKey,
+    index: &mut File,
+    parts: &mut Vec<StorageKey>,
+    progress: &mut mpsc::Sender<PullProgress>,
+    progress_resource: &Arc<ProgressResource>,
+) -> Result<ContentDigest, ImageError> {
+    let mut reader = HashingReader {
+        decoder: flate2::bufread::MultiGzDecoder::new(Cursor::new(source)),
+        position: |decoder: &flate2::bufread::MultiGzDecoder<Cursor<&[u8]>>| {
+            decoder.get_ref().position()
+        },
+        hasher: HashWriter::default(),
+        progress,
+        progress_resource,
+    };
+    tar::store_file_parts(storage, archive, &mut reader, index, parts)?;
+    Ok(reader.hasher.finalize())
+}
+
+/// Hashes everything read from a decoder, reporting its `position` in the
+/// source
+struct HashingReader<'a, R, F> {
+    decoder: R,
+    position: F,
+    hasher: HashWriter,
+    progress: &'a mut mpsc::Sender<PullProgress>,
+    progress_resource: &'a Arc<ProgressResource>,
+}
+
+impl<'a, R: Read, F: Fn(&R) -> u64> Read for HashingReader<'a, R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.decoder.read(buf)?;
+        if size > 0 {
+            self.hasher.write_all(&buf[..size])?;
+            let _ = self.progress.try_send(PullProgress::Update(ProgressUpdate {
+                resource: self.progress_resource.clone(),
+                phase: ProgressPhase::Decompress,
+                event: ProgressEvent::Progress((self.position)(&self.decoder)),
+            }));
+        }
+        Ok(size)
+    }
+}
+
+/// Copy everything from a decoder, reporting its `position` in the source
+fn copy_with_progress<R: Read, W: Write, F: Fn(&R) -> u64>(
+    mut decoder: R,
+    position: F,
+    writer: &mut W,
+    progress: &mut mpsc::Sender<PullProgress>,
+    progress_resource: &Arc<ProgressResource>,
+) -> std::io::Result<()> {
+    let mut buffer = [0u8; 256 * 1024];
+    loop {
+        match decoder.read(&mut buffer)? {
+            0 => return Ok(()),
+            size => {
+                writer.write_all(&buffer[..size])?;
+                let _ = progress.try_send(PullProgress::Update(ProgressUpdate {
+                    resource: progress_resource.clone(),
+                    phase: ProgressPhase::Decompress,
+                    event: ProgressEvent::Progress(position(&decoder)),
+                }));
+            }
+        }
+    }
+}
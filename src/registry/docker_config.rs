@@ -0,0 +1,121 @@
+//! Reuse credentials stored by `docker login`
+
+use crate::{
+    errors::ImageError,
+    image::Registry,
+    registry::{auth::Auth, DefaultRegistry},
+};
+use std::{
+    collections::HashMap,
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+/// The parts of Docker's `config.json` that relate to registry logins
+///
+/// Reference: <https://docs.docker.com/engine/reference/commandline/login/>
+#[derive(Debug, Default, Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    auths: HashMap<String, AuthEntry>,
+    #[serde(default, rename = "credHelpers")]
+    cred_helpers: HashMap<String, String>,
+    #[serde(rename = "credsStore")]
+    creds_store: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct AuthEntry {
+    auth: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+/// Determine where docker keeps its client configuration
+///
+/// This is `$DOCKER_CONFIG/config.json` if that variable is set, otherwise
+/// `$HOME/.docker/config.json`.
+pub fn default_path() -> Option<PathBuf> {
+    match env::var("DOCKER_CONFIG") {
+        Ok(dir) => Some(Path::new(&dir).join("config.json")),
+        Err(_) => match env::var("HOME") {
+            Ok(home) => Some(Path::new(&home).join(".docker").join("config.json")),
+            Err(_) => None,
+        },
+    }
+}
+
+/// Load logins and credential helpers from a config file into [Auth]
+///
+/// If the file is missing and `required` is false, this does nothing.
+pub fn load(
+    auth: &mut Auth,
+    default_registry: &DefaultRegistry,
+    path: &Path,
+    required: bool,
+) -> Result<(), ImageError> {
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if !required && e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let config: ConfigFile = serde_json::from_slice(&contents)?;
+    log::debug!("using docker config from {:?}", path);
+
+    if let Some(helper) = config.creds_store {
+        auth.default_credential_helper(helper);
+    }
+    for (key, helper) in config.cred_helpers {
+        let registry = registry_for_key(default_registry, &key)?;
+        auth.credential_helper(registry, helper, key);
+    }
+    for (key, entry) in config.auths {
+        let registry = registry_for_key(default_registry, &key)?;
+        // entries with no credentials just record which registries a
+        // credential store knows about
+        if let Some((username, password)) = entry_login(&entry)? {
+            auth.login(registry, username, Some(password));
+        }
+    }
+    Ok(())
+}
+
+/// Docker config keys may be bare registry names or full URLs, and Docker Hub
+/// is usually stored under its legacy index URL.
+fn registry_for_key(default_registry: &DefaultRegistry, key: &str) -> Result<Registry, ImageError> {
+    let name = key
+        .trim_start_matches("https://")
+        .trim_start_matches("http://");
+    let name = name.split('/').next().unwrap_or(name);
+    let name = if name == "index.docker.io" {
+        "docker.io"
+    } else {
+        name
+    };
+    let registry = Registry::parse(name)
+        .map_err(|_| ImageError::InvalidDockerConfig(format!("registry name {:?}", key)))?;
+    if default_registry.is_default(&Some(registry.clone())) {
+        Ok(default_registry.network_name.clone())
+    } else {
+        Ok(registry)
+    }
+}
+
+fn entry_login(entry: &AuthEntry) -> Result<Option<(String, String)>, ImageError> {
+    if let (Some(username), Some(password)) = (&entry.username, &entry.password) {
+        return Ok(Some((username.clone(), password.clone())));
+    }
+    let encoded = match &entry.auth {
+        None => return Ok(None),
+        Some(encoded) if encoded.is_empty() => return Ok(None),
+        Some(encoded) => encoded,
+    };
+    let invalid = || ImageError::InvalidDockerConfig("stored auth string".to_string());
+    let decoded = base64::decode(encoded).map_err(|_| invalid())?;
+    let decoded = String::from_utf8(decoded).map_err(|_| invalid())?;
+    let mut parts = decoded.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(username), Some(password)) => Ok(Some((username.to_string(), password.to_string()))),
+        _ => Err(invalid()),
+    }
+}
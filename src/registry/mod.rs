@@ -1,10 +1,15 @@
 //! Support for downloading container images from a registry server
 
+mod abort;
 mod auth;
 mod builder;
 mod client;
 mod default;
+mod docker_config;
+mod oci_layout;
 mod progress;
+mod retry;
+mod throttle;
 
 pub use builder::RegistryClientBuilder;
 pub use client::RegistryClient;
@@ -0,0 +1,201 @@
+//! Support for loading images from an OCI image layout directory
+//!
+//! This is the format `skopeo copy oci:...` and `buildah push oci:...` write:
+//! an `index.json` listing image manifests by digest, next to a `blobs`
+//! directory holding every manifest, config, and layer as a file named by its
+//! own digest.
+//!
+//! Reference: https://github.com/opencontainers/image-spec/blob/master/image-layout.md
+
+use crate::{
+    errors::ImageError,
+    filesystem::{
+        storage::{FileStorage, StorageKey},
+        tar,
+        vfs::Filesystem,
+    },
+    image::{ContentDigest, Image, ImageName, LayerInfo, Tag},
+    manifest::{media_types, Link, Manifest, RuntimeConfig, FS_TYPE},
+};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fs,
+    fs::File,
+    io,
+    io::{BufReader, Read},
+    path::{Path, PathBuf},
+};
+
+/// Annotation naming each image in an OCI layout's index, usually a tag
+const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+
+/// Images loaded from a layout have no registry name of their own
+const REPOSITORY: &str = "oci-layout";
+
+#[derive(Deserialize)]
+struct Index {
+    manifests: Vec<Descriptor>,
+}
+
+#[derive(Deserialize)]
+struct Descriptor {
+    #[serde(rename = "mediaType")]
+    media_type: String,
+    digest: String,
+    size: Option<u64>,
+    #[serde(default)]
+    annotations: HashMap<String, String>,
+}
+
+impl Descriptor {
+    fn ref_name(&self) -> Option<&str> {
+        self.annotations
+            .get(REF_NAME_ANNOTATION)
+            .map(String::as_str)
+    }
+
+    fn link(&self) -> Link {
+        Link {
+            media_type: self.media_type.clone(),
+            size: self.size,
+            digest: self.digest.clone(),
+        }
+    }
+}
+
+/// Choose one image from the index, by its ref name if we have one
+fn find_manifest<'a>(index: &'a Index, tag: Option<&str>) -> Result<&'a Descriptor, ImageError> {
+    let mut matches = index
+        .manifests
+        .iter()
+        .filter(|desc| tag.is_none() || desc.ref_name() == tag);
+    match (matches.next(), matches.next()) {
+        (Some(desc), None) => Ok(desc),
+        _ => Err(ImageError::OciLayoutImageNotFound(tag.map(str::to_owned))),
+    }
+}
+
+/// Open the file holding one blob, after checking its size
+fn open_blob(dir: &Path, link: &Link) -> Result<(File, ContentDigest), ImageError> {
+    let digest = ContentDigest::parse(&link.digest)?;
+    let path: PathBuf = [
+        dir,
+        Path::new("blobs"),
+        Path::new(digest.format_str()),
+        Path::new(digest.hex_str()),
+    ]
+    .iter()
+    .collect();
+    let file = File::open(path)?;
+    match link.size {
+        Some(size) if file.metadata()?.len() != size => Err(ImageError::UnexpectedContentSize),
+        _ => Ok((file, digest)),
+    }
+}
+
+/// Copy data into storage as a blob, returning its digest
+fn store_blob<R: Read>(storage: &FileStorage, mut source: R) -> Result<ContentDigest, ImageError> {
+    let mut writer = storage.begin_write()?;
+    if let Err(err) = io::copy(&mut source, &mut writer) {
+        writer.remove_temp()?;
+        return Err(err.into());
+    }
+    let content_digest = writer.finalize()?;
+    storage.commit_write(writer, &StorageKey::Blob(content_digest.clone()))?;
+    Ok(content_digest)
+}
+
+/// Import one blob as-is, checking it against the digest it's stored under
+fn import_blob(storage: &FileStorage, dir: &Path, link: &Link) -> Result<Vec<u8>, ImageError> {
+    let (mut file, expected) = open_blob(dir, link)?;
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)?;
+    let found = ContentDigest::from_content(&contents);
+    if found != expected {
+        return Err(ImageError::ContentDigestMismatch { expected, found });
+    }
+    storage.insert_blob(&contents)?;
+    Ok(contents)
+}
+
+/// Import one layer, decompressing it if needed, and return the digest of
+/// the layer tar
+///
+/// Compressed layers are only stored in decompressed form. Their digests are
+/// checked indirectly, by comparing the decompressed digest with the config.
+fn import_layer(
+    storage: &FileStorage,
+    dir: &Path,
+    link: &Link,
+) -> Result<ContentDigest, ImageError> {
+    let (file, _) = open_blob(dir, link)?;
+    let source = BufReader::new(file);
+    match link.media_type.as_str() {
+        media_types::LAYER_TAR_GZIP | media_types::OCI_LAYER_TAR_GZIP => {
+            store_blob(storage, flate2::bufread::MultiGzDecoder::new(source))
+        }
+        media_types::OCI_LAYER_TAR_ZSTD => {
+            store_blob(storage, zstd::stream::read::Decoder::with_buffer(source)?)
+        }
+        media_types::OCI_LAYER_TAR => store_blob(storage, source),
+        other => Err(ImageError::UnsupportedLayerType(other.to_owned())),
+    }
+}
+
+/// Load an image from an OCI layout, importing what it needs into storage
+///
+/// With no `tag`, the layout must contain exactly one image.
+pub fn load(storage: &FileStorage, dir: &Path, tag: Option<&str>) -> Result<Image, ImageError> {
+    let index: Index = serde_json::from_slice(&fs::read(dir.join("index.json"))?)?;
+    let manifest_desc = find_manifest(&index, tag)?;
+    match manifest_desc.media_type.as_str() {
+        media_types::OCI_MANIFEST | media_types::MANIFEST => (),
+        _ => return Err(ImageError::UnsupportedManifestType),
+    }
+    let manifest_link = manifest_desc.link();
+    let manifest: Manifest = serde_json::from_slice(&import_blob(storage, dir, &manifest_link)?)?;
+
+    let config = match manifest.config.media_type.as_str() {
+        media_types::OCI_CONFIG | media_types::RUNTIME_CONFIG => {
+            let contents = import_blob(storage, dir, &manifest.config)?;
+            serde_json::from_slice::<RuntimeConfig>(&contents)?
+        }
+        other => return Err(ImageError::UnsupportedRuntimeConfigType(other.to_owned())),
+    };
+    if config.rootfs.fs_type != FS_TYPE {
+        return Err(ImageError::UnsupportedRootFilesystemType(
+            config.rootfs.fs_type.clone(),
+        ));
+    }
+    if config.rootfs.diff_ids.len() != manifest.layers.len() {
+        return Err(ImageError::UnexpectedDecompressedLayerContent);
+    }
+
+    let mut filesystem = Filesystem::new();
+    let mut layers = Vec::with_capacity(manifest.layers.len());
+    for (link, diff_id) in manifest.layers.iter().zip(&config.rootfs.diff_ids) {
+        let diff_id = ContentDigest::parse(diff_id)?;
+        let key = StorageKey::Blob(diff_id.clone());
+        if !storage.layer_exists(&key) && import_layer(storage, dir, link)? != diff_id {
+            return Err(ImageError::UnexpectedDecompressedLayerContent);
+        }
+        tar::extract(&mut filesystem, storage, &key)?;
+        layers.push(LayerInfo {
+            digest: ContentDigest::parse(&link.digest)?,
+            diff_id,
+            size: link.size,
+        });
+    }
+    filesystem.seal_image();
+
+    let tag = tag.filter(|tag| Tag::parse(tag).is_ok());
+    let name = ImageName::from_parts(None, REPOSITORY, tag, Some(&manifest_link.digest))?;
+    Ok(Image {
+        name,
+        config,
+        layers,
+        filesystem,
+        storage: storage.clone(),
+    })
+}
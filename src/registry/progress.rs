@@ -3,6 +3,7 @@
 use crate::{
     errors::ImageError,
     image::{ContentDigest, Image, ImageName, ImageVersion, Registry, Repository},
+    registry::abort::AbortOnDrop,
 };
 use std::{fmt, sync::Arc};
 use tokio::sync::mpsc;
@@ -11,8 +12,13 @@ use tokio::sync::mpsc;
 ///
 /// This is a stream of progress updates, culminating in a complete [Image] or
 /// an error. Created by [crate::RegistryClient::pull_progress()]
+///
+/// Dropping a [Pull] before it's done cancels the pull, stopping any
+/// downloads in progress and removing their partially written files.
 pub struct Pull {
     pub(crate) receiver: mpsc::Receiver<PullProgress>,
+    // Only held so that dropping the Pull aborts the task
+    pub(crate) _task: AbortOnDrop<()>,
 }
 
 impl Pull {
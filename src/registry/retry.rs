@@ -0,0 +1,95 @@
+//! Retrying requests that fail for reasons likely to go away on their own
+
+use crate::errors::ImageError;
+use reqwest::{header, Response, StatusCode};
+use std::time::Duration;
+
+/// How many times to retry, and how long to wait in between
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait before retry number `attempt`, counting from zero
+    ///
+    /// The wait doubles after each retry, unless the server asked for a
+    /// specific delay. Either way, it never exceeds `max_backoff`.
+    pub fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        retry_after
+            .unwrap_or_else(|| self.backoff * 2u32.saturating_pow(attempt.min(16)))
+            .min(self.max_backoff)
+    }
+}
+
+/// Is this status code worth trying again later?
+fn is_transient_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Is this error from a dropped connection or a transient status code?
+pub fn is_transient(err: &ImageError) -> bool {
+    match err {
+        ImageError::NetworkRequest(err) => match err.status() {
+            Some(status) => is_transient_status(status),
+            None => !err.is_builder(),
+        },
+        _ => false,
+    }
+}
+
+/// The delay a server asked for, if it's in whole seconds
+///
+/// `Retry-After` can also hold an HTTP date, which we ignore in favor of our
+/// own backoff.
+pub fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(header::RETRY_AFTER)?;
+    let seconds = value.to_str().ok()?.trim().parse().ok()?;
+    Some(Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles() {
+        let policy = RetryPolicy {
+            max_retries: 5,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+        };
+        assert_eq!(policy.delay(0, None), Duration::from_millis(100));
+        assert_eq!(policy.delay(2, None), Duration::from_millis(400));
+        assert_eq!(
+            policy.delay(2, Some(Duration::from_secs(7))),
+            Duration::from_secs(7)
+        );
+    }
+
+    #[test]
+    fn delay_is_clamped() {
+        let policy = RetryPolicy {
+            max_retries: 5,
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(10),
+        };
+        assert_eq!(policy.delay(8, None), Duration::from_secs(10));
+        assert_eq!(
+            policy.delay(0, Some(Duration::from_secs(86400))),
+            Duration::from_secs(10)
+        );
+    }
+}
@@ -0,0 +1,81 @@
+//! Download rate limiting shared by every connection from one client
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::time::delay_for;
+
+/// A token bucket holding up to this many seconds of transfer at full rate
+const BURST_SECONDS: f64 = 0.25;
+
+/// Rate limit shared by all clones of one [Throttle]
+///
+/// Each chunk of downloaded data takes tokens from the same bucket, so
+/// concurrent downloads split the available rate between them. The bucket
+/// can go into debt when a chunk is larger than the tokens available, and
+/// the download that took it then waits until the debt is paid off.
+#[derive(Clone, Debug)]
+pub struct Throttle {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    bytes_per_sec: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1) as f64;
+        let capacity = bytes_per_sec * BURST_SECONDS;
+        Throttle {
+            bucket: Arc::new(Mutex::new(Bucket {
+                bytes_per_sec,
+                capacity,
+                tokens: capacity,
+                updated: Instant::now(),
+            })),
+        }
+    }
+
+    /// Account for `len` bytes just received, waiting if we're over the limit
+    pub async fn consume(&self, len: usize) {
+        let delay = self.bucket.lock().unwrap().take(len);
+        if delay > Duration::from_secs(0) {
+            delay_for(delay).await;
+        }
+    }
+}
+
+impl Bucket {
+    fn take(&mut self, len: usize) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.updated = now;
+        self.tokens = (self.tokens + elapsed * self.bytes_per_sec).min(self.capacity);
+        self.tokens -= len as f64;
+        if self.tokens < 0.0 {
+            Duration::from_secs_f64(-self.tokens / self.bytes_per_sec)
+        } else {
+            Duration::from_secs(0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_debt() {
+        let throttle = Throttle::new(1000);
+        let mut bucket = throttle.bucket.lock().unwrap();
+        assert_eq!(bucket.take(200), Duration::from_secs(0));
+        let delay = bucket.take(550);
+        assert!(delay > Duration::from_millis(400) && delay <= Duration::from_millis(500));
+    }
+}
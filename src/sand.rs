@@ -9,18 +9,33 @@ const PROGRAM_DATA: &[u8] = include_bytes!(concat!(
 #[cfg(doc)]
 const PROGRAM_DATA: &[u8] = b"";
 
-use crate::errors::RuntimeError;
+use crate::{errors::RuntimeError, process::MemFile, strace};
 use protocol::{LogLevel, LogMessage, VPid};
 use std::{
     fs::File,
+    io,
     io::Write,
     os::unix::{
         io::{AsRawFd, RawFd},
         process::CommandExt,
     },
     process::{Command, Stdio},
+    sync::Arc,
 };
 
+/// Callback for each log message from the tracer
+pub type LogHandler = Arc<dyn Fn(VPid, LogLevel, LogMessage) + Send + Sync>;
+
+/// Adjustments made to the sand process before it starts, which the tracer
+/// and the sandboxed program both inherit
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct ProcessPriority {
+    pub nice: i32,
+    pub oom_score_adj: i32,
+    pub set_nice: bool,
+    pub set_oom_score_adj: bool,
+}
+
 lazy_static! {
     static ref PROGRAM_FILE: Result<File, RuntimeError> = create_program_file();
 }
@@ -44,7 +59,7 @@ fn create_program_file() -> Result<File, RuntimeError> {
     Ok(memfd.into_file())
 }
 
-pub fn command(fd: RawFd) -> Result<Command, RuntimeError> {
+pub fn command(fd: RawFd, priority: &ProcessPriority) -> Result<Command, RuntimeError> {
     let file = match &*PROGRAM_FILE {
         Err(err) => return Err(RuntimeError::ProgramAllocError(err.to_string())),
         Ok(file) => file,
@@ -56,9 +71,51 @@ pub fn command(fd: RawFd) -> Result<Command, RuntimeError> {
     cmd.arg0("sand");
     cmd.env_clear();
     cmd.env("FD", fd.to_string());
+    apply_priority(&mut cmd, priority);
     Ok(cmd)
 }
 
+/// Set the niceness and OOM score in the child between fork and exec, before
+/// the sand process installs any seccomp policy
+fn apply_priority(cmd: &mut Command, priority: &ProcessPriority) {
+    let nice = priority.nice;
+    let set_nice = priority.set_nice;
+    let oom_score_adj = if priority.set_oom_score_adj {
+        Some(priority.oom_score_adj.to_string())
+    } else {
+        None
+    };
+    if !set_nice && oom_score_adj.is_none() {
+        return;
+    }
+    // Only async-signal-safe calls in here; the string was formatted before
+    // the fork.
+    unsafe {
+        cmd.pre_exec(move || {
+            if set_nice && libc::setpriority(libc::PRIO_PROCESS, 0, nice) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if let Some(adj) = &oom_score_adj {
+                let path = b"/proc/self/oom_score_adj\0";
+                let fd = libc::open(
+                    path.as_ptr() as *const libc::c_char,
+                    libc::O_WRONLY | libc::O_CLOEXEC,
+                );
+                if fd < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                let written = libc::write(fd, adj.as_ptr() as *const libc::c_void, adj.len());
+                let error = io::Error::last_os_error();
+                libc::close(fd);
+                if written != adj.len() as isize {
+                    return Err(error);
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
 pub fn max_log_level() -> LogLevel {
     if log::log_enabled!(log::Level::Trace) {
         LogLevel::Trace
@@ -75,7 +132,7 @@ pub fn max_log_level() -> LogLevel {
     }
 }
 
-pub fn task_log(task: VPid, level: LogLevel, message: LogMessage) {
+pub fn task_log(task: VPid, level: LogLevel, message: LogMessage, mem: Option<&MemFile>) {
     let level = match level {
         LogLevel::Off => return,
         LogLevel::Error => log::Level::Error,
@@ -84,5 +141,22 @@ pub fn task_log(task: VPid, level: LogLevel, message: LogMessage) {
         LogLevel::Debug => log::Level::Debug,
         LogLevel::Trace => log::Level::Trace,
     };
-    log::log!(level, "{:?} {:?}", task, message);
+    if !log::log_enabled!(level) {
+        return;
+    }
+    match &message {
+        LogMessage::Emulated(call) => log::log!(
+            level,
+            "{:?} Emulated {}",
+            task,
+            strace::format_syscall(call, mem)
+        ),
+        LogMessage::Remote(call) => log::log!(
+            level,
+            "{:?} Remote {}",
+            task,
+            strace::format_syscall(call, mem)
+        ),
+        _ => log::log!(level, "{:?} {:?}", task, message),
+    }
 }
@@ -0,0 +1,216 @@
+//! Human readable formatting for syscalls logged by the sandbox runtime
+
+use crate::{
+    process::MemFile,
+    sand::protocol::{abi::Syscall, VPtr, VString},
+};
+use std::fmt::Write;
+
+/// Longest string argument we'll print before truncating it
+const MAX_STRING_LEN: usize = 256;
+
+#[derive(Debug, Clone, Copy)]
+enum Arg {
+    Int,
+    Fd,
+    Hex,
+    Oct,
+    Ptr,
+    Str,
+}
+
+struct SyscallInfo {
+    name: &'static str,
+    args: &'static [(&'static str, Arg)],
+}
+
+fn lookup(nr: isize) -> Option<SyscallInfo> {
+    use Arg::*;
+    let (name, args): (&str, &[(&str, Arg)]) = match nr as libc::c_long {
+        libc::SYS_read => ("read", &[("fd", Fd), ("buf", Ptr), ("count", Int)]),
+        libc::SYS_write => ("write", &[("fd", Fd), ("buf", Ptr), ("count", Int)]),
+        libc::SYS_pread64 => (
+            "pread64",
+            &[("fd", Fd), ("buf", Ptr), ("count", Int), ("offset", Int)],
+        ),
+        libc::SYS_pwrite64 => (
+            "pwrite64",
+            &[("fd", Fd), ("buf", Ptr), ("count", Int), ("offset", Int)],
+        ),
+        libc::SYS_open => ("open", &[("path", Str), ("flags", Hex), ("mode", Oct)]),
+        libc::SYS_openat => (
+            "openat",
+            &[("dirfd", Fd), ("path", Str), ("flags", Hex), ("mode", Oct)],
+        ),
+        libc::SYS_close => ("close", &[("fd", Fd)]),
+        libc::SYS_stat => ("stat", &[("path", Str), ("statbuf", Ptr)]),
+        libc::SYS_lstat => ("lstat", &[("path", Str), ("statbuf", Ptr)]),
+        libc::SYS_fstat => ("fstat", &[("fd", Fd), ("statbuf", Ptr)]),
+        libc::SYS_newfstatat => (
+            "newfstatat",
+            &[
+                ("dirfd", Fd),
+                ("path", Str),
+                ("statbuf", Ptr),
+                ("flags", Hex),
+            ],
+        ),
+        libc::SYS_statfs => ("statfs", &[("path", Str), ("buf", Ptr)]),
+        libc::SYS_fstatfs => ("fstatfs", &[("fd", Fd), ("buf", Ptr)]),
+        libc::SYS_access => ("access", &[("path", Str), ("mode", Oct)]),
+        libc::SYS_readlink => ("readlink", &[("path", Str), ("buf", Ptr), ("bufsiz", Int)]),
+        libc::SYS_chdir => ("chdir", &[("path", Str)]),
+        libc::SYS_fchdir => ("fchdir", &[("fd", Fd)]),
+        libc::SYS_getcwd => ("getcwd", &[("buf", Ptr), ("size", Int)]),
+        libc::SYS_getdents64 => ("getdents64", &[("fd", Fd), ("dirp", Ptr), ("count", Int)]),
+        libc::SYS_execve => ("execve", &[("filename", Str), ("argv", Ptr), ("envp", Ptr)]),
+        libc::SYS_dup => ("dup", &[("oldfd", Fd)]),
+        libc::SYS_dup2 => ("dup2", &[("oldfd", Fd), ("newfd", Fd)]),
+        libc::SYS_ioctl => ("ioctl", &[("fd", Fd), ("request", Hex), ("arg", Hex)]),
+        libc::SYS_brk => ("brk", &[("addr", Ptr)]),
+        libc::SYS_mmap => (
+            "mmap",
+            &[
+                ("addr", Ptr),
+                ("length", Int),
+                ("prot", Hex),
+                ("flags", Hex),
+                ("fd", Fd),
+                ("offset", Int),
+            ],
+        ),
+        libc::SYS_munmap => ("munmap", &[("addr", Ptr), ("length", Int)]),
+        libc::SYS_uname => ("uname", &[("buf", Ptr)]),
+        libc::SYS_wait4 => (
+            "wait4",
+            &[
+                ("pid", Int),
+                ("wstatus", Ptr),
+                ("options", Hex),
+                ("rusage", Ptr),
+            ],
+        ),
+        libc::SYS_getxattr => (
+            "getxattr",
+            &[("path", Str), ("name", Str), ("value", Ptr), ("size", Int)],
+        ),
+        libc::SYS_lgetxattr => (
+            "lgetxattr",
+            &[("path", Str), ("name", Str), ("value", Ptr), ("size", Int)],
+        ),
+        libc::SYS_setxattr => (
+            "setxattr",
+            &[
+                ("path", Str),
+                ("name", Str),
+                ("value", Ptr),
+                ("size", Int),
+                ("flags", Hex),
+            ],
+        ),
+        libc::SYS_lsetxattr => (
+            "lsetxattr",
+            &[
+                ("path", Str),
+                ("name", Str),
+                ("value", Ptr),
+                ("size", Int),
+                ("flags", Hex),
+            ],
+        ),
+        _ => return None,
+    };
+    Some(SyscallInfo { name, args })
+}
+
+fn write_string(out: &mut String, mem: Option<&MemFile>, ptr: VPtr) {
+    match mem.map(|mem| mem.read_string_os(&VString(ptr))) {
+        Some(Ok(s)) => {
+            let s = s.to_string_lossy();
+            if s.chars().count() > MAX_STRING_LEN {
+                let truncated: String = s.chars().take(MAX_STRING_LEN).collect();
+                write!(out, "{:?}...", truncated).unwrap();
+            } else {
+                write!(out, "{:?}", s).unwrap();
+            }
+        }
+        _ => write!(out, "{:?}", ptr).unwrap(),
+    }
+}
+
+/// Format a syscall in a style similar to strace
+///
+/// Known syscalls get their arguments named, and string arguments are read
+/// from the guest's memory if it's available. Anything else falls back on
+/// the raw register values.
+pub fn format_syscall(call: &Syscall, mem: Option<&MemFile>) -> String {
+    let info = match lookup(call.nr) {
+        Some(info) => info,
+        None => return format!("{:?}", call),
+    };
+    let mut out = String::new();
+    write!(out, "{}(", info.name).unwrap();
+    for (idx, (name, kind)) in info.args.iter().enumerate() {
+        if idx > 0 {
+            out.push_str(", ");
+        }
+        let value = call.args[idx];
+        write!(out, "{}=", name).unwrap();
+        match kind {
+            Arg::Int | Arg::Fd => write!(out, "{}", value).unwrap(),
+            Arg::Hex => write!(out, "{:#x}", value).unwrap(),
+            Arg::Oct => write!(out, "{:#o}", value).unwrap(),
+            Arg::Ptr => write!(out, "{:?}", VPtr(value as usize)).unwrap(),
+            Arg::Str => write_string(&mut out, mem, VPtr(value as usize)),
+        }
+    }
+    write!(out, ") -> {}", call.ret).unwrap();
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sand::protocol::SysPid;
+    use std::ffi::CString;
+
+    fn open_call(path: &CString) -> Syscall {
+        Syscall {
+            nr: libc::SYS_open as isize,
+            args: [path.as_ptr() as isize, 0x80000, 0o644, 0, 0, 0],
+            ret: 3,
+            ip: 0,
+            sp: 0,
+        }
+    }
+
+    #[test]
+    fn open_resolves_path() {
+        let self_mem = MemFile::open(SysPid(unsafe { libc::getpid() as u32 })).unwrap();
+        let path = CString::new("/etc/passwd").unwrap();
+        assert_eq!(
+            format_syscall(&open_call(&path), Some(&self_mem)),
+            "open(path=\"/etc/passwd\", flags=0x80000, mode=0o644) -> 3"
+        );
+    }
+
+    #[test]
+    fn unreadable_string_falls_back_to_pointer() {
+        let path = CString::new("/etc/passwd").unwrap();
+        let formatted = format_syscall(&open_call(&path), None);
+        assert!(formatted.starts_with("open(path="));
+        assert!(!formatted.contains("passwd"));
+    }
+
+    #[test]
+    fn xattr_names() {
+        assert_eq!(
+            lookup(libc::SYS_lgetxattr as isize).unwrap().name,
+            "lgetxattr"
+        );
+        assert_eq!(
+            lookup(libc::SYS_lsetxattr as isize).unwrap().name,
+            "lsetxattr"
+        );
+    }
+}
@@ -1,25 +1,118 @@
 use crate::{
-    filesystem::vfs::Filesystem,
+    errors::VFSError,
+    filesystem::vfs::{Filesystem, ResolveFlags, XAttrFlags},
     process::Process,
-    sand::protocol::{Errno, FileStat, FollowLinks, VFile, VString},
+    sand::protocol::{abi, Errno, FileStat, FollowLinks, VFile, VPid, VPtr, VString},
 };
-use std::{ffi::CString, path::Path};
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    ffi::{CString, OsString},
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Path, PathBuf},
+};
+
+/// Most results kept in a [LookupCache] before it starts over
+const MAX_CACHED_LOOKUPS: usize = 256;
+
+/// Path lookups a process has made since the filesystem last changed
+///
+/// Programs tend to look up the same paths again and again: a file checked
+/// with `access()` or `stat()` before it's opened, each directory on a
+/// search path tried by every library load and exec, or the same
+/// configuration file read by each new program. Repeats are coalesced into
+/// the first lookup's result, failures included, until a writer or new
+/// limits might change the answer. Each process has its own, since
+/// `/proc/self` and relative paths resolve differently in each.
+#[derive(Debug, Default)]
+pub struct LookupCache {
+    generation: u64,
+    results: HashMap<LookupKey, Result<VFile, VFSError>>,
+    walks: usize,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+struct LookupKey {
+    dir: VFile,
+    path: PathBuf,
+    follow_links: bool,
+    resolve: ResolveFlags,
+}
+
+impl LookupCache {
+    pub fn lookup(
+        &mut self,
+        filesystem: &Filesystem,
+        dir: &VFile,
+        path: &Path,
+        follow_links: &FollowLinks,
+    ) -> Result<VFile, VFSError> {
+        let resolve = ResolveFlags::default();
+        self.lookup_restricted(filesystem, dir, path, follow_links, &resolve)
+    }
+
+    pub fn lookup_restricted(
+        &mut self,
+        filesystem: &Filesystem,
+        dir: &VFile,
+        path: &Path,
+        follow_links: &FollowLinks,
+        resolve: &ResolveFlags,
+    ) -> Result<VFile, VFSError> {
+        if self.generation != filesystem.generation() {
+            self.generation = filesystem.generation();
+            self.results.clear();
+        }
+        let key = LookupKey {
+            dir: dir.clone(),
+            path: path.to_owned(),
+            follow_links: *follow_links == FollowLinks::Follow,
+            resolve: *resolve,
+        };
+        if let Some(result) = self.results.get(&key) {
+            return result.clone();
+        }
+        self.walks += 1;
+        let result = filesystem.lookup_restricted(dir, path, follow_links, resolve);
+        if self.results.len() >= MAX_CACHED_LOOKUPS {
+            self.results.clear();
+        }
+        self.results.insert(key, result.clone());
+        result
+    }
+
+    /// Count the lookups that weren't already cached
+    #[cfg(test)]
+    fn walk_count(&self) -> usize {
+        self.walks
+    }
+}
 
 pub async fn change_working_dir(
     process: &mut Process,
-    _filesystem: &Filesystem,
+    filesystem: &Filesystem,
     path: &VString,
 ) -> Result<(), Errno> {
-    let path = process.mem.read_user_string(path)?;
-    log::warn!("change_working_dir({:?})", path);
+    let path_str = process.mem.read_user_path(path)?;
+    let path = Path::new(&path_str);
+    let dir = &process.status.current_dir;
+    let vfile = process
+        .lookups
+        .lookup(filesystem, dir, path, &FollowLinks::Follow)?;
+    if !filesystem.is_directory(&vfile)? {
+        return Err(Errno(-libc::ENOTDIR));
+    }
+    log::debug!("change_working_dir({:?}) -> {:?}", path, vfile);
+    process.status.current_dir = vfile;
     Ok(())
 }
 
 pub async fn get_working_dir(
-    _process: &mut Process,
-    _filesystem: &Filesystem,
+    process: &mut Process,
+    filesystem: &Filesystem,
 ) -> Result<CString, Errno> {
-    Ok(CString::new("working dir goes here").unwrap())
+    let path = filesystem.dir_path(&process.status.current_dir)?;
+    CString::new(path.into_os_string().into_vec()).map_err(|_| Errno(-libc::EINVAL))
 }
 
 pub async fn readlink(
@@ -27,44 +120,148 @@ pub async fn readlink(
     filesystem: &Filesystem,
     path: &VString,
 ) -> Result<CString, Errno> {
-    let path_str = process.mem.read_user_string(path)?;
+    let path_str = process.mem.read_user_path(path)?;
     let path = Path::new(&path_str);
     let dir = &process.status.current_dir;
-    let vfile = filesystem.lookup(dir, &path, &FollowLinks::NoFollow)?;
+    let vfile = process
+        .lookups
+        .lookup(filesystem, dir, path, &FollowLinks::NoFollow)?;
     let cstr = filesystem.readlink(&vfile)?;
     log::debug!("readlink({:?}) -> {:?}", path, cstr);
     Ok(cstr.to_owned())
 }
 
+/// Open a file, returning it along with the flags to open its contents with
 pub async fn file_open(
     process: &mut Process,
-    filesystem: &Filesystem,
+    filesystem: &mut Filesystem,
+    dir: &Option<VFile>,
+    path: &VString,
+    flags: i32,
+    mode: i32,
+) -> Result<(VFile, i32), Errno> {
+    let resolve = ResolveFlags::default();
+    open_restricted(process, filesystem, dir, path, flags, mode, &resolve)
+}
+
+/// Open a file using the `struct open_how` from `openat2`
+///
+/// Resolve flags are checked before anything else, and unsupported ones fail
+/// with `EINVAL`. There's only one filesystem and no magic links, so those
+/// restrictions always hold.
+pub async fn file_open_how(
+    process: &mut Process,
+    filesystem: &mut Filesystem,
+    dir: &Option<VFile>,
+    path: &VString,
+    how: VPtr,
+    size: usize,
+) -> Result<(VFile, i32), Errno> {
+    if size < abi::OPEN_HOW_SIZE_VER0 {
+        return Err(Errno(-libc::EINVAL));
+    }
+    if size > abi::PATH_MAX {
+        return Err(Errno(-libc::E2BIG));
+    }
+    let mut bytes = vec![0u8; size];
+    process
+        .mem
+        .read_bytes(how, &mut bytes)
+        .map_err(|_| Errno(-libc::EFAULT))?;
+    // Newer fields we don't know about must be zero
+    if bytes[abi::OPEN_HOW_SIZE_VER0..].iter().any(|b| *b != 0) {
+        return Err(Errno(-libc::E2BIG));
+    }
+    let field = |i: usize| u64::from_ne_bytes(bytes[i * 8..(i + 1) * 8].try_into().unwrap());
+    let (flags, mode, resolve) = (field(0), field(1), field(2));
+
+    let supported = abi::RESOLVE_NO_XDEV
+        | abi::RESOLVE_NO_MAGICLINKS
+        | abi::RESOLVE_NO_SYMLINKS
+        | abi::RESOLVE_BENEATH;
+    let creating = flags & (libc::O_CREAT | libc::O_TMPFILE) as u64 != 0;
+    if flags > u32::MAX as u64
+        || mode & !0o7777 != 0
+        || (mode != 0 && !creating)
+        || resolve & !supported != 0
+    {
+        return Err(Errno(-libc::EINVAL));
+    }
+    let resolve = ResolveFlags {
+        no_symlinks: resolve & abi::RESOLVE_NO_SYMLINKS != 0,
+        beneath: resolve & abi::RESOLVE_BENEATH != 0,
+    };
+    open_restricted(
+        process,
+        filesystem,
+        dir,
+        path,
+        flags as i32,
+        mode as i32,
+        &resolve,
+    )
+}
+
+/// Open or create a file
+///
+/// New files can only be created in a tmpfs, where they get permissions from
+/// `mode` and the process umask.
+fn open_restricted(
+    process: &mut Process,
+    filesystem: &mut Filesystem,
     dir: &Option<VFile>,
     path: &VString,
     flags: i32,
     mode: i32,
-) -> Result<VFile, Errno> {
-    let path_str = process.mem.read_user_string(path)?;
+    resolve: &ResolveFlags,
+) -> Result<(VFile, i32), Errno> {
+    let path_str = process.mem.read_user_path(path)?;
     let path = Path::new(&path_str);
     let dir = match dir {
-        Some(dir) => &dir,
+        Some(dir) => dir,
         None => &process.status.current_dir,
     };
-    let vfile = filesystem.lookup(&dir, &path, &FollowLinks::Follow)?;
+    let creating = flags & libc::O_CREAT != 0;
+    let lookup =
+        process
+            .lookups
+            .lookup_restricted(filesystem, dir, path, &FollowLinks::Follow, resolve);
+    let vfile = match lookup {
+        Err(VFSError::NotFound) if creating => {
+            let umask = process.umask().map_err(|_| Errno(-libc::ESRCH))?;
+            let mode = mode as u32 & 0o7777 & !umask;
+            filesystem.writer_at(dir).create_file(path, mode)?
+        }
+        Ok(_) if creating && flags & libc::O_EXCL != 0 => return Err(Errno(-libc::EEXIST)),
+        result => result?,
+    };
     log::debug!("file_open{:?} -> {:?}", (dir, path, flags, mode), vfile);
-    Ok(vfile)
+    let writing = (flags & libc::O_ACCMODE) != libc::O_RDONLY
+        || (flags & (libc::O_CREAT | libc::O_TRUNC)) != 0;
+    if writing && filesystem.is_read_only(&vfile) {
+        return Err(Errno(-libc::EROFS));
+    }
+    let absolute = if path.is_absolute() {
+        Some(path.to_owned())
+    } else if *dir == Filesystem::root() {
+        Some(Path::new("/").join(path))
+    } else {
+        None
+    };
+    process.status.last_open = Some((vfile.clone(), absolute));
+    Ok((vfile, flags))
 }
 
-pub async fn file_stat(
+fn lookup_file(
     process: &mut Process,
     filesystem: &Filesystem,
     file: &Option<VFile>,
     path: &Option<VString>,
     follow_links: &FollowLinks,
-) -> Result<(VFile, FileStat), Errno> {
+) -> Result<(VFile, Option<PathBuf>), Errno> {
     let path = match path {
         Some(path) => {
-            let path_str = process.mem.read_user_string(path)?;
+            let path_str = process.mem.read_user_path(path)?;
             let path = Path::new(&path_str);
             Some(path.to_owned())
         }
@@ -76,9 +273,22 @@ pub async fn file_stat(
     };
     let file = match &path {
         None => file.to_owned(),
-        Some(path) => filesystem.lookup(file, path, follow_links)?,
+        Some(path) => process
+            .lookups
+            .lookup(filesystem, file, path, follow_links)?,
     };
-    let stat = filesystem.stat(&file)?.to_owned();
+    Ok((file, path))
+}
+
+pub async fn file_stat(
+    process: &mut Process,
+    filesystem: &Filesystem,
+    file: &Option<VFile>,
+    path: &Option<VString>,
+    follow_links: &FollowLinks,
+) -> Result<(VFile, FileStat), Errno> {
+    let (file, path) = lookup_file(process, filesystem, file, path, follow_links)?;
+    let stat = filesystem.stat(&file)?;
     log::debug!(
         "file_stat{:?} -> {:?}",
         (path, follow_links),
@@ -86,3 +296,307 @@ pub async fn file_stat(
     );
     Ok((file, stat))
 }
+
+/// Permissions are checked the way they are for root: only writes to
+/// read-only files and executing files with no execute bits are refused.
+pub async fn file_access(
+    process: &mut Process,
+    filesystem: &Filesystem,
+    dir: &Option<VFile>,
+    path: &Option<VString>,
+    mode: i32,
+    follow_links: &FollowLinks,
+) -> Result<(), Errno> {
+    let (file, path) = lookup_file(process, filesystem, dir, path, follow_links)?;
+    log::debug!("file_access{:?} -> {:?}", (path, mode, follow_links), file);
+    if mode & libc::W_OK != 0 && filesystem.is_read_only(&file) {
+        return Err(Errno(-libc::EROFS));
+    }
+    if mode & libc::X_OK != 0 {
+        let stat = filesystem.stat(&file)?;
+        if stat.st_mode & abi::S_IFMT != abi::S_IFDIR && stat.st_mode & 0o111 == 0 {
+            return Err(Errno(-libc::EACCES));
+        }
+    }
+    Ok(())
+}
+
+fn read_xattr_name(process: &mut Process, name: &VString) -> Result<OsString, Errno> {
+    let name = process
+        .mem
+        .read_string_os_bounded(name, abi::XATTR_NAME_MAX + 1)
+        .map_err(|_| Errno(-libc::EFAULT))?;
+    match name {
+        Some(name) if !name.is_empty() => Ok(name),
+        _ => Err(Errno(-libc::ERANGE)),
+    }
+}
+
+pub async fn get_xattr(
+    process: &mut Process,
+    filesystem: &Filesystem,
+    file: &Option<VFile>,
+    path: &Option<VString>,
+    follow_links: &FollowLinks,
+    name: &VString,
+) -> Result<Vec<u8>, Errno> {
+    let (file, _) = lookup_file(process, filesystem, file, path, follow_links)?;
+    let name = read_xattr_name(process, name)?;
+    Ok(filesystem.get_xattr(&file, &name)?.to_vec())
+}
+
+pub async fn list_xattr(
+    process: &mut Process,
+    filesystem: &Filesystem,
+    file: &Option<VFile>,
+    path: &Option<VString>,
+    follow_links: &FollowLinks,
+) -> Result<Vec<u8>, Errno> {
+    let (file, _) = lookup_file(process, filesystem, file, path, follow_links)?;
+    Ok(filesystem.list_xattr(&file)?)
+}
+
+pub async fn set_xattr(
+    process: &mut Process,
+    filesystem: &mut Filesystem,
+    file: &Option<VFile>,
+    path: &Option<VString>,
+    follow_links: &FollowLinks,
+    name: &VString,
+    value: VPtr,
+    size: usize,
+    flags: i32,
+) -> Result<(), Errno> {
+    let flags = match flags {
+        0 => XAttrFlags::CreateOrReplace,
+        abi::XATTR_CREATE => XAttrFlags::Create,
+        abi::XATTR_REPLACE => XAttrFlags::Replace,
+        _ => return Err(Errno(-libc::EINVAL)),
+    };
+    if size > abi::XATTR_SIZE_MAX {
+        return Err(Errno(-libc::E2BIG));
+    }
+    let (file, _) = lookup_file(process, filesystem, file, path, follow_links)?;
+    let name = read_xattr_name(process, name)?;
+    let mut buf = vec![0u8; size];
+    process
+        .mem
+        .read_bytes(value, &mut buf)
+        .map_err(|_| Errno(-libc::EFAULT))?;
+    Ok(filesystem.writer().set_xattr(&file, &name, &buf, flags)?)
+}
+
+pub async fn remove_xattr(
+    process: &mut Process,
+    filesystem: &mut Filesystem,
+    file: &Option<VFile>,
+    path: &Option<VString>,
+    follow_links: &FollowLinks,
+    name: &VString,
+) -> Result<(), Errno> {
+    let (file, _) = lookup_file(process, filesystem, file, path, follow_links)?;
+    let name = read_xattr_name(process, name)?;
+    Ok(filesystem.writer().remove_xattr(&file, &name)?)
+}
+
+/// Create a directory, with permissions from `mode` and the process umask
+///
+/// Like removing files, this only works in the writable overlay.
+pub async fn make_dir(
+    process: &mut Process,
+    filesystem: &mut Filesystem,
+    dir: &Option<VFile>,
+    path: &VString,
+    mode: i32,
+) -> Result<(), Errno> {
+    let path_str = process.mem.read_user_path(path)?;
+    let path = Path::new(&path_str);
+    let umask = process.umask().map_err(|_| Errno(-libc::ESRCH))?;
+    let mode = mode as u32 & 0o1777 & !umask;
+    let dir = match dir {
+        Some(dir) => dir,
+        None => &process.status.current_dir,
+    };
+    log::debug!("make_dir{:?}", (dir, path, mode));
+    Ok(filesystem.writer_at(dir).make_dir(path, mode)?)
+}
+
+/// Remove a file, or an empty directory with `AT_REMOVEDIR`
+///
+/// Only entries in the writable overlay can be removed. Everything from
+/// the image, and anything under a read-only path, fails with `EROFS`.
+pub async fn file_unlink(
+    process: &mut Process,
+    filesystem: &mut Filesystem,
+    dir: &Option<VFile>,
+    path: &VString,
+    flags: i32,
+) -> Result<(), Errno> {
+    if flags & !libc::AT_REMOVEDIR != 0 {
+        return Err(Errno(-libc::EINVAL));
+    }
+    let path_str = process.mem.read_user_path(path)?;
+    let path = Path::new(&path_str);
+    let dir = match dir {
+        Some(dir) => dir,
+        None => &process.status.current_dir,
+    };
+    log::debug!("file_unlink{:?}", (dir, path, flags));
+    let mut writer = filesystem.writer_at(dir);
+    if flags & libc::AT_REMOVEDIR == 0 {
+        return Ok(writer.remove_file(path)?);
+    }
+    // Path drops a trailing "." that rmdir must refuse, rather than
+    // removing the directory it names
+    let last = path
+        .as_os_str()
+        .as_bytes()
+        .split(|b| *b == b'/')
+        .filter(|part| !part.is_empty())
+        .last();
+    match last {
+        Some(b".") => Err(Errno(-libc::EINVAL)),
+        Some(b"..") => Err(Errno(-libc::ENOTEMPTY)),
+        _ => Ok(writer.remove_dir(path)?),
+    }
+}
+
+/// Where a process's open file appears in its `/proc/<pid>/fd` directory
+fn fd_path(task: VPid, fd: u32) -> PathBuf {
+    PathBuf::from(format!("/proc/{}/fd/{}", task.0, fd))
+}
+
+/// Add a newly opened file to `/proc/<pid>/fd`
+///
+/// Each entry links to the absolute path the file was opened by. Files we
+/// can't name get a label with their inode number instead, which like the
+/// kernel's own labels for sockets and pipes doesn't resolve to anything.
+pub async fn fd_opened(
+    process: &mut Process,
+    filesystem: &mut Filesystem,
+    task: VPid,
+    fd: u32,
+    file: &VFile,
+) -> Result<(), Errno> {
+    let target = match process.status.last_open.take() {
+        Some((opened, Some(path))) if opened == *file => path.into_os_string().into_vec(),
+        _ => format!("anon_inode:[{}]", file.inode).into_bytes(),
+    };
+    write_fd_link(filesystem, &fd_path(task, fd), target)
+}
+
+/// Give a duplicated fd the same `/proc/<pid>/fd` entry as its source
+pub async fn fd_duplicated(
+    _process: &mut Process,
+    filesystem: &mut Filesystem,
+    task: VPid,
+    src: u32,
+    dest: u32,
+) -> Result<(), Errno> {
+    if src == dest {
+        return Ok(());
+    }
+    let (src, dest) = (fd_path(task, src), fd_path(task, dest));
+    if is_mounted(filesystem, &src) {
+        // Link to the mount by name, rather than adding another name for it
+        let target = src.into_os_string().into_vec();
+        return write_fd_link(filesystem, &dest, target);
+    }
+    if is_mounted(filesystem, &dest) {
+        return Ok(());
+    }
+    match filesystem.writer().write_hardlink(&dest, &src) {
+        // The source was never tracked, but the destination was replaced
+        Err(VFSError::NotFound) => fd_removed(filesystem, &dest),
+        result => Ok(result?),
+    }
+}
+
+/// Remove a closed fd from `/proc/<pid>/fd`
+pub async fn fd_closed(
+    _process: &mut Process,
+    filesystem: &mut Filesystem,
+    task: VPid,
+    fd: u32,
+) -> Result<(), Errno> {
+    fd_removed(filesystem, &fd_path(task, fd))
+}
+
+fn write_fd_link(filesystem: &mut Filesystem, path: &Path, target: Vec<u8>) -> Result<(), Errno> {
+    if is_mounted(filesystem, path) {
+        return Ok(());
+    }
+    let stat = FileStat {
+        st_mode: abi::S_IFLNK | 0o777,
+        st_size: target.len() as i64,
+        ..Default::default()
+    };
+    // Paths come from C strings, so they can't hold a nul
+    let target = CString::new(target).unwrap();
+    Ok(filesystem.writer().write_symlink(path, stat, target)?)
+}
+
+fn fd_removed(filesystem: &mut Filesystem, path: &Path) -> Result<(), Errno> {
+    if is_mounted(filesystem, path) {
+        return Ok(());
+    }
+    match filesystem.writer().remove_file(path) {
+        Err(VFSError::NotFound) => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+/// Check for something other than an fd entry at an fd path
+///
+/// Fd entries are always links. Each process's stdio streams are mounted at
+/// `/proc/<pid>/fd/{0,1,2}` instead, and they stay put when it closes or
+/// replaces those fds, so `/dev/stdout` and friends keep working.
+fn is_mounted(filesystem: &Filesystem, path: &Path) -> bool {
+    filesystem
+        .lookup(&Filesystem::root(), path, &FollowLinks::NoFollow)
+        .and_then(|vfile| filesystem.stat(&vfile))
+        .map(|stat| stat.st_mode & abi::S_IFMT != abi::S_IFLNK)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookups_cached_until_write() {
+        let mut fs = Filesystem::new();
+        fs.writer()
+            .write_storage_file(Path::new("/etc/passwd"), Default::default(), None)
+            .unwrap();
+        let root = Filesystem::root();
+        let mut cache = LookupCache::default();
+        let follow = FollowLinks::Follow;
+
+        let passwd = cache.lookup(&fs, &root, Path::new("/etc/passwd"), &follow);
+        let again = cache.lookup(&fs, &root, Path::new("/etc/passwd"), &follow);
+        assert_eq!(passwd.unwrap(), again.unwrap());
+        cache
+            .lookup(&fs, &root, Path::new("/etc/passwd"), &FollowLinks::NoFollow)
+            .unwrap();
+        assert_eq!(cache.walk_count(), 2);
+
+        for _ in 0..2 {
+            assert!(matches!(
+                cache.lookup(&fs, &root, Path::new("/etc/group"), &follow),
+                Err(VFSError::NotFound)
+            ));
+        }
+        assert_eq!(cache.walk_count(), 3);
+
+        fs.writer()
+            .write_storage_file(Path::new("/etc/group"), Default::default(), None)
+            .unwrap();
+        let group = cache.lookup(&fs, &root, Path::new("/etc/group"), &follow);
+        assert_eq!(
+            group.unwrap(),
+            fs.lookup(&root, Path::new("/etc/group"), &follow).unwrap()
+        );
+        assert_eq!(cache.walk_count(), 4);
+    }
+}
@@ -1,6 +1,15 @@
-use bandsocks::{Container, ContainerBuilder, RuntimeError};
+use bandsocks::{
+    Container, ContainerBuilder, Image, ImageError, LogMessage, RegistryClient, RuntimeError,
+    SeccompAction, SeccompProfile, SharedStream, VFSError,
+};
 use futures_util::stream::{FuturesUnordered, StreamExt};
-use std::io::{BufRead, Cursor};
+use std::{
+    collections::BTreeSet,
+    io::{BufRead, Cursor, Read, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 use tokio::{runtime::Runtime, task};
 
 const IMAGE: &str =
@@ -14,6 +23,15 @@ async fn common() -> ContainerBuilder {
         .expect("container pull")
 }
 
+async fn pull_image() -> Arc<Image> {
+    let _ = env_logger::builder().is_test(true).try_init();
+    RegistryClient::new()
+        .unwrap()
+        .pull(&IMAGE.parse().unwrap())
+        .await
+        .expect("image pull")
+}
+
 #[test]
 fn pull() {
     Runtime::new().unwrap().block_on(async {
@@ -84,6 +102,41 @@ fn busybox_sh_c_echo() {
     })
 }
 
+#[test]
+fn busybox_wait_for_output_line() {
+    use tokio::io::AsyncBufReadExt;
+    Runtime::new().unwrap().block_on(async {
+        let mut container = common()
+            .await
+            .args(&[
+                "sh",
+                "-c",
+                "echo ready; while read line; do echo got $line; done",
+            ])
+            .spawn()
+            .unwrap();
+        let mut stdout = container.take_stdout().unwrap().unwrap();
+        assert!(container.take_stdout().unwrap().is_none());
+
+        let mut line = String::new();
+        stdout.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "ready\n");
+
+        let mut stdin = container.stdin.take().unwrap();
+        stdin.write_all(b"one\n").unwrap();
+        line.clear();
+        stdout.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "got one\n");
+
+        // closing stdin ends the loop, and stdout is no longer captured
+        drop(stdin);
+        let output = container.output().await.unwrap();
+        assert!(output.status.success());
+        assert!(output.stdout.is_empty());
+        assert!(output.stderr.is_empty());
+    })
+}
+
 #[test]
 fn busybox_sh_c_loop() {
     Runtime::new().unwrap().block_on(async {
@@ -186,6 +239,172 @@ fn busybox_uname() {
     })
 }
 
+#[test]
+fn busybox_uname_custom() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .hostname("sandbox-1")
+            .kernel_release("5.10.0")
+            .args(&["uname", "-nr"])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "sandbox-1 5.10.0\n");
+    })
+}
+
+#[test]
+fn busybox_uname_too_long() {
+    Runtime::new().unwrap().block_on(async {
+        let result = common().await.hostname("x".repeat(65)).spawn();
+        assert!(matches!(result, Err(RuntimeError::InvalidUtsName(_))));
+    })
+}
+
+#[test]
+fn busybox_test_executable() {
+    Runtime::new().unwrap().block_on(async {
+        // test and echo are shell builtins, so this runs in one process
+        let container = common().await;
+        let output = container
+            .clone()
+            .args(&[
+                "sh",
+                "-c",
+                "test -x /bin/sh && echo sh; test -x /etc/passwd || echo passwd",
+            ])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "sh\npasswd\n");
+
+        let output = container.args(&["which", "sh"]).output().await.unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "/bin/sh\n");
+    })
+}
+
+#[test]
+fn busybox_unhandled_ioctl() {
+    Runtime::new().unwrap().block_on(async {
+        // BLKGETSIZE64 isn't on the ioctl allowlist, so this must fail with ENOTTY
+        // instead of reporting a bogus size
+        let output = common()
+            .await
+            .args(&["blockdev", "--getsize64", "/bin/sh"])
+            .output()
+            .await
+            .unwrap();
+        assert!(!output.status.success());
+        assert!(output.stdout.is_empty());
+        assert_eq!(output.stderr_str(), "blockdev: BLKGETSIZE64: Not a tty\n");
+    })
+}
+
+#[test]
+fn busybox_seccomp_profile_denies_write() {
+    Runtime::new().unwrap().block_on(async {
+        // write() normally passes straight through to the host; with the profile
+        // it fails, so echo has nowhere to report its output or its error
+        let profile = SeccompProfile::new().deny(
+            libc::SYS_write as u32,
+            SeccompAction::Errno(libc::EACCES as u16),
+        );
+        let output = common()
+            .await
+            .seccomp_profile(profile)
+            .args(&["echo", "hello"])
+            .output()
+            .await
+            .unwrap();
+        assert!(!output.status.success());
+        assert!(output.stdout.is_empty());
+        assert!(output.stderr.is_empty());
+    })
+}
+
+#[test]
+fn busybox_passthrough_without_read() {
+    Runtime::new().unwrap().block_on(async {
+        let read = libc::SYS_read as u64;
+        assert!(!bandsocks::default_passthrough_syscalls().is_empty());
+        let without_read: Vec<u64> = bandsocks::default_passthrough_syscalls()
+            .iter()
+            .cloned()
+            .filter(|nr| *nr != read)
+            .collect();
+
+        let output = common()
+            .await
+            .args(&["cat", "/etc/passwd"])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert!(!output.stdout.is_empty());
+
+        let output = common()
+            .await
+            .passthrough_syscalls(&without_read)
+            .args(&["cat", "/etc/passwd"])
+            .output()
+            .await
+            .unwrap();
+        assert!(!output.status.success());
+        assert!(output.stdout.is_empty());
+        assert!(output.stderr_str().contains("not implemented"));
+    })
+}
+
+#[test]
+fn busybox_seccomp_violation() {
+    Runtime::new().unwrap().block_on(async {
+        let profile = SeccompProfile::new().deny(libc::SYS_write as u32, SeccompAction::Trap);
+        let result = common()
+            .await
+            .seccomp_profile(profile)
+            .args(&["echo", "hello"])
+            .output()
+            .await;
+        match result {
+            Err(RuntimeError::SeccompViolation(nr)) => assert_eq!(nr, libc::SYS_write as isize),
+            other => panic!("unexpected result, {:?}", other),
+        }
+    })
+}
+
+#[test]
+fn busybox_seccomp_logging() {
+    Runtime::new().unwrap().block_on(async {
+        // logged syscalls still reach the host
+        let output = common()
+            .await
+            .seccomp_logging(vec![libc::SYS_write as u32])
+            .args(&["echo", "hello"])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "hello\n");
+    })
+}
+
+#[test]
+fn busybox_missing_entrypoint() {
+    Runtime::new().unwrap().block_on(async {
+        let result = common().await.entrypoint(&["/bin/removed"]).output().await;
+        match result {
+            Err(RuntimeError::LoaderFailed { detail }) => {
+                assert!(detail.contains("No such file or directory"))
+            }
+            other => panic!("unexpected result, {:?}", other),
+        }
+    })
+}
+
 #[test]
 fn busybox_sleep_sequential() {
     const NUM: usize = 100;
@@ -242,3 +461,1004 @@ fn busybox_bool_parallel() {
         }
     })
 }
+
+#[test]
+fn image_stat_busybox() {
+    Runtime::new().unwrap().block_on(async {
+        let image = pull_image().await;
+        let stat = image.stat(Path::new("/bin/busybox")).unwrap();
+        assert_eq!(stat.st_mode & 0o170000, 0o100000);
+        assert_eq!(stat.st_mode & 0o111, 0o111);
+        assert!(stat.st_size > 0);
+        assert!(image.stat(Path::new("/bin/nonexistent")).is_err());
+    })
+}
+
+#[test]
+fn image_layers_busybox() {
+    Runtime::new().unwrap().block_on(async {
+        let image = pull_image().await;
+        let layers = image.layers();
+        assert_eq!(layers.len(), 1);
+        assert!(layers[0].digest.as_str().starts_with("sha256:"));
+        assert!(layers[0].diff_id.as_str().starts_with("sha256:"));
+        assert!(layers[0].size.unwrap() > 0);
+    })
+}
+
+#[test]
+fn image_list_bin() {
+    Runtime::new().unwrap().block_on(async {
+        let image = pull_image().await;
+        let names = image.list_dir(Path::new("/bin")).unwrap();
+        for expected in &["busybox", "cat", "sh", "true"] {
+            assert!(names.iter().any(|name| name == expected));
+        }
+        assert!(image.list_dir(Path::new("/bin/busybox")).is_err());
+    })
+}
+
+#[test]
+fn image_read_passwd() {
+    Runtime::new().unwrap().block_on(async {
+        let image = pull_image().await;
+        let passwd = image.read(Path::new("/etc/passwd")).await.unwrap();
+        assert!(passwd.starts_with(b"root:x:0:0:"));
+    })
+}
+
+#[test]
+fn busybox_read_only_path() {
+    Runtime::new().unwrap().block_on(async {
+        let (_usr_local, usr_remote) = SharedStream::pair().unwrap();
+        let (mut tmp_local, tmp_remote) = SharedStream::pair().unwrap();
+        let output = common()
+            .await
+            .mount("/usr/log", &usr_remote)
+            .mount("/tmp/log", &tmp_remote)
+            .read_only_path("/usr")
+            .args(&["sh", "-c", "echo tmp > /tmp/log && echo usr > /usr/log"])
+            .output()
+            .await
+            .unwrap();
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("Read-only file system"));
+        let mut written = [0u8; 4];
+        tmp_local.read_exact(&mut written).unwrap();
+        assert_eq!(&written, b"tmp\n");
+    })
+}
+
+#[test]
+fn busybox_nice() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common().await.nice(5).arg("nice").output().await.unwrap();
+        assert!(output.status.success());
+        let reported: i32 = String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .parse()
+            .unwrap();
+        assert_eq!(reported, 5);
+    })
+}
+
+#[test]
+fn busybox_timezone() {
+    Runtime::new().unwrap().block_on(async {
+        // The epoch was in winter, so Berlin was an hour ahead of UTC
+        let output = common()
+            .await
+            .timezone("Europe/Berlin")
+            .args(&["date", "-d", "@0", "+%Z %z %H:%M"])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "CET +0100 01:00\n");
+
+        let output = common()
+            .await
+            .timezone("Europe/Berlin")
+            .args(&["ls", "/etc/localtime"])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+    })
+}
+
+#[test]
+fn busybox_timezone_unknown() {
+    Runtime::new().unwrap().block_on(async {
+        match common().await.timezone("../../etc/passwd").spawn() {
+            Err(RuntimeError::UnknownTimezone(_)) => (),
+            other => panic!("unexpected result, {:?}", other),
+        }
+    })
+}
+
+#[test]
+fn busybox_stdin_from_file() {
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    for i in 0..1000 {
+        writeln!(file, "line {}", i).unwrap();
+    }
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .stdin_from_file(file.path())
+            .args(&["wc", "-l"])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str().trim(), "1000");
+    })
+}
+
+#[test]
+fn busybox_stdin_bytes() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .stdin_bytes("hello\n")
+            .args(&["cat"])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "hello\n");
+    })
+}
+
+#[test]
+fn busybox_dev_stdio_links() {
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    write!(file, "from stdin").unwrap();
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .stdin_from_file(file.path())
+            .args(&[
+                "sh",
+                "-c",
+                "echo hi >/dev/stdout; echo oops >/dev/stderr; cat /dev/fd/0",
+            ])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "hi\nfrom stdin");
+        assert_eq!(output.stderr_str(), "oops\n");
+    })
+}
+
+#[test]
+fn busybox_stdin_from_missing_file() {
+    Runtime::new().unwrap().block_on(async {
+        match common()
+            .await
+            .stdin_from_file("/nonexistent/stdin")
+            .arg("cat")
+            .spawn()
+        {
+            Err(RuntimeError::IOError(_)) => (),
+            other => panic!("unexpected result, {:?}", other),
+        }
+    })
+}
+
+#[test]
+fn busybox_image_user() {
+    // A busybox-based image with `USER nobody` in its config
+    const USER_IMAGE: &str = "prom/node-exporter:v1.0.1";
+    Runtime::new().unwrap().block_on(async {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let container = || async {
+            Container::pull(&USER_IMAGE.parse().unwrap())
+                .await
+                .expect("container pull")
+                .entrypoint(&["id"])
+        };
+
+        let output = container().await.arg("-u").output().await.unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "65534\n");
+
+        let output = container().await.arg("-g").output().await.unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "65534\n");
+
+        let output = container().await.uid(0).arg("-u").output().await.unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "0\n");
+    })
+}
+
+#[test]
+fn busybox_cpus() {
+    Runtime::new().unwrap().block_on(async {
+        let container = common().await.cpus(2);
+        let runs: &[(&[&str], &str)] = &[
+            (&["nproc"], "2\n"),
+            (&["grep", "-c", "^processor", "/proc/cpuinfo"], "2\n"),
+            (&["cat", "/sys/devices/system/cpu/online"], "0-1\n"),
+        ];
+        for (args, expected) in runs {
+            let output = container.clone().args(*args).output().await.unwrap();
+            assert!(output.status.success());
+            assert_eq!(output.stdout_str(), *expected);
+        }
+    })
+}
+
+#[test]
+fn busybox_uid_gid_override() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .uid(1000)
+            .gid(100)
+            .args(&["id", "-u"])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "1000\n");
+
+        let output = common()
+            .await
+            .gid(100)
+            .args(&["id", "-g"])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "100\n");
+    })
+}
+
+#[test]
+fn busybox_network_files() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .synthesize_network_files()
+            .args(&["grep", "-w", "localhost", "/etc/hosts"])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert!(output.stdout_str().starts_with("127.0.0.1\tlocalhost\n"));
+
+        let output = common()
+            .await
+            .synthesize_network_files()
+            .args(&[
+                "sh",
+                "-c",
+                "test -f /etc/resolv.conf && ! echo 2>/dev/null >/etc/hosts",
+            ])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+    })
+}
+
+#[test]
+fn busybox_exec_oversized_arg() {
+    Runtime::new().unwrap().block_on(async {
+        // Each half fits in one argument, but the shell joins them into one
+        // that doesn't
+        let half = "0".repeat(100_000);
+        let output = common()
+            .await
+            .args(&["sh", "-c", "/bin/true \"$1$2\"", "sh", &half, &half])
+            .output()
+            .await
+            .unwrap();
+        assert_eq!(output.status.code(), Some(126));
+        assert!(output.stderr_str().contains("Argument list too long"));
+    })
+}
+
+#[test]
+fn busybox_exec_oversized_env() {
+    Runtime::new().unwrap().block_on(async {
+        let container = common()
+            .await
+            .arg_max(64 * 1024)
+            .env("A", "0".repeat(50_000));
+        let output = container.clone().arg("/bin/true").output().await.unwrap();
+        assert!(output.status.success());
+
+        // A second copy of the variable takes the environment over the limit
+        let output = container
+            .args(&["sh", "-c", "export B=$A; /bin/true"])
+            .output()
+            .await
+            .unwrap();
+        assert_eq!(output.status.code(), Some(126));
+        assert!(output.stderr_str().contains("Argument list too long"));
+    })
+}
+
+#[test]
+fn busybox_oom_score_adj() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .oom_score_adj(500)
+            .args(&["cat", "/proc/self/oom_score_adj"])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "500\n");
+    })
+}
+
+#[test]
+fn busybox_exec_probe() {
+    use tokio::io::AsyncBufReadExt;
+    Runtime::new().unwrap().block_on(async {
+        let no_env: Vec<(&str, &str)> = Vec::new();
+        let mut container = common()
+            .await
+            .tmpfs("/tmp", Some(1 << 20))
+            .args(&["sh", "-c", "echo up > /tmp/state; echo ready; read line"])
+            .spawn()
+            .unwrap();
+        let mut stdout = container.take_stdout().unwrap().unwrap();
+        let mut line = String::new();
+        stdout.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "ready\n");
+
+        // Probes see the running container's files and processes
+        let status = container
+            .exec_probe(&["grep", "-q", "up", "/tmp/state"], no_env.clone())
+            .await
+            .unwrap();
+        assert!(status.success());
+        let status = container
+            .exec_probe(&["test", "-e", "/tmp/missing"], no_env.clone())
+            .await
+            .unwrap();
+        assert_eq!(status.code(), Some(1));
+        let status = container
+            .exec_probe(
+                &["sh", "-c", "test \"$PROBE\" = ready && test -d /proc/1/fd"],
+                vec![("PROBE", "ready")],
+            )
+            .await
+            .unwrap();
+        assert!(status.success());
+        assert_eq!(container.pids(), vec![1]);
+
+        // Each finished probe's /proc directory is gone, stdio and all
+        let status = container
+            .exec_probe(
+                &["sh", "-c", "test ! -e /proc/2 && test ! -e /proc/4/fd/1"],
+                no_env.clone(),
+            )
+            .await
+            .unwrap();
+        assert!(status.success());
+
+        container.stdin.take().unwrap().write_all(b"\n").unwrap();
+        assert!(container.wait().await.unwrap().success());
+    })
+}
+
+#[test]
+fn busybox_stop_signal_on_timeout() {
+    use tokio::io::AsyncBufReadExt;
+    Runtime::new().unwrap().block_on(async {
+        let script = "trap 'echo quit; exit 7' QUIT; trap 'echo term; exit 8' TERM; \
+                      echo ready; read line";
+        let mut container = common()
+            .await
+            .stop_signal(libc::SIGQUIT)
+            .args(&["sh", "-c", script])
+            .spawn()
+            .unwrap();
+        let mut stdout = container.take_stdout().unwrap().unwrap();
+        let mut line = String::new();
+        stdout.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "ready\n");
+
+        // stdin stays open, so only the stop signal ends the read
+        let status = container
+            .wait_timeout(Duration::from_millis(500))
+            .await
+            .unwrap();
+        assert_eq!(status.code(), Some(7));
+        line.clear();
+        stdout.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "quit\n");
+    })
+}
+
+#[test]
+fn busybox_stop_after_grace_period() {
+    use tokio::io::AsyncBufReadExt;
+    Runtime::new().unwrap().block_on(async {
+        let script = "trap '' TERM; echo ready; read line";
+        let mut container = common().await.args(&["sh", "-c", script]).spawn().unwrap();
+        let mut stdout = container.take_stdout().unwrap().unwrap();
+        let mut line = String::new();
+        stdout.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "ready\n");
+
+        let started = std::time::Instant::now();
+        let status = container.stop(Duration::from_millis(500)).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(500));
+        assert_eq!(status.signal(), Some(libc::SIGKILL));
+    })
+}
+
+#[test]
+fn busybox_stop_within_grace_period() {
+    use tokio::io::AsyncBufReadExt;
+    Runtime::new().unwrap().block_on(async {
+        let script = "trap 'echo cleanup; exit 0' TERM; echo ready; read line";
+        let mut container = common().await.args(&["sh", "-c", script]).spawn().unwrap();
+        let mut stdout = container.take_stdout().unwrap().unwrap();
+        let mut line = String::new();
+        stdout.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "ready\n");
+
+        let status = container.stop(Duration::from_secs(10)).await.unwrap();
+        assert!(status.success());
+        line.clear();
+        stdout.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "cleanup\n");
+    })
+}
+
+const APPLET_MATRIX: &[(&str, &[&str], &str, i32)] = &[
+    ("echo", &["hello", "applet"], "hello applet\n", 0),
+    (
+        "cat",
+        &["/etc/passwd"],
+        "root:x:0:0:root:/root:/bin/sh\n",
+        0,
+    ),
+    ("ls", &["-d", "/etc", "/bin"], "/bin\n/etc\n", 0),
+    ("sh", &["-c", "echo $0; exit 3"], "sh\n", 3),
+    ("true", &[], "", 0),
+    ("false", &[], "", 1),
+];
+
+#[test]
+fn busybox_applet_matrix() {
+    Runtime::new().unwrap().block_on(async {
+        let base = common().await;
+        for (applet, args, stdout, code) in APPLET_MATRIX {
+            // Once by the applet's own name in PATH, and once by running the
+            // multicall binary with argv[0] set to that name
+            let by_name = base.clone().arg(applet).args(args.iter());
+            let by_arg0 = base
+                .clone()
+                .arg("/bin/busybox")
+                .args(args.iter())
+                .arg0(applet);
+            for builder in vec![by_name, by_arg0] {
+                let output = builder.output().await.unwrap();
+                assert_eq!(output.status.code(), Some(*code), "{} {:?}", applet, output);
+                assert!(output.stderr.is_empty(), "{} {:?}", applet, output);
+                assert!(
+                    output.stdout_str().starts_with(stdout),
+                    "{} {:?}",
+                    applet,
+                    output
+                );
+            }
+        }
+    })
+}
+
+#[test]
+fn busybox_detached_until_stopped() {
+    Runtime::new().unwrap().block_on(async {
+        let mut container = common().await.args(&["sleep", "1000"]).detach().unwrap();
+        assert!(container.is_running());
+        let status = container.stop(Duration::from_secs(10)).await.unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGTERM));
+    })
+}
+
+#[test]
+fn busybox_detached_exits() {
+    Runtime::new().unwrap().block_on(async {
+        let mut container = common()
+            .await
+            .args(&["sh", "-c", "exit 5"])
+            .detach()
+            .unwrap();
+        let started = std::time::Instant::now();
+        while container.is_running() {
+            assert!(started.elapsed() < Duration::from_secs(10));
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        assert_eq!(container.wait().await.unwrap().code(), Some(5));
+    })
+}
+
+#[test]
+fn busybox_pids() {
+    Runtime::new().unwrap().block_on(async {
+        let mut container = common()
+            .await
+            .args(&["sh", "-c", "read line"])
+            .spawn()
+            .unwrap();
+        let started = std::time::Instant::now();
+        while container.pids().is_empty() {
+            assert!(started.elapsed() < Duration::from_secs(10));
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        assert_eq!(container.pids(), vec![1]);
+
+        drop(container.stdin.take());
+        while container.is_running() {
+            assert!(started.elapsed() < Duration::from_secs(10));
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        assert!(container.pids().is_empty());
+    })
+}
+
+#[test]
+fn busybox_log_handler() {
+    Runtime::new().unwrap().block_on(async {
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let handler_trace = trace.clone();
+        let output = common()
+            .await
+            .log_handler(move |task, _level, message| {
+                if let LogMessage::Emulated(call) = message {
+                    handler_trace.lock().unwrap().push((task.0, call.nr));
+                }
+            })
+            .args(&["cat", "/etc/passwd"])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        let trace = trace.lock().unwrap();
+        assert!(trace.iter().all(|(task, _)| *task == 1));
+        let opens = [libc::SYS_open as isize, libc::SYS_openat as isize];
+        assert!(trace.iter().any(|(_, nr)| opens.contains(nr)));
+    })
+}
+
+#[test]
+fn busybox_extra_passthrough_refused() {
+    Runtime::new().unwrap().block_on(async {
+        let result = common()
+            .await
+            .extra_passthrough_syscalls(&[libc::SYS_clock_gettime as u64, libc::SYS_ptrace as u64])
+            .spawn();
+        assert!(matches!(
+            result,
+            Err(RuntimeError::NotOptionalPassthroughSyscall(nr)) if nr == libc::SYS_ptrace as u64
+        ));
+    })
+}
+
+#[test]
+fn busybox_signalled_exit() {
+    Runtime::new().unwrap().block_on(async {
+        let status = common()
+            .await
+            .args(&["sh", "-c", "kill -KILL $$"])
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(status.code(), None);
+        assert_eq!(status.signal(), Some(libc::SIGKILL));
+
+        // The same exit code without a signal is a clean exit
+        let status = common()
+            .await
+            .args(&["sh", "-c", "exit 137"])
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(status.code(), Some(137));
+        assert_eq!(status.signal(), None);
+    })
+}
+
+#[test]
+fn busybox_signal_kill() {
+    use tokio::io::AsyncBufReadExt;
+    Runtime::new().unwrap().block_on(async {
+        let container = common().await.args(&["sleep", "1000"]).spawn().unwrap();
+        container.signal(libc::SIGKILL);
+        let status = container.wait().await.unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGKILL));
+
+        // Unlike the stop signal, kill() can't be ignored
+        let script = "trap '' TERM; echo ready; read line";
+        let mut container = common().await.args(&["sh", "-c", script]).spawn().unwrap();
+        let mut stdout = container.take_stdout().unwrap().unwrap();
+        let mut line = String::new();
+        stdout.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "ready\n");
+        container.terminate();
+        container.kill().unwrap();
+        let status = container.wait().await.unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGKILL));
+
+        // Signals after the container exits are ignored
+        let mut container = common().await.args(&["true"]).spawn().unwrap();
+        while container.is_running() {
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        container.signal(libc::SIGKILL);
+        container.kill().unwrap();
+        assert!(container.wait().await.unwrap().success());
+    })
+}
+
+#[test]
+fn busybox_timeout() {
+    Runtime::new().unwrap().block_on(async {
+        let started = std::time::Instant::now();
+        let result = common()
+            .await
+            .timeout(Duration::from_secs(1))
+            .args(&["sleep", "100"])
+            .run()
+            .await;
+        match result {
+            Err(RuntimeError::Timeout) => (),
+            other => panic!("unexpected result, {:?}", other),
+        }
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_secs(1) && elapsed < Duration::from_secs(10));
+
+        let status = common()
+            .await
+            .timeout(Duration::from_secs(10))
+            .args(&["true"])
+            .run()
+            .await
+            .unwrap();
+        assert!(status.success());
+    })
+}
+
+#[test]
+fn busybox_date() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .args(&["date", "+%s"])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        let host_now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let container_now: i64 = output.stdout_str().trim().parse().unwrap();
+        assert!((container_now - host_now).abs() < 60);
+    })
+}
+
+#[test]
+fn busybox_list_directories() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .args(&["ls", "-a", "/"])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        let stdout = output.stdout_str();
+        let names: Vec<&str> = stdout.lines().collect();
+        for name in &[".", "..", "bin", "etc", "proc", "usr"] {
+            assert!(names.contains(name), "{:?} not in {:?}", name, names);
+        }
+
+        // Far more entries than fit in one getdents64 call, with no repeats
+        let output = common().await.args(&["ls", "/bin"]).output().await.unwrap();
+        assert!(output.status.success());
+        let stdout = output.stdout_str();
+        let names: Vec<&str> = stdout.lines().collect();
+        let unique: BTreeSet<&str> = names.iter().cloned().collect();
+        assert!(names.len() > 300);
+        assert_eq!(names.len(), unique.len());
+    })
+}
+
+#[test]
+fn busybox_seek_in_file() {
+    Runtime::new().unwrap().block_on(async {
+        // lseek is passed through to the host, where guest fds are real fds
+        let output = common()
+            .await
+            .args(&["dd", "if=/etc/passwd", "bs=1", "skip=5", "count=4"])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "x:0:");
+    })
+}
+
+#[test]
+fn busybox_merge_stderr_into_stdout() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .args(&["sh", "-c", "{ echo out; echo err >&2; } 2>&1"])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "out\nerr\n");
+        assert_eq!(output.stderr_str(), "");
+    })
+}
+
+#[test]
+fn busybox_remove_overlay_files() {
+    Runtime::new().unwrap().block_on(async {
+        let (_a_local, a_remote) = SharedStream::pair().unwrap();
+        let container = common().await.mount("/run/sockets/a", &a_remote);
+
+        let output = container
+            .clone()
+            .args(&["rmdir", "/run/sockets"])
+            .output()
+            .await
+            .unwrap();
+        assert!(!output.status.success());
+        assert!(output.stderr_str().contains("Directory not empty"));
+
+        // Naming the directory twice checks that the first removal took
+        let output = container
+            .clone()
+            .args(&["rm", "-r", "/run/sockets", "/run/sockets"])
+            .output()
+            .await
+            .unwrap();
+        assert!(!output.status.success());
+        assert_eq!(output.stderr_str().lines().count(), 1);
+        assert!(output.stderr_str().contains("No such file or directory"));
+
+        let output = container
+            .args(&["rm", "/etc/passwd"])
+            .output()
+            .await
+            .unwrap();
+        assert!(!output.status.success());
+        assert!(output.stderr_str().contains("Read-only file system"));
+    })
+}
+
+#[test]
+fn busybox_make_overlay_directories() {
+    Runtime::new().unwrap().block_on(async {
+        let (_local, remote) = SharedStream::pair().unwrap();
+        let container = common().await.mount("/run/sockets/a", &remote);
+
+        let output = container
+            .clone()
+            .args(&["mkdir", "-p", "/run/build/x/y"])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+
+        let output = container
+            .clone()
+            .args(&["mkdir", "/run/sockets"])
+            .output()
+            .await
+            .unwrap();
+        assert!(!output.status.success());
+        assert!(output.stderr_str().contains("File exists"));
+
+        let output = container
+            .args(&["mkdir", "/etc/new"])
+            .output()
+            .await
+            .unwrap();
+        assert!(!output.status.success());
+        assert!(output.stderr_str().contains("Read-only file system"));
+    })
+}
+
+#[test]
+fn busybox_tmpfs() {
+    Runtime::new().unwrap().block_on(async {
+        let container = common().await.tmpfs("/tmp", Some(1 << 20));
+
+        // Redirections are shell builtins, so only the final cat is a new
+        // program
+        let output = container
+            .clone()
+            .args(&[
+                "sh",
+                "-c",
+                "echo hello > /tmp/a && echo there >> /tmp/a && cat /tmp/a",
+            ])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "hello\nthere\n");
+
+        let output = container
+            .args(&["sh", "-c", "echo nope > /etc/new"])
+            .output()
+            .await
+            .unwrap();
+        assert!(!output.status.success());
+        assert!(output.stderr_str().contains("Read-only file system"));
+    })
+}
+
+#[test]
+fn busybox_tmpfs_size_limit() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .tmpfs("/tmp", Some(1000))
+            .args(&[
+                "sh",
+                "-c",
+                "printf %01000d 0 > /tmp/a && echo more > /tmp/b; \
+                 : > /tmp/a && echo more > /tmp/b && cat /tmp/b",
+            ])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "more\n");
+        assert!(output.stderr_str().contains("No space left on device"));
+    })
+}
+
+#[test]
+fn busybox_write_file() {
+    Runtime::new().unwrap().block_on(async {
+        let container = common()
+            .await
+            .write_file("/etc/resolv.conf", "nameserver 192.0.2.1\n", 0o644)
+            .write_file("/opt/new/script", "#!/bin/sh\necho injected\n", 0o755);
+        let runs: &[(&[&str], &str)] = &[
+            (&["cat", "/etc/resolv.conf"], "nameserver 192.0.2.1\n"),
+            (&["/opt/new/script"], "injected\n"),
+        ];
+        for (args, expected) in runs {
+            let output = container.clone().args(*args).output().await.unwrap();
+            assert!(output.status.success());
+            assert_eq!(output.stdout_str(), *expected);
+        }
+
+        let output = container
+            .args(&["sh", "-c", "echo nope >> /etc/resolv.conf"])
+            .output()
+            .await
+            .unwrap();
+        assert!(!output.status.success());
+        assert!(output.stderr_str().contains("Read-only file system"));
+    })
+}
+
+#[test]
+fn busybox_bind_mount() {
+    let host = tempfile::tempdir().unwrap();
+    std::fs::create_dir(host.path().join("dir")).unwrap();
+    std::fs::write(host.path().join("dir/data.txt"), "from the host\n").unwrap();
+    std::os::unix::fs::symlink("dir/data.txt", host.path().join("data")).unwrap();
+    Runtime::new().unwrap().block_on(async {
+        let container = common().await.bind_mount("/data", host.path());
+        for (args, expected) in &[
+            (&["cat", "/data/data"], "from the host\n"),
+            (&["readlink", "/data/data"], "dir/data.txt\n"),
+            (&["ls", "/data/dir"], "data.txt\n"),
+        ] {
+            let output = container.clone().args(*args).output().await.unwrap();
+            assert!(output.status.success());
+            assert_eq!(output.stdout_str(), *expected);
+        }
+        let output = container
+            .args(&["sh", "-c", "echo nope > /data/new"])
+            .output()
+            .await
+            .unwrap();
+        assert!(!output.status.success());
+        assert!(output.stderr_str().contains("Read-only file system"));
+    })
+}
+
+#[test]
+fn busybox_bind_mount_escaping_link() {
+    let host = tempfile::tempdir().unwrap();
+    std::os::unix::fs::symlink("../etc/passwd", host.path().join("passwd")).unwrap();
+    Runtime::new().unwrap().block_on(async {
+        let result = common().await.bind_mount("/data", host.path()).spawn();
+        assert!(matches!(
+            result,
+            Err(RuntimeError::VFSError(VFSError::PathNotBeneath))
+        ));
+    })
+}
+
+#[test]
+fn busybox_working_dir() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .working_dir("/etc")
+            .args(&["pwd"])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "/etc\n");
+
+        let output = common()
+            .await
+            .args(&[
+                "sh",
+                "-c",
+                "pwd; cd /usr/bin && pwd && cd .. && pwd; cd /bin/sh",
+            ])
+            .output()
+            .await
+            .unwrap();
+        assert!(!output.status.success());
+        assert_eq!(output.stdout_str(), "/\n/usr/bin\n/usr\n");
+        assert!(output.stderr_str().contains("Not a directory"));
+    })
+}
+
+#[test]
+fn busybox_working_dir_relative() {
+    Runtime::new().unwrap().block_on(async {
+        match common().await.working_dir("etc").spawn() {
+            Err(RuntimeError::ImageError(ImageError::RelativeWorkingDir(_))) => (),
+            other => panic!("unexpected result, {:?}", other),
+        }
+    })
+}
+
+#[test]
+fn busybox_image_env() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .env("foo", "bar")
+            .args(&["env"])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            output.stdout_str(),
+            "PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin\nfoo=bar\n"
+        );
+
+        let output = common()
+            .await
+            .env_clear()
+            .env("foo", "bar")
+            .args(&["/bin/env"])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "foo=bar\n");
+    })
+}
@@ -0,0 +1,100 @@
+// Kept apart from the other registry tests, since it changes PATH for the
+// whole process
+
+use bandsocks::RegistryClient;
+use std::{
+    env, fs,
+    io::{BufRead, BufReader, Write},
+    net::TcpListener,
+    os::unix::fs::PermissionsExt,
+    sync::mpsc,
+    thread,
+};
+use tokio::runtime::Runtime;
+
+/// Server that only asks for a Basic login, reporting each Authorization
+/// header it's sent
+fn login_server() -> (u16, mpsc::Receiver<Option<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut authorization = None;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                let mut header = line.splitn(2, ':');
+                if let (Some(name), Some(value)) = (header.next(), header.next()) {
+                    if name.eq_ignore_ascii_case("authorization") {
+                        authorization = Some(value.trim().to_string());
+                    }
+                }
+            }
+            let status = match authorization {
+                None => "401 Unauthorized\r\nWWW-Authenticate: Basic realm=\"mock\"",
+                Some(_) => "404 Not Found",
+            };
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status
+            )
+            .unwrap();
+            let _ = sender.send(authorization);
+        }
+    });
+    (port, receiver)
+}
+
+#[test]
+fn docker_config_credential_helper() {
+    let (port, requests) = login_server();
+    let dir = tempfile::tempdir().unwrap();
+    let helper = dir.path().join("docker-credential-mock");
+    fs::write(
+        &helper,
+        "#!/bin/sh\n\
+         read server\n\
+         echo \"{\\\"ServerURL\\\":\\\"$server\\\",\\\"Username\\\":\\\"user\\\",\\\"Secret\\\":\\\"secret\\\"}\"\n",
+    )
+    .unwrap();
+    fs::set_permissions(&helper, fs::Permissions::from_mode(0o755)).unwrap();
+    let path = env::var("PATH").unwrap_or_default();
+    env::set_var("PATH", format!("{}:{}", dir.path().display(), path));
+
+    let config_path = dir.path().join("config.json");
+    fs::write(
+        &config_path,
+        serde_json::to_vec(&serde_json::json!({
+            "credHelpers": { format!("localhost:{}", port): "mock" },
+        }))
+        .unwrap(),
+    )
+    .unwrap();
+
+    let name = format!(
+        "localhost:{}/test/private@sha256:0000000000000000000000000000000000000000000000000000000000000000",
+        port
+    );
+    Runtime::new().unwrap().block_on(async {
+        let client = RegistryClient::builder()
+            .ephemeral_cache()
+            .docker_config(Some(&config_path))
+            .build()
+            .unwrap();
+        // The image doesn't exist either way, but the helper's login is tried
+        assert!(client.pull(&name.parse().unwrap()).await.is_err());
+    });
+    assert_eq!(requests.recv().unwrap(), None);
+    // "dXNlcjpzZWNyZXQ=" is base64 for "user:secret"
+    assert_eq!(
+        requests.recv().unwrap().as_deref(),
+        Some("Basic dXNlcjpzZWNyZXQ=")
+    );
+}
@@ -0,0 +1,1461 @@
+use bandsocks::{
+    Container, ContentDigest, DefaultRegistry, ImageError, ProgressResource, PullProgress,
+    RegistryClient, VFSError,
+};
+use flate2::{write::GzEncoder, Compression};
+use openssl::{
+    asn1::Asn1Time,
+    bn::BigNum,
+    hash::MessageDigest,
+    pkey::{PKey, Private},
+    rsa::Rsa,
+    ssl::{SslAcceptor, SslMethod},
+    x509::{
+        extension::{BasicConstraints, SubjectAlternativeName},
+        X509NameBuilder, X509,
+    },
+};
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::runtime::Runtime;
+
+fn layer_tar_gzip() -> Vec<u8> {
+    let mut tar = tar::Builder::new(Vec::new());
+    let content = b"hello from schema1\n";
+    let mut header = tar::Header::new_gnu();
+    header.set_size(content.len() as u64);
+    header.set_mode(0o644);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(0);
+    header.set_device_major(0).unwrap();
+    header.set_device_minor(0).unwrap();
+    header.set_cksum();
+    tar.append_data(&mut header, "etc/motd", &content[..])
+        .unwrap();
+    let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+    gz.write_all(&tar.into_inner().unwrap()).unwrap();
+    gz.finish().unwrap()
+}
+
+fn schema1_manifest(layer_digest: &ContentDigest) -> Vec<u8> {
+    let compat = serde_json::json!({
+        "id": "0000000000000000000000000000000000000000000000000000000000000001",
+        "architecture": "amd64",
+        "os": "linux",
+        "created": "2015-01-01T00:00:00Z",
+        "config": {
+            "Env": ["PATH=/bin"],
+            "Cmd": ["cat", "/etc/motd"],
+            "WorkingDir": "",
+            "Entrypoint": null,
+        },
+    });
+    serde_json::to_vec(&serde_json::json!({
+        "schemaVersion": 1,
+        "name": "test/legacy",
+        "tag": "latest",
+        "architecture": "amd64",
+        "fsLayers": [{ "blobSum": layer_digest.as_str() }],
+        "history": [{ "v1Compatibility": compat.to_string() }],
+    }))
+    .unwrap()
+}
+
+/// Credentials a mock registry insists on
+struct MockAuth {
+    /// The exact `Authorization` header value it accepts
+    authorization: String,
+    /// The `WWW-Authenticate` challenge sent back without it
+    challenge: String,
+}
+
+impl MockAuth {
+    fn basic(credentials: &str) -> Self {
+        MockAuth {
+            authorization: format!("Basic {}", credentials),
+            challenge: "Basic realm=\"mock\"".to_string(),
+        }
+    }
+}
+
+/// Minimal HTTP registry serving fixed objects, one request per connection
+///
+/// If `auth` is set, requests without its authorization get its challenge
+/// instead. Like some real servers, this applies gzip content encoding unless
+/// the client asks for `identity`.
+fn mock_registry(objects: HashMap<String, (&'static str, Vec<u8>)>, auth: Option<MockAuth>) -> u16 {
+    flaky_registry(objects, auth, 0).0
+}
+
+/// Number of requests a mock registry has received for each path
+type RequestCounts = Arc<Mutex<HashMap<String, usize>>>;
+
+/// Mock registry where the first few requests for each object fail,
+/// alternating between a 503 error and a connection that drops partway
+/// through the body
+fn flaky_registry(
+    objects: HashMap<String, (&'static str, Vec<u8>)>,
+    auth: Option<MockAuth>,
+    failures: usize,
+) -> (u16, RequestCounts) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let requests = RequestCounts::default();
+    let thread_requests = requests.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut request_auth = None;
+            let mut identity_encoding = false;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                let mut header = line.splitn(2, ':');
+                if let (Some(name), Some(value)) = (header.next(), header.next()) {
+                    if name.eq_ignore_ascii_case("authorization") {
+                        request_auth = Some(value.trim().to_string());
+                    }
+                    if name.eq_ignore_ascii_case("accept-encoding") {
+                        identity_encoding = value.trim() == "identity";
+                    }
+                }
+            }
+            match &auth {
+                Some(auth) if request_auth.as_ref() != Some(&auth.authorization) => {
+                    write!(
+                        stream,
+                        "HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                        auth.challenge
+                    )
+                    .unwrap();
+                    continue;
+                }
+                _ => (),
+            }
+            let path = request_line.split(' ').nth(1).unwrap_or("");
+            let attempt = {
+                let mut requests = thread_requests.lock().unwrap();
+                let attempt = requests.entry(path.to_owned()).or_insert(0);
+                *attempt += 1;
+                *attempt
+            };
+            let failing = attempt <= failures;
+            match objects.get(path) {
+                Some(_) if failing && attempt % 2 == 1 => {
+                    write!(
+                        stream,
+                        "HTTP/1.1 503 Service Unavailable\r\nRetry-After: 0\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    )
+                    .unwrap();
+                }
+                Some((content_type, body)) if failing => {
+                    write!(
+                        stream,
+                        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        content_type,
+                        body.len()
+                    )
+                    .unwrap();
+                    stream.write_all(&body[..body.len() / 2]).unwrap();
+                }
+                Some((content_type, body)) => {
+                    let (encoding, body) = if identity_encoding {
+                        ("identity", body.clone())
+                    } else {
+                        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                        encoder.write_all(body).unwrap();
+                        ("gzip", encoder.finish().unwrap())
+                    };
+                    write!(
+                        stream,
+                        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Encoding: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        content_type,
+                        encoding,
+                        body.len()
+                    )
+                    .unwrap();
+                    stream.write_all(&body).unwrap();
+                }
+                None => write!(
+                    stream,
+                    "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                )
+                .unwrap(),
+            }
+        }
+    });
+    (port, requests)
+}
+
+/// Serve a one-layer schema1 image, returning the port and manifest digest
+fn schema1_image() -> (u16, ContentDigest) {
+    schema1_image_with_auth(None)
+}
+
+fn schema1_image_with_auth(auth: Option<MockAuth>) -> (u16, ContentDigest) {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let layer = layer_tar_gzip();
+    let layer_digest = ContentDigest::from_content(&layer);
+    let manifest = schema1_manifest(&layer_digest);
+    let manifest_digest = ContentDigest::from_content(&manifest);
+
+    let mut objects = HashMap::new();
+    objects.insert(
+        format!("/v2/test/legacy/manifests/{}", manifest_digest),
+        (
+            "application/vnd.docker.distribution.manifest.v1+json",
+            manifest,
+        ),
+    );
+    objects.insert(
+        format!("/v2/test/legacy/blobs/{}", layer_digest),
+        ("application/octet-stream", layer),
+    );
+    (mock_registry(objects, auth), manifest_digest)
+}
+
+/// Serve nothing but a manifest with the given content type, returning the
+/// port and manifest digest
+fn manifest_only(content_type: &'static str, manifest: Vec<u8>) -> (u16, ContentDigest) {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let manifest_digest = ContentDigest::from_content(&manifest);
+    let mut objects = HashMap::new();
+    objects.insert(
+        format!("/v2/test/other/manifests/{}", manifest_digest),
+        (content_type, manifest),
+    );
+    (mock_registry(objects, None), manifest_digest)
+}
+
+fn runtime_config(diff_id: &ContentDigest) -> Vec<u8> {
+    runtime_config_with(diff_id, serde_json::json!({}))
+}
+
+/// A runtime config like `runtime_config`, with `fields` replacing entries in
+/// its container config section
+fn runtime_config_with(diff_id: &ContentDigest, fields: serde_json::Value) -> Vec<u8> {
+    let mut config = serde_json::json!({
+        "architecture": "amd64",
+        "os": "linux",
+        "created": "2015-01-01T00:00:00Z",
+        "docker_version": "",
+        "config": {
+            "User": "",
+            "Env": ["PATH=/bin"],
+            "Cmd": ["cat", "/etc/motd"],
+            "Image": "",
+            "WorkingDir": "",
+            "Entrypoint": null,
+        },
+        "rootfs": { "type": "layers", "diff_ids": [diff_id.as_str()] },
+    });
+    for (key, value) in fields.as_object().unwrap() {
+        config["config"][key] = value.clone();
+    }
+    serde_json::to_vec(&config).unwrap()
+}
+
+/// Serve a one-layer schema2 image whose manifest lists `config_digest`,
+/// returning the port and manifest digest
+///
+/// The config is served at that digest regardless of its actual content.
+fn schema2_image(config: Vec<u8>, config_digest: &ContentDigest) -> (u16, ContentDigest) {
+    schema2_image_with_layer(config, config_digest, layer_tar_gzip())
+}
+
+fn schema2_image_with_layer(
+    config: Vec<u8>,
+    config_digest: &ContentDigest,
+    layer: Vec<u8>,
+) -> (u16, ContentDigest) {
+    let (objects, manifest_digest) = schema2_objects(config, config_digest, layer);
+    (mock_registry(objects, None), manifest_digest)
+}
+
+/// Everything a registry needs to serve a one-layer schema2 image, and the
+/// manifest digest
+fn schema2_objects(
+    config: Vec<u8>,
+    config_digest: &ContentDigest,
+    layer: Vec<u8>,
+) -> (HashMap<String, (&'static str, Vec<u8>)>, ContentDigest) {
+    image_objects(DOCKER_TYPES, config, config_digest, layer)
+}
+
+/// Media types for a manifest, its config, and its layer
+type ImageTypes = [&'static str; 3];
+
+const DOCKER_TYPES: ImageTypes = [
+    "application/vnd.docker.distribution.manifest.v2+json",
+    "application/vnd.docker.container.image.v1+json",
+    "application/vnd.docker.image.rootfs.diff.tar.gzip",
+];
+
+const OCI_TYPES: ImageTypes = [
+    "application/vnd.oci.image.manifest.v1+json",
+    "application/vnd.oci.image.config.v1+json",
+    "application/vnd.oci.image.layer.v1.tar+gzip",
+];
+
+fn image_objects(
+    [manifest_type, config_type, layer_type]: ImageTypes,
+    config: Vec<u8>,
+    config_digest: &ContentDigest,
+    layer: Vec<u8>,
+) -> (HashMap<String, (&'static str, Vec<u8>)>, ContentDigest) {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let layer_digest = ContentDigest::from_content(&layer);
+    let manifest = serde_json::to_vec(&serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": manifest_type,
+        "config": {
+            "mediaType": config_type,
+            "size": config.len(),
+            "digest": config_digest.as_str(),
+        },
+        "layers": [{
+            "mediaType": layer_type,
+            "size": layer.len(),
+            "digest": layer_digest.as_str(),
+        }],
+    }))
+    .unwrap();
+    let manifest_digest = ContentDigest::from_content(&manifest);
+
+    let mut objects = HashMap::new();
+    objects.insert(
+        format!("/v2/test/modern/manifests/{}", manifest_digest),
+        (manifest_type, manifest),
+    );
+    objects.insert(
+        format!("/v2/test/modern/blobs/{}", config_digest),
+        ("application/octet-stream", config),
+    );
+    objects.insert(
+        format!("/v2/test/modern/blobs/{}", layer_digest),
+        ("application/octet-stream", layer),
+    );
+    (objects, manifest_digest)
+}
+
+fn layer_diff_id() -> ContentDigest {
+    let mut tar = Vec::new();
+    flate2::read::GzDecoder::new(&layer_tar_gzip()[..])
+        .read_to_end(&mut tar)
+        .unwrap();
+    ContentDigest::from_content(&tar)
+}
+
+#[test]
+fn pull_schema1_from_mock_registry() {
+    let (port, manifest_digest) = schema1_image();
+    Runtime::new().unwrap().block_on(async {
+        let client = RegistryClient::builder().ephemeral_cache().build().unwrap();
+        let name = format!("localhost:{}/test/legacy@{}", port, manifest_digest);
+        let image = client.pull(&name.parse().unwrap()).await.unwrap();
+        assert_eq!(image.content_digest(), manifest_digest);
+        Container::new(image).unwrap();
+    })
+}
+
+#[test]
+fn read_file_from_image() {
+    let (port, manifest_digest) = schema1_image();
+    Runtime::new().unwrap().block_on(async {
+        let client = RegistryClient::builder().ephemeral_cache().build().unwrap();
+        let name = format!("localhost:{}/test/legacy@{}", port, manifest_digest);
+        let image = client.pull(&name.parse().unwrap()).await.unwrap();
+        let motd = Path::new("/etc/motd");
+        assert_eq!(image.read(motd).await.unwrap(), b"hello from schema1\n");
+        match image.read_with_limit(motd, 4).await {
+            Err(VFSError::FileTooLarge) => (),
+            other => panic!("unexpected result, {:?}", other),
+        }
+        match image.read(Path::new("/etc")).await {
+            Err(VFSError::FileExpected) => (),
+            other => panic!("unexpected result, {:?}", other),
+        }
+    })
+}
+
+#[test]
+fn export_image_filesystem_tar() {
+    let (port, manifest_digest) = schema1_image();
+    Runtime::new().unwrap().block_on(async {
+        let client = RegistryClient::builder().ephemeral_cache().build().unwrap();
+        let name = format!("localhost:{}/test/legacy@{}", port, manifest_digest);
+        let image = client.pull(&name.parse().unwrap()).await.unwrap();
+        let exported = image.export_tar(Vec::new()).unwrap();
+        let mut archive = tar::Archive::new(&exported[..]);
+        let mut motd = archive
+            .entries()
+            .unwrap()
+            .map(Result::unwrap)
+            .find(|entry| entry.path().unwrap() == Path::new("etc/motd"))
+            .unwrap();
+        let mut contents = Vec::new();
+        motd.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"hello from schema1\n");
+        assert_eq!(motd.header().mode().unwrap(), 0o644);
+    })
+}
+
+#[test]
+fn legacy_image_layers() {
+    let (port, manifest_digest) = schema1_image();
+    Runtime::new().unwrap().block_on(async {
+        let client = RegistryClient::builder().ephemeral_cache().build().unwrap();
+        let name = format!("localhost:{}/test/legacy@{}", port, manifest_digest);
+        let image = client.pull(&name.parse().unwrap()).await.unwrap();
+        let layers = image.layers();
+        assert_eq!(layers.len(), 1);
+        assert_eq!(
+            layers[0].digest,
+            ContentDigest::from_content(&layer_tar_gzip())
+        );
+        assert_ne!(layers[0].diff_id, layers[0].digest);
+        assert_eq!(layers[0].size, None);
+    })
+}
+
+#[test]
+fn config_digest_mismatch() {
+    let config = runtime_config(&layer_diff_id());
+    let wrong_digest = ContentDigest::from_content(b"some other config");
+    let (port, manifest_digest) = schema2_image(config, &wrong_digest);
+    Runtime::new().unwrap().block_on(async {
+        let client = RegistryClient::builder().ephemeral_cache().build().unwrap();
+        let name = format!("localhost:{}/test/modern@{}", port, manifest_digest);
+        match client.pull(&name.parse().unwrap()).await {
+            Err(ImageError::ContentDigestMismatch { expected, .. }) => {
+                assert_eq!(expected, wrong_digest)
+            }
+            other => panic!("unexpected result, {:?}", other),
+        }
+    })
+}
+
+fn manifest_list(entries: &[(&ContentDigest, &str)]) -> Vec<u8> {
+    let manifests: Vec<_> = entries
+        .iter()
+        .map(|(digest, architecture)| {
+            serde_json::json!({
+                "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+                "size": 1000,
+                "digest": digest.as_str(),
+                "platform": { "architecture": architecture, "os": "linux" },
+            })
+        })
+        .collect();
+    serde_json::to_vec(&serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.docker.distribution.manifest.list.v2+json",
+        "manifests": manifests,
+    }))
+    .unwrap()
+}
+
+#[test]
+fn pull_manifest_list() {
+    let config = runtime_config(&layer_diff_id());
+    let config_digest = ContentDigest::from_content(&config);
+    let (mut objects, manifest_digest) = schema2_objects(config, &config_digest, layer_tar_gzip());
+    let other_digest = ContentDigest::from_content(b"some other image");
+    let list = manifest_list(&[(&other_digest, "arm64"), (&manifest_digest, "amd64")]);
+    let list_digest = ContentDigest::from_content(&list);
+    objects.insert(
+        format!("/v2/test/modern/manifests/{}", list_digest),
+        (
+            "application/vnd.docker.distribution.manifest.list.v2+json",
+            list,
+        ),
+    );
+    let port = mock_registry(objects, None);
+    Runtime::new().unwrap().block_on(async {
+        let client = RegistryClient::builder().ephemeral_cache().build().unwrap();
+        // The list is named by its own digest, the image by the one we chose
+        let name = format!("localhost:{}/test/modern@{}", port, list_digest);
+        let image = client.pull(&name.parse().unwrap()).await.unwrap();
+        assert_eq!(image.content_digest(), manifest_digest);
+    })
+}
+
+#[test]
+fn pull_oci_image() {
+    let mut tar = Vec::new();
+    flate2::read::GzDecoder::new(&layer_tar_gzip()[..])
+        .read_to_end(&mut tar)
+        .unwrap();
+    let mut uncompressed_types = OCI_TYPES;
+    uncompressed_types[2] = "application/vnd.oci.image.layer.v1.tar";
+    for (types, layer) in [(OCI_TYPES, layer_tar_gzip()), (uncompressed_types, tar)] {
+        let config = runtime_config(&layer_diff_id());
+        let config_digest = ContentDigest::from_content(&config);
+        let (objects, manifest_digest) = image_objects(types, config, &config_digest, layer);
+        let port = mock_registry(objects, None);
+        Runtime::new().unwrap().block_on(async {
+            let client = RegistryClient::builder().ephemeral_cache().build().unwrap();
+            let name = format!("localhost:{}/test/modern@{}", port, manifest_digest);
+            let image = client.pull(&name.parse().unwrap()).await.unwrap();
+            assert_eq!(image.content_digest(), manifest_digest);
+            let motd = image.read(Path::new("/etc/motd")).await.unwrap();
+            assert_eq!(motd, b"hello from schema1\n");
+        })
+    }
+}
+
+#[test]
+fn pull_zstd_layer() {
+    let mut tar = Vec::new();
+    flate2::read::GzDecoder::new(&layer_tar_gzip()[..])
+        .read_to_end(&mut tar)
+        .unwrap();
+    let layer = zstd::stream::encode_all(&tar[..], 3).unwrap();
+    let mut types = OCI_TYPES;
+    types[2] = "application/vnd.oci.image.layer.v1.tar+zstd";
+    let config = runtime_config(&layer_diff_id());
+    let config_digest = ContentDigest::from_content(&config);
+    let (objects, manifest_digest) = image_objects(types, config, &config_digest, layer);
+    let port = mock_registry(objects, None);
+    Runtime::new().unwrap().block_on(async {
+        // Zstd layers are stored decompressed even without a decompressed cache
+        let client = RegistryClient::builder()
+            .ephemeral_cache()
+            .no_decompressed_cache()
+            .build()
+            .unwrap();
+        let name = format!("localhost:{}/test/modern@{}", port, manifest_digest);
+        let image = client.pull(&name.parse().unwrap()).await.unwrap();
+        let motd = image.read(Path::new("/etc/motd")).await.unwrap();
+        assert_eq!(motd, b"hello from schema1\n");
+    })
+}
+
+#[test]
+fn retry_transient_failures() {
+    let config = runtime_config(&layer_diff_id());
+    let config_digest = ContentDigest::from_content(&config);
+    let (objects, manifest_digest) = schema2_objects(config, &config_digest, layer_tar_gzip());
+    let (port, _) = flaky_registry(objects.clone(), None, 2);
+    Runtime::new().unwrap().block_on(async {
+        let client = RegistryClient::builder()
+            .ephemeral_cache()
+            .retry_backoff(Duration::from_millis(10))
+            .build()
+            .unwrap();
+        let name = format!("localhost:{}/test/modern@{}", port, manifest_digest);
+        let image = client.pull(&name.parse().unwrap()).await.unwrap();
+        let motd = image.read(Path::new("/etc/motd")).await.unwrap();
+        assert_eq!(motd, b"hello from schema1\n");
+    });
+
+    // Without enough retries, the last failure comes through
+    let (port, _) = flaky_registry(objects, None, 2);
+    Runtime::new().unwrap().block_on(async {
+        let client = RegistryClient::builder()
+            .ephemeral_cache()
+            .max_retries(1)
+            .retry_backoff(Duration::from_millis(10))
+            .build()
+            .unwrap();
+        let name = format!("localhost:{}/test/modern@{}", port, manifest_digest);
+        match client.pull(&name.parse().unwrap()).await {
+            Err(ImageError::NetworkRequest(_)) => (),
+            other => panic!("unexpected result, {:?}", other.map(|_| ())),
+        }
+    })
+}
+
+#[test]
+fn concurrent_pulls_share_downloads() {
+    let config = runtime_config(&layer_diff_id());
+    let config_digest = ContentDigest::from_content(&config);
+    let (objects, manifest_digest) = schema2_objects(config, &config_digest, layer_tar_gzip());
+    let num_objects = objects.len();
+    let (port, requests) = flaky_registry(objects, None, 0);
+    let cache_dir = tempfile::tempdir().unwrap();
+    let name = format!("localhost:{}/test/modern@{}", port, manifest_digest);
+    Runtime::new().unwrap().block_on(async {
+        // Separate clients, like separate processes sharing one cache
+        let pulls: Vec<_> = (0..2)
+            .map(|_| {
+                let client = RegistryClient::builder()
+                    .cache_dir(cache_dir.path())
+                    .max_download_rate(2000)
+                    .build()
+                    .unwrap();
+                let name = name.parse().unwrap();
+                tokio::spawn(async move { client.pull(&name).await })
+            })
+            .collect();
+        for pull in pulls {
+            let image = pull.await.unwrap().unwrap();
+            assert_eq!(image.content_digest(), manifest_digest);
+        }
+    });
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), num_objects);
+    assert!(requests.values().all(|count| *count == 1));
+}
+
+#[test]
+fn manifest_list_without_platform() {
+    let other_digest = ContentDigest::from_content(b"some other image");
+    let (port, list_digest) = manifest_only(
+        "application/vnd.docker.distribution.manifest.list.v2+json",
+        manifest_list(&[(&other_digest, "arm64")]),
+    );
+    Runtime::new().unwrap().block_on(async {
+        let client = RegistryClient::builder().ephemeral_cache().build().unwrap();
+        let name = format!("localhost:{}/test/other@{}", port, list_digest);
+        match client.pull(&name.parse().unwrap()).await {
+            Err(ImageError::NoMatchingPlatform) => (),
+            other => panic!("unexpected result, {:?}", other),
+        }
+    })
+}
+
+#[test]
+fn garbage_manifest_content_type() {
+    let (port, manifest_digest) = manifest_only(
+        "text/html; charset=utf-8",
+        b"<html><body>Please sign in to continue</body></html>".to_vec(),
+    );
+    Runtime::new().unwrap().block_on(async {
+        let client = RegistryClient::builder().ephemeral_cache().build().unwrap();
+        let name = format!("localhost:{}/test/other@{}", port, manifest_digest);
+        match client.pull(&name.parse().unwrap()).await {
+            Err(ImageError::JSON(_)) => (),
+            other => panic!("unexpected result, {:?}", other),
+        }
+    })
+}
+
+#[test]
+fn stored_blob_matches_registry_bytes() {
+    let config = runtime_config(&layer_diff_id());
+    let config_digest = ContentDigest::from_content(&config);
+    let (port, manifest_digest) = schema2_image(config.clone(), &config_digest);
+    let cache_dir = tempfile::tempdir().unwrap();
+    Runtime::new().unwrap().block_on(async {
+        let client = RegistryClient::builder()
+            .cache_dir(cache_dir.path())
+            .build()
+            .unwrap();
+        let name = format!("localhost:{}/test/modern@{}", port, manifest_digest);
+        client.pull(&name.parse().unwrap()).await.unwrap();
+    });
+
+    // The mock server would gzip its responses if we let it
+    let stored = std::fs::read_dir(cache_dir.path().join("blobs"))
+        .unwrap()
+        .map(|entry| std::fs::read(entry.unwrap().path()).unwrap())
+        .find(|stored| ContentDigest::from_content(stored) == config_digest);
+    assert_eq!(stored, Some(config));
+}
+
+#[test]
+fn throttled_pull() {
+    // Pad the config out to 60 KB, then pull at 40 KB/s
+    let mut config: serde_json::Value =
+        serde_json::from_slice(&runtime_config(&layer_diff_id())).unwrap();
+    config["comment"] = "x".repeat(60_000).into();
+    let config = serde_json::to_vec(&config).unwrap();
+    let config_digest = ContentDigest::from_content(&config);
+    let (port, manifest_digest) = schema2_image(config, &config_digest);
+    Runtime::new().unwrap().block_on(async {
+        let client = RegistryClient::builder()
+            .ephemeral_cache()
+            .max_download_rate(40_000)
+            .build()
+            .unwrap();
+        let name = format!("localhost:{}/test/modern@{}", port, manifest_digest);
+        let started = Instant::now();
+        client.pull(&name.parse().unwrap()).await.unwrap();
+        // Everything past the initial quarter second burst is rate limited
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(1250), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(10), "{:?}", elapsed);
+    })
+}
+
+#[test]
+fn dropped_pull_is_cancelled() {
+    // Slow enough that each chunk of the config stalls the download for
+    // several seconds
+    let mut config: serde_json::Value =
+        serde_json::from_slice(&runtime_config(&layer_diff_id())).unwrap();
+    config["comment"] = "x".repeat(100_000).into();
+    let config = serde_json::to_vec(&config).unwrap();
+    let config_digest = ContentDigest::from_content(&config);
+    let (port, manifest_digest) = schema2_image(config, &config_digest);
+    let cache = tempfile::TempDir::new().unwrap();
+    let temp_files = || match std::fs::read_dir(cache.path().join("tmp")) {
+        Ok(dir) => dir.count(),
+        Err(_) => 0,
+    };
+
+    Runtime::new().unwrap().block_on(async {
+        let client = RegistryClient::builder()
+            .cache_dir(cache.path())
+            .max_download_rate(500)
+            .build()
+            .unwrap();
+        let name = format!("localhost:{}/test/modern@{}", port, manifest_digest);
+        let mut pull = client.pull_progress(&name.parse().unwrap());
+        let config_resource = ProgressResource::Blob(config_digest.clone());
+        loop {
+            match pull.progress().await {
+                PullProgress::Update(update) if *update.resource == config_resource => break,
+                PullProgress::Update(_) => (),
+                PullProgress::Done(result) => panic!("pull finished early, {:?}", result),
+            }
+        }
+        let started = Instant::now();
+        while temp_files() == 0 {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+
+        // The download is stuck waiting on the rate limit, not on progress
+        // updates, so only cancellation can stop it this soon
+        let started = Instant::now();
+        drop(pull);
+        while temp_files() > 0 {
+            assert!(started.elapsed() < Duration::from_secs(1));
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        let stored = std::fs::read_dir(cache.path().join("blobs"))
+            .into_iter()
+            .flatten()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_string_lossy().contains(config_digest.hex_str())
+            })
+            .count();
+        assert_eq!(stored, 0);
+    })
+}
+
+/// A layer with two files, compressed as two concatenated gzip members that
+/// split the tar between them, returned with the digest of the whole tar
+fn multi_member_layer() -> (Vec<u8>, ContentDigest) {
+    let mut tar = tar::Builder::new(Vec::new());
+    for (path, content) in &[
+        ("etc/motd", "first member\n"),
+        ("etc/issue", "second member\n"),
+    ] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        header.set_device_major(0).unwrap();
+        header.set_device_minor(0).unwrap();
+        header.set_cksum();
+        tar.append_data(&mut header, path, content.as_bytes())
+            .unwrap();
+    }
+    let tar = tar.into_inner().unwrap();
+    let mut layer = Vec::new();
+    // Each file above takes one header block and one data block
+    for member in &[&tar[..1024], &tar[1024..]] {
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(member).unwrap();
+        layer.extend(gz.finish().unwrap());
+    }
+    (layer, ContentDigest::from_content(&tar))
+}
+
+#[test]
+fn pull_multi_member_gzip_layer() {
+    let (layer, diff_id) = multi_member_layer();
+    let config = runtime_config(&diff_id);
+    let config_digest = ContentDigest::from_content(&config);
+    let (port, manifest_digest) = schema2_image_with_layer(config, &config_digest, layer);
+    let name = format!("localhost:{}/test/modern@{}", port, manifest_digest);
+    Runtime::new().unwrap().block_on(async {
+        for decompressed_cache in &[true, false] {
+            let mut builder = RegistryClient::builder().ephemeral_cache();
+            if !decompressed_cache {
+                builder = builder.no_decompressed_cache();
+            }
+            let client = builder.build().unwrap();
+            let image = client.pull(&name.parse().unwrap()).await.unwrap();
+            assert_eq!(image.layers()[0].diff_id, diff_id);
+            assert_eq!(
+                image.read(Path::new("/etc/motd")).await.unwrap(),
+                b"first member\n"
+            );
+            assert_eq!(
+                image.read(Path::new("/etc/issue")).await.unwrap(),
+                b"second member\n"
+            );
+        }
+    })
+}
+
+#[test]
+fn inspect_without_layers() {
+    let diff_id = layer_diff_id();
+    let config = runtime_config_with(
+        &diff_id,
+        serde_json::json!({
+            "Entrypoint": ["/bin/server", "--port=80"],
+            "Cmd": ["--verbose"],
+        }),
+    );
+    let config_digest = ContentDigest::from_content(&config);
+    let layer = layer_tar_gzip();
+    let layer_digest = ContentDigest::from_content(&layer);
+    let layer_size = layer.len() as u64;
+    let (mut objects, manifest_digest) = schema2_objects(config, &config_digest, layer);
+
+    // The layer isn't served at all, so pulling it would fail
+    objects.remove(&format!("/v2/test/modern/blobs/{}", layer_digest));
+    let port = mock_registry(objects, None);
+
+    Runtime::new().unwrap().block_on(async {
+        let client = RegistryClient::builder().ephemeral_cache().build().unwrap();
+        let name = format!("localhost:{}/test/modern@{}", port, manifest_digest);
+        let inspect = client.inspect(&name.parse().unwrap()).await.unwrap();
+        assert_eq!(inspect.content_digest(), manifest_digest);
+        assert_eq!(
+            inspect.entrypoint(),
+            Some(&["/bin/server".to_owned(), "--port=80".to_owned()][..])
+        );
+        assert_eq!(inspect.cmd(), ["--verbose"]);
+        assert_eq!(inspect.layers().len(), 1);
+        assert_eq!(inspect.layers()[0].digest, layer_digest);
+        assert_eq!(inspect.layers()[0].diff_id, diff_id);
+        assert_eq!(inspect.layers()[0].size, Some(layer_size));
+        assert!(client.pull(&name.parse().unwrap()).await.is_err());
+    })
+}
+
+#[test]
+fn inspect_legacy_manifest() {
+    let (port, manifest_digest) = schema1_image();
+    Runtime::new().unwrap().block_on(async {
+        let client = RegistryClient::builder().ephemeral_cache().build().unwrap();
+        let name = format!("localhost:{}/test/legacy@{}", port, manifest_digest);
+        match client.inspect(&name.parse().unwrap()).await {
+            Err(ImageError::UnsupportedManifestType) => (),
+            other => panic!("unexpected result, {:?}", other),
+        }
+    })
+}
+
+#[test]
+fn pull_without_decompressed_cache() {
+    let config = runtime_config(&layer_diff_id());
+    let config_digest = ContentDigest::from_content(&config);
+    let (port, manifest_digest) = schema2_image(config, &config_digest);
+    let cache_dir = tempfile::tempdir().unwrap();
+    let name = format!("localhost:{}/test/modern@{}", port, manifest_digest);
+    for _ in 0..2 {
+        Runtime::new().unwrap().block_on(async {
+            let client = RegistryClient::builder()
+                .cache_dir(cache_dir.path())
+                .no_decompressed_cache()
+                .build()
+                .unwrap();
+            let image = client.pull(&name.parse().unwrap()).await.unwrap();
+            assert_eq!(
+                image.read(Path::new("/etc/motd")).await.unwrap(),
+                b"hello from schema1\n"
+            );
+        });
+    }
+
+    let stored: Vec<ContentDigest> = std::fs::read_dir(cache_dir.path().join("blobs"))
+        .unwrap()
+        .map(|entry| ContentDigest::from_content(&std::fs::read(entry.unwrap().path()).unwrap()))
+        .collect();
+    assert!(stored.contains(&ContentDigest::from_content(&layer_tar_gzip())));
+    assert!(!stored.contains(&layer_diff_id()));
+}
+
+/// A layer with a few larger files that don't compress well, and its diff_id
+fn large_layer() -> (Vec<u8>, ContentDigest) {
+    let mut tar = tar::Builder::new(Vec::new());
+    let mut state: u32 = 1;
+    for index in 0..4 {
+        let content: Vec<u8> = (0..1 << 20)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect();
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        header.set_device_major(0).unwrap();
+        header.set_device_minor(0).unwrap();
+        header.set_cksum();
+        tar.append_data(&mut header, format!("data/{}", index), &content[..])
+            .unwrap();
+    }
+    let tar = tar.into_inner().unwrap();
+    let mut gz = GzEncoder::new(Vec::new(), Compression::fast());
+    gz.write_all(&tar).unwrap();
+    (gz.finish().unwrap(), ContentDigest::from_content(&tar))
+}
+
+/// Disk space used by every file under a directory
+fn dir_size(path: &Path) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::read_dir(path)
+        .unwrap()
+        .map(|entry| {
+            let entry = entry.unwrap();
+            if entry.file_type().unwrap().is_dir() {
+                dir_size(&entry.path())
+            } else {
+                entry.metadata().unwrap().blocks() * 512
+            }
+        })
+        .sum()
+}
+
+#[test]
+fn streaming_extract_skips_decompressed_blob() {
+    let (layer, diff_id) = large_layer();
+    let config = runtime_config(&diff_id);
+    let config_digest = ContentDigest::from_content(&config);
+    let (port, manifest_digest) = schema2_image_with_layer(config, &config_digest, layer);
+    let name = format!("localhost:{}/test/modern@{}", port, manifest_digest);
+
+    let mut cache_sizes = Vec::new();
+    for streaming in &[true, false] {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let started = Instant::now();
+        Runtime::new().unwrap().block_on(async {
+            let mut builder = RegistryClient::builder().cache_dir(cache_dir.path());
+            if !streaming {
+                builder = builder.no_streaming_extract();
+            }
+            let image = builder.build().unwrap().pull(&name.parse().unwrap()).await;
+            let image = image.unwrap();
+            for index in 0..4 {
+                let path = format!("/data/{}", index);
+                image.read(Path::new(&path)).await.unwrap();
+            }
+        });
+        let size = dir_size(cache_dir.path());
+        println!(
+            "streaming extract {}: {:?}, {} bytes cached",
+            streaming,
+            started.elapsed(),
+            size
+        );
+        cache_sizes.push(size);
+
+        let stored: Vec<ContentDigest> = std::fs::read_dir(cache_dir.path().join("blobs"))
+            .unwrap()
+            .map(|entry| {
+                ContentDigest::from_content(&std::fs::read(entry.unwrap().path()).unwrap())
+            })
+            .collect();
+        assert_eq!(stored.contains(&diff_id), !streaming);
+        let parts = std::fs::read_dir(cache_dir.path().join("parts"))
+            .unwrap()
+            .flat_map(|dir| std::fs::read_dir(dir.unwrap().path()).unwrap())
+            .count();
+        assert_eq!(parts, 4);
+
+        // Loading again only needs what's in the cache
+        Runtime::new().unwrap().block_on(async {
+            let client = RegistryClient::builder()
+                .cache_dir(cache_dir.path())
+                .offline()
+                .build()
+                .unwrap();
+            let image = client.pull(&name.parse().unwrap()).await.unwrap();
+            assert_eq!(
+                image.read(Path::new("/data/0")).await.unwrap().len(),
+                1 << 20
+            );
+        });
+    }
+    // Both end up with a copy of every file, but without streaming there's
+    // also the whole decompressed layer
+    assert!(cache_sizes[0] + (3 << 20) < cache_sizes[1]);
+}
+
+#[test]
+fn cached_config_digest_mismatch() {
+    let config = runtime_config(&layer_diff_id());
+    let config_digest = ContentDigest::from_content(&config);
+    let (port, manifest_digest) = schema2_image(config.clone(), &config_digest);
+    let cache_dir = tempfile::tempdir().unwrap();
+    let name = format!("localhost:{}/test/modern@{}", port, manifest_digest);
+    Runtime::new().unwrap().block_on(async {
+        let client = RegistryClient::builder()
+            .cache_dir(cache_dir.path())
+            .build()
+            .unwrap();
+        let image = client.pull(&name.parse().unwrap()).await.unwrap();
+        assert_eq!(image.content_digest(), manifest_digest);
+    });
+
+    // Tamper with the cached config without changing its size
+    let blobs = cache_dir.path().join("blobs");
+    let cached = std::fs::read_dir(&blobs)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| std::fs::read(path).unwrap() == config)
+        .unwrap();
+    let tampered = String::from_utf8(config).unwrap().replace("amd64", "arm64");
+    std::fs::set_permissions(&cached, std::fs::Permissions::from_mode(0o644)).unwrap();
+    std::fs::write(&cached, tampered).unwrap();
+
+    Runtime::new().unwrap().block_on(async {
+        let client = RegistryClient::builder()
+            .cache_dir(cache_dir.path())
+            .build()
+            .unwrap();
+        match client.pull(&name.parse().unwrap()).await {
+            Err(ImageError::ContentDigestMismatch { expected, .. }) => {
+                assert_eq!(expected, config_digest)
+            }
+            other => panic!("unexpected result, {:?}", other),
+        }
+    })
+}
+
+#[test]
+fn require_digest_rejects_tag() {
+    let (port, _) = schema1_image();
+    Runtime::new().unwrap().block_on(async {
+        let client = RegistryClient::builder()
+            .ephemeral_cache()
+            .require_digest()
+            .build()
+            .unwrap();
+        let name = format!("localhost:{}/test/legacy:latest", port);
+        match client.pull(&name.parse().unwrap()).await {
+            Err(ImageError::DigestRequired(_)) => (),
+            other => panic!("unexpected result, {:?}", other),
+        }
+    })
+}
+
+#[test]
+fn require_digest_allows_digest() {
+    let (port, manifest_digest) = schema1_image();
+    Runtime::new().unwrap().block_on(async {
+        let client = RegistryClient::builder()
+            .ephemeral_cache()
+            .require_digest()
+            .build()
+            .unwrap();
+        let name = format!("localhost:{}/test/legacy@{}", port, manifest_digest);
+        let image = client.pull(&name.parse().unwrap()).await.unwrap();
+        assert_eq!(image.content_digest(), manifest_digest);
+    })
+}
+
+#[test]
+fn no_default_registry_rejects_short_name() {
+    Runtime::new().unwrap().block_on(async {
+        let client = RegistryClient::builder()
+            .offline()
+            .ephemeral_cache()
+            .no_default_registry()
+            .build()
+            .unwrap();
+        match client.pull(&"test/legacy:latest".parse().unwrap()).await {
+            Err(ImageError::RegistryRequired(_)) => (),
+            other => panic!("unexpected result, {:?}", other),
+        }
+    })
+}
+
+#[test]
+fn no_default_registry_allows_qualified_name() {
+    let (port, manifest_digest) = schema1_image();
+    Runtime::new().unwrap().block_on(async {
+        let client = RegistryClient::builder()
+            .ephemeral_cache()
+            .no_default_registry()
+            .build()
+            .unwrap();
+        let name = format!("localhost:{}/test/legacy@{}", port, manifest_digest);
+        let image = client.pull(&name.parse().unwrap()).await.unwrap();
+        assert_eq!(image.content_digest(), manifest_digest);
+    })
+}
+
+#[test]
+fn image_shell_and_stop_signal() {
+    let config = runtime_config_with(
+        &layer_diff_id(),
+        serde_json::json!({
+            "Shell": ["/bin/ash", "-eo", "pipefail", "-c"],
+            "StopSignal": "SIGQUIT",
+        }),
+    );
+    let config_digest = ContentDigest::from_content(&config);
+    let (port, manifest_digest) = schema2_image(config, &config_digest);
+    Runtime::new().unwrap().block_on(async {
+        let client = RegistryClient::builder().ephemeral_cache().build().unwrap();
+        let name = format!("localhost:{}/test/modern@{}", port, manifest_digest);
+        let image = client.pull(&name.parse().unwrap()).await.unwrap();
+        assert_eq!(image.stop_signal(), Some(libc::SIGQUIT));
+        assert_eq!(
+            image.shell().unwrap(),
+            &["/bin/ash", "-eo", "pipefail", "-c"]
+        );
+    })
+}
+
+#[test]
+fn image_without_stop_signal() {
+    let config = runtime_config(&layer_diff_id());
+    let config_digest = ContentDigest::from_content(&config);
+    let (port, manifest_digest) = schema2_image(config, &config_digest);
+    Runtime::new().unwrap().block_on(async {
+        let client = RegistryClient::builder().ephemeral_cache().build().unwrap();
+        let name = format!("localhost:{}/test/modern@{}", port, manifest_digest);
+        let image = client.pull(&name.parse().unwrap()).await.unwrap();
+        assert_eq!(image.stop_signal(), None);
+        assert_eq!(image.shell(), None);
+    })
+}
+
+#[test]
+fn validate_entrypoint_rejects_missing_file() {
+    let config = runtime_config_with(
+        &layer_diff_id(),
+        serde_json::json!({
+            "Entrypoint": ["/usr/local/bin/not-here"],
+            "Cmd": ["--help"],
+        }),
+    );
+    let config_digest = ContentDigest::from_content(&config);
+    let (port, manifest_digest) = schema2_image(config, &config_digest);
+    Runtime::new().unwrap().block_on(async {
+        let name = format!("localhost:{}/test/modern@{}", port, manifest_digest);
+        let name = name.parse().unwrap();
+
+        let client = RegistryClient::builder().ephemeral_cache().build().unwrap();
+        assert!(client.pull(&name).await.is_ok());
+
+        let client = RegistryClient::builder()
+            .ephemeral_cache()
+            .validate_entrypoint()
+            .build()
+            .unwrap();
+        match client.pull(&name).await {
+            Err(ImageError::EntrypointMissing(path)) => {
+                assert_eq!(path, "/usr/local/bin/not-here")
+            }
+            other => panic!("unexpected result, {:?}", other.map(|_| ())),
+        }
+    })
+}
+
+#[test]
+fn custom_default_registry_library_prefix() {
+    let (port, manifest_digest) = schema1_image();
+    Runtime::new().unwrap().block_on(async {
+        let client = RegistryClient::builder()
+            .ephemeral_cache()
+            .default_registry(DefaultRegistry {
+                network_name: format!("localhost:{}", port).parse().unwrap(),
+                also_known_as: vec!["mirror.example".parse().unwrap()],
+                library_prefix: Some("test".parse().unwrap()),
+            })
+            .build()
+            .unwrap();
+
+        // a single-component name picks up the library prefix, and is
+        // fetched from /v2/test/legacy on the mock server
+        let name = format!("legacy@{}", manifest_digest);
+        let image = client.pull(&name.parse().unwrap()).await.unwrap();
+        assert_eq!(image.content_digest(), manifest_digest);
+
+        // the other name for this registry resolves the same way
+        let name = format!("mirror.example/legacy@{}", manifest_digest);
+        let image = client.pull(&name.parse().unwrap()).await.unwrap();
+        assert_eq!(image.content_digest(), manifest_digest);
+    })
+}
+
+#[test]
+fn docker_config_static_login() {
+    // "dXNlcjpzZWNyZXQ=" is base64 for "user:secret"
+    let (port, manifest_digest) =
+        schema1_image_with_auth(Some(MockAuth::basic("dXNlcjpzZWNyZXQ=")));
+    let config_dir = tempfile::tempdir().unwrap();
+    let config_path = config_dir.path().join("config.json");
+    std::fs::write(
+        &config_path,
+        serde_json::to_vec(&serde_json::json!({
+            "auths": {
+                format!("localhost:{}", port): { "auth": "dXNlcjpzZWNyZXQ=" },
+            },
+        }))
+        .unwrap(),
+    )
+    .unwrap();
+    let name = format!("localhost:{}/test/legacy@{}", port, manifest_digest);
+    Runtime::new().unwrap().block_on(async {
+        let client = RegistryClient::builder().ephemeral_cache().build().unwrap();
+        assert!(client.pull(&name.parse().unwrap()).await.is_err());
+
+        let client = RegistryClient::builder()
+            .ephemeral_cache()
+            .docker_config(Some(&config_path))
+            .build()
+            .unwrap();
+        let image = client.pull(&name.parse().unwrap()).await.unwrap();
+        assert_eq!(image.content_digest(), manifest_digest);
+    })
+}
+
+#[test]
+fn docker_config_missing_file() {
+    let config_dir = tempfile::tempdir().unwrap();
+    let config_path = config_dir.path().join("config.json");
+    assert!(RegistryClient::builder()
+        .ephemeral_cache()
+        .docker_config(Some(&config_path))
+        .build()
+        .is_err());
+}
+
+#[test]
+fn docker_config_default_path() {
+    // "dXNlcjpzZWNyZXQ=" is base64 for "user:secret"
+    let (port, manifest_digest) =
+        schema1_image_with_auth(Some(MockAuth::basic("dXNlcjpzZWNyZXQ=")));
+    let config_dir = tempfile::tempdir().unwrap();
+    // No other test here reads the default config, so they can't see this
+    std::env::set_var("DOCKER_CONFIG", config_dir.path());
+    let name = format!("localhost:{}/test/legacy@{}", port, manifest_digest);
+    Runtime::new().unwrap().block_on(async {
+        // Without a file there's nothing to load, and no error either
+        let client = RegistryClient::builder()
+            .ephemeral_cache()
+            .docker_config(None)
+            .build()
+            .unwrap();
+        assert!(client.pull(&name.parse().unwrap()).await.is_err());
+
+        std::fs::write(
+            config_dir.path().join("config.json"),
+            serde_json::to_vec(&serde_json::json!({
+                "auths": {
+                    format!("localhost:{}", port): { "auth": "dXNlcjpzZWNyZXQ=" },
+                },
+            }))
+            .unwrap(),
+        )
+        .unwrap();
+        let client = RegistryClient::builder()
+            .ephemeral_cache()
+            .docker_config(None)
+            .build()
+            .unwrap();
+        let image = client.pull(&name.parse().unwrap()).await.unwrap();
+        assert_eq!(image.content_digest(), manifest_digest);
+    })
+}
+
+/// Every file in a directory tree
+fn files_under(path: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(path).unwrap() {
+        let entry = entry.unwrap();
+        if entry.file_type().unwrap().is_dir() {
+            files.extend(files_under(&entry.path()));
+        } else {
+            files.push(entry.path());
+        }
+    }
+    files
+}
+
+/// Self-signed certificate for `127.0.0.1`, and its private key
+fn localhost_certificate() -> (X509, PKey<Private>) {
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", "127.0.0.1").unwrap();
+    let name = name.build();
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    let serial = BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap();
+    builder.set_serial_number(&serial).unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(&name).unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder
+        .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    builder
+        .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    let basic_constraints = BasicConstraints::new().critical().ca().build().unwrap();
+    builder.append_extension(basic_constraints).unwrap();
+    let alt_name = SubjectAlternativeName::new()
+        .ip("127.0.0.1")
+        .build(&builder.x509v3_context(None, None))
+        .unwrap();
+    builder.append_extension(alt_name).unwrap();
+    builder.sign(&key, MessageDigest::sha256()).unwrap();
+    (builder.build(), key)
+}
+
+/// HTTPS token server, one request per connection, returning its port, the
+/// certificate to trust, and the `Authorization` header of each request
+fn mock_token_server() -> (u16, reqwest::Certificate, Arc<Mutex<Vec<Option<String>>>>) {
+    let (certificate, key) = localhost_certificate();
+    let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+    acceptor.set_private_key(&key).unwrap();
+    acceptor.set_certificate(&certificate).unwrap();
+    let acceptor = acceptor.build();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let thread_requests = requests.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match acceptor.accept(stream.unwrap()) {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let mut request_auth = None;
+            let mut reader = BufReader::new(&mut stream);
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                let mut header = line.splitn(2, ':');
+                if let (Some(name), Some(value)) = (header.next(), header.next()) {
+                    if name.eq_ignore_ascii_case("authorization") {
+                        request_auth = Some(value.trim().to_string());
+                    }
+                }
+            }
+            thread_requests.lock().unwrap().push(request_auth);
+            let body = br#"{"token":"mock-token","expires_in":300}"#;
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            )
+            .unwrap();
+            stream.write_all(body).unwrap();
+            let _ = stream.shutdown();
+        }
+    });
+    let certificate = reqwest::Certificate::from_pem(&certificate.to_pem().unwrap()).unwrap();
+    (port, certificate, requests)
+}
+
+#[test]
+fn cached_token_skips_token_server() {
+    let (token_port, certificate, token_requests) = mock_token_server();
+    // Three images in one repository, differing only in their command
+    let mut objects = HashMap::new();
+    let digests: Vec<ContentDigest> = ["first", "second", "third"]
+        .iter()
+        .map(|cmd| {
+            let config = runtime_config_with(&layer_diff_id(), serde_json::json!({ "Cmd": [cmd] }));
+            let config_digest = ContentDigest::from_content(&config);
+            let (image_objects, digest) = schema2_objects(config, &config_digest, layer_tar_gzip());
+            objects.extend(image_objects);
+            digest
+        })
+        .collect();
+    let port = mock_registry(
+        objects,
+        Some(MockAuth {
+            authorization: "Bearer mock-token".to_string(),
+            challenge: format!(
+                "Bearer realm=\"https://127.0.0.1:{}/token\",service=\"mock\",scope=\"repository:test/modern:pull\"",
+                token_port
+            ),
+        }),
+    );
+    let registry: bandsocks::Registry = format!("localhost:{}", port).parse().unwrap();
+    let name = |index: usize| format!("{}/test/modern@{}", registry, digests[index]);
+    let cache_dir = tempfile::tempdir().unwrap();
+    let client = || {
+        RegistryClient::builder()
+            .cache_dir(cache_dir.path())
+            .add_root_certificate(certificate.clone())
+    };
+
+    Runtime::new().unwrap().block_on(async {
+        let image = client()
+            .build()
+            .unwrap()
+            .pull(&name(0).parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(image.content_digest(), digests[0]);
+        assert_eq!(*token_requests.lock().unwrap(), [None]);
+
+        // Tokens are saved privately, in case the cache dir is shared
+        let token_files = files_under(&cache_dir.path().join("token"));
+        assert_eq!(token_files.len(), 1);
+        let mode = std::fs::metadata(&token_files[0])
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // A new client pulling something else from the same repository
+        // reuses the token rather than asking for another one
+        let image = client()
+            .build()
+            .unwrap()
+            .pull(&name(1).parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(image.content_digest(), digests[1]);
+        assert_eq!(token_requests.lock().unwrap().len(), 1);
+
+        // A client with a login is someone else, and needs its own token
+        let image = client()
+            .login(
+                registry.clone(),
+                "user".to_string(),
+                Some("secret".to_string()),
+            )
+            .build()
+            .unwrap()
+            .pull(&name(2).parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(image.content_digest(), digests[2]);
+        assert_eq!(
+            *token_requests.lock().unwrap(),
+            [None, Some("Basic dXNlcjpzZWNyZXQ=".to_string())]
+        );
+    })
+}
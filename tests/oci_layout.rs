@@ -0,0 +1,229 @@
+use bandsocks::{Container, ContentDigest, ImageError, RegistryClient};
+use flate2::{write::GzEncoder, Compression};
+use std::{fs, io::Write, path::Path};
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+
+const BUSYBOX_IMAGE: &str =
+    "busybox@sha256:e06f93f59fe842fb490ba992bae19fdd5a05373547b52f8184650c2509908114";
+
+/// Build a layer holding regular files, given as (path, mode, content)
+fn layer_tar(files: &[(&str, u32, &[u8])]) -> Vec<u8> {
+    let mut tar = tar::Builder::new(Vec::new());
+    for (path, mode, content) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(*mode);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        header.set_device_major(0).unwrap();
+        header.set_device_minor(0).unwrap();
+        header.set_cksum();
+        tar.append_data(&mut header, path, *content).unwrap();
+    }
+    tar.into_inner().unwrap()
+}
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+    gz.write_all(data).unwrap();
+    gz.finish().unwrap()
+}
+
+/// Write a blob into the layout, returning its descriptor
+fn write_blob(dir: &Path, media_type: &str, data: &[u8]) -> serde_json::Value {
+    let digest = ContentDigest::from_content(data);
+    let blobs = dir.join("blobs").join(digest.format_str());
+    fs::create_dir_all(&blobs).unwrap();
+    fs::write(blobs.join(digest.hex_str()), data).unwrap();
+    serde_json::json!({
+        "mediaType": media_type,
+        "size": data.len(),
+        "digest": digest.as_str(),
+    })
+}
+
+/// Write one single-layer image with an `/etc/motd` into the layout,
+/// returning its manifest descriptor
+fn write_image(dir: &Path, motd: &[u8], compressed: bool) -> serde_json::Value {
+    write_image_files(dir, &[("etc/motd", 0o644, motd)], compressed)
+}
+
+/// Write one single-layer image holding the given files into the layout,
+/// returning its manifest descriptor
+fn write_image_files(
+    dir: &Path,
+    files: &[(&str, u32, &[u8])],
+    compressed: bool,
+) -> serde_json::Value {
+    let tar = layer_tar(files);
+    let diff_id = ContentDigest::from_content(&tar);
+    let layer = if compressed {
+        write_blob(
+            dir,
+            "application/vnd.oci.image.layer.v1.tar+gzip",
+            &gzip(&tar),
+        )
+    } else {
+        write_blob(dir, "application/vnd.oci.image.layer.v1.tar", &tar)
+    };
+    let config = serde_json::to_vec(&serde_json::json!({
+        "architecture": "amd64",
+        "os": "linux",
+        "config": {
+            "Env": ["PATH=/bin"],
+            "Cmd": ["cat", "/etc/motd"],
+        },
+        "rootfs": { "type": "layers", "diff_ids": [diff_id.as_str()] },
+    }))
+    .unwrap();
+    let config = write_blob(dir, "application/vnd.oci.image.config.v1+json", &config);
+    let manifest = serde_json::to_vec(&serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.manifest.v1+json",
+        "config": config,
+        "layers": [layer],
+    }))
+    .unwrap();
+    write_blob(dir, "application/vnd.oci.image.manifest.v1+json", &manifest)
+}
+
+fn write_layout(dir: &Path, manifests: Vec<(&str, serde_json::Value)>) {
+    let manifests: Vec<serde_json::Value> = manifests
+        .into_iter()
+        .map(|(tag, mut desc)| {
+            desc["annotations"] = serde_json::json!({ "org.opencontainers.image.ref.name": tag });
+            desc
+        })
+        .collect();
+    fs::write(dir.join("oci-layout"), br#"{"imageLayoutVersion":"1.0.0"}"#).unwrap();
+    fs::write(
+        dir.join("index.json"),
+        serde_json::to_vec(&serde_json::json!({
+            "schemaVersion": 2,
+            "manifests": manifests,
+        }))
+        .unwrap(),
+    )
+    .unwrap();
+}
+
+#[test]
+fn load_tagged_images_from_layout() {
+    let layout = TempDir::new().unwrap();
+    let v1 = write_image(layout.path(), b"version one\n", true);
+    let v2 = write_image(layout.path(), b"version two\n", false);
+    let v2_digest = v2["digest"].as_str().unwrap().to_owned();
+    write_layout(layout.path(), vec![("v1", v1), ("v2", v2)]);
+
+    Runtime::new().unwrap().block_on(async {
+        let client = RegistryClient::builder().ephemeral_cache().build().unwrap();
+        let motd = Path::new("/etc/motd");
+
+        let image = client
+            .from_oci_layout(layout.path(), Some("v1"))
+            .await
+            .unwrap();
+        assert_eq!(image.read(motd).await.unwrap(), b"version one\n");
+        assert_eq!(image.name().tag_str(), Some("v1"));
+
+        let image = client
+            .from_oci_layout(layout.path(), Some("v2"))
+            .await
+            .unwrap();
+        assert_eq!(image.read(motd).await.unwrap(), b"version two\n");
+        assert_eq!(image.content_digest().as_str(), v2_digest);
+        Container::new(image).unwrap();
+
+        match client.from_oci_layout(layout.path(), None).await {
+            Err(ImageError::OciLayoutImageNotFound(None)) => (),
+            other => panic!("unexpected result, {:?}", other.map(|_| ())),
+        }
+        match client.from_oci_layout(layout.path(), Some("v3")).await {
+            Err(ImageError::OciLayoutImageNotFound(Some(tag))) => assert_eq!(tag, "v3"),
+            other => panic!("unexpected result, {:?}", other.map(|_| ())),
+        }
+    })
+}
+
+#[test]
+fn load_single_image_from_layout() {
+    let layout = TempDir::new().unwrap();
+    let image = write_image(layout.path(), b"only one\n", true);
+    write_layout(layout.path(), vec![("latest", image)]);
+
+    Runtime::new().unwrap().block_on(async {
+        let client = RegistryClient::builder().ephemeral_cache().build().unwrap();
+        let image = client.from_oci_layout(layout.path(), None).await.unwrap();
+        assert_eq!(
+            image.read(Path::new("/etc/motd")).await.unwrap(),
+            b"only one\n"
+        );
+        assert_eq!(image.layers().len(), 1);
+    })
+}
+
+#[test]
+fn corrupt_layout_blob() {
+    let layout = TempDir::new().unwrap();
+    let image = write_image(layout.path(), b"soon corrupt\n", true);
+    let digest = ContentDigest::parse(image["digest"].as_str().unwrap()).unwrap();
+    write_layout(layout.path(), vec![("latest", image)]);
+    let path = layout
+        .path()
+        .join("blobs")
+        .join(digest.format_str())
+        .join(digest.hex_str());
+    let mut manifest = fs::read(&path).unwrap();
+    manifest[0] = b' ';
+    fs::write(&path, manifest).unwrap();
+
+    Runtime::new().unwrap().block_on(async {
+        let client = RegistryClient::builder().ephemeral_cache().build().unwrap();
+        match client.from_oci_layout(layout.path(), None).await {
+            Err(ImageError::ContentDigestMismatch { expected, .. }) => {
+                assert_eq!(expected, digest)
+            }
+            other => panic!("unexpected result, {:?}", other.map(|_| ())),
+        }
+    })
+}
+
+#[test]
+fn run_container_from_layout() {
+    file_limit::set_to_max().unwrap();
+    let _ = env_logger::builder().is_test(true).try_init();
+    Runtime::new().unwrap().block_on(async {
+        // Borrow a static busybox binary, so the layout has something to run
+        let busybox = RegistryClient::new()
+            .unwrap()
+            .pull(&BUSYBOX_IMAGE.parse().unwrap())
+            .await
+            .expect("image pull")
+            .read(Path::new("/bin/busybox"))
+            .await
+            .unwrap();
+
+        let layout = TempDir::new().unwrap();
+        let image = write_image_files(
+            layout.path(),
+            &[
+                ("bin/busybox", 0o755, &busybox),
+                ("etc/motd", 0o644, b"hello from a layout\n"),
+            ],
+            true,
+        );
+        write_layout(layout.path(), vec![("latest", image)]);
+
+        let output = Container::from_oci_layout(layout.path(), Some("latest"))
+            .await
+            .unwrap()
+            .args(&["/bin/busybox", "cat", "/etc/motd"])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "hello from a layout\n");
+    })
+}
@@ -1,4 +1,9 @@
-use bandsocks::{Container, ContainerBuilder};
+use bandsocks::{Container, ContainerBuilder, LogMessage, SharedStream};
+use std::{
+    io::{Read, Write},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 use tokio::runtime::Runtime;
 
 const IMAGE: &str =
@@ -64,3 +69,859 @@ print(os.listdir('/'))
         ));
     })
 }
+
+#[test]
+fn python_madvise() {
+    Runtime::new().unwrap().block_on(async {
+        let container = common()
+            .await
+            .arg("python")
+            .arg("-c")
+            .arg(
+                r"
+import mmap
+m = mmap.mmap(-1, 4 * mmap.PAGESIZE, flags=mmap.MAP_PRIVATE)
+m.write(b'hello')
+m.madvise(20)  # MADV_COLD
+m.madvise(18)  # MADV_WIPEONFORK
+m.madvise(4)   # MADV_DONTNEED
+print(m[:5])
+try:
+    m.madvise(999)
+except OSError as e:
+    print(e.errno)
+",
+            )
+            .spawn()
+            .unwrap();
+        let output = container.output().await.unwrap();
+        assert!(output.status.success());
+        assert!(output.stderr.is_empty());
+        assert_eq!(output.stdout_str(), "b'\\x00\\x00\\x00\\x00\\x00'\n22\n");
+    })
+}
+
+#[test]
+fn python_nanosleep_interrupted_by_signal() {
+    use tokio::io::AsyncBufReadExt;
+    Runtime::new().unwrap().block_on(async {
+        let mut container = common()
+            .await
+            .arg("python")
+            .arg("-u")
+            .arg("-c")
+            .arg(
+                r"
+import ctypes, signal
+libc = ctypes.CDLL(None, use_errno=True)
+signal.signal(signal.SIGUSR1, lambda sig, frame: print('handled'))
+duration = (ctypes.c_long * 2)(30, 0)
+print('ready')
+result = libc.nanosleep(duration, None)
+print(result, ctypes.get_errno())
+",
+            )
+            .spawn()
+            .unwrap();
+        let mut stdout = container.take_stdout().unwrap().unwrap();
+        let mut line = String::new();
+        stdout.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "ready\n");
+
+        // Give it a moment to begin sleeping
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+        container.signal(libc::SIGUSR1);
+        let mut lines = Vec::new();
+        for _ in 0..2 {
+            line.clear();
+            stdout.read_line(&mut line).await.unwrap();
+            lines.push(line.clone());
+        }
+        assert_eq!(lines, vec!["handled\n", "-1 4\n"]);
+        assert!(container.wait().await.unwrap().success());
+    })
+}
+
+#[test]
+fn python_emulated_calls_restart_after_signal() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .arg("python")
+            .arg("-c")
+            .arg(
+                r"
+import ctypes, signal
+libc = ctypes.CDLL(None, use_errno=True)
+count = 0
+def handler(sig, frame):
+    global count
+    count += 1
+signal.signal(signal.SIGALRM, handler)
+signal.siginterrupt(signal.SIGALRM, False)
+signal.setitimer(signal.ITIMER_REAL, 0.001, 0.001)
+buf = ctypes.create_string_buffer(4096)
+failures = 0
+while count < 20:
+    for call in (lambda: libc.getrandom(buf, 64, 0), lambda: libc.uname(buf)):
+        if call() < 0:
+            failures += 1
+signal.setitimer(signal.ITIMER_REAL, 0)
+print(failures)
+",
+            )
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "0\n");
+    })
+}
+
+#[test]
+fn python_openat2_resolve_flags() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .arg("python")
+            .arg("-c")
+            .arg(
+                r"
+import ctypes
+libc = ctypes.CDLL(None, use_errno=True)
+class OpenHow(ctypes.Structure):
+    _fields_ = [(name, ctypes.c_uint64) for name in ('flags', 'mode', 'resolve')]
+def openat2(path, resolve):
+    how = OpenHow(0, 0, resolve)
+    fd = libc.syscall(437, -100, path.encode(), ctypes.byref(how), ctypes.sizeof(how))
+    return 'ok' if fd >= 0 else ctypes.get_errno()
+print(openat2('/usr/local/bin/python3', 0),
+      openat2('/usr/local/bin/python3', 0x04),
+      openat2('/usr/local/bin/python3.10', 0x04),
+      openat2('/etc/passwd', 0x08),
+      openat2('/etc/passwd', 0x10))
+",
+            )
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "ok 40 ok 18 22\n");
+    })
+}
+
+#[test]
+fn python_cpu_count() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .cpus(2)
+            .arg("python")
+            .arg("-c")
+            .arg(
+                r"
+import os
+print(sorted(os.sched_getaffinity(0)), os.cpu_count())
+",
+            )
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "[0, 1] 2\n");
+    })
+}
+
+#[test]
+fn python_sched_yield() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .arg("python")
+            .arg("-c")
+            .arg(
+                r"
+import os
+for _ in range(100000):
+    os.sched_yield()
+os.sched_setscheduler(0, os.SCHED_BATCH, os.sched_param(0))
+print(os.sched_getscheduler(0), os.sched_getparam(0).sched_priority)
+for policy, priority in ((os.SCHED_FIFO, 10), (os.SCHED_OTHER, 1), (99, 0)):
+    try:
+        os.sched_setscheduler(0, policy, os.sched_param(priority))
+    except OSError as e:
+        print(e.errno)
+",
+            )
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "0 0\n1\n22\n22\n");
+    })
+}
+
+#[test]
+fn python_proc_self_fd() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .arg("python")
+            .arg("-c")
+            .arg(
+                r"
+import os
+a, b = open('/etc/passwd'), open('/etc/group')
+fds = os.listdir('/proc/self/fd')
+print(all(str(f.fileno()) in fds for f in (a, b)),
+      os.readlink('/proc/self/fd/%d' % a.fileno()),
+      os.readlink('/dev/fd/%d' % b.fileno()))
+closed = str(b.fileno())
+b.close()
+print(closed in os.listdir('/proc/self/fd'))
+",
+            )
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "True /etc/passwd /etc/group\nFalse\n");
+    })
+}
+
+#[test]
+fn python_close_on_exec() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .arg("python")
+            .arg("-c")
+            .arg(
+                r"
+import os, sys
+after_exec = '''
+import errno, os, sys
+closed, kept = sys.argv[1:]
+fds = os.listdir('/proc/self/fd')
+print(closed in fds, kept in fds, os.readlink('/proc/self/fd/' + kept))
+try:
+    os.fstat(int(closed))
+except OSError as e:
+    print(e.errno == errno.EBADF)
+'''
+closed = os.dup2(os.open('/etc/group', os.O_RDONLY), 20, inheritable=False)
+kept = os.open('/etc/passwd', os.O_RDONLY)
+os.set_inheritable(kept, True)
+os.execv(sys.executable, [sys.executable, '-c', after_exec, str(closed), str(kept)])
+",
+            )
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "False True /etc/passwd\nTrue\n");
+    })
+}
+
+#[test]
+fn python_replace_stdio_fds() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .arg("python")
+            .arg("-c")
+            .arg(
+                r"
+import os
+os.close(1)
+os.dup2(os.open('/etc/passwd', os.O_RDONLY), 2)
+out = os.open('/dev/stdout', os.O_WRONLY)
+err = os.open('/dev/stderr', os.O_WRONLY)
+os.write(out, b'%d out\n' % out)
+os.write(err, b'err\n')
+",
+            )
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "1 out\n");
+        assert_eq!(output.stderr_str(), "err\n");
+    })
+}
+
+#[test]
+fn python_fcntl() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .arg("python")
+            .arg("-c")
+            .arg(
+                r"
+import errno, fcntl, os
+fd = os.open('/etc/passwd', os.O_RDONLY)
+fcntl.fcntl(fd, fcntl.F_SETFD, 0)
+before = fcntl.fcntl(fd, fcntl.F_GETFD)
+fcntl.fcntl(fd, fcntl.F_SETFD, fcntl.FD_CLOEXEC)
+after = fcntl.fcntl(fd, fcntl.F_GETFD)
+flags = fcntl.fcntl(fd, fcntl.F_GETFL)
+fcntl.fcntl(fd, fcntl.F_SETFL, flags | os.O_NONBLOCK)
+nonblock = fcntl.fcntl(fd, fcntl.F_GETFL) & os.O_NONBLOCK != 0
+print(before, after, nonblock)
+dup = fcntl.fcntl(fd, fcntl.F_DUPFD, 10)
+print(dup >= 10, os.readlink('/proc/self/fd/%d' % dup), os.read(dup, 5))
+try:
+    fcntl.fcntl(fd, 9999)
+except OSError as e:
+    print(e.errno == errno.EINVAL)
+memfd = os.memfd_create('sealed', os.MFD_ALLOW_SEALING)
+fcntl.fcntl(memfd, fcntl.F_ADD_SEALS, fcntl.F_SEAL_GROW)
+print(fcntl.fcntl(memfd, fcntl.F_GET_SEALS) == fcntl.F_SEAL_GROW)
+r, w = os.pipe()
+fcntl.fcntl(w, fcntl.F_SETPIPE_SZ, 65536 * 2)
+print(fcntl.fcntl(w, fcntl.F_GETPIPE_SZ))
+",
+            )
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            output.stdout_str(),
+            "0 1 True\nTrue /etc/passwd b'root:'\nTrue\nTrue\n131072\n"
+        );
+    })
+}
+
+#[test]
+fn python_getrandom() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .arg("python")
+            .arg("-c")
+            .arg(
+                r"
+import os
+print(os.getrandom(0), len(os.getrandom(64)), len(os.getrandom(16, os.GRND_NONBLOCK)))
+big = os.getrandom(100000)
+print(len(big), len(set(big)) > 200)
+try:
+    os.getrandom(16, 0x80)
+except OSError as e:
+    print(e.errno == 22)
+",
+            )
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "b'' 64 16\n100000 True\nTrue\n");
+    })
+}
+
+#[test]
+fn python_make_overlay_directories() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .arg("python")
+            .arg("-c")
+            .arg(
+                r"
+import os
+os.makedirs('/run/build/x/y')
+print(oct(os.stat('/run/build/x/y').st_mode))
+os.umask(0o077)
+os.mkdir('/run/private')
+print(oct(os.stat('/run/private').st_mode))
+print(os.listdir('/run/build'), os.listdir('/run/build/x'))
+",
+            )
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "0o40755\n0o40700\n['x'] ['y']\n");
+    })
+}
+
+#[test]
+fn python_tmpfs() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .tmpfs("/tmp", Some(1000))
+            .arg("python")
+            .arg("-c")
+            .arg(
+                r"
+import errno, os, shutil
+os.mkdir('/tmp/dir')
+with open('/tmp/dir/a', 'w') as f:
+    f.write('x' * 1000)
+print(os.listdir('/tmp'), os.listdir('/tmp/dir'))
+try:
+    open('/tmp/b', 'w')
+except OSError as e:
+    print(e.errno == errno.ENOSPC)
+shutil.rmtree('/tmp/dir')
+with open('/tmp/b', 'w') as f:
+    f.write('more')
+print(os.listdir('/tmp'), open('/tmp/b').read())
+",
+            )
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "['dir'] ['a']\nTrue\n['b'] more\n");
+    })
+}
+
+#[test]
+fn python_tmpfs_write_past_limit() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .tmpfs("/tmp", Some(8192))
+            .arg("python")
+            .arg("-c")
+            .arg(
+                r"
+import errno, os
+fd = os.open('/tmp/a', os.O_WRONLY | os.O_CREAT)
+written = 0
+try:
+    while True:
+        written += os.write(fd, b'x' * 1000)
+except OSError as e:
+    print(e.errno == errno.ENOSPC, written, os.stat('/tmp/a').st_size)
+",
+            )
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "True 8192 8192\n");
+    })
+}
+
+#[test]
+fn python_pipe() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .arg("python")
+            .arg("-c")
+            .arg(
+                r"
+import errno, fcntl, os
+r, w = os.pipe()
+os.write(w, b'through the pipe')
+print(os.read(r, 100))
+r2, w2 = os.pipe2(os.O_CLOEXEC | os.O_NONBLOCK)
+print(fcntl.fcntl(r2, fcntl.F_GETFD), fcntl.fcntl(w2, fcntl.F_GETFL) & os.O_NONBLOCK != 0)
+try:
+    os.read(r2, 1)
+except BlockingIOError:
+    print('empty')
+os.close(w2)
+print(os.read(r2, 1))
+try:
+    os.pipe2(0x1)
+except OSError as e:
+    print(e.errno == errno.EINVAL)
+",
+            )
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            output.stdout_str(),
+            "b'through the pipe'\n1 True\nempty\nb''\nTrue\n"
+        );
+    })
+}
+
+#[test]
+fn python_raw_clock_gettime() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .arg("python")
+            .arg("-c")
+            .arg(
+                r"
+import ctypes, time
+class timespec(ctypes.Structure):
+    _fields_ = [('tv_sec', ctypes.c_long), ('tv_nsec', ctypes.c_long)]
+libc = ctypes.CDLL(None, use_errno=True)
+ts = timespec()
+result = libc.syscall(228, 0, ctypes.byref(ts))
+print(result, ctypes.get_errno(), abs(ts.tv_sec - int(time.time())) < 60)
+",
+            )
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "0 0 True\n");
+    })
+}
+
+#[test]
+fn python_lseek() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .arg("python")
+            .arg("-c")
+            .arg(
+                r"
+import errno, os
+fd = os.open('/etc/passwd', os.O_RDONLY)
+size = os.fstat(fd).st_size
+print(os.lseek(fd, 5, os.SEEK_SET), os.read(fd, 1))
+print(os.lseek(fd, 2, os.SEEK_CUR), os.read(fd, 1))
+print(os.lseek(fd, 0, os.SEEK_END) == size, os.read(fd, 1))
+try:
+    os.lseek(1, 0, os.SEEK_CUR)
+except OSError as e:
+    print(e.errno == errno.ESPIPE)
+",
+            )
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "5 b'x'\n8 b':'\nTrue b''\nTrue\n");
+    })
+}
+
+#[test]
+fn python_abort() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .arg("python")
+            .arg("-c")
+            .arg(
+                r"
+import errno, os
+print(os.kill(os.getpid(), 0), flush=True)
+try:
+    os.kill(os.getpid() + 1, 0)
+except OSError as e:
+    print(e.errno == errno.ESRCH, flush=True)
+os.abort()
+",
+            )
+            .output()
+            .await
+            .unwrap();
+        assert!(!output.status.success());
+        assert_eq!(output.status.code(), None);
+        assert_eq!(output.status.signal(), Some(libc::SIGABRT));
+        assert_eq!(output.stdout_str(), "None\nTrue\n");
+    })
+}
+
+#[test]
+fn python_unix_socket() {
+    Runtime::new().unwrap().block_on(async {
+        let (mut local, remote) = SharedStream::pair().unwrap();
+        local.write_all(b"pong").unwrap();
+        let output = common()
+            .await
+            .mount("/run/host.sock", &remote)
+            .arg("python")
+            .arg("-c")
+            .arg(
+                r"
+import errno, os, socket
+s = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+s.connect('/run/host.sock')
+os.write(s.fileno(), b'ping')
+print(os.read(s.fileno(), 4))
+for path, err in (
+    ('/etc/passwd', errno.ECONNREFUSED),
+    ('/run/missing', errno.ENOENT),
+    ('\0host.sock', errno.ECONNREFUSED),
+):
+    try:
+        socket.socket(socket.AF_UNIX, socket.SOCK_STREAM).connect(path)
+    except OSError as e:
+        print(e.errno == err)
+try:
+    socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+except OSError as e:
+    print(e.errno == errno.EAFNOSUPPORT)
+",
+            )
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "b'pong'\nTrue\nTrue\nTrue\nTrue\n");
+        let mut buf = [0u8; 4];
+        local.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+    })
+}
+
+#[test]
+fn python_sysinfo_memory() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .memory_limit(768 << 20)
+            .arg("python")
+            .arg("-c")
+            .arg(
+                r"
+import os
+print(os.sysconf('SC_PHYS_PAGES') * os.sysconf('SC_PAGE_SIZE'))
+print(os.sysconf('SC_AVPHYS_PAGES') * os.sysconf('SC_PAGE_SIZE'))
+",
+            )
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "805306368\n805306368\n");
+    })
+}
+
+#[test]
+fn python_prctl_name() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .arg("python")
+            .arg("-c")
+            .arg(
+                r"
+import ctypes, errno
+libc = ctypes.CDLL(None, use_errno=True)
+print(libc.prctl(15, b'worker-thread-name', 0, 0, 0))
+name = ctypes.create_string_buffer(16)
+print(libc.prctl(16, name, 0, 0, 0), name.value)
+print(libc.prctl(0x7fff, 0, 0, 0, 0), ctypes.get_errno() == errno.EINVAL)
+fs = ctypes.c_ulong()
+print(libc.syscall(158, 0x1003, ctypes.byref(fs)), fs.value != 0)
+",
+            )
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            output.stdout_str(),
+            "0\n0 b'worker-thread-'\n-1 True\n0 True\n"
+        );
+    })
+}
+
+#[test]
+fn python_statx() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .arg("python")
+            .arg("-c")
+            .arg(
+                r"
+import ctypes, errno, os, struct
+libc = ctypes.CDLL(None, use_errno=True)
+AT_FDCWD, AT_SYMLINK_NOFOLLOW, AT_EMPTY_PATH = -100, 0x100, 0x1000
+def statx(dirfd, path, flags):
+    buf = ctypes.create_string_buffer(256)
+    if libc.syscall(332, dirfd, path, flags, 0x7ff, buf) != 0:
+        return ctypes.get_errno()
+    mask, = struct.unpack_from('I', buf, 0)
+    mode, = struct.unpack_from('H', buf, 28)
+    ino, size = struct.unpack_from('QQ', buf, 32)
+    return mask, oct(mode), ino, size
+st = os.stat('/usr/local/bin/python3')
+print(statx(AT_FDCWD, b'/usr/local/bin/python3', 0) == (0x7ff, oct(st.st_mode), st.st_ino, st.st_size))
+print(statx(AT_FDCWD, b'/usr/local/bin/python3', AT_SYMLINK_NOFOLLOW)[1])
+print(statx(AT_FDCWD, b'/etc/passwd', 0)[1])
+fd = os.open('/etc', os.O_RDONLY)
+print(statx(fd, b'passwd', 0) == statx(AT_FDCWD, b'/etc/passwd', 0))
+print(statx(fd, b'', AT_EMPTY_PATH)[1])
+print(statx(fd, b'', 0) == errno.ENOENT, statx(fd, b'missing', 0) == errno.ENOENT)
+",
+            )
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            output.stdout_str(),
+            "True\n0o120777\n0o100644\nTrue\n0o40755\nTrue True\n"
+        );
+    })
+}
+
+#[test]
+fn python_access() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .arg("python")
+            .arg("-c")
+            .arg(
+                r"
+import os
+fd = os.open('/etc', os.O_RDONLY)
+print(os.access('/bin/sh', os.X_OK), os.access('/etc', os.X_OK, dir_fd=fd))
+print(os.access('passwd', os.R_OK, dir_fd=fd), os.access('passwd', os.X_OK, dir_fd=fd))
+print(os.access('/etc/passwd', os.W_OK), os.access('missing', os.F_OK, dir_fd=fd))
+print(os.access('passwd', os.F_OK, dir_fd=fd, effective_ids=True))
+",
+            )
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            output.stdout_str(),
+            "True True\nTrue False\nFalse False\nTrue\n"
+        );
+    })
+}
+
+#[test]
+fn python_openat_dir() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .arg("python")
+            .arg("-c")
+            .arg(
+                r"
+import os
+fd = os.open('/etc', os.O_RDONLY)
+rel = os.read(os.open('passwd', os.O_RDONLY, dir_fd=fd), 4096)
+print(rel == open('/etc/passwd', 'rb').read())
+print(len(os.listdir(os.open('.', os.O_RDONLY, dir_fd=fd))) > 0)
+os.close(fd)
+try:
+    os.open('passwd', os.O_RDONLY, dir_fd=fd)
+except OSError as e:
+    print(e.strerror)
+print(os.read(os.open('/etc/passwd', os.O_RDONLY, dir_fd=fd), 4) == rel[:4])
+",
+            )
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            output.stdout_str(),
+            "True\nTrue\nBad file descriptor\nTrue\n"
+        );
+    })
+}
+
+#[test]
+fn python_getcwd_symlink() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .arg("python")
+            .arg("-c")
+            .arg(
+                r"
+import ctypes, errno, os
+os.chdir('/var/lock')
+print(os.getcwd())
+os.chdir('..')
+print(os.getcwd())
+os.chdir('lock/../../usr/./lib')
+print(os.getcwd())
+libc = ctypes.CDLL(None, use_errno=True)
+libc.getcwd.restype = ctypes.c_char_p
+buf = ctypes.create_string_buffer(9)
+print(libc.getcwd(buf, 8), ctypes.get_errno() == errno.ERANGE)
+print(libc.getcwd(buf, 9))
+",
+            )
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            output.stdout_str(),
+            "/run/lock\n/run\n/usr/lib\nNone True\nb'/usr/lib'\n"
+        );
+    })
+}
+
+#[test]
+fn python_extra_passthrough() {
+    Runtime::new().unwrap().block_on(async {
+        let getrandom = libc::SYS_getrandom as u64;
+        for &passthrough in &[false, true] {
+            let emulated = Arc::new(Mutex::new(Vec::new()));
+            let handler_emulated = emulated.clone();
+            let mut builder = common().await.log_handler(move |_task, _level, message| {
+                if let LogMessage::Emulated(call) = message {
+                    handler_emulated.lock().unwrap().push(call.nr as u64);
+                }
+            });
+            if passthrough {
+                builder = builder.extra_passthrough_syscalls(&[getrandom]);
+            }
+            let output = builder
+                .arg("python")
+                .arg("-c")
+                .arg("import os; print(len(os.urandom(16)))")
+                .output()
+                .await
+                .unwrap();
+            assert!(output.status.success());
+            assert_eq!(output.stdout_str(), "16\n");
+            let emulated = emulated.lock().unwrap();
+            assert_eq!(emulated.contains(&getrandom), !passthrough);
+        }
+    })
+}
+
+#[test]
+fn python_stat_ptrace_requests() {
+    // A benchmark for emulated syscalls, which are mostly ptrace requests.
+    // The difference between two runs is what the stat() calls cost.
+    Runtime::new().unwrap().block_on(async {
+        const STATS: usize = 100;
+        let mut totals = Vec::new();
+        for &count in &[0, STATS] {
+            let requests = Arc::new(Mutex::new(None));
+            let handler_requests = requests.clone();
+            let output = common()
+                .await
+                .log_handler(move |_task, _level, message| {
+                    if let LogMessage::PtraceRequests(count) = message {
+                        *handler_requests.lock().unwrap() = Some(count);
+                    }
+                })
+                .arg("python")
+                .arg("-c")
+                .arg(format!(
+                    "import os\nfor _ in range({}):\n    os.stat('/etc/passwd')",
+                    count
+                ))
+                .output()
+                .await
+                .unwrap();
+            assert!(output.status.success());
+            let requests = requests.lock().unwrap().expect("request count");
+            totals.push(requests);
+        }
+        let per_stat = totals[1].saturating_sub(totals[0]) / STATS;
+        assert!(per_stat <= 30, "{} ptrace requests per stat()", per_stat);
+    })
+}